@@ -0,0 +1,244 @@
+//! NT status codes, what every SMB response says about how the request
+//! went. Both dialects carry them in their header, so they live here
+//! rather than in either one.
+
+use std::fmt;
+use std::io;
+
+/// An NTSTATUS. Any value the wire carries is kept as is, the codes a
+/// server is likely to hand back are the `STATUS_*` consts.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct NtStatus(pub u32);
+
+/// The top two bits of a status.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Success,
+    Informational,
+    Warning,
+    Error,
+}
+
+impl NtStatus {
+    pub fn severity(self) -> Severity {
+        match self.0 >> 30 {
+            0 => Severity::Success,
+            1 => Severity::Informational,
+            2 => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Set for codes defined by someone other than Microsoft.
+    pub fn is_customer(self) -> bool {
+        self.0 & 0x2000_0000 != 0
+    }
+
+    /// Which part of the system the code belongs to, 0 for most of the
+    /// ones an SMB server sends.
+    pub fn facility(self) -> u16 {
+        ((self.0 >> 16) & 0x0FFF) as u16
+    }
+
+    pub fn code(self) -> u16 {
+        self.0 as u16
+    }
+
+    /// NT_SUCCESS, successes and informational statuses.
+    pub fn is_success(self) -> bool {
+        matches!(self.severity(), Severity::Success | Severity::Informational)
+    }
+
+    pub fn is_error(self) -> bool {
+        self.severity() == Severity::Error
+    }
+
+    /// The status a failed syscall that set `errno` ends up as.
+    pub fn from_errno(errno: i32) -> NtStatus {
+        match errno {
+            libc::EPERM | libc::EACCES => STATUS_ACCESS_DENIED,
+            libc::ENOENT => STATUS_OBJECT_NAME_NOT_FOUND,
+            libc::ENOTDIR => STATUS_NOT_A_DIRECTORY,
+            libc::EISDIR => STATUS_FILE_IS_A_DIRECTORY,
+            libc::EEXIST => STATUS_OBJECT_NAME_COLLISION,
+            libc::ENOTEMPTY => STATUS_DIRECTORY_NOT_EMPTY,
+            libc::ENOSPC | libc::EFBIG | libc::EDQUOT => STATUS_DISK_FULL,
+            libc::EROFS => STATUS_MEDIA_WRITE_PROTECTED,
+            libc::ENAMETOOLONG => STATUS_NAME_TOO_LONG,
+            libc::ELOOP | libc::EMLINK => STATUS_TOO_MANY_LINKS,
+            libc::EXDEV => STATUS_NOT_SAME_DEVICE,
+            libc::EBUSY | libc::ETXTBSY => STATUS_SHARING_VIOLATION,
+            libc::EAGAIN => STATUS_NETWORK_BUSY,
+            libc::EINTR => STATUS_RETRY,
+            libc::EBADF | libc::ESTALE => STATUS_INVALID_HANDLE,
+            libc::EINVAL | libc::ESPIPE => STATUS_INVALID_PARAMETER,
+            libc::ENFILE | libc::EMFILE => STATUS_TOO_MANY_OPENED_FILES,
+            libc::ENOMEM => STATUS_NO_MEMORY,
+            libc::EIO => STATUS_UNEXPECTED_IO_ERROR,
+            libc::ENODEV | libc::ENXIO => STATUS_NO_SUCH_DEVICE,
+            libc::ENOSYS | libc::EOPNOTSUPP => STATUS_NOT_SUPPORTED,
+            libc::ETIMEDOUT => STATUS_IO_TIMEOUT,
+            libc::EPIPE => STATUS_PIPE_BROKEN,
+            libc::ECONNRESET => STATUS_CONNECTION_RESET,
+            libc::ECONNREFUSED => STATUS_CONNECTION_REFUSED,
+            libc::ECONNABORTED => STATUS_CONNECTION_ABORTED,
+            libc::EHOSTUNREACH => STATUS_HOST_UNREACHABLE,
+            libc::ENETUNREACH => STATUS_NETWORK_UNREACHABLE,
+            _ => STATUS_UNSUCCESSFUL,
+        }
+    }
+}
+
+impl From<io::ErrorKind> for NtStatus {
+    fn from(kind: io::ErrorKind) -> NtStatus {
+        use io::ErrorKind;
+        match kind {
+            ErrorKind::NotFound => STATUS_OBJECT_NAME_NOT_FOUND,
+            ErrorKind::PermissionDenied => STATUS_ACCESS_DENIED,
+            ErrorKind::AlreadyExists => STATUS_OBJECT_NAME_COLLISION,
+            ErrorKind::NotADirectory => STATUS_NOT_A_DIRECTORY,
+            ErrorKind::IsADirectory => STATUS_FILE_IS_A_DIRECTORY,
+            ErrorKind::DirectoryNotEmpty => STATUS_DIRECTORY_NOT_EMPTY,
+            ErrorKind::StorageFull | ErrorKind::FileTooLarge | ErrorKind::QuotaExceeded => {
+                STATUS_DISK_FULL
+            }
+            ErrorKind::ReadOnlyFilesystem => STATUS_MEDIA_WRITE_PROTECTED,
+            ErrorKind::InvalidFilename => STATUS_OBJECT_NAME_INVALID,
+            ErrorKind::InvalidInput => STATUS_INVALID_PARAMETER,
+            ErrorKind::TooManyLinks => STATUS_TOO_MANY_LINKS,
+            ErrorKind::CrossesDevices => STATUS_NOT_SAME_DEVICE,
+            ErrorKind::ResourceBusy | ErrorKind::ExecutableFileBusy => STATUS_SHARING_VIOLATION,
+            ErrorKind::WouldBlock => STATUS_NETWORK_BUSY,
+            ErrorKind::Interrupted => STATUS_RETRY,
+            ErrorKind::StaleNetworkFileHandle => STATUS_INVALID_HANDLE,
+            ErrorKind::OutOfMemory => STATUS_NO_MEMORY,
+            ErrorKind::Unsupported => STATUS_NOT_SUPPORTED,
+            ErrorKind::TimedOut => STATUS_IO_TIMEOUT,
+            ErrorKind::UnexpectedEof => STATUS_END_OF_FILE,
+            ErrorKind::BrokenPipe => STATUS_PIPE_BROKEN,
+            ErrorKind::ConnectionReset => STATUS_CONNECTION_RESET,
+            ErrorKind::ConnectionRefused => STATUS_CONNECTION_REFUSED,
+            ErrorKind::ConnectionAborted => STATUS_CONNECTION_ABORTED,
+            ErrorKind::HostUnreachable => STATUS_HOST_UNREACHABLE,
+            ErrorKind::NetworkUnreachable => STATUS_NETWORK_UNREACHABLE,
+            _ => STATUS_UNSUCCESSFUL,
+        }
+    }
+}
+
+/// The closest status to what went wrong on the local filesystem, going
+/// by errno when there is one since it says more than the ErrorKind does.
+impl From<&io::Error> for NtStatus {
+    fn from(e: &io::Error) -> NtStatus {
+        match e.raw_os_error() {
+            Some(errno) => NtStatus::from_errno(errno),
+            None => e.kind().into(),
+        }
+    }
+}
+
+impl From<io::Error> for NtStatus {
+    fn from(e: io::Error) -> NtStatus {
+        NtStatus::from(&e)
+    }
+}
+
+impl From<u32> for NtStatus {
+    fn from(status: u32) -> NtStatus {
+        NtStatus(status)
+    }
+}
+
+impl From<NtStatus> for u32 {
+    fn from(status: NtStatus) -> u32 {
+        status.0
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#010X}", self.0),
+        }
+    }
+}
+
+impl fmt::Debug for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NtStatus({self})")
+    }
+}
+
+macro_rules! statuses {
+    ($($name:ident = $value:literal,)*) => {
+        $(pub const $name: NtStatus = NtStatus($value);)*
+
+        impl NtStatus {
+            /// What ntstatus.h calls it, if it's a code we know.
+            pub fn name(self) -> Option<&'static str> {
+                match self.0 {
+                    $($value => Some(stringify!($name)),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+mod codes;
+pub use codes::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        assert_eq!(STATUS_SUCCESS.severity(), Severity::Success);
+        assert_eq!(STATUS_PENDING.severity(), Severity::Success);
+        assert_eq!(
+            STATUS_OBJECT_NAME_EXISTS.severity(),
+            Severity::Informational
+        );
+        assert_eq!(STATUS_BUFFER_OVERFLOW.severity(), Severity::Warning);
+        assert_eq!(STATUS_ACCESS_DENIED.severity(), Severity::Error);
+        assert!(STATUS_NOTIFY_ENUM_DIR.is_success());
+        assert!(!STATUS_NO_MORE_FILES.is_success());
+        assert!(!STATUS_NO_MORE_FILES.is_error());
+        assert!(STATUS_LOGON_FAILURE.is_error());
+
+        let status = STATUS_SMB_NO_PREAUTH_INTEGRITY_HASH_OVERLAP;
+        assert_eq!(status.facility(), 0x5D);
+        assert_eq!(status.code(), 0);
+        assert!(!status.is_customer());
+        assert!(NtStatus(0xE001_0001).is_customer());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(STATUS_ACCESS_DENIED.to_string(), "STATUS_ACCESS_DENIED");
+        assert_eq!(NtStatus(0xC0DE_0001).to_string(), "0xC0DE0001");
+        assert_eq!(format!("{:?}", STATUS_SUCCESS), "NtStatus(STATUS_SUCCESS)");
+    }
+
+    #[test]
+    fn io_errors() {
+        assert_eq!(
+            NtStatus::from(io::ErrorKind::NotFound),
+            STATUS_OBJECT_NAME_NOT_FOUND
+        );
+        assert_eq!(
+            NtStatus::from(&io::Error::from_raw_os_error(libc::ENOTEMPTY)),
+            STATUS_DIRECTORY_NOT_EMPTY
+        );
+        assert_eq!(
+            NtStatus::from(io::Error::other("something else")),
+            STATUS_UNSUCCESSFUL
+        );
+        assert_eq!(
+            NtStatus::from_errno(libc::EROFS),
+            STATUS_MEDIA_WRITE_PROTECTED
+        );
+    }
+}
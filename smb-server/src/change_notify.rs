@@ -0,0 +1,428 @@
+//! CHANGE_NOTIFY, answered from inotify events on the directories being watched.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use inotify::{EventMask, EventOwned, Inotify, WatchDescriptor, WatchMask, Watches};
+use smb2::message::{FileNotifyInformation, SmbBody, SmbChangeNotify, SmbChangeNotifyResponse};
+use smb2::message::{SmbMessage, SmbMessageHeader, SMB2_WATCH_TREE};
+use smb2::message::{FILE_ACTION_ADDED, FILE_ACTION_MODIFIED, FILE_ACTION_REMOVED};
+use smb2::message::{FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME};
+use smb2::message::{FILE_NOTIFY_CHANGE_ATTRIBUTES, FILE_NOTIFY_CHANGE_CREATION};
+use smb2::message::{FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME};
+use smb2::message::{FILE_NOTIFY_CHANGE_LAST_ACCESS, FILE_NOTIFY_CHANGE_LAST_WRITE};
+use smb2::message::{FILE_NOTIFY_CHANGE_SECURITY, FILE_NOTIFY_CHANGE_SIZE};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Mutex;
+
+use crate::create::READ_ACCESS;
+use crate::status::*;
+use crate::{cancels, error_message, fs, HandlerResult, Server};
+
+/// How many changes to hold on to for a directory nobody's currently waiting
+/// on, before giving up and telling the client to go and look for itself.
+const MAX_BUFFERED_CHANGES: usize = 1024;
+
+// everything a client could ask about that inotify can tell us.
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ONLYDIR);
+
+/// A CHANGE_NOTIFY that went async, waiting for something to change.
+struct PendingNotify {
+    connection_id: u64,
+    /// What the final response goes out with, already async.
+    header: SmbMessageHeader,
+    output_buffer_length: u32,
+}
+
+/// A directory open that's had CHANGE_NOTIFY sent on it. Changes are
+/// kept from then on, even between requests, until the open is closed.
+struct Watcher {
+    path: PathBuf,
+    tree: bool,
+    completion_filter: u32,
+    changes: Vec<FileNotifyInformation>,
+    /// More changed than we kept track of.
+    overflowed: bool,
+    pending: VecDeque<PendingNotify>,
+}
+
+impl Watcher {
+    /// What `path` is called from where this watcher is looking,
+    /// or None if it's out of sight.
+    fn name_for(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.path).ok()?;
+        let depth = relative.components().count();
+        (depth == 1 || self.tree && depth > 1).then(|| fs::share_relative_name(&self.path, path))
+    }
+
+    fn push(&mut self, action: u32, file_name: String) {
+        if self.overflowed {
+            return;
+        }
+        if self.changes.len() == MAX_BUFFERED_CHANGES {
+            self.changes.clear();
+            self.overflowed = true;
+            return;
+        }
+        self.changes
+            .push(FileNotifyInformation { action, file_name });
+    }
+
+    /// Hands over everything that's changed, in a response that
+    /// fits in `output_buffer_length`, and starts afresh.
+    fn take_changes(&mut self, output_buffer_length: u32) -> HandlerResult {
+        let changes = std::mem::take(&mut self.changes);
+        if std::mem::take(&mut self.overflowed) {
+            return Err(STATUS_NOTIFY_ENUM_DIR.into());
+        }
+        let buffer = FileNotifyInformation::list_to_vec(&changes);
+        if buffer.len() > output_buffer_length as usize {
+            return Err(STATUS_NOTIFY_ENUM_DIR.into());
+        }
+        Ok(SmbBody::ChangeNotifyResponse(SmbChangeNotifyResponse {
+            size: 9,
+            output_buffer_offset: SmbChangeNotifyResponse::OUTPUT_BUFFER_OFFSET,
+            output_buffer_length: buffer.len() as u32,
+            buffer,
+        }))
+    }
+}
+
+#[derive(Default)]
+pub struct ChangeNotifier {
+    /// None when there's no inotify to be had, so nothing can be watched.
+    watches: Option<Watches>,
+    /// Which directory each inotify watch is on.
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    // keyed by the volatile part of the file id.
+    watchers: HashMap<u64, Watcher>,
+}
+
+impl ChangeNotifier {
+    /// Starts watching `dir`, and everything under it too if `tree`.
+    fn watch(&mut self, dir: &Path, tree: bool) {
+        let Some(watches) = &mut self.watches else {
+            return;
+        };
+        match watches.add(dir, WATCH_MASK) {
+            Ok(wd) => {
+                self.dirs.insert(wd, dir.to_path_buf());
+            }
+            Err(e) => println!("couldn't watch {dir:?}: {e}"),
+        }
+        if !tree {
+            return;
+        }
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            // symlinks could go anywhere, including around in circles.
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                self.watch(&entry.path(), true);
+            }
+        }
+    }
+
+    /// Whether anybody is still interested in what happens in `dir`.
+    fn is_watched(&self, dir: &Path) -> bool {
+        self.watchers
+            .values()
+            .any(|watcher| dir == watcher.path || watcher.tree && dir.starts_with(&watcher.path))
+    }
+
+    /// Stops watching the directories nobody is interested in any more.
+    fn prune(&mut self) {
+        let unwatched: Vec<_> = self
+            .dirs
+            .iter()
+            .filter(|(_, dir)| !self.is_watched(dir))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in unwatched {
+            self.dirs.remove(&wd);
+            if let Some(watches) = &mut self.watches {
+                // it's already gone if the directory was deleted.
+                let _ = watches.remove(wd);
+            }
+        }
+    }
+}
+
+/// Hands inotify events to `server` as they happen, for as long as
+/// the server is up. Without inotify, CHANGE_NOTIFY isn't supported.
+pub async fn watch_changes(server: Arc<Mutex<Server>>) {
+    let (mut inotify, watches) = match Inotify::init().and_then(|inotify| {
+        let watches = inotify.watches();
+        // SAFETY: the AsyncFd owns the inotify instance, so its fd stays open
+        // and the same for as long as it's registered.
+        Ok((unsafe { AsyncFd::register(inotify) }?, watches))
+    }) {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            println!("no inotify, so nobody gets told about changes: {e}");
+            return;
+        }
+    };
+    server.lock().await.notifier.watches = Some(watches);
+    let mut buffer = [0; 4096];
+    loop {
+        let Ok(mut guard) = inotify.readable_mut().await else {
+            return;
+        };
+        let events = guard.try_io(|inotify| {
+            let events = inotify.get_mut().read_events(&mut buffer)?;
+            Ok(events.map(|event| event.to_owned()).collect::<Vec<_>>())
+        });
+        match events {
+            Ok(Ok(events)) => server.lock().await.handle_changes(events),
+            Ok(Err(e)) => {
+                println!("couldn't read inotify events: {e}");
+                return;
+            }
+            // spurious wakeup, readiness has been cleared.
+            Err(_would_block) => {}
+        }
+    }
+}
+
+impl Server {
+    pub(crate) fn change_notify(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        notify: &SmbChangeNotify,
+    ) -> HandlerResult {
+        let volatile = notify.file_id.volatile;
+        let open = self.open(connection_id, header, notify.file_id)?;
+        if open.file.is_some() {
+            return Err(STATUS_INVALID_PARAMETER.into());
+        }
+        if open.desired_access & READ_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+        let path = open.path.clone();
+        if self.notifier.watches.is_none() {
+            return Err(STATUS_NOT_SUPPORTED.into());
+        }
+
+        if let Entry::Vacant(entry) = self.notifier.watchers.entry(volatile) {
+            let tree = notify.flags & SMB2_WATCH_TREE != 0;
+            entry.insert(Watcher {
+                path: path.clone(),
+                tree,
+                completion_filter: notify.completion_filter,
+                changes: vec![],
+                overflowed: false,
+                pending: VecDeque::new(),
+            });
+            self.notifier.watch(&path, tree);
+        }
+        let watcher = self.notifier.watchers.get_mut(&volatile).unwrap();
+        if watcher.pending.is_empty() && (watcher.overflowed || !watcher.changes.is_empty()) {
+            return watcher.take_changes(notify.output_buffer_length);
+        }
+
+        self.go_async(header);
+        let watcher = self.notifier.watchers.get_mut(&volatile).unwrap();
+        watcher.pending.push_back(PendingNotify {
+            connection_id,
+            header: SmbMessageHeader {
+                // the interim response already handed out credits.
+                credit_request_response: 0,
+                ..header.clone()
+            },
+            output_buffer_length: notify.output_buffer_length,
+        });
+        Err(STATUS_PENDING.into())
+    }
+
+    /// Works out who cares about what just happened on disk, and
+    /// answers anybody that was waiting to hear about it.
+    pub(crate) fn handle_changes(&mut self, events: Vec<EventOwned>) {
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                // the kernel dropped events, so nobody knows what changed.
+                for watcher in self.notifier.watchers.values_mut() {
+                    watcher.changes.clear();
+                    watcher.overflowed = true;
+                }
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                // the directory is gone, and so is its watch.
+                self.notifier.dirs.remove(&event.wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (self.notifier.dirs.get(&event.wd), &event.name) else {
+                continue;
+            };
+            let path = dir.join(name);
+            let is_dir = event.mask.contains(EventMask::ISDIR);
+            let name_filter = if is_dir {
+                FILE_NOTIFY_CHANGE_DIR_NAME
+            } else {
+                FILE_NOTIFY_CHANGE_FILE_NAME
+            };
+            let (action, filter) = if event.mask.contains(EventMask::CREATE) {
+                (FILE_ACTION_ADDED, name_filter)
+            } else if event.mask.contains(EventMask::DELETE) {
+                (FILE_ACTION_REMOVED, name_filter)
+            } else if event.mask.contains(EventMask::MOVED_FROM) {
+                (FILE_ACTION_RENAMED_OLD_NAME, name_filter)
+            } else if event.mask.contains(EventMask::MOVED_TO) {
+                (FILE_ACTION_RENAMED_NEW_NAME, name_filter)
+            } else if event.mask.contains(EventMask::MODIFY) {
+                (
+                    FILE_ACTION_MODIFIED,
+                    FILE_NOTIFY_CHANGE_SIZE | FILE_NOTIFY_CHANGE_LAST_WRITE,
+                )
+            } else if event.mask.contains(EventMask::ATTRIB) {
+                // inotify doesn't say which of these it was.
+                (
+                    FILE_ACTION_MODIFIED,
+                    FILE_NOTIFY_CHANGE_ATTRIBUTES
+                        | FILE_NOTIFY_CHANGE_LAST_WRITE
+                        | FILE_NOTIFY_CHANGE_LAST_ACCESS
+                        | FILE_NOTIFY_CHANGE_CREATION
+                        | FILE_NOTIFY_CHANGE_SECURITY,
+                )
+            } else {
+                continue;
+            };
+
+            let appeared = event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+            if is_dir && appeared && self.notifier.is_watched(&path) {
+                // a new subdirectory of a tree somebody's watching.
+                self.notifier.watch(&path, true);
+            }
+            for watcher in self.notifier.watchers.values_mut() {
+                if watcher.completion_filter & filter == 0 {
+                    continue;
+                }
+                if let Some(file_name) = watcher.name_for(&path) {
+                    watcher.push(action, file_name);
+                }
+            }
+        }
+
+        let ready: Vec<_> = self
+            .notifier
+            .watchers
+            .iter()
+            .filter(|(_, watcher)| {
+                !watcher.pending.is_empty() && (watcher.overflowed || !watcher.changes.is_empty())
+            })
+            .map(|(&volatile, _)| volatile)
+            .collect();
+        for volatile in ready {
+            let watcher = self.notifier.watchers.get_mut(&volatile).unwrap();
+            let pending = watcher.pending.pop_front().unwrap();
+            let message = match watcher.take_changes(pending.output_buffer_length) {
+                Ok(body) => SmbMessage {
+                    header: pending.header,
+                    body,
+                },
+                Err(failure) => {
+                    self.failure_message(pending.connection_id, pending.header, failure)
+                }
+            };
+            self.send(pending.connection_id, message);
+        }
+    }
+
+    /// Fails the waiting CHANGE_NOTIFY `cancel` is for, if that's what it's for.
+    pub(crate) fn cancel_notify(&mut self, connection_id: u64, cancel: &SmbMessageHeader) -> bool {
+        let cancelled = self.notifier.watchers.values_mut().find_map(|watcher| {
+            let i = watcher.pending.iter().position(|pending| {
+                pending.connection_id == connection_id && cancels(cancel, &pending.header)
+            })?;
+            watcher.pending.remove(i)
+        });
+        let Some(pending) = cancelled else {
+            return false;
+        };
+        self.send(
+            pending.connection_id,
+            error_message(pending.header, STATUS_CANCELLED),
+        );
+        true
+    }
+
+    /// Forgets about a directory open that's closing, telling
+    /// anybody still waiting on it that they're not going to hear more.
+    pub(crate) fn stop_watching(&mut self, volatile: u64) {
+        let Some(watcher) = self.notifier.watchers.remove(&volatile) else {
+            return;
+        };
+        for pending in watcher.pending {
+            self.send(
+                pending.connection_id,
+                error_message(pending.header, STATUS_NOTIFY_CLEANUP),
+            );
+        }
+        self.notifier.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher(tree: bool) -> Watcher {
+        Watcher {
+            path: PathBuf::from("/share/dir"),
+            tree,
+            completion_filter: FILE_NOTIFY_CHANGE_FILE_NAME,
+            changes: vec![],
+            overflowed: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn only_tree_watchers_see_below_their_directory() {
+        let (flat, tree) = (watcher(false), watcher(true));
+        let child = Path::new("/share/dir/a.txt");
+        let grandchild = Path::new("/share/dir/sub/b.txt");
+        assert_eq!(flat.name_for(child).as_deref(), Some("a.txt"));
+        assert_eq!(flat.name_for(grandchild), None);
+        assert_eq!(tree.name_for(grandchild).as_deref(), Some("sub\\b.txt"));
+        assert_eq!(tree.name_for(Path::new("/share/other/c.txt")), None);
+        assert_eq!(tree.name_for(Path::new("/share/dir")), None);
+    }
+
+    #[test]
+    fn too_many_changes_means_enum_dir() {
+        let mut watcher = watcher(false);
+        watcher.push(FILE_ACTION_ADDED, "a".into());
+        // doesn't fit in what the client asked for.
+        assert_eq!(
+            watcher.take_changes(4).err(),
+            Some(STATUS_NOTIFY_ENUM_DIR.into())
+        );
+        assert!(watcher.changes.is_empty());
+
+        for _ in 0..=MAX_BUFFERED_CHANGES {
+            watcher.push(FILE_ACTION_ADDED, "a".into());
+        }
+        assert!(watcher.overflowed);
+        assert_eq!(
+            watcher.take_changes(u32::MAX).err(),
+            Some(STATUS_NOTIFY_ENUM_DIR.into())
+        );
+        // and then it's back to normal.
+        watcher.push(FILE_ACTION_REMOVED, "a".into());
+        assert!(watcher.take_changes(u32::MAX).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+//! Compounded requests, several messages sent in one go. Related ones
+//! pick up the session, tree connect and file the one before them used,
+//! which is how a client can CREATE, QUERY_INFO and CLOSE a file without
+//! waiting to find out what FileId it got.
+
+use bytes::Bytes;
+use smb2::message::SmbMessageHeader;
+use smb2::message::{compound_members, InvalidNextCommand, SmbBody, SmbFileId, SmbMessage};
+use smb2::message::{HeaderFlags, SmbMessageHeaderVariant, RELATED_FILE_ID};
+
+use crate::status::*;
+use crate::{error_message, response_header, Server};
+
+/// What the next related operation in a compound carries on with.
+struct Related {
+    session_id: u64,
+    tree_id: u32,
+    file_id: Option<SmbFileId>,
+    status: NtStatus,
+}
+
+/// Fills in what a related operation left for the one before it to decide.
+fn relate(message: &mut SmbMessage, previous: Option<&Related>) -> Result<(), NtStatus> {
+    let previous = previous.ok_or(STATUS_INVALID_PARAMETER)?;
+    // if the CREATE failed, so does everything that was going to use what it opened.
+    if previous.status.is_error() {
+        return Err(previous.status);
+    }
+    message.header.session_id = previous.session_id;
+    if let SmbMessageHeaderVariant::Sync { tree_id } = &mut message.header.variant {
+        *tree_id = previous.tree_id;
+    }
+    if let Some(file_id) = message.body.file_id_mut() {
+        if file_id.persistent == RELATED_FILE_ID && file_id.volatile == RELATED_FILE_ID {
+            *file_id = previous.file_id.ok_or(STATUS_INVALID_PARAMETER)?;
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    /// Runs every message in a PDU in order, returning the responses to go
+    /// back compounded the same way. `encrypted` is the session the PDU was
+    /// encrypted for, if it was, which only vouches for messages on it.
+    pub(crate) fn handle_compound(
+        &mut self,
+        connection_id: u64,
+        buf: &Bytes,
+        encrypted: Option<u64>,
+    ) -> Vec<SmbMessage> {
+        let mut responses = vec![];
+        let mut previous: Option<Related> = None;
+        for member in compound_members(buf) {
+            let member = match member {
+                Ok(member) => member,
+                Err(InvalidNextCommand(rest)) => {
+                    // there's no telling where anything after it starts.
+                    if let Ok((_remaining, header)) = SmbMessageHeader::try_parse(rest) {
+                        let header = response_header(&header, STATUS_SUCCESS);
+                        responses.push(error_message(header, STATUS_INVALID_PARAMETER));
+                    }
+                    break;
+                }
+            };
+            // WRITE payloads will point right into this, rather than being copied out.
+            let member = buf.slice_ref(member);
+            let Ok((_remaining, mut message)) = SmbMessage::try_parse_shared(&member) else {
+                previous = None;
+                if let Ok((_remaining, header)) = SmbMessageHeader::try_parse(&member) {
+                    // a command we don't handle (e.g. FLUSH), or one we couldn't make sense of.
+                    println!("couldn't parse command {:?}", header.command);
+                    let header = response_header(&header, STATUS_SUCCESS);
+                    responses.push(error_message(header, STATUS_NOT_SUPPORTED));
+                    continue;
+                }
+                // without a header, or with a command that doesn't exist, there's no
+                // MessageId to answer or way to find the next one.
+                println!("compound member without a header {:x?}", &member);
+                break;
+            };
+            let related = message
+                .header
+                .flags
+                .contains(HeaderFlags::RELATED_OPERATIONS);
+            if related {
+                if let Err(status) = relate(&mut message, previous.as_ref()) {
+                    let mut header = response_header(&message.header, STATUS_SUCCESS);
+                    header.flags |= HeaderFlags::RELATED_OPERATIONS;
+                    responses.push(error_message(header, status));
+                    continue;
+                }
+            }
+            let encrypted = encrypted == Some(message.header.session_id);
+            if let Err(status) =
+                self.authenticate(connection_id, &message.header, &member, encrypted)
+            {
+                // nothing after it can use what it would have done either.
+                previous = None;
+                responses.push(error_message(
+                    response_header(&message.header, STATUS_SUCCESS),
+                    status,
+                ));
+                continue;
+            }
+            let Some(mut response) = self.handle_message(connection_id, &message, &member) else {
+                if !self.connections.contains_key(&connection_id) {
+                    break;
+                }
+                continue;
+            };
+            if related {
+                response.header.flags |= HeaderFlags::RELATED_OPERATIONS;
+            }
+            let tree_id = match (response.header.variant, message.header.variant) {
+                (SmbMessageHeaderVariant::Sync { tree_id }, _)
+                | (_, SmbMessageHeaderVariant::Sync { tree_id }) => tree_id,
+                _ => 0,
+            };
+            let file_id = match &response.body {
+                SmbBody::CreateResponse(create) => Some(create.file_id),
+                _ => message.body.file_id_mut().map(|file_id| *file_id),
+            };
+            previous = Some(Related {
+                session_id: response.header.session_id,
+                tree_id,
+                file_id,
+                status: response.header.status,
+            });
+            responses.push(response);
+        }
+        responses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smb2::message::{Command, SmbClose};
+
+    fn related_close() -> SmbMessage {
+        SmbMessage {
+            header: SmbMessageHeader {
+                protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+                header_size: 64,
+                credit_charge: 1,
+                status: STATUS_SUCCESS,
+                command: Command::Close,
+                credit_request_response: 1,
+                flags: HeaderFlags::RELATED_OPERATIONS,
+                next_command: 0,
+                message_id: 3,
+                variant: SmbMessageHeaderVariant::Sync { tree_id: u32::MAX },
+                session_id: u64::MAX,
+                signature: 0,
+            },
+            body: SmbBody::Close(SmbClose {
+                size: 24,
+                flags: 0,
+                file_id: SmbFileId {
+                    persistent: RELATED_FILE_ID,
+                    volatile: RELATED_FILE_ID,
+                },
+            }),
+        }
+    }
+
+    #[test]
+    fn related_operations_carry_on_from_the_last_one() {
+        let file_id = SmbFileId {
+            persistent: 4,
+            volatile: 4,
+        };
+        let mut previous = Related {
+            session_id: 7,
+            tree_id: 2,
+            file_id: Some(file_id),
+            status: STATUS_SUCCESS,
+        };
+        let mut message = related_close();
+        relate(&mut message, Some(&previous)).unwrap();
+        assert_eq!(message.header.session_id, 7);
+        assert_eq!(
+            message.header.variant,
+            SmbMessageHeaderVariant::Sync { tree_id: 2 }
+        );
+        assert_eq!(message.body.file_id_mut().copied(), Some(file_id));
+
+        previous.status = STATUS_OBJECT_NAME_NOT_FOUND;
+        let mut message = related_close();
+        assert_eq!(
+            relate(&mut message, Some(&previous)),
+            Err(STATUS_OBJECT_NAME_NOT_FOUND)
+        );
+        assert_eq!(relate(&mut message, None), Err(STATUS_INVALID_PARAMETER));
+    }
+}
@@ -0,0 +1,437 @@
+//! A (very) small subset of the smb.conf format:
+//!
+//! ```text
+//! [global]
+//! # comments start with '#' or ';'
+//! # where persistent handles are kept, for continuously available shares.
+//! persistent handle store = /var/lib/bad-samba/handles
+//! # the oldest and newest dialects clients may use, SMB2_02 to SMB3_11.
+//! server min protocol = SMB2_10
+//! server max protocol = SMB3_11
+//! # whether clients have to sign what they send, auto or mandatory.
+//! server signing = mandatory
+//! # whether everything has to be encrypted, off or required. Shares can
+//! # insist on it for themselves too.
+//! smb encrypt = off
+//! # the users clients can log on as, in smbpasswd format.
+//! smb passwd file = /etc/bad-samba/smbpasswd
+//! # whether clients that don't log on as one of them get in as a guest,
+//! # never or bad user.
+//! map to guest = never
+//!
+//! [public]
+//! path = /srv/public
+//! continuously available = yes
+//! smb encrypt = required
+//! ```
+//!
+//! Every section other than `[global]` is a share.
+
+use std::path::{Path, PathBuf};
+
+use smb2::message::Dialect;
+
+/// Where persistent handles go when the config doesn't say.
+const DEFAULT_HANDLE_STORE: &str = "/var/lib/bad-samba/handles";
+
+#[derive(Debug)]
+pub struct Config {
+    pub shares: Vec<ShareConfig>,
+    pub handle_store: PathBuf,
+    pub min_protocol: Dialect,
+    pub max_protocol: Dialect,
+    pub signing_required: bool,
+    /// Whether every session has to be encrypted.
+    pub encrypt_data: bool,
+    /// Where the users clients can log on as are kept, if anywhere.
+    pub passwd_file: Option<PathBuf>,
+    /// Whether clients that don't log on as a user in `passwd_file`, or
+    /// don't say who they are at all, get a guest session rather than
+    /// STATUS_LOGON_FAILURE.
+    pub map_to_guest: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            shares: vec![],
+            handle_store: PathBuf::from(DEFAULT_HANDLE_STORE),
+            min_protocol: Dialect::Smb202,
+            max_protocol: Dialect::Smb311,
+            signing_required: false,
+            encrypt_data: false,
+            passwd_file: None,
+            map_to_guest: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ShareConfig {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether opens on it can be persistent, surviving a server restart.
+    pub continuously_available: bool,
+    /// Whether everything done on it has to be encrypted.
+    pub encrypt_data: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    /// A line that isn't a section, a `key = value` pair or a comment.
+    Syntax {
+        line: usize,
+    },
+    /// A setting this server doesn't know about.
+    UnknownKey {
+        line: usize,
+        key: String,
+    },
+    /// A yes/no setting that's neither.
+    InvalidBool {
+        line: usize,
+        key: String,
+    },
+    /// A protocol setting that doesn't name an SMB2 dialect.
+    InvalidProtocol {
+        line: usize,
+        key: String,
+    },
+    /// A signing setting that's neither auto nor mandatory.
+    InvalidSigning {
+        line: usize,
+        key: String,
+    },
+    /// An encryption setting that's neither off nor required.
+    InvalidEncryption {
+        line: usize,
+        key: String,
+    },
+    /// A map to guest setting that's neither never nor bad user.
+    InvalidMapToGuest {
+        line: usize,
+        key: String,
+    },
+    /// A share that doesn't say where it lives.
+    MissingPath {
+        share: String,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read config: {e}"),
+            Self::Syntax { line } => write!(f, "line {line}: expected `key = value`"),
+            Self::UnknownKey { line, key } => write!(f, "line {line}: unknown setting `{key}`"),
+            Self::InvalidBool { line, key } => {
+                write!(f, "line {line}: `{key}` should be yes or no")
+            }
+            Self::InvalidProtocol { line, key } => write!(
+                f,
+                "line {line}: `{key}` should be one of SMB2_02, SMB2_10, SMB3_00, SMB3_02 or SMB3_11"
+            ),
+            Self::InvalidSigning { line, key } => {
+                write!(f, "line {line}: `{key}` should be auto or mandatory")
+            }
+            Self::InvalidEncryption { line, key } => {
+                write!(f, "line {line}: `{key}` should be off or required")
+            }
+            Self::InvalidMapToGuest { line, key } => {
+                write!(f, "line {line}: `{key}` should be never or bad user")
+            }
+            Self::MissingPath { share } => write!(f, "share [{share}] has no path"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        // the share we're in the middle of, if any.
+        let mut current: Option<PartialShare> = None;
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line_number = line_number + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if let Some(share) = current.take() {
+                    config.shares.push(finish_share(share)?);
+                }
+                if !section.eq_ignore_ascii_case("global") {
+                    current = Some(PartialShare {
+                        name: section.to_string(),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(ConfigError::Syntax { line: line_number });
+            };
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+            match (&mut current, key.as_str()) {
+                (None, "persistent handle store") => config.handle_store = PathBuf::from(value),
+                (None, "smb passwd file") => config.passwd_file = Some(PathBuf::from(value)),
+                (None, "server min protocol" | "server max protocol") => {
+                    let dialect = parse_protocol(value).ok_or(ConfigError::InvalidProtocol {
+                        line: line_number,
+                        key: key.clone(),
+                    })?;
+                    if key == "server min protocol" {
+                        config.min_protocol = dialect;
+                    } else {
+                        config.max_protocol = dialect;
+                    }
+                }
+                (None, "server signing") => {
+                    config.signing_required =
+                        parse_signing(value).ok_or(ConfigError::InvalidSigning {
+                            line: line_number,
+                            key,
+                        })?
+                }
+                (None, "smb encrypt") => {
+                    config.encrypt_data =
+                        parse_encryption(value).ok_or(ConfigError::InvalidEncryption {
+                            line: line_number,
+                            key,
+                        })?
+                }
+                (None, "map to guest") => {
+                    config.map_to_guest =
+                        parse_map_to_guest(value).ok_or(ConfigError::InvalidMapToGuest {
+                            line: line_number,
+                            key,
+                        })?
+                }
+                (Some(share), "path") => share.path = Some(PathBuf::from(value)),
+                (Some(share), "continuously available") => {
+                    share.continuously_available =
+                        parse_bool(value).ok_or(ConfigError::InvalidBool {
+                            line: line_number,
+                            key,
+                        })?
+                }
+                (Some(share), "smb encrypt") => {
+                    share.encrypt_data =
+                        parse_encryption(value).ok_or(ConfigError::InvalidEncryption {
+                            line: line_number,
+                            key,
+                        })?
+                }
+                _ => {
+                    return Err(ConfigError::UnknownKey {
+                        line: line_number,
+                        key,
+                    })
+                }
+            }
+        }
+        if let Some(share) = current.take() {
+            config.shares.push(finish_share(share)?);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Default)]
+struct PartialShare {
+    name: String,
+    path: Option<PathBuf>,
+    continuously_available: bool,
+    encrypt_data: bool,
+}
+
+fn finish_share(share: PartialShare) -> Result<ShareConfig, ConfigError> {
+    match share.path {
+        Some(path) => Ok(ShareConfig {
+            name: share.name,
+            path,
+            continuously_available: share.continuously_available,
+            encrypt_data: share.encrypt_data,
+        }),
+        None => Err(ConfigError::MissingPath { share: share.name }),
+    }
+}
+
+/// The ways smb.conf spells yes and no.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" | "true" | "1" => Some(true),
+        "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether a `server signing` value makes signing mandatory. SMB2 can't turn
+/// signing off, only stop insisting on it, so disabled is the same as auto.
+fn parse_signing(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "auto" | "default" | "disabled" => Some(false),
+        "mandatory" | "required" => Some(true),
+        _ => None,
+    }
+}
+
+/// Whether an `smb encrypt` value makes encryption mandatory. A client that
+/// can encrypt always may, so anything short of required is the same as off.
+fn parse_encryption(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "off" | "disabled" | "auto" | "default" | "if_required" | "desired" | "enabled" => {
+            Some(false)
+        }
+        "required" | "mandatory" | "forced" => Some(true),
+        _ => None,
+    }
+}
+
+/// Whether a `map to guest` value lets clients in as a guest. Bad password,
+/// which lets in people who got a real user's password wrong, isn't one.
+fn parse_map_to_guest(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "never" => Some(false),
+        "bad user" => Some(true),
+        _ => None,
+    }
+}
+
+/// The names smb.conf gives dialects, SMB2 and SMB3 being the newest of each.
+fn parse_protocol(value: &str) -> Option<Dialect> {
+    match value.to_ascii_uppercase().as_str() {
+        "SMB2_02" => Some(Dialect::Smb202),
+        "SMB2_10" | "SMB2" => Some(Dialect::Smb210),
+        "SMB3_00" => Some(Dialect::Smb300),
+        "SMB3_02" => Some(Dialect::Smb302),
+        "SMB3_11" | "SMB3" => Some(Dialect::Smb311),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shares() {
+        let config = Config::parse(
+            "[global]\n\
+             # nothing here yet\n\
+             \n\
+             [Public]\n\
+             path = /srv/public\n\
+             [home]\n\
+             ; comment\n\
+             Path=/home\n",
+        )
+        .unwrap();
+        assert_eq!(config.shares.len(), 2);
+        assert_eq!(config.shares[0].name, "Public");
+        assert_eq!(config.shares[0].path, PathBuf::from("/srv/public"));
+        assert_eq!(config.shares[1].name, "home");
+        assert_eq!(config.shares[1].path, PathBuf::from("/home"));
+    }
+
+    #[test]
+    fn continuously_available_shares() {
+        let config = Config::parse(
+            "[global]\n\
+             persistent handle store = /tmp/handles\n\
+             [ca]\n\
+             path = /srv/ca\n\
+             continuously available = Yes\n",
+        )
+        .unwrap();
+        assert_eq!(config.handle_store, PathBuf::from("/tmp/handles"));
+        assert!(config.shares[0].continuously_available);
+        assert!(matches!(
+            Config::parse("[ca]\npath = /srv/ca\ncontinuously available = maybe\n"),
+            Err(ConfigError::InvalidBool { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn protocol_range() {
+        let config = Config::parse(
+            "[global]\n\
+             server min protocol = SMB2_10\n\
+             server max protocol = smb3\n",
+        )
+        .unwrap();
+        assert_eq!(config.min_protocol, Dialect::Smb210);
+        assert_eq!(config.max_protocol, Dialect::Smb311);
+        assert!(matches!(
+            Config::parse("server min protocol = NT1\n"),
+            Err(ConfigError::InvalidProtocol { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn signing() {
+        assert!(!Config::default().signing_required);
+        let config = Config::parse("[global]\nserver signing = Mandatory\n").unwrap();
+        assert!(config.signing_required);
+        assert!(matches!(
+            Config::parse("server signing = sometimes\n"),
+            Err(ConfigError::InvalidSigning { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn encryption() {
+        let config = Config::parse(
+            "[global]\n\
+             smb encrypt = off\n\
+             [secret]\n\
+             path = /srv/secret\n\
+             smb encrypt = Required\n",
+        )
+        .unwrap();
+        assert!(!config.encrypt_data);
+        assert!(config.shares[0].encrypt_data);
+        assert!(matches!(
+            Config::parse("smb encrypt = please\n"),
+            Err(ConfigError::InvalidEncryption { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn passwd_file() {
+        assert_eq!(Config::default().passwd_file, None);
+        let config = Config::parse("[global]\nsmb passwd file = /etc/smbpasswd\n").unwrap();
+        assert_eq!(config.passwd_file, Some(PathBuf::from("/etc/smbpasswd")));
+    }
+
+    #[test]
+    fn map_to_guest() {
+        assert!(!Config::default().map_to_guest);
+        let config = Config::parse("[global]\nmap to guest = Bad User\n").unwrap();
+        assert!(config.map_to_guest);
+        assert!(matches!(
+            Config::parse("map to guest = bad password\n"),
+            Err(ConfigError::InvalidMapToGuest { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn share_without_path() {
+        assert!(matches!(
+            Config::parse("[public]\n"),
+            Err(ConfigError::MissingPath { .. })
+        ));
+    }
+}
@@ -0,0 +1,595 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use smb2::message::{CreateAction, CreateDisposition, OplockLevel, SmbBody, SmbFileId};
+use smb2::message::{SmbClose, SmbCloseResponse, SmbCreate, SmbCreateContext, SmbCreateResponse};
+use smb2::message::{SmbErrorContext, SymbolicLinkReparseBuffer, SYMLINK_FLAG_RELATIVE};
+use smb2::message::{SmbMessageHeader, SmbMessageHeaderVariant, SMB2_CLOSE_FLAG_POSTQUERY_ATTRIB};
+use smb2::message::{DELETE, FILE_EXECUTE, FILE_READ_DATA, GENERIC_EXECUTE, GENERIC_READ};
+use smb2::message::{FILE_APPEND_DATA, FILE_DELETE_ON_CLOSE, FILE_DIRECTORY_FILE};
+use smb2::message::{FILE_NON_DIRECTORY_FILE, FILE_WRITE_DATA, GENERIC_ALL, GENERIC_WRITE};
+use smb2::message::{FILE_OPEN_REPARSE_POINT, MAXIMUM_ALLOWED};
+use smb2::message::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+use crate::durable::Durable;
+use crate::fs::{self, FileTimes};
+use crate::lease::LeaseId;
+use crate::oplock;
+use crate::query_directory::DirectorySearch;
+use crate::status::*;
+use crate::{Failure, HandlerResult, Server, MAXIMAL_ACCESS};
+
+/// A file or directory a client has open.
+pub struct Open {
+    pub session_id: u64,
+    pub tree_id: u32,
+    /// Where it lives on disk.
+    pub path: PathBuf,
+    /// None for directories.
+    pub file: Option<File>,
+    pub desired_access: u32,
+    pub share_access: u32,
+    pub delete_on_close: bool,
+    /// FilePositionInformation, which only matters to the client.
+    pub position: u64,
+    /// The last lock sequence number seen in each slot, so replayed
+    /// LOCK requests don't get applied twice.
+    pub lock_sequences: [Option<u8>; 64],
+    /// Where QUERY_DIRECTORY is up to, for directories being listed.
+    pub search: Option<DirectorySearch>,
+    pub oplock_level: OplockLevel,
+    /// The lease it's sharing, if it has one rather than an oplock.
+    pub lease: Option<LeaseId>,
+    /// Some if it's to be kept around when its connection goes.
+    pub durable: Option<Durable>,
+}
+
+pub const WRITE_ACCESS: u32 = FILE_WRITE_DATA | FILE_APPEND_DATA | GENERIC_WRITE | GENERIC_ALL;
+pub const READ_ACCESS: u32 =
+    FILE_READ_DATA | FILE_EXECUTE | GENERIC_READ | GENERIC_EXECUTE | GENERIC_ALL;
+pub const DELETE_ACCESS: u32 = DELETE | GENERIC_ALL;
+
+/// Whether an open with `access`/`share` can't coexist with one
+/// that has `other_access`/`other_share`.
+pub(crate) fn sharing_violation(
+    access: u32,
+    share: u32,
+    other_access: u32,
+    other_share: u32,
+) -> bool {
+    let denies = |access: u32, share: u32| {
+        (access & READ_ACCESS != 0 && share & FILE_SHARE_READ == 0)
+            || (access & WRITE_ACCESS != 0 && share & FILE_SHARE_WRITE == 0)
+            || (access & DELETE_ACCESS != 0 && share & FILE_SHARE_DELETE == 0)
+    };
+    denies(access, other_share) || denies(other_access, share)
+}
+
+/// Symlinks that stay inside the share are followed like anything else, but
+/// ones that lead out of it are left for the client to follow on its side,
+/// with STATUS_STOPPED_ON_SYMLINK saying where they go.
+fn stopped_on_symlink(root: &Path, path: &Path, open_reparse_point: bool) -> Option<Failure> {
+    let canonical_root = root.canonicalize().ok()?;
+    let mut components = path.strip_prefix(root).ok()?.components();
+    let mut link = root.to_path_buf();
+    while let Some(component) = components.next() {
+        link.push(component);
+        let unparsed = components.as_path();
+        let is_symlink = std::fs::symlink_metadata(&link).is_ok_and(|m| m.is_symlink());
+        if !is_symlink || (open_reparse_point && unparsed.as_os_str().is_empty()) {
+            continue;
+        }
+        // one that doesn't go anywhere yet fails however it would have anyway.
+        let Ok(resolved) = link.canonicalize() else {
+            continue;
+        };
+        if resolved.starts_with(&canonical_root) {
+            continue;
+        }
+        let target = std::fs::read_link(&link).ok()?;
+        let name = target.to_string_lossy().replace('/', "\\");
+        // the rest of the path, separator and all, as the client sent it.
+        let unparsed_path_length = match unparsed.to_str() {
+            Some("") | None => 0,
+            Some(unparsed) => (unparsed.encode_utf16().count() + 1) * 2,
+        };
+        return Some(Failure {
+            status: STATUS_STOPPED_ON_SYMLINK,
+            error_data: vec![SmbErrorContext::SymbolicLink {
+                unparsed_path_length: unparsed_path_length as u16,
+                link: SymbolicLinkReparseBuffer {
+                    substitute_name: name.clone(),
+                    print_name: name,
+                    flags: if target.is_relative() {
+                        SYMLINK_FLAG_RELATIVE
+                    } else {
+                        0
+                    },
+                },
+            }],
+        });
+    }
+    None
+}
+
+/// Whether opening `path` would end up somewhere outside `root`, going by
+/// where its symlinks point. Anything that can't be resolved might.
+fn leaves_share(root: &Path, path: &Path) -> bool {
+    match (root.canonicalize(), path.canonicalize()) {
+        (Ok(root), Ok(resolved)) => !resolved.starts_with(root),
+        _ => true,
+    }
+}
+
+impl Server {
+    pub(crate) fn create(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        create: &SmbCreate,
+    ) -> HandlerResult {
+        if let Some(result) = self.reconnect_durable(connection_id, header, create) {
+            return result;
+        }
+        let tree_id = self.tree_id(connection_id, header)?;
+        let Some(root) = self.share(connection_id, header)?.path.clone() else {
+            // there aren't any named pipes to open on IPC$ yet.
+            return Err(STATUS_OBJECT_NAME_NOT_FOUND.into());
+        };
+        let path = fs::resolve(&root, &create.name)?;
+        let open_reparse_point = create.create_options & FILE_OPEN_REPARSE_POINT != 0;
+        if let Some(failure) = stopped_on_symlink(&root, &path, open_reparse_point) {
+            return Err(failure);
+        }
+        self.close_orphans(&path);
+        if self.break_oplocks(header.session_id, &path, create) {
+            self.wait_for_oplock_breaks(connection_id, header, tree_id, path, create);
+            return Err(STATUS_PENDING.into());
+        }
+        self.create_open(header.session_id, tree_id, path, create)
+    }
+
+    /// The rest of a CREATE, once nobody else's oplock is in the way.
+    pub(crate) fn create_open(
+        &mut self,
+        session_id: u64,
+        tree_id: u32,
+        path: PathBuf,
+        create: &SmbCreate,
+    ) -> HandlerResult {
+        // FILE_OPEN_REPARSE_POINT gets a symlink at the end of the path past
+        // stopped_on_symlink, to open the link itself. One that leads out of
+        // the share mustn't be followed anyway.
+        let no_follow = create.create_options & FILE_OPEN_REPARSE_POINT != 0
+            && self
+                .share_root(session_id, tree_id)
+                .is_none_or(|root| leaves_share(root, &path));
+        let existing = match no_follow {
+            true => std::fs::symlink_metadata(&path),
+            false => std::fs::metadata(&path),
+        }
+        .ok();
+        let wants_directory = create.create_options & FILE_DIRECTORY_FILE != 0;
+
+        if let Some(metadata) = &existing {
+            if wants_directory && !metadata.is_dir() {
+                return Err(STATUS_NOT_A_DIRECTORY.into());
+            }
+            if create.create_options & FILE_NON_DIRECTORY_FILE != 0 && metadata.is_dir() {
+                return Err(STATUS_FILE_IS_A_DIRECTORY.into());
+            }
+        }
+
+        if existing.is_some()
+            && self.opens.values().any(|open| {
+                open.path == path
+                    && sharing_violation(
+                        create.desired_access,
+                        create.share_access,
+                        open.desired_access,
+                        open.share_access,
+                    )
+            })
+        {
+            return Err(STATUS_SHARING_VIOLATION.into());
+        }
+
+        let create_action = match (create.create_disposition, &existing) {
+            (CreateDisposition::Open | CreateDisposition::OpenIf, Some(_)) => CreateAction::Opened,
+            (CreateDisposition::Overwrite | CreateDisposition::OverwriteIf, Some(_)) => {
+                CreateAction::Overwritten
+            }
+            (CreateDisposition::Supersede, Some(_)) => CreateAction::Superseded,
+            (CreateDisposition::Create, Some(_)) => return Err(STATUS_OBJECT_NAME_COLLISION.into()),
+            (CreateDisposition::Open | CreateDisposition::Overwrite, None) => {
+                return Err(match path.parent().map(|parent| parent.is_dir()) {
+                    Some(true) => STATUS_OBJECT_NAME_NOT_FOUND.into(),
+                    _ => STATUS_OBJECT_PATH_NOT_FOUND.into(),
+                })
+            }
+            (_, None) => CreateAction::Created,
+        };
+
+        let is_directory = match &existing {
+            Some(metadata) => metadata.is_dir(),
+            None => wants_directory,
+        };
+        self.check_durable_request(session_id, create)?;
+        let requested_lease = self.requested_lease(session_id, create);
+        if let Some((id, _)) = requested_lease {
+            self.check_lease_path(id, &path)?;
+        }
+        let file = if is_directory {
+            if create_action == CreateAction::Created {
+                std::fs::create_dir(&path).map_err(|e| NtStatus::from(&e))?;
+            }
+            None
+        } else {
+            Some(self.open_file(&path, create, create_action, no_follow)?)
+        };
+
+        let metadata = std::fs::metadata(&path).map_err(|e| NtStatus::from(&e))?;
+        let mut create_contexts: Vec<_> = create
+            .create_contexts
+            .iter()
+            .filter_map(|create_context| match create_context {
+                SmbCreateContext::QueryMaximalAccessRequest { .. } => {
+                    Some(SmbCreateContext::QueryMaximalAccessResponse {
+                        query_status: STATUS_SUCCESS,
+                        maximal_access: MAXIMAL_ACCESS,
+                    })
+                }
+                SmbCreateContext::QueryOnDiskId => Some(SmbCreateContext::QueryOnDiskIdResponse {
+                    disk_file_id: metadata.ino(),
+                    volume_id: metadata.dev(),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        let lease = requested_lease.map(|(id, _)| id);
+        let shared = self
+            .opens
+            .values()
+            .any(|open| open.path == path && (lease.is_none() || open.lease != lease));
+        let (oplock_level, lease_state) = match (requested_lease, &file) {
+            (Some((id, requested)), _) => {
+                let granted = self.grant_lease(id, requested, &path, is_directory, shared);
+                let lease_state = granted.state();
+                create_contexts.push(SmbCreateContext::Lease(granted));
+                (OplockLevel::Lease, lease_state)
+            }
+            (None, Some(_)) => (oplock::grant(create.requested_oplock_level, shared), 0),
+            (None, None) => (OplockLevel::None, 0),
+        };
+        let durable = self
+            .grant_durable(session_id, tree_id, create, oplock_level, lease_state)
+            .map(|(durable, response)| {
+                create_contexts.push(response);
+                durable
+            });
+        if create_action == CreateAction::Created {
+            self.break_parent_leases(&path, lease);
+        }
+
+        self.next_file_id += 1;
+        let file_id = SmbFileId {
+            persistent: self.next_file_id,
+            volatile: self.next_file_id,
+        };
+        self.opens.insert(
+            file_id.volatile,
+            Open {
+                session_id,
+                tree_id,
+                path: path.clone(),
+                file,
+                desired_access: create.desired_access,
+                share_access: create.share_access,
+                delete_on_close: create.create_options & FILE_DELETE_ON_CLOSE != 0,
+                position: 0,
+                lock_sequences: [None; 64],
+                search: None,
+                oplock_level,
+                lease,
+                durable,
+            },
+        );
+        self.journal(file_id.volatile);
+        self.create_response(file_id, create_action, oplock_level, create_contexts)
+    }
+
+    /// The response to a CREATE that ended up with `file_id`.
+    pub(crate) fn create_response(
+        &self,
+        file_id: SmbFileId,
+        create_action: CreateAction,
+        oplock_level: OplockLevel,
+        create_contexts: Vec<SmbCreateContext>,
+    ) -> HandlerResult {
+        let open = &self.opens[&file_id.volatile];
+        let (path, is_directory) = (&open.path, open.file.is_none());
+        let metadata = std::fs::metadata(path).map_err(|e| NtStatus::from(&e))?;
+
+        let times = FileTimes::from_metadata(&metadata);
+        let create_contexts_length = SmbCreateContext::list_to_vec(&create_contexts).len() as u32;
+        Ok(SmbBody::CreateResponse(SmbCreateResponse {
+            size: 89,
+            oplock_level,
+            flags: 0,
+            create_action,
+            creation_time: times.creation_time,
+            last_access_time: times.last_access_time,
+            last_write_time: times.last_write_time,
+            change_time: times.change_time,
+            allocation_size: fs::allocation_size(&metadata),
+            end_of_file: if is_directory { 0 } else { metadata.len() },
+            file_attributes: fs::file_attributes(path, &metadata),
+            file_id,
+            create_contexts_offset: if create_contexts.is_empty() {
+                0
+            } else {
+                SmbCreateResponse::CREATE_CONTEXTS_OFFSET
+            },
+            create_contexts_length,
+            create_contexts,
+        }))
+    }
+
+    fn open_file(
+        &self,
+        path: &PathBuf,
+        create: &SmbCreate,
+        create_action: CreateAction,
+        no_follow: bool,
+    ) -> Result<File, NtStatus> {
+        let wants_write =
+            create.desired_access & WRITE_ACCESS != 0 || create_action != CreateAction::Opened;
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(wants_write)
+            .create_new(create_action == CreateAction::Created)
+            .truncate(matches!(
+                create_action,
+                CreateAction::Overwritten | CreateAction::Superseded
+            ));
+        if no_follow {
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        match options.open(path) {
+            // MAXIMUM_ALLOWED means "whatever you'll let me have",
+            // so settle for read only if that's all there is.
+            Err(e)
+                if e.kind() == std::io::ErrorKind::PermissionDenied
+                    && create.desired_access & MAXIMUM_ALLOWED != 0
+                    && create_action == CreateAction::Opened =>
+            {
+                options
+                    .write(false)
+                    .open(path)
+                    .map_err(|e| NtStatus::from(&e))
+            }
+            result => result.map_err(|e| NtStatus::from(&e)),
+        }
+    }
+
+    /// Where on disk the share `tree_id` is connected to lives.
+    fn share_root(&self, session_id: u64, tree_id: u32) -> Option<&Path> {
+        let tree = self.sessions.get(&session_id)?.trees.get(&tree_id)?;
+        self.shares.get(&tree.share)?.path.as_deref()
+    }
+
+    /// Looks up an open the client is allowed to be using.
+    /// The open `file_id` names, so long as it was opened through the
+    /// request's session and tree connect. Another tree connect to a
+    /// different share would get around whatever that share insists on.
+    pub(crate) fn open(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        file_id: SmbFileId,
+    ) -> Result<&mut Open, NtStatus> {
+        self.session_on(connection_id, header.session_id)?;
+        let SmbMessageHeaderVariant::Sync { tree_id } = header.variant else {
+            return Err(STATUS_FILE_CLOSED);
+        };
+        match self.opens.get_mut(&file_id.volatile) {
+            Some(open) if open.session_id == header.session_id && open.tree_id == tree_id => {
+                Ok(open)
+            }
+            _ => Err(STATUS_FILE_CLOSED),
+        }
+    }
+
+    pub(crate) fn close(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        close: &SmbClose,
+    ) -> HandlerResult {
+        self.open(connection_id, header, close.file_id)?;
+        let open = self.close_open(close.file_id.volatile);
+        let mut response = SmbCloseResponse {
+            size: 60,
+            ..Default::default()
+        };
+        if close.flags & SMB2_CLOSE_FLAG_POSTQUERY_ATTRIB != 0 {
+            if let Ok(metadata) = std::fs::metadata(&open.path) {
+                let times = FileTimes::from_metadata(&metadata);
+                response = SmbCloseResponse {
+                    size: 60,
+                    flags: SMB2_CLOSE_FLAG_POSTQUERY_ATTRIB,
+                    creation_time: times.creation_time,
+                    last_access_time: times.last_access_time,
+                    last_write_time: times.last_write_time,
+                    change_time: times.change_time,
+                    allocation_size: fs::allocation_size(&metadata),
+                    end_of_file: if metadata.is_dir() { 0 } else { metadata.len() },
+                    file_attributes: fs::file_attributes(&open.path, &metadata),
+                };
+            }
+        }
+        Ok(SmbBody::CloseResponse(response))
+    }
+
+    /// Forgets about an open, doing whatever the client asked
+    /// to be done once it was closed.
+    pub(crate) fn close_open(&mut self, volatile: u64) -> Open {
+        let open = self
+            .opens
+            .remove(&volatile)
+            .expect("closing an open that doesn't exist");
+        self.forget_persistent(volatile, &open);
+        self.release_oplock(volatile);
+        match &open.file {
+            Some(file) => self.release_locks(volatile, file),
+            None => self.stop_watching(volatile),
+        }
+        if open.delete_on_close {
+            let result = match open.file {
+                Some(_) => std::fs::remove_file(&open.path),
+                None => std::fs::remove_dir(&open.path),
+            };
+            match result {
+                Ok(()) => self.break_parent_leases(&open.path, open.lease),
+                Err(e) => println!("couldn't delete {:?} on close: {e}", open.path),
+            }
+        }
+        if let Some(id) = open.lease {
+            self.release_lease(id);
+        }
+        open
+    }
+
+    /// Closes everything opened through the given tree connect.
+    pub(crate) fn close_tree_opens(&mut self, session_id: u64, tree_id: u32) {
+        let file_ids: Vec<_> = self
+            .opens
+            .iter()
+            .filter(|(_, open)| open.session_id == session_id && open.tree_id == tree_id)
+            .map(|(&volatile, _)| volatile)
+            .collect();
+        for volatile in file_ids {
+            self.close_open(volatile);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_symlinks_out_of_the_share_are_left_to_the_client() {
+        let dir = std::env::temp_dir().join(format!("smb-server-symlinks-{}", std::process::id()));
+        let share = dir.join("share");
+        std::fs::create_dir_all(share.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink("sub", share.join("in")).unwrap();
+        std::os::unix::fs::symlink("../outside", share.join("out")).unwrap();
+
+        assert_eq!(stopped_on_symlink(&share, &share.join("in/a"), false), None);
+        let failure = stopped_on_symlink(&share, &share.join("out/a"), false).unwrap();
+        assert_eq!(failure.status, STATUS_STOPPED_ON_SYMLINK);
+        assert_eq!(
+            failure.error_data,
+            vec![SmbErrorContext::SymbolicLink {
+                // "\a"
+                unparsed_path_length: 4,
+                link: SymbolicLinkReparseBuffer {
+                    substitute_name: "..\\outside".into(),
+                    print_name: "..\\outside".into(),
+                    flags: SYMLINK_FLAG_RELATIVE,
+                },
+            }]
+        );
+        // unless it's the link itself that's wanted, which is then opened without following it.
+        assert_eq!(stopped_on_symlink(&share, &share.join("out"), true), None);
+        assert!(leaves_share(&share, &share.join("out")));
+        assert!(!leaves_share(&share, &share.join("in")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opens_only_through_the_tree_they_were_opened_on() {
+        let mut server = Server::default();
+        let connection_id = server.connect(tokio::sync::mpsc::unbounded_channel().0, None);
+        for session_id in [1, 5] {
+            server.sessions.insert(
+                session_id,
+                crate::Session {
+                    connection_id,
+                    guest: false,
+                    user: Some("alice".into()),
+                    preauth_integrity: None,
+                    ntlmssp: None,
+                    signing_key: None,
+                    signing_required: false,
+                    encryption: None,
+                    encrypt_data: false,
+                    trees: Default::default(),
+                    next_tree_id: 0,
+                },
+            );
+        }
+        server.opens.insert(
+            4,
+            Open {
+                session_id: 1,
+                tree_id: 2,
+                path: PathBuf::from("/srv/share/a"),
+                file: None,
+                desired_access: 0,
+                share_access: 0,
+                delete_on_close: false,
+                position: 0,
+                lock_sequences: [None; 64],
+                search: None,
+                oplock_level: OplockLevel::None,
+                lease: None,
+                durable: None,
+            },
+        );
+        let file_id = SmbFileId {
+            persistent: 4,
+            volatile: 4,
+        };
+        let mut header = crate::response_header(
+            &SmbMessageHeader {
+                protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+                header_size: 64,
+                credit_charge: 1,
+                status: STATUS_SUCCESS,
+                command: smb2::message::Command::Read,
+                credit_request_response: 1,
+                flags: smb2::message::HeaderFlags::empty(),
+                next_command: 0,
+                message_id: 1,
+                variant: SmbMessageHeaderVariant::Sync { tree_id: 2 },
+                session_id: 1,
+                signature: 0,
+            },
+            STATUS_SUCCESS,
+        );
+        assert!(server.open(connection_id, &header, file_id).is_ok());
+        // nor from a connection the session wasn't set up on.
+        let other = server.connect(tokio::sync::mpsc::unbounded_channel().0, None);
+        assert_eq!(
+            server.open(other, &header, file_id).err(),
+            Some(STATUS_USER_SESSION_DELETED)
+        );
+        header.variant = SmbMessageHeaderVariant::Sync { tree_id: 3 };
+        assert_eq!(
+            server.open(connection_id, &header, file_id).err(),
+            Some(STATUS_FILE_CLOSED)
+        );
+        header.variant = SmbMessageHeaderVariant::Sync { tree_id: 2 };
+        header.session_id = 5;
+        assert_eq!(
+            server.open(connection_id, &header, file_id).err(),
+            Some(STATUS_FILE_CLOSED)
+        );
+    }
+}
@@ -0,0 +1,467 @@
+//! Durable handles, which outlive the connection they were opened on
+//! for long enough for the client to come back and reclaim them.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use smb2::message::{CreateAction, Dialect, OplockLevel, SmbCreate, SmbCreateContext, SmbFileId};
+use smb2::message::{SmbMessageHeader, SMB2_DHANDLE_FLAG_PERSISTENT, SMB2_LEASE_HANDLE_CACHING};
+use tokio::sync::Mutex;
+
+use crate::status::*;
+use crate::{HandlerResult, Server};
+
+/// v1 handles don't get to say how long they want, so they get what Windows gives them.
+const DURABLE_V1_TIMEOUT: Duration = Duration::from_secs(16 * 60);
+/// For v2 handles that leave it up to us.
+const DEFAULT_DURABLE_V2_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_DURABLE_V2_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What's needed to hand a durable open back to the client that opened it.
+pub struct Durable {
+    /// How long it's kept around once its connection has gone.
+    pub timeout: Duration,
+    /// Only v2 handles have one.
+    pub create_guid: Option<u128>,
+    pub client_guid: u128,
+    /// The share it was opened on, and the only one it can be reclaimed
+    /// through. Another might not let the client do what this one does.
+    pub share: String,
+    /// Who opened it, lowercase, and the only one who can reclaim it.
+    pub owner: String,
+    /// When it's closed for good, if its connection has gone.
+    pub expires: Option<Instant>,
+    /// Journaled to the handle store, so it survives the server restarting.
+    pub persistent: bool,
+}
+
+/// Checks once a second for durable opens nobody came back for in time.
+pub async fn expire_durable_handles(server: Arc<Mutex<Server>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        server.lock().await.expire_orphans(Instant::now());
+    }
+}
+
+impl Server {
+    /// Turns down durable handle requests that can't be granted whatever
+    /// happens with the rest of the CREATE.
+    pub(crate) fn check_durable_request(
+        &self,
+        session_id: u64,
+        create: &SmbCreate,
+    ) -> Result<(), NtStatus> {
+        let mut v1 = false;
+        let mut create_guid = None;
+        for create_context in &create.create_contexts {
+            match create_context {
+                SmbCreateContext::DurableHandleRequest => v1 = true,
+                SmbCreateContext::DurableHandleRequestV2 {
+                    create_guid: requested,
+                    ..
+                } => create_guid = Some(*requested),
+                _ => {}
+            }
+        }
+        let Some(create_guid) = create_guid else {
+            return Ok(());
+        };
+        if v1 {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        let client_guid = self
+            .negotiated(session_id)
+            .map(|negotiated| negotiated.client_guid);
+        let in_use = self.opens.values().any(|open| {
+            open.durable.as_ref().is_some_and(|durable| {
+                durable.create_guid == Some(create_guid) && Some(durable.client_guid) == client_guid
+            })
+        });
+        if in_use {
+            return Err(STATUS_DUPLICATE_OBJECTID);
+        }
+        Ok(())
+    }
+
+    /// Makes a new open durable if it asked to be and has the caching that
+    /// makes that worthwhile, or persistent if it asked to be and is on a
+    /// continuously available share, returning what to tell the client about it.
+    /// Guests and anonymous sessions don't get either: they could be anyone,
+    /// so there'd be no telling who should get to reclaim it.
+    pub(crate) fn grant_durable(
+        &self,
+        session_id: u64,
+        tree_id: u32,
+        create: &SmbCreate,
+        oplock_level: OplockLevel,
+        lease_state: u32,
+    ) -> Option<(Durable, SmbCreateContext)> {
+        let negotiated = self.negotiated(session_id)?;
+        let session = self.sessions.get(&session_id)?;
+        let share = session.trees.get(&tree_id)?.share.clone();
+        if session.guest {
+            return None;
+        }
+        let owner = session.user.as_deref()?.to_lowercase();
+        // without a batch oplock or handle caching, the client wouldn't keep the handle around.
+        let caching = oplock_level == OplockLevel::Batch
+            || (oplock_level == OplockLevel::Lease && lease_state & SMB2_LEASE_HANDLE_CACHING != 0);
+        let persistent_allowed = negotiated.dialect >= Dialect::Smb300
+            && self.handle_store.is_some()
+            && self.continuously_available(session_id, tree_id);
+        create
+            .create_contexts
+            .iter()
+            .find_map(|create_context| match create_context {
+                SmbCreateContext::DurableHandleRequest if caching => Some((
+                    Durable {
+                        timeout: DURABLE_V1_TIMEOUT,
+                        create_guid: None,
+                        client_guid: negotiated.client_guid,
+                        share: share.clone(),
+                        owner: owner.clone(),
+                        expires: None,
+                        persistent: false,
+                    },
+                    SmbCreateContext::DurableHandleResponse,
+                )),
+                SmbCreateContext::DurableHandleRequestV2 {
+                    timeout,
+                    flags,
+                    create_guid,
+                } if negotiated.dialect >= Dialect::Smb300 => {
+                    // persistent handles don't need caching, they outlive everything anyway.
+                    let persistent =
+                        flags & SMB2_DHANDLE_FLAG_PERSISTENT != 0 && persistent_allowed;
+                    if !caching && !persistent {
+                        return None;
+                    }
+                    let timeout = match *timeout {
+                        0 => DEFAULT_DURABLE_V2_TIMEOUT,
+                        millis => Duration::from_millis(millis.into()).min(MAX_DURABLE_V2_TIMEOUT),
+                    };
+                    Some((
+                        Durable {
+                            timeout,
+                            create_guid: Some(*create_guid),
+                            client_guid: negotiated.client_guid,
+                            share: share.clone(),
+                            owner: owner.clone(),
+                            expires: None,
+                            persistent,
+                        },
+                        SmbCreateContext::DurableHandleResponseV2 {
+                            timeout: timeout.as_millis() as u32,
+                            flags: if persistent {
+                                SMB2_DHANDLE_FLAG_PERSISTENT
+                            } else {
+                                0
+                            },
+                        },
+                    ))
+                }
+                _ => None,
+            })
+    }
+
+    /// A CREATE reclaiming a durable open after its connection went away,
+    /// or None if it isn't one.
+    pub(crate) fn reconnect_durable(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        create: &SmbCreate,
+    ) -> Option<HandlerResult> {
+        let (file_id, create_guid) =
+            create
+                .create_contexts
+                .iter()
+                .find_map(|create_context| match create_context {
+                    SmbCreateContext::DurableHandleReconnect { file_id } => Some((*file_id, None)),
+                    SmbCreateContext::DurableHandleReconnectV2 {
+                        file_id,
+                        create_guid,
+                        ..
+                    } => Some((*file_id, Some(*create_guid))),
+                    _ => None,
+                })?;
+        Some(self.reconnect(connection_id, header, file_id, create_guid))
+    }
+
+    fn reconnect(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        file_id: SmbFileId,
+        create_guid: Option<u128>,
+    ) -> HandlerResult {
+        let tree_id = self.tree_id(connection_id, header)?;
+        let client_guid = self
+            .negotiated(header.session_id)
+            .map(|negotiated| negotiated.client_guid);
+        let session = &self.sessions[&header.session_id];
+        let share = session.trees[&tree_id].share.clone();
+        let user = session.user.as_deref().map(str::to_lowercase);
+        // we hand out the same number for both halves.
+        if file_id.persistent != file_id.volatile {
+            return Err(STATUS_OBJECT_NAME_NOT_FOUND.into());
+        }
+        let open = self
+            .opens
+            .get_mut(&file_id.volatile)
+            .ok_or(STATUS_OBJECT_NAME_NOT_FOUND)?;
+        let durable = open.durable.as_mut().ok_or(STATUS_OBJECT_NAME_NOT_FOUND)?;
+        // still in use, or some other client's.
+        if durable.expires.is_none()
+            || durable.create_guid != create_guid
+            || Some(durable.client_guid) != client_guid
+        {
+            return Err(STATUS_OBJECT_NAME_NOT_FOUND.into());
+        }
+        if durable.share != share {
+            println!(
+                "tried to reclaim an open on {} through {share}",
+                durable.share
+            );
+            return Err(STATUS_OBJECT_NAME_NOT_FOUND.into());
+        }
+        if user.as_ref() != Some(&durable.owner) {
+            println!("{user:?} tried to reclaim {}'s open", durable.owner);
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+        durable.expires = None;
+        open.session_id = header.session_id;
+        open.tree_id = tree_id;
+        println!("reconnected to {:?}", open.path);
+
+        let oplock_level = open.oplock_level;
+        let create_contexts = open
+            .lease
+            .and_then(|id| self.lease_context(id))
+            .map(SmbCreateContext::Lease)
+            .into_iter()
+            .collect();
+        self.create_response(file_id, CreateAction::Opened, oplock_level, create_contexts)
+    }
+
+    /// Keeps the durable opens made through `session_ids` around after their
+    /// connection's gone, rather than closing them with everything else.
+    pub(crate) fn orphan_durable_opens(&mut self, session_ids: &[u64]) {
+        let now = Instant::now();
+        for open in self.opens.values_mut() {
+            let Some(durable) = &mut open.durable else {
+                continue;
+            };
+            if session_ids.contains(&open.session_id) {
+                durable.expires = Some(now + durable.timeout);
+                // sessions never get an id of 0, so nobody can use it until it's reclaimed.
+                open.session_id = 0;
+            }
+        }
+    }
+
+    /// Closes the durable opens of `path` that are still waiting to be
+    /// reclaimed, since somebody else wants the file now. Persistent ones
+    /// are kept, the whole point of them is that they're still there.
+    pub(crate) fn close_orphans(&mut self, path: &Path) {
+        let orphans: Vec<_> = self
+            .opens
+            .iter()
+            .filter(|(_, open)| {
+                open.path == path
+                    && is_orphan(open.durable.as_ref())
+                    && !open
+                        .durable
+                        .as_ref()
+                        .is_some_and(|durable| durable.persistent)
+            })
+            .map(|(&volatile, _)| volatile)
+            .collect();
+        for volatile in orphans {
+            self.close_open(volatile);
+        }
+    }
+
+    /// Closes every durable open nobody came back for in time.
+    pub(crate) fn expire_orphans(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .opens
+            .iter()
+            .filter(|(_, open)| {
+                open.durable
+                    .as_ref()
+                    .and_then(|durable| durable.expires)
+                    .is_some_and(|expires| expires <= now)
+            })
+            .map(|(&volatile, _)| volatile)
+            .collect();
+        for volatile in expired {
+            let open = self.close_open(volatile);
+            println!("durable open of {:?} expired", open.path);
+        }
+    }
+}
+
+fn is_orphan(durable: Option<&Durable>) -> bool {
+    durable.is_some_and(|durable| durable.expires.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use smb2::message::SmbMessageHeaderVariant;
+    use smb2::message::{Capabilities, Command, HeaderFlags, SecurityMode};
+    use smb2::message::{CreateDisposition, ImpersonationLevel};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::create::Open;
+    use crate::{Negotiated, Session, TreeConnect};
+
+    /// A session as `user` (None for a guest) with a tree connect, on
+    /// a connection of its own, and that connection and a request on it.
+    fn log_on(server: &mut Server, user: Option<&str>) -> (u64, SmbMessageHeader) {
+        let connection_id = server.connect(mpsc::unbounded_channel().0, None);
+        server
+            .connections
+            .get_mut(&connection_id)
+            .unwrap()
+            .negotiated = Some(Negotiated {
+            dialect: Dialect::Smb302,
+            algorithms: Default::default(),
+            capabilities: Capabilities::empty(),
+            client_capabilities: Capabilities::empty(),
+            client_guid: 7,
+            client_security_mode: SecurityMode::empty(),
+            client_dialects: vec![],
+        });
+        server.next_session_id += 1;
+        server.sessions.insert(
+            server.next_session_id,
+            Session {
+                connection_id,
+                guest: user.is_none(),
+                user: user.map(String::from),
+                preauth_integrity: None,
+                ntlmssp: None,
+                signing_key: None,
+                signing_required: false,
+                encryption: None,
+                encrypt_data: false,
+                trees: HashMap::from([(1, TreeConnect { share: "a".into() })]),
+                next_tree_id: 1,
+            },
+        );
+        let header = SmbMessageHeader {
+            protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+            header_size: 64,
+            credit_charge: 1,
+            status: STATUS_SUCCESS,
+            command: Command::Create,
+            credit_request_response: 1,
+            flags: HeaderFlags::empty(),
+            next_command: 0,
+            message_id: 1,
+            variant: SmbMessageHeaderVariant::Sync { tree_id: 1 },
+            session_id: server.next_session_id,
+            signature: 0,
+        };
+        (connection_id, header)
+    }
+
+    #[test]
+    fn only_the_owner_reclaims_an_open() {
+        let mut server = Server::default();
+        server.opens.insert(
+            4,
+            Open {
+                session_id: 0,
+                tree_id: 0,
+                // a directory, so there's no file to have open.
+                path: std::env::temp_dir(),
+                file: None,
+                desired_access: 0,
+                share_access: 0,
+                delete_on_close: false,
+                position: 0,
+                lock_sequences: [None; 64],
+                search: None,
+                oplock_level: OplockLevel::None,
+                lease: None,
+                durable: Some(Durable {
+                    timeout: DEFAULT_DURABLE_V2_TIMEOUT,
+                    create_guid: None,
+                    client_guid: 7,
+                    share: "a".into(),
+                    owner: "alice".into(),
+                    expires: Some(Instant::now() + DEFAULT_DURABLE_V2_TIMEOUT),
+                    persistent: false,
+                }),
+            },
+        );
+        let file_id = SmbFileId {
+            persistent: 4,
+            volatile: 4,
+        };
+        for user in [Some("bob"), None] {
+            let (connection_id, header) = log_on(&mut server, user);
+            let result = server.reconnect(connection_id, &header, file_id, None);
+            assert_eq!(result.err(), Some(STATUS_ACCESS_DENIED.into()));
+        }
+        // nor can another client, even as the same user.
+        let (connection_id, header) = log_on(&mut server, Some("alice"));
+        let negotiated = server
+            .connections
+            .get_mut(&connection_id)
+            .and_then(|connection| connection.negotiated.as_mut())
+            .unwrap();
+        negotiated.client_guid = 8;
+        let result = server.reconnect(connection_id, &header, file_id, None);
+        assert_eq!(result.err(), Some(STATUS_OBJECT_NAME_NOT_FOUND.into()));
+        // or the same one through another share.
+        let (connection_id, header) = log_on(&mut server, Some("alice"));
+        let session = server.sessions.get_mut(&header.session_id).unwrap();
+        session.trees.get_mut(&1).unwrap().share = "b".into();
+        let result = server.reconnect(connection_id, &header, file_id, None);
+        assert_eq!(result.err(), Some(STATUS_OBJECT_NAME_NOT_FOUND.into()));
+
+        let (connection_id, header) = log_on(&mut server, Some("Alice"));
+        assert!(server
+            .reconnect(connection_id, &header, file_id, None)
+            .is_ok());
+        assert_eq!(server.opens[&4].session_id, header.session_id);
+
+        // guests could be anyone, so they don't get one to reclaim.
+        let create = SmbCreate {
+            size: 57,
+            security_flags: 0,
+            requested_oplock_level: OplockLevel::Batch,
+            impersonation_level: ImpersonationLevel::Impersonation,
+            smb_create_flags: 0,
+            desired_access: 0,
+            file_attributes: 0,
+            share_access: 0,
+            create_disposition: CreateDisposition::Open,
+            create_options: 0,
+            name_offset: 0,
+            name_length: 0,
+            create_contexts_offset: 0,
+            create_contexts_length: 0,
+            name: "a".into(),
+            create_contexts: vec![SmbCreateContext::DurableHandleRequestV2 {
+                timeout: 0,
+                flags: 0,
+                create_guid: 9,
+            }],
+        };
+        for (user, granted) in [(Some("alice"), true), (None, false)] {
+            let (_connection_id, header) = log_on(&mut server, user);
+            let durable =
+                server.grant_durable(header.session_id, 1, &create, OplockLevel::Batch, 0);
+            assert_eq!(durable.is_some(), granted);
+        }
+    }
+}
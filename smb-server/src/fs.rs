@@ -0,0 +1,281 @@
+//! Translating between what the local filesystem has and what SMB wants to see.
+
+use std::ffi::{CString, OsStr};
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use smb2::message::{FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_HIDDEN};
+use smb2::message::{FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SPARSE_FILE};
+
+use crate::status::{NtStatus, STATUS_OBJECT_NAME_INVALID, STATUS_OBJECT_PATH_SYNTAX_BAD};
+
+// the number of 100ns intervals between 1601-01-01 and 1970-01-01.
+const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+
+/// Turns a share relative `dir\file` name into a path under `root`,
+/// refusing anything that would climb out of the share.
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, NtStatus> {
+    if name.contains(['/', '\0']) {
+        return Err(STATUS_OBJECT_NAME_INVALID);
+    }
+    let mut path = root.to_path_buf();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(STATUS_OBJECT_PATH_SYNTAX_BAD),
+        }
+    }
+    Ok(path)
+}
+
+/// A Windows FILETIME: 100ns intervals since 1601.
+pub fn filetime(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH_AS_FILETIME + (since.as_nanos() / 100) as u64,
+        Err(e) => UNIX_EPOCH_AS_FILETIME.saturating_sub((e.duration().as_nanos() / 100) as u64),
+    }
+}
+
+/// The other way around, for when a client wants to change a timestamp.
+pub fn from_filetime(filetime: u64) -> SystemTime {
+    let since = Duration::from_nanos(filetime.abs_diff(UNIX_EPOCH_AS_FILETIME) * 100);
+    if filetime >= UNIX_EPOCH_AS_FILETIME {
+        UNIX_EPOCH + since
+    } else {
+        UNIX_EPOCH - since
+    }
+}
+
+/// The timestamps every info response wants, already in FILETIME.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileTimes {
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+}
+
+impl FileTimes {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let last_write_time = metadata.modified().map(filetime).unwrap_or_default();
+        let change_time = UNIX_EPOCH_AS_FILETIME
+            + metadata.ctime() as u64 * 10_000_000
+            + metadata.ctime_nsec() as u64 / 100;
+        Self {
+            // not every filesystem keeps a birth time around.
+            creation_time: metadata.created().map(filetime).unwrap_or(last_write_time),
+            last_access_time: metadata.accessed().map(filetime).unwrap_or_default(),
+            last_write_time,
+            change_time,
+        }
+    }
+}
+
+/// `path` the way a client would name it: relative to the share
+/// root, `\` separated, and empty for the root itself.
+pub fn share_relative_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+pub fn file_attributes(path: &Path, metadata: &Metadata) -> u32 {
+    let mut attributes = if metadata.is_dir() {
+        FILE_ATTRIBUTE_DIRECTORY
+    } else {
+        FILE_ATTRIBUTE_ARCHIVE
+    };
+    if metadata.permissions().readonly() {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    if path
+        .file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    {
+        attributes |= FILE_ATTRIBUTE_HIDDEN;
+    }
+    if metadata.is_file() && metadata.blocks() * 512 < metadata.len() {
+        attributes |= FILE_ATTRIBUTE_SPARSE_FILE;
+    }
+    attributes
+}
+
+pub fn allocation_size(metadata: &Metadata) -> u64 {
+    metadata.blocks() * 512
+}
+
+/// How big the filesystem `path` lives on is, and how much of it is left.
+pub fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::uninit();
+    // SAFETY: path is NUL terminated, and stat is only read if statvfs filled it in.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// Zeroes `offset..offset + length` of `file`, deallocating it where the
+/// filesystem lets us. Never changes the size of the file.
+pub fn zero_range(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    let (Ok(start), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(length))
+    else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    // SAFETY: fallocate doesn't touch any memory of ours.
+    let punched = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            start,
+            len,
+        )
+    };
+    if punched == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(e);
+    }
+    // no holes here, so write the zeroes out the hard way.
+    let zeroes = vec![0; length.min(1024 * 1024) as usize];
+    let mut written = 0;
+    while written < length {
+        let chunk = (length - written).min(zeroes.len() as u64) as usize;
+        file.write_all_at(&zeroes[..chunk], offset + written)?;
+        written += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Opens `path`, which has to be beneath `root`, one component at a time
+/// without following a symlink at any of them, so nothing swapped in for a
+/// directory along the way can lead out of `root`. `root` itself is trusted.
+pub fn open_beneath(root: &Path, path: &Path, write: bool) -> io::Result<File> {
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut components = relative.components().peekable();
+    let mut file = File::open(root)?;
+    while let Some(component) = components.next() {
+        let Component::Normal(name) = component else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        let flags = match components.peek() {
+            Some(_) => libc::O_RDONLY | libc::O_DIRECTORY,
+            None if write => libc::O_RDWR,
+            None => libc::O_RDONLY,
+        };
+        file = openat(&file, name, flags | libc::O_NOFOLLOW | libc::O_CLOEXEC)?;
+    }
+    Ok(file)
+}
+
+fn openat(dir: &File, name: &OsStr, flags: libc::c_int) -> io::Result<File> {
+    let name = CString::new(name.as_bytes())?;
+    // SAFETY: name is NUL terminated, and the fd openat hands back is ours alone.
+    match unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// The `(offset, length)` ranges of `offset..offset + length` that
+/// actually have data behind them, as opposed to being holes.
+pub fn allocated_ranges(file: &File, offset: u64, length: u64) -> io::Result<Vec<(u64, u64)>> {
+    let end = offset.saturating_add(length).min(file.metadata()?.len());
+    let seek = |from: u64, whence| {
+        // SAFETY: lseek doesn't touch any memory of ours.
+        match unsafe { libc::lseek(file.as_raw_fd(), from as libc::off_t, whence) } {
+            -1 => Err(io::Error::last_os_error()),
+            to => Ok(to as u64),
+        }
+    };
+    let mut ranges = vec![];
+    let mut position = offset;
+    while position < end {
+        let data = match seek(position, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // nothing but hole from here to the end of the file.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // the filesystem doesn't know about holes, so it's all data.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => position,
+            Err(e) => return Err(e),
+        };
+        if data >= end {
+            break;
+        }
+        let hole = seek(data, libc::SEEK_HOLE).unwrap_or(end).min(end);
+        ranges.push((data, hole - data));
+        position = hole;
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zeroed_ranges_read_back_as_zero_and_data_stays_allocated() {
+        const MIB: u64 = 1024 * 1024;
+        let path = std::env::temp_dir().join(format!("smb-server-zero-{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all_at(&vec![1; 3 * MIB as usize], 0).unwrap();
+
+        zero_range(&file, MIB, MIB).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 3 * MIB);
+        let mut middle = vec![1; MIB as usize];
+        file.read_exact_at(&mut middle, MIB).unwrap();
+        assert!(middle.iter().all(|&byte| byte == 0));
+
+        // whether or not the filesystem punched a hole, the data either side is still there.
+        let ranges = allocated_ranges(&file, 0, u64::MAX).unwrap();
+        let covered = |offset: u64| {
+            ranges
+                .iter()
+                .any(|&(start, length)| (start..start + length).contains(&offset))
+        };
+        assert!(covered(0) && covered(MIB - 1));
+        assert!(covered(2 * MIB) && covered(3 * MIB - 1));
+        assert!(!covered(3 * MIB));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn opens_beneath_the_root_without_following_symlinks() {
+        let dir = std::env::temp_dir().join(format!("smb-server-beneath-{}", std::process::id()));
+        let share = dir.join("share");
+        std::fs::create_dir_all(share.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::fs::write(share.join("sub/file"), b"in").unwrap();
+        std::fs::write(dir.join("outside/file"), b"out").unwrap();
+        std::os::unix::fs::symlink("../outside", share.join("link")).unwrap();
+        std::os::unix::fs::symlink("file", share.join("sub/last")).unwrap();
+
+        assert!(open_beneath(&share, &share.join("sub/file"), true).is_ok());
+        assert!(open_beneath(&share, &share.join("sub"), false).is_ok());
+        // a symlink anywhere on the way is as far as it goes.
+        assert!(open_beneath(&share, &share.join("link/file"), false).is_err());
+        assert!(open_beneath(&share, &share.join("sub/last"), false).is_err());
+        assert!(open_beneath(&share, &share.join("sub/../../outside/file"), false).is_err());
+        assert!(open_beneath(&share, &dir.join("outside/file"), false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
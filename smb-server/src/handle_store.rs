@@ -0,0 +1,473 @@
+//! Persistent handles, for continuously available shares. Everything needed
+//! to hand an open back to its client is journaled to disk whenever it
+//! changes, so it can be put back together after the server restarts.
+//!
+//! Each open gets a file of its own in the store, named after its file id,
+//! holding one `key value...` line per field with every number in hex.
+//! Files are replaced by writing a new one alongside and renaming it over
+//! the old, so a crash part way through never leaves half a record behind.
+
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use smb2::message::{OplockLevel, SmbLockElement};
+
+use crate::create::{Open, WRITE_ACCESS};
+use crate::durable::Durable;
+use crate::lock::file_key;
+use crate::{fs, Server};
+
+/// A lease as it was when it was last journaled.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PersistedLease {
+    pub key: u128,
+    pub state: u32,
+    pub epoch: Option<u16>,
+    pub parent_key: Option<u128>,
+}
+
+/// Everything about a persistent open that has to survive a restart.
+#[derive(Debug, PartialEq)]
+pub struct PersistedOpen {
+    pub file_id: u64,
+    pub create_guid: u128,
+    pub client_guid: u128,
+    /// The share it was opened on, lowercase.
+    pub share: String,
+    /// Where the share lived on disk, which `path` can't leave.
+    pub root: PathBuf,
+    /// Who opened it.
+    pub owner: String,
+    pub path: PathBuf,
+    pub directory: bool,
+    pub desired_access: u32,
+    pub share_access: u32,
+    pub oplock_level: OplockLevel,
+    pub timeout: Duration,
+    pub lease: Option<PersistedLease>,
+    pub locks: Vec<SmbLockElement>,
+}
+
+impl PersistedOpen {
+    fn to_text(&self) -> String {
+        let mut out = format!(
+            "file_id {:x}\ncreate_guid {:x}\nclient_guid {:x}\nshare {}\nroot {}\npath {}\n\
+             directory {:x}\ndesired_access {:x}\nshare_access {:x}\noplock_level {:x}\n\
+             timeout {:x}\n",
+            self.file_id,
+            self.create_guid,
+            self.client_guid,
+            hex(self.share.as_bytes()),
+            // paths don't have to be UTF-8, and could have newlines in them.
+            hex(self.root.as_os_str().as_bytes()),
+            hex(self.path.as_os_str().as_bytes()),
+            self.directory as u8,
+            self.desired_access,
+            self.share_access,
+            self.oplock_level as u8,
+            self.timeout.as_millis(),
+        );
+        out += &format!("owner {}\n", hex(self.owner.as_bytes()));
+        if let Some(lease) = &self.lease {
+            let optional = |value: Option<u128>| match value {
+                Some(value) => format!("{value:x}"),
+                None => "-".into(),
+            };
+            out += &format!(
+                "lease {:x} {:x} {} {}\n",
+                lease.key,
+                lease.state,
+                optional(lease.epoch.map(u128::from)),
+                optional(lease.parent_key),
+            );
+        }
+        for lock in &self.locks {
+            out += &format!(
+                "lock {:x} {:x} {:x}\n",
+                lock.offset, lock.length, lock.flags
+            );
+        }
+        out
+    }
+
+    /// None if the record is missing something, or isn't one at all.
+    fn parse(text: &str) -> Option<Self> {
+        let mut file_id = None;
+        let mut create_guid = None;
+        let mut client_guid = None;
+        let mut share = None;
+        let mut root = None;
+        let mut owner = None;
+        let mut path = None;
+        let mut directory = None;
+        let mut desired_access = None;
+        let mut share_access = None;
+        let mut oplock_level = None;
+        let mut timeout = None;
+        let mut lease = None;
+        let mut locks = vec![];
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let key = fields.next()?;
+            let mut next = || u128::from_str_radix(fields.next()?, 16).ok();
+            match key {
+                "file_id" => file_id = Some(next()? as u64),
+                "create_guid" => create_guid = Some(next()?),
+                "client_guid" => client_guid = Some(next()?),
+                "share" => {
+                    share = Some(String::from_utf8(unhex(line.strip_prefix("share ")?)?).ok()?)
+                }
+                "owner" => {
+                    owner = Some(String::from_utf8(unhex(line.strip_prefix("owner ")?)?).ok()?)
+                }
+                "root" => root = Some(unhex(line.strip_prefix("root ")?)?),
+                "path" => path = Some(unhex(line.strip_prefix("path ")?)?),
+                "directory" => directory = Some(next()? != 0),
+                "desired_access" => desired_access = Some(next()? as u32),
+                "share_access" => share_access = Some(next()? as u32),
+                "oplock_level" => oplock_level = OplockLevel::try_from(next()? as u8).ok(),
+                "timeout" => timeout = Some(Duration::from_millis(next()? as u64)),
+                "lease" => {
+                    let (key, state) = (next()?, next()? as u32);
+                    // "-" doesn't parse, which is what it's for.
+                    let (epoch, parent_key) = (next().map(|epoch| epoch as u16), next());
+                    lease = Some(PersistedLease {
+                        key,
+                        state,
+                        epoch,
+                        parent_key,
+                    });
+                }
+                "lock" => locks.push(SmbLockElement {
+                    offset: next()? as u64,
+                    length: next()? as u64,
+                    flags: next()? as u32,
+                }),
+                _ => return None,
+            }
+        }
+        Some(Self {
+            file_id: file_id?,
+            create_guid: create_guid?,
+            client_guid: client_guid?,
+            share: share?,
+            root: PathBuf::from(OsString::from_vec(root?)),
+            owner: owner?,
+            path: PathBuf::from(OsString::from_vec(path?)),
+            directory: directory?,
+            desired_access: desired_access?,
+            share_access: share_access?,
+            oplock_level: oplock_level?,
+            timeout: timeout?,
+            lease,
+            locks,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The directory persistent opens are journaled to.
+pub struct HandleStore {
+    dir: PathBuf,
+}
+
+impl HandleStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn record_path(&self, file_id: u64) -> PathBuf {
+        self.dir.join(format!("{file_id:016x}"))
+    }
+
+    pub fn save(&self, open: &PersistedOpen) -> io::Result<()> {
+        let path = self.record_path(open.file_id);
+        let temp = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        file.write_all(open.to_text().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)
+    }
+
+    pub fn remove(&self, file_id: u64) -> io::Result<()> {
+        match std::fs::remove_file(self.record_path(file_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every open in the store. Records that can't be read are left where
+    /// they are for somebody to look at, rather than stopping the rest.
+    pub fn load(&self) -> io::Result<Vec<PersistedOpen>> {
+        let mut opens = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            // left over from a save that never finished.
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            match std::fs::read_to_string(&path).map(|text| PersistedOpen::parse(&text)) {
+                Ok(Some(open)) => opens.push(open),
+                Ok(None) => println!("ignoring unreadable handle record {path:?}"),
+                Err(e) => println!("couldn't read handle record {path:?}: {e}"),
+            }
+        }
+        Ok(opens)
+    }
+}
+
+impl Server {
+    /// Whether the tree connect an open was made through is to a
+    /// continuously available share.
+    pub(crate) fn continuously_available(&self, session_id: u64, tree_id: u32) -> bool {
+        self.sessions
+            .get(&session_id)
+            .and_then(|session| session.trees.get(&tree_id))
+            .and_then(|tree| self.shares.get(&tree.share))
+            .is_some_and(|share| share.continuously_available)
+    }
+
+    /// Writes out the current state of an open, if it's a persistent one.
+    pub(crate) fn journal(&self, volatile: u64) {
+        let (Some(store), Some(open)) = (&self.handle_store, self.opens.get(&volatile)) else {
+            return;
+        };
+        let Some(durable) = open.durable.as_ref().filter(|durable| durable.persistent) else {
+            return;
+        };
+        let Some(root) = self
+            .shares
+            .get(&durable.share)
+            .and_then(|share| share.path.clone())
+        else {
+            println!(
+                "{} has nowhere on disk, not journaling {:?}",
+                durable.share, open.path
+            );
+            return;
+        };
+        let locks = match &open.file {
+            Some(file) => file_key(file)
+                .map(|file| self.locks.held_by(file, volatile))
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        let record = PersistedOpen {
+            file_id: volatile,
+            create_guid: durable.create_guid.unwrap_or(0),
+            client_guid: durable.client_guid,
+            share: durable.share.clone(),
+            root,
+            owner: durable.owner.clone(),
+            path: open.path.clone(),
+            directory: open.file.is_none(),
+            desired_access: open.desired_access,
+            share_access: open.share_access,
+            oplock_level: open.oplock_level,
+            timeout: durable.timeout,
+            lease: open.lease.and_then(|id| self.persisted_lease(id)),
+            locks,
+        };
+        if let Err(e) = store.save(&record) {
+            println!("couldn't journal open of {:?}: {e}", open.path);
+        }
+    }
+
+    /// Journals every open sharing a lease, since they all record its state.
+    pub(crate) fn journal_lease_holders(&self, client_guid: u128, key: u128) {
+        for (&volatile, open) in &self.opens {
+            if open.lease == Some((client_guid, key)) {
+                self.journal(volatile);
+            }
+        }
+    }
+
+    /// Drops a persistent open from the store once it's closed for good.
+    pub(crate) fn forget_persistent(&self, volatile: u64, open: &Open) {
+        let persistent = open
+            .durable
+            .as_ref()
+            .is_some_and(|durable| durable.persistent);
+        if let (true, Some(store)) = (persistent, &self.handle_store) {
+            if let Err(e) = store.remove(volatile) {
+                println!("couldn't remove handle record for {:?}: {e}", open.path);
+            }
+        }
+    }
+
+    /// Puts back every persistent open from before the server restarted,
+    /// waiting to be reclaimed as if its connection had just gone.
+    pub(crate) fn restore_persistent_opens(&mut self) {
+        let Some(store) = &self.handle_store else {
+            return;
+        };
+        let records = match store.load() {
+            Ok(records) => records,
+            Err(e) => {
+                println!("couldn't load persistent handles: {e}");
+                return;
+            }
+        };
+        let now = Instant::now();
+        for record in records {
+            // a share that's gone, or lives somewhere else now, isn't where
+            // any of its opens were.
+            let root = self
+                .shares
+                .get(&record.share)
+                .and_then(|share| share.path.as_ref());
+            if root != Some(&record.root) {
+                println!(
+                    "{} isn't at {:?} any more, not reopening {:?}",
+                    record.share, record.root, record.path
+                );
+                self.forget_record(record.file_id);
+                continue;
+            }
+            // whatever's there now might not be what was opened before, and
+            // until it's reclaimed there's no tree connect to say where's safe
+            // to follow a symlink to, so none are, anywhere along the path.
+            let write = !record.directory && record.desired_access & WRITE_ACCESS != 0;
+            let file = match fs::open_beneath(&record.root, &record.path, write) {
+                Ok(file) => (!record.directory).then_some(file),
+                Err(e) => {
+                    println!(
+                        "couldn't reopen {:?} beneath {:?}: {e}",
+                        record.path, record.root
+                    );
+                    self.forget_record(record.file_id);
+                    continue;
+                }
+            };
+            let volatile = record.file_id;
+            if let Some(file) = file.as_ref().and_then(|file| file_key(file).ok()) {
+                for element in &record.locks {
+                    self.locks.restore(file, volatile, element);
+                }
+            }
+            let lease = record
+                .lease
+                .map(|lease| self.restore_lease(record.client_guid, &lease, &record.path));
+            println!("restored persistent open of {:?}", record.path);
+            self.opens.insert(
+                volatile,
+                Open {
+                    // nobody can use it until it's reclaimed.
+                    session_id: 0,
+                    tree_id: 0,
+                    path: record.path,
+                    file,
+                    desired_access: record.desired_access,
+                    share_access: record.share_access,
+                    delete_on_close: false,
+                    position: 0,
+                    lock_sequences: [None; 64],
+                    search: None,
+                    oplock_level: record.oplock_level,
+                    lease,
+                    durable: Some(Durable {
+                        timeout: record.timeout,
+                        create_guid: Some(record.create_guid),
+                        client_guid: record.client_guid,
+                        share: record.share,
+                        owner: record.owner,
+                        expires: Some(now + record.timeout),
+                        persistent: true,
+                    }),
+                },
+            );
+            self.next_file_id = self.next_file_id.max(volatile);
+        }
+    }
+
+    fn forget_record(&self, file_id: u64) {
+        if let Some(store) = &self.handle_store {
+            let _ = store.remove(file_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smb2::message::{SMB2_LOCKFLAG_EXCLUSIVE_LOCK, SMB2_LOCKFLAG_SHARED_LOCK};
+
+    fn example_open() -> PersistedOpen {
+        PersistedOpen {
+            file_id: 7,
+            create_guid: 0x0123_4567_89AB_CDEF_0123_4567_89AB_CDEF,
+            client_guid: 42,
+            share: "public".into(),
+            root: PathBuf::from("/srv/public"),
+            owner: "alice smith".into(),
+            path: PathBuf::from("/srv/public/some dir/a\nb.txt"),
+            directory: false,
+            desired_access: 0x0012_019F,
+            share_access: 0x7,
+            oplock_level: OplockLevel::Lease,
+            timeout: Duration::from_secs(60),
+            lease: Some(PersistedLease {
+                key: 99,
+                state: 0x7,
+                epoch: Some(3),
+                parent_key: None,
+            }),
+            locks: vec![
+                SmbLockElement {
+                    offset: 0,
+                    length: 10,
+                    flags: SMB2_LOCKFLAG_SHARED_LOCK,
+                },
+                SmbLockElement {
+                    offset: 100,
+                    length: u64::MAX,
+                    flags: SMB2_LOCKFLAG_EXCLUSIVE_LOCK,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let open = example_open();
+        assert_eq!(PersistedOpen::parse(&open.to_text()), Some(open));
+        assert_eq!(PersistedOpen::parse("file_id 7\n"), None);
+        assert_eq!(PersistedOpen::parse("nonsense 1\n"), None);
+    }
+
+    #[test]
+    fn store_saves_loads_and_removes() {
+        let dir = std::env::temp_dir().join(format!("smb-server-handles-{}", std::process::id()));
+        let store = HandleStore::open(&dir).unwrap();
+        store.save(&example_open()).unwrap();
+        assert_eq!(store.load().unwrap(), vec![example_open()]);
+        store.remove(7).unwrap();
+        assert_eq!(store.load().unwrap(), vec![]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! IOCTL, which is really a dispatcher for a pile of unrelated FSCTLs.
+
+use std::os::unix::fs::FileExt;
+
+use smb2::message::FSCTL_VALIDATE_NEGOTIATE_INFO;
+use smb2::message::{FileAllocatedRangeBuffer, FileZeroDataInformation, NetworkInterfaceInfo};
+use smb2::message::{SmbBody, SmbFileId, SmbIoctl, SmbIoctlResponse, SmbMessageHeader};
+use smb2::message::{SrvCopychunkCopy, SrvCopychunkResponse, SrvRequestResumeKey};
+use smb2::message::{SymbolicLinkReparseBuffer, SYMLINK_FLAG_RELATIVE};
+use smb2::message::{ValidateNegotiateInfo, ValidateNegotiateInfoResponse};
+use smb2::message::{FILE_WRITE_ATTRIBUTES, SMB2_0_IOCTL_IS_FSCTL};
+use smb2::message::{FSCTL_GET_REPARSE_POINT, FSCTL_PIPE_TRANSCEIVE};
+use smb2::message::{FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_QUERY_NETWORK_INTERFACE_INFO};
+use smb2::message::{FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA, FSCTL_SRV_COPYCHUNK};
+use smb2::message::{FSCTL_SRV_COPYCHUNK_WRITE, FSCTL_SRV_REQUEST_RESUME_KEY};
+
+use crate::create::{READ_ACCESS, WRITE_ACCESS};
+use crate::status::*;
+use crate::SERVER_GUID;
+use crate::{buffer_too_small, fs, HandlerResult, Server, MAX_READ_WRITE_SIZE};
+
+/// Handles one FSCTL, returning what goes in the response's output buffer.
+/// Like any other handler it can set a status on `header` to go with it.
+type FsctlHandler =
+    fn(&mut Server, u64, &mut SmbMessageHeader, &SmbIoctl) -> Result<Vec<u8>, NtStatus>;
+
+/// Every FSCTL we understand. Anything else is STATUS_NOT_SUPPORTED.
+const FSCTL_HANDLERS: [(u32, FsctlHandler); 10] = [
+    (
+        FSCTL_VALIDATE_NEGOTIATE_INFO,
+        Server::validate_negotiate_info,
+    ),
+    (
+        FSCTL_QUERY_NETWORK_INTERFACE_INFO,
+        Server::query_network_interface_info,
+    ),
+    (FSCTL_PIPE_TRANSCEIVE, Server::pipe_transceive),
+    (FSCTL_SRV_REQUEST_RESUME_KEY, Server::request_resume_key),
+    (FSCTL_SRV_COPYCHUNK, Server::copychunk),
+    (FSCTL_SRV_COPYCHUNK_WRITE, Server::copychunk),
+    (FSCTL_SET_SPARSE, Server::set_sparse),
+    (FSCTL_SET_ZERO_DATA, Server::set_zero_data),
+    (FSCTL_QUERY_ALLOCATED_RANGES, Server::query_allocated_ranges),
+    (FSCTL_GET_REPARSE_POINT, Server::get_reparse_point),
+];
+
+// how much a single copychunk request is allowed to do, the same as Windows.
+const COPYCHUNK_MAX_CHUNKS: u32 = 256;
+const COPYCHUNK_MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+const COPYCHUNK_MAX_TOTAL_SIZE: u32 = 16 * 1024 * 1024;
+
+// there's only ever the one interface: the one the client is talking to.
+const INTERFACE_LINK_SPEED: u64 = 1_000_000_000;
+
+impl Server {
+    pub(crate) fn ioctl(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> HandlerResult {
+        if ioctl.flags & SMB2_0_IOCTL_IS_FSCTL == 0 {
+            return Err(STATUS_NOT_SUPPORTED.into());
+        }
+        if ioctl.input.len() as u32 > MAX_READ_WRITE_SIZE
+            || ioctl.max_output_response > MAX_READ_WRITE_SIZE
+        {
+            return Err(STATUS_INVALID_PARAMETER.into());
+        }
+        let Some(&(_, handler)) = FSCTL_HANDLERS
+            .iter()
+            .find(|(ctl_code, _)| *ctl_code == ioctl.ctl_code)
+        else {
+            return Err(STATUS_NOT_SUPPORTED.into());
+        };
+        let output = handler(self, connection_id, header, ioctl)?;
+        if output.len() > ioctl.max_output_response as usize {
+            return Err(buffer_too_small(output.len()));
+        }
+        Ok(SmbBody::IoctlResponse(SmbIoctlResponse {
+            size: 49,
+            ctl_code: ioctl.ctl_code,
+            file_id: ioctl.file_id,
+            input_offset: SmbIoctlResponse::BUFFER_OFFSET,
+            input_count: 0,
+            output_offset: SmbIoctlResponse::BUFFER_OFFSET,
+            output_count: output.len() as u32,
+            flags: 0,
+            input: vec![],
+            output,
+        }))
+    }
+
+    fn validate_negotiate_info(
+        &mut self,
+        connection_id: u64,
+        _header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let (_, info) =
+            ValidateNegotiateInfo::parse(&ioctl.input).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        let negotiated = self
+            .connections
+            .get(&connection_id)
+            .and_then(|connection| connection.negotiated.as_ref())
+            .ok_or(STATUS_INVALID_PARAMETER)?;
+        let matches = info.capabilities == negotiated.client_capabilities
+            && info.guid == negotiated.client_guid
+            && info.security_mode == negotiated.client_security_mode
+            && info.dialects == negotiated.client_dialects
+            && self.dialects.select(&info.dialects) == Some(negotiated.dialect);
+        if !matches {
+            // somebody in the middle rewrote the NEGOTIATE, so this
+            // connection can't be trusted with anything else.
+            println!("negotiate on connection {connection_id} doesn't validate, hanging up");
+            self.connections.remove(&connection_id);
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        Ok(ValidateNegotiateInfoResponse {
+            capabilities: negotiated.capabilities,
+            guid: SERVER_GUID,
+            security_mode: self.security_mode,
+            dialect: negotiated.dialect,
+        }
+        .to_vec())
+    }
+
+    fn query_network_interface_info(
+        &mut self,
+        connection_id: u64,
+        _header: &mut SmbMessageHeader,
+        _ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let address = self
+            .connections
+            .get(&connection_id)
+            .and_then(|connection| connection.local_addr)
+            .ok_or(STATUS_NOT_SUPPORTED)?;
+        Ok(NetworkInterfaceInfo::list_to_vec(&[NetworkInterfaceInfo {
+            if_index: 1,
+            capability: 0,
+            link_speed: INTERFACE_LINK_SPEED,
+            address,
+        }]))
+    }
+
+    fn pipe_transceive(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        // there aren't any named pipes yet, so whatever this is, it isn't one.
+        self.open(connection_id, header, ioctl.file_id)?;
+        Err(STATUS_INVALID_DEVICE_REQUEST)
+    }
+
+    fn request_resume_key(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        self.open(connection_id, header, ioctl.file_id)?;
+        let mut resume_key = [0; 24];
+        resume_key[..16].copy_from_slice(&ioctl.file_id.to_vec());
+        Ok(SrvRequestResumeKey { resume_key }.to_vec())
+    }
+
+    fn copychunk(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let (_, copy) =
+            SrvCopychunkCopy::parse(&ioctl.input).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        let total: u64 = copy.chunks.iter().map(|chunk| chunk.length as u64).sum();
+        if copy.chunks.len() as u32 > COPYCHUNK_MAX_CHUNKS
+            || copy
+                .chunks
+                .iter()
+                .any(|chunk| chunk.length == 0 || chunk.length > COPYCHUNK_MAX_CHUNK_SIZE)
+            || total > COPYCHUNK_MAX_TOTAL_SIZE as u64
+        {
+            // tell the client what it's allowed to ask for instead.
+            header.status = STATUS_INVALID_PARAMETER;
+            return Ok(SrvCopychunkResponse {
+                chunks_written: COPYCHUNK_MAX_CHUNKS,
+                chunk_bytes_written: COPYCHUNK_MAX_CHUNK_SIZE,
+                total_bytes_written: COPYCHUNK_MAX_TOTAL_SIZE,
+            }
+            .to_vec());
+        }
+
+        let target = self.open(connection_id, header, ioctl.file_id)?;
+        // plain COPYCHUNK wants to read the target too, for no good reason.
+        let needs_read = ioctl.ctl_code == FSCTL_SRV_COPYCHUNK;
+        if target.desired_access & WRITE_ACCESS == 0
+            || needs_read && target.desired_access & READ_ACCESS == 0
+        {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        if target.file.is_none() {
+            return Err(STATUS_INVALID_DEVICE_REQUEST);
+        }
+        let (_, source_id) = SmbFileId::parse(&copy.source_key).unwrap();
+        let source = self
+            .open(connection_id, header, source_id)
+            .map_err(|_| STATUS_OBJECT_NAME_NOT_FOUND)?;
+        if source.desired_access & READ_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        if source.file.is_none() {
+            return Err(STATUS_INVALID_DEVICE_REQUEST);
+        }
+
+        let mut response = SrvCopychunkResponse::default();
+        let mut data = vec![];
+        for chunk in &copy.chunks {
+            let length = chunk.length as u64;
+            self.check_lock_conflict(
+                connection_id,
+                header,
+                source_id,
+                chunk.source_offset,
+                length,
+                false,
+            )?;
+            self.check_lock_conflict(
+                connection_id,
+                header,
+                ioctl.file_id,
+                chunk.target_offset,
+                length,
+                true,
+            )?;
+            let source = self.opens[&source_id.volatile].file.as_ref().unwrap();
+            let target = self.opens[&ioctl.file_id.volatile].file.as_ref().unwrap();
+
+            data.resize(chunk.length as usize, 0);
+            source
+                .read_exact_at(&mut data, chunk.source_offset)
+                .map_err(|e| match e.kind() {
+                    // the chunk runs off the end of the source.
+                    std::io::ErrorKind::UnexpectedEof => STATUS_INVALID_VIEW_SIZE,
+                    _ => NtStatus::from(&e),
+                })?;
+            target
+                .write_all_at(&data, chunk.target_offset)
+                .map_err(|e| NtStatus::from(&e))?;
+            response.chunks_written += 1;
+            response.total_bytes_written += chunk.length;
+        }
+        Ok(response.to_vec())
+    }
+
+    fn set_sparse(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let open = self.open(connection_id, header, ioctl.file_id)?;
+        if open.desired_access & (WRITE_ACCESS | FILE_WRITE_ATTRIBUTES) == 0 {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        if open.file.is_none() {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        // every file here can already have holes in it, whatever the client
+        // asked for (and the input saying what that is is optional anyway).
+        Ok(vec![])
+    }
+
+    fn set_zero_data(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let (_, zero) =
+            FileZeroDataInformation::parse(&ioctl.input).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        if zero.file_offset > zero.beyond_final_zero {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        let length = zero.beyond_final_zero - zero.file_offset;
+        self.check_lock_conflict(
+            connection_id,
+            header,
+            ioctl.file_id,
+            zero.file_offset,
+            length,
+            true,
+        )?;
+        let open = self.open(connection_id, header, ioctl.file_id)?;
+        if open.desired_access & WRITE_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        let file = open.file.as_ref().ok_or(STATUS_INVALID_PARAMETER)?;
+        let size = file.metadata().map_err(|e| NtStatus::from(&e))?.len();
+        // anything past the end of the file is already as zero as it gets.
+        let length = length.min(size.saturating_sub(zero.file_offset));
+        if length > 0 {
+            fs::zero_range(file, zero.file_offset, length).map_err(|e| NtStatus::from(&e))?;
+        }
+        Ok(vec![])
+    }
+
+    fn query_allocated_ranges(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let (_, range) =
+            FileAllocatedRangeBuffer::parse(&ioctl.input).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        let open = self.open(connection_id, header, ioctl.file_id)?;
+        if open.desired_access & READ_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        let file = open.file.as_ref().ok_or(STATUS_INVALID_PARAMETER)?;
+        let ranges = fs::allocated_ranges(file, range.file_offset, range.length)
+            .map_err(|e| NtStatus::from(&e))?;
+
+        let fits = ioctl.max_output_response as usize / FileAllocatedRangeBuffer::SIZE;
+        if ranges.len() > fits {
+            if fits == 0 {
+                return Err(STATUS_BUFFER_TOO_SMALL);
+            }
+            header.status = STATUS_BUFFER_OVERFLOW;
+        }
+        Ok(ranges
+            .into_iter()
+            .take(fits)
+            .flat_map(|(file_offset, length)| {
+                FileAllocatedRangeBuffer {
+                    file_offset,
+                    length,
+                }
+                .to_vec()
+            })
+            .collect())
+    }
+
+    fn get_reparse_point(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        ioctl: &SmbIoctl,
+    ) -> Result<Vec<u8>, NtStatus> {
+        let open = self.open(connection_id, header, ioctl.file_id)?;
+        let is_symlink = std::fs::symlink_metadata(&open.path)
+            .map_err(|e| NtStatus::from(&e))?
+            .is_symlink();
+        if !is_symlink {
+            return Err(STATUS_NOT_A_REPARSE_POINT);
+        }
+        let target = std::fs::read_link(&open.path).map_err(|e| NtStatus::from(&e))?;
+        let name = target.to_string_lossy().replace('/', "\\");
+        Ok(SymbolicLinkReparseBuffer {
+            substitute_name: name.clone(),
+            print_name: name,
+            flags: if target.is_relative() {
+                SYMLINK_FLAG_RELATIVE
+            } else {
+                0
+            },
+        }
+        .to_vec())
+    }
+}
@@ -0,0 +1,418 @@
+//! Leases, the SMB 2.1+ replacement for oplocks, which can be shared
+//! between several opens as long as they're all using the same key.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use smb2::message::{Dialect, SmbMessage};
+use smb2::message::{SmbBody, SmbCreate, SmbLease, SmbLeaseBreak, SmbLeaseBreakNotification};
+use smb2::message::{SMB2_LEASE_FLAG_BREAK_IN_PROGRESS, SMB2_LEASE_FLAG_PARENT_LEASE_KEY_SET};
+use smb2::message::{SMB2_LEASE_HANDLE_CACHING, SMB2_LEASE_NONE, SMB2_LEASE_READ_CACHING};
+use smb2::message::{SMB2_LEASE_WRITE_CACHING, SMB2_NOTIFY_BREAK_LEASE_FLAG_ACK_REQUIRED};
+
+use crate::create::sharing_violation;
+use crate::handle_store::PersistedLease;
+use crate::oplock::break_header;
+use crate::status::*;
+use crate::{HandlerResult, Server};
+
+/// How long a client gets to acknowledge a lease break, the same as for oplocks.
+const LEASE_BREAK_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// Lease keys are only unique per client, so they're kept
+/// alongside the ClientGuid of whoever asked for them.
+pub type LeaseId = (u128, u128);
+
+struct LeaseBreak {
+    to: u32,
+    deadline: Instant,
+}
+
+struct Lease {
+    /// Every open sharing a lease has to be of the same file.
+    path: PathBuf,
+    /// Some mix of the `SMB2_LEASE_*_CACHING` flags.
+    state: u32,
+    /// None for v1 leases, which don't have one.
+    epoch: Option<u16>,
+    /// The lease on the directory the file's in, for v2 leases that said.
+    parent_key: Option<u128>,
+    /// Waiting on the client to acknowledge a break.
+    breaking: Option<LeaseBreak>,
+}
+
+impl Lease {
+    /// The lease as it goes in a CREATE response.
+    fn to_context(&self, key: u128) -> SmbLease {
+        let break_flag = match self.breaking {
+            Some(_) => SMB2_LEASE_FLAG_BREAK_IN_PROGRESS,
+            None => 0,
+        };
+        match self.epoch {
+            None => SmbLease::V1 {
+                key,
+                state: self.state,
+                flags: break_flag,
+                duration: 0,
+            },
+            Some(epoch) => SmbLease::V2 {
+                key,
+                state: self.state,
+                flags: break_flag
+                    | match self.parent_key {
+                        Some(_) => SMB2_LEASE_FLAG_PARENT_LEASE_KEY_SET,
+                        None => 0,
+                    },
+                duration: 0,
+                parent_key: self.parent_key.unwrap_or(0),
+                epoch,
+            },
+        }
+    }
+}
+
+/// Every lease any client holds, on any file.
+#[derive(Default)]
+pub struct LeaseTable {
+    leases: HashMap<LeaseId, Lease>,
+}
+
+/// Trims a requested lease state down to one that can actually be granted.
+fn valid_state(state: u32, directory: bool) -> u32 {
+    let mut state =
+        state & (SMB2_LEASE_READ_CACHING | SMB2_LEASE_HANDLE_CACHING | SMB2_LEASE_WRITE_CACHING);
+    // nobody's going to be writing to a directory through its handle.
+    if directory {
+        state &= !SMB2_LEASE_WRITE_CACHING;
+    }
+    // handle or write caching without read caching doesn't mean anything.
+    if state & SMB2_LEASE_READ_CACHING == 0 {
+        return SMB2_LEASE_NONE;
+    }
+    state
+}
+
+impl Server {
+    /// The ClientGuid leases for `session_id` are kept under, or None if
+    /// the client didn't negotiate a dialect that has leases.
+    pub(crate) fn lease_client(&self, session_id: u64) -> Option<u128> {
+        let negotiated = self.negotiated(session_id)?;
+        (negotiated.dialect >= Dialect::Smb210).then_some(negotiated.client_guid)
+    }
+
+    /// The lease a CREATE wants, if it wants one and is allowed one.
+    pub(crate) fn requested_lease<'a>(
+        &self,
+        session_id: u64,
+        create: &'a SmbCreate,
+    ) -> Option<(LeaseId, &'a SmbLease)> {
+        let client_guid = self.lease_client(session_id)?;
+        let lease = create.requested_lease()?;
+        Some(((client_guid, lease.key()), lease))
+    }
+
+    /// A lease key can't be reused for a different file.
+    pub(crate) fn check_lease_path(&self, id: LeaseId, path: &Path) -> Result<(), NtStatus> {
+        match self.leases.leases.get(&id) {
+            Some(lease) if lease.path != path => Err(STATUS_INVALID_PARAMETER),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hands out as much of `requested` as can be, upgrading the lease if it
+    /// already exists, and returns what to tell the client it got.
+    pub(crate) fn grant_lease(
+        &mut self,
+        id: LeaseId,
+        requested: &SmbLease,
+        path: &Path,
+        directory: bool,
+        shared: bool,
+    ) -> SmbLease {
+        let mut state = valid_state(requested.state(), directory);
+        // somebody else can write behind our back.
+        if shared {
+            state &= !SMB2_LEASE_WRITE_CACHING;
+        }
+        let lease = self.leases.leases.entry(id).or_insert_with(|| Lease {
+            path: path.to_path_buf(),
+            state: SMB2_LEASE_NONE,
+            epoch: match requested {
+                SmbLease::V1 { .. } => None,
+                SmbLease::V2 { epoch, .. } => Some(*epoch),
+            },
+            parent_key: match requested {
+                SmbLease::V2 {
+                    flags, parent_key, ..
+                } if flags & SMB2_LEASE_FLAG_PARENT_LEASE_KEY_SET != 0 => Some(*parent_key),
+                _ => None,
+            },
+            breaking: None,
+        });
+        // leases only ever get upgraded by a CREATE, never downgraded.
+        if lease.breaking.is_none() && state & lease.state == lease.state && state != lease.state {
+            lease.state = state;
+            if let Some(epoch) = &mut lease.epoch {
+                *epoch = epoch.wrapping_add(1);
+            }
+        }
+        lease.to_context(id.1)
+    }
+
+    /// What to tell a client reclaiming an open about the lease it has.
+    pub(crate) fn lease_context(&self, id: LeaseId) -> Option<SmbLease> {
+        Some(self.leases.leases.get(&id)?.to_context(id.1))
+    }
+
+    /// Starts breaking every other lease on `path` that's in the way of
+    /// `create`, returning whether it has to wait for any of them.
+    pub(crate) fn break_leases(
+        &mut self,
+        requester: Option<LeaseId>,
+        path: &Path,
+        create: &SmbCreate,
+        overwrite: bool,
+    ) -> bool {
+        let others: Vec<_> = self
+            .leases
+            .leases
+            .iter()
+            .filter(|(&id, lease)| lease.path == path && Some(id) != requester)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut wait = false;
+        for id in others {
+            let lease = &self.leases.leases[&id];
+            if lease.breaking.is_some() {
+                wait = true;
+                continue;
+            }
+            let mut to = if overwrite {
+                SMB2_LEASE_NONE
+            } else {
+                lease.state & !SMB2_LEASE_WRITE_CACHING
+            };
+            // cached handles have to be closed for the new open to get in.
+            let conflicts = self.opens.values().any(|open| {
+                open.lease == Some(id)
+                    && sharing_violation(
+                        create.desired_access,
+                        create.share_access,
+                        open.desired_access,
+                        open.share_access,
+                    )
+            });
+            if conflicts {
+                to = valid_state(to & !SMB2_LEASE_HANDLE_CACHING, false);
+            }
+            if to != lease.state {
+                wait |= self.break_lease(id, to);
+            }
+        }
+        wait
+    }
+
+    /// Takes read caching away from every lease on `path` other than
+    /// `writer`'s, since it's just been written to.
+    pub(crate) fn break_read_leases(&mut self, path: &Path, writer: Option<LeaseId>) {
+        let readers: Vec<_> = self
+            .leases
+            .leases
+            .iter()
+            .filter(|(&id, lease)| {
+                lease.path == path
+                    && Some(id) != writer
+                    && lease.breaking.is_none()
+                    && lease.state != SMB2_LEASE_NONE
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in readers {
+            self.break_lease(id, SMB2_LEASE_NONE);
+        }
+    }
+
+    /// Lets everybody with a lease on the directory `path` is in know that
+    /// its contents have changed, except whoever did the changing.
+    pub(crate) fn break_parent_leases(&mut self, path: &Path, child: Option<LeaseId>) {
+        let Some(parent) = path.parent() else {
+            return;
+        };
+        let except = child.and_then(|id| Some((id.0, self.leases.leases.get(&id)?.parent_key?)));
+        let holders: Vec<_> = self
+            .leases
+            .leases
+            .iter()
+            .filter(|(&id, lease)| {
+                lease.path == parent
+                    && Some(id) != except
+                    && lease.breaking.is_none()
+                    && lease.state != SMB2_LEASE_NONE
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in holders {
+            self.break_lease(id, SMB2_LEASE_NONE);
+        }
+    }
+
+    /// Tells the client its lease is being broken to `to`, returning
+    /// whether it has to acknowledge that before anything else can happen.
+    fn break_lease(&mut self, id: LeaseId, to: u32) -> bool {
+        let lease = self.leases.leases.get_mut(&id).unwrap();
+        let current = lease.state;
+        let ack_required = current & (SMB2_LEASE_WRITE_CACHING | SMB2_LEASE_HANDLE_CACHING) != 0;
+        if let Some(epoch) = &mut lease.epoch {
+            *epoch = epoch.wrapping_add(1);
+        }
+        let new_epoch = lease.epoch.unwrap_or(0);
+        if ack_required {
+            lease.breaking = Some(LeaseBreak {
+                to,
+                deadline: Instant::now() + LEASE_BREAK_TIMEOUT,
+            });
+        } else {
+            lease.state = to;
+        }
+
+        let Some(open) = self.opens.values().find(|open| open.lease == Some(id)) else {
+            return ack_required;
+        };
+        let Some(session) = self.sessions.get(&open.session_id) else {
+            return ack_required;
+        };
+        self.send(
+            session.connection_id,
+            SmbMessage {
+                header: break_header(open),
+                body: SmbBody::LeaseBreakNotification(SmbLeaseBreakNotification {
+                    size: 44,
+                    new_epoch,
+                    flags: if ack_required {
+                        SMB2_NOTIFY_BREAK_LEASE_FLAG_ACK_REQUIRED
+                    } else {
+                        0
+                    },
+                    lease_key: id.1,
+                    current_lease_state: current,
+                    new_lease_state: to,
+                }),
+            },
+        );
+        ack_required
+    }
+
+    /// The client acknowledging a lease break we sent it.
+    pub(crate) fn lease_break(&mut self, connection_id: u64, ack: &SmbLeaseBreak) -> HandlerResult {
+        let client_guid = self
+            .connections
+            .get(&connection_id)
+            .and_then(|connection| connection.negotiated.as_ref())
+            .map(|negotiated| negotiated.client_guid)
+            .ok_or(STATUS_INVALID_PARAMETER)?;
+        let lease = self
+            .leases
+            .leases
+            .get_mut(&(client_guid, ack.lease_key))
+            .ok_or(STATUS_OBJECT_NAME_NOT_FOUND)?;
+        let pending = lease.breaking.take().ok_or(STATUS_UNSUCCESSFUL)?;
+        // it's only allowed to go as far as it was told, or further.
+        let accepted = ack.lease_state & !pending.to == 0;
+        lease.state = if accepted {
+            ack.lease_state
+        } else {
+            pending.to
+        };
+        self.journal_lease_holders(client_guid, ack.lease_key);
+        self.retry_waiting_creates();
+        if !accepted {
+            return Err(STATUS_REQUEST_NOT_ACCEPTED.into());
+        }
+        Ok(SmbBody::LeaseBreakResponse(SmbLeaseBreak {
+            size: 36,
+            lease_key: ack.lease_key,
+            lease_state: ack.lease_state,
+        }))
+    }
+
+    /// Gives up on any lease breaks that have run out of time, as if
+    /// they'd been acknowledged. Returns whether there were any.
+    pub(crate) fn expire_lease_breaks(&mut self, now: Instant) -> bool {
+        let mut expired = false;
+        for lease in self.leases.leases.values_mut() {
+            if let Some(pending) = lease.breaking.take_if(|pending| pending.deadline <= now) {
+                println!("lease break on {:?} timed out", lease.path);
+                lease.state = pending.to;
+                expired = true;
+            }
+        }
+        expired
+    }
+
+    /// A lease as it goes in the handle store.
+    pub(crate) fn persisted_lease(&self, id: LeaseId) -> Option<PersistedLease> {
+        let lease = self.leases.leases.get(&id)?;
+        Some(PersistedLease {
+            key: id.1,
+            // a break that was still going when we went down never finishes.
+            state: lease
+                .breaking
+                .as_ref()
+                .map_or(lease.state, |pending| pending.to),
+            epoch: lease.epoch,
+            parent_key: lease.parent_key,
+        })
+    }
+
+    /// Puts back a lease a persistent open had before the server restarted.
+    pub(crate) fn restore_lease(
+        &mut self,
+        client_guid: u128,
+        persisted: &PersistedLease,
+        path: &Path,
+    ) -> LeaseId {
+        let id = (client_guid, persisted.key);
+        self.leases.leases.entry(id).or_insert_with(|| Lease {
+            path: path.to_path_buf(),
+            state: persisted.state,
+            epoch: persisted.epoch,
+            parent_key: persisted.parent_key,
+            breaking: None,
+        });
+        id
+    }
+
+    /// Forgets a lease once nothing's using it any more.
+    pub(crate) fn release_lease(&mut self, id: LeaseId) {
+        if self.opens.values().any(|open| open.lease == Some(id)) {
+            return;
+        }
+        let Some(lease) = self.leases.leases.remove(&id) else {
+            return;
+        };
+        if lease.breaking.is_some() {
+            self.retry_waiting_creates();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_meaningful_states_get_granted() {
+        let rwh = SMB2_LEASE_READ_CACHING | SMB2_LEASE_WRITE_CACHING | SMB2_LEASE_HANDLE_CACHING;
+        assert_eq!(valid_state(rwh, false), rwh);
+        assert_eq!(
+            valid_state(rwh, true),
+            SMB2_LEASE_READ_CACHING | SMB2_LEASE_HANDLE_CACHING
+        );
+        assert_eq!(
+            valid_state(SMB2_LEASE_HANDLE_CACHING, false),
+            SMB2_LEASE_NONE
+        );
+        assert_eq!(valid_state(0x100 | SMB2_LEASE_READ_CACHING, false), 1);
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+
+use smb2::message::{SmbBody, SmbFileId, SmbLock, SmbLockElement, SmbLockResponse};
+use smb2::message::{SmbMessage, SmbMessageHeader};
+use smb2::message::{SMB2_LOCKFLAG_EXCLUSIVE_LOCK, SMB2_LOCKFLAG_FAIL_IMMEDIATELY};
+use smb2::message::{SMB2_LOCKFLAG_SHARED_LOCK, SMB2_LOCKFLAG_UNLOCK};
+
+use crate::status::*;
+use crate::{cancels, error_message, HandlerResult, Server};
+
+/// Locks belong to the file rather than the name it was opened by,
+/// so they're kept by device and inode.
+pub type FileKey = (u64, u64);
+
+pub fn file_key(file: &File) -> Result<FileKey, NtStatus> {
+    let metadata = file.metadata().map_err(|e| NtStatus::from(&e))?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ByteRangeLock {
+    /// The open holding it.
+    volatile: u64,
+    offset: u64,
+    length: u64,
+    exclusive: bool,
+}
+
+impl ByteRangeLock {
+    fn overlaps(&self, offset: u64, length: u64) -> bool {
+        // zero length ranges never get in anybody's way.
+        if self.length == 0 || length == 0 {
+            return false;
+        }
+        let (start, end) = (
+            self.offset as u128,
+            self.offset as u128 + self.length as u128,
+        );
+        (offset as u128) < end && start < offset as u128 + length as u128
+    }
+}
+
+/// A blocking lock waiting on whatever is in its way to be unlocked.
+struct PendingLock {
+    connection_id: u64,
+    /// What the final response goes out with, already async.
+    header: SmbMessageHeader,
+    file: FileKey,
+    volatile: u64,
+    element: SmbLockElement,
+}
+
+/// Every byte range lock held on every file, across all opens and sessions.
+#[derive(Default)]
+pub struct LockManager {
+    locks: HashMap<FileKey, Vec<ByteRangeLock>>,
+    pending: Vec<PendingLock>,
+}
+
+impl LockManager {
+    fn try_lock(&mut self, file: FileKey, volatile: u64, element: &SmbLockElement) -> bool {
+        let exclusive = element.flags & SMB2_LOCKFLAG_EXCLUSIVE_LOCK != 0;
+        let locks = self.locks.entry(file).or_default();
+        let conflict = locks.iter().any(|lock| {
+            lock.overlaps(element.offset, element.length)
+                && (exclusive || (lock.exclusive && lock.volatile != volatile))
+        });
+        if !conflict {
+            locks.push(ByteRangeLock {
+                volatile,
+                offset: element.offset,
+                length: element.length,
+                exclusive,
+            });
+        }
+        !conflict
+    }
+
+    /// Unlocks have to name a range exactly the way it was locked.
+    fn unlock(
+        &mut self,
+        file: FileKey,
+        volatile: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<(), NtStatus> {
+        let locks = self.locks.get_mut(&file).ok_or(STATUS_RANGE_NOT_LOCKED)?;
+        let index = locks
+            .iter()
+            .position(|lock| {
+                lock.volatile == volatile && lock.offset == offset && lock.length == length
+            })
+            .ok_or(STATUS_RANGE_NOT_LOCKED)?;
+        locks.remove(index);
+        if locks.is_empty() {
+            self.locks.remove(&file);
+        }
+        Ok(())
+    }
+
+    /// Drops every lock an open was holding.
+    fn release_open(&mut self, file: FileKey, volatile: u64) {
+        if let Some(locks) = self.locks.get_mut(&file) {
+            locks.retain(|lock| lock.volatile != volatile);
+            if locks.is_empty() {
+                self.locks.remove(&file);
+            }
+        }
+    }
+
+    /// The locks `volatile` holds on `file`, as they'd be asked for again.
+    pub fn held_by(&self, file: FileKey, volatile: u64) -> Vec<SmbLockElement> {
+        let Some(locks) = self.locks.get(&file) else {
+            return vec![];
+        };
+        locks
+            .iter()
+            .filter(|lock| lock.volatile == volatile)
+            .map(|lock| SmbLockElement {
+                offset: lock.offset,
+                length: lock.length,
+                flags: if lock.exclusive {
+                    SMB2_LOCKFLAG_EXCLUSIVE_LOCK
+                } else {
+                    SMB2_LOCKFLAG_SHARED_LOCK
+                },
+            })
+            .collect()
+    }
+
+    /// Puts back a lock an open held before the server restarted.
+    pub fn restore(&mut self, file: FileKey, volatile: u64, element: &SmbLockElement) {
+        if !self.try_lock(file, volatile, element) {
+            println!("couldn't restore lock at {} on {file:?}", element.offset);
+        }
+    }
+
+    /// Whether a read or write through `volatile` would run into
+    /// somebody else's lock. Shared locks keep everyone from writing,
+    /// the holder included.
+    pub fn check_io(
+        &self,
+        file: FileKey,
+        volatile: u64,
+        offset: u64,
+        length: u64,
+        write: bool,
+    ) -> Result<(), NtStatus> {
+        let Some(locks) = self.locks.get(&file) else {
+            return Ok(());
+        };
+        let conflict = locks.iter().any(|lock| {
+            lock.overlaps(offset, length)
+                && if lock.exclusive {
+                    lock.volatile != volatile
+                } else {
+                    write
+                }
+        });
+        if conflict {
+            return Err(STATUS_FILE_LOCK_CONFLICT);
+        }
+        Ok(())
+    }
+}
+
+impl Server {
+    pub(crate) fn lock(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        lock: &SmbLock,
+    ) -> HandlerResult {
+        let open = self.open(connection_id, header, lock.file_id)?;
+        let file = file_key(open.file.as_ref().ok_or(STATUS_INVALID_PARAMETER)?)?;
+        // a resent request for something that already happened.
+        let sequence_slot = match lock.lock_sequence_index {
+            index @ 1..=64 => Some(index as usize - 1),
+            _ => None,
+        };
+        if let Some(slot) = sequence_slot {
+            if open.lock_sequences[slot] == Some(lock.lock_sequence_number) {
+                return Ok(SmbBody::LockResponse(SmbLockResponse { size: 4 }));
+            }
+            open.lock_sequences[slot] = None;
+        }
+        let volatile = lock.file_id.volatile;
+
+        let Some(first) = lock.locks.first() else {
+            return Err(STATUS_INVALID_PARAMETER.into());
+        };
+        if first.flags & SMB2_LOCKFLAG_UNLOCK != 0 {
+            for element in &lock.locks {
+                if element.flags != SMB2_LOCKFLAG_UNLOCK {
+                    return Err(STATUS_INVALID_PARAMETER.into());
+                }
+                self.locks
+                    .unlock(file, volatile, element.offset, element.length)?;
+            }
+            self.retry_pending_locks();
+            self.journal(volatile);
+        } else {
+            let blocking =
+                lock.locks.len() == 1 && first.flags & SMB2_LOCKFLAG_FAIL_IMMEDIATELY == 0;
+            for element in &lock.locks {
+                let valid = matches!(
+                    element.flags & !SMB2_LOCKFLAG_FAIL_IMMEDIATELY,
+                    SMB2_LOCKFLAG_SHARED_LOCK | SMB2_LOCKFLAG_EXCLUSIVE_LOCK
+                );
+                if !valid || (!blocking && element.flags & SMB2_LOCKFLAG_FAIL_IMMEDIATELY == 0) {
+                    return Err(STATUS_INVALID_PARAMETER.into());
+                }
+            }
+            for (i, element) in lock.locks.iter().enumerate() {
+                if self.locks.try_lock(file, volatile, element) {
+                    continue;
+                }
+                if blocking {
+                    self.go_async(header);
+                    self.locks.pending.push(PendingLock {
+                        connection_id,
+                        header: SmbMessageHeader {
+                            // the interim response already handed out credits.
+                            credit_request_response: 0,
+                            ..header.clone()
+                        },
+                        file,
+                        volatile,
+                        element: *element,
+                    });
+                    return Err(STATUS_PENDING.into());
+                }
+                // all or nothing, so give back whatever this request already got.
+                for acquired in &lock.locks[..i] {
+                    let _ = self
+                        .locks
+                        .unlock(file, volatile, acquired.offset, acquired.length);
+                }
+                return Err(STATUS_LOCK_NOT_GRANTED.into());
+            }
+            self.journal(volatile);
+        }
+
+        if let (Some(slot), Some(open)) = (sequence_slot, self.opens.get_mut(&volatile)) {
+            open.lock_sequences[slot] = Some(lock.lock_sequence_number);
+        }
+        Ok(SmbBody::LockResponse(SmbLockResponse { size: 4 }))
+    }
+
+    /// Gives any blocked locks that can now be granted their final response.
+    fn retry_pending_locks(&mut self) {
+        let mut i = 0;
+        while i < self.locks.pending.len() {
+            let pending = &self.locks.pending[i];
+            let (file, volatile, element) = (pending.file, pending.volatile, pending.element);
+            if !self.locks.try_lock(file, volatile, &element) {
+                i += 1;
+                continue;
+            }
+            let pending = self.locks.pending.remove(i);
+            self.journal(pending.volatile);
+            self.send(
+                pending.connection_id,
+                SmbMessage {
+                    header: pending.header,
+                    body: SmbBody::LockResponse(SmbLockResponse { size: 4 }),
+                },
+            );
+        }
+    }
+
+    /// Lets go of everything a closing open had locked, or was waiting to lock.
+    pub(crate) fn release_locks(&mut self, volatile: u64, file: &File) {
+        let Ok(file) = file_key(file) else {
+            return;
+        };
+        self.locks.release_open(file, volatile);
+        let (cancelled, pending) = std::mem::take(&mut self.locks.pending)
+            .into_iter()
+            .partition(|pending| pending.volatile == volatile);
+        self.locks.pending = pending;
+        for pending in cancelled {
+            self.send(
+                pending.connection_id,
+                error_message(pending.header, STATUS_CANCELLED),
+            );
+        }
+        self.retry_pending_locks();
+    }
+
+    /// Fails the blocked lock `cancel` is for, if that's what it's for.
+    pub(crate) fn cancel_lock(&mut self, connection_id: u64, cancel: &SmbMessageHeader) -> bool {
+        let Some(i) = self.locks.pending.iter().position(|pending| {
+            pending.connection_id == connection_id && cancels(cancel, &pending.header)
+        }) else {
+            return false;
+        };
+        let pending = self.locks.pending.remove(i);
+        self.send(
+            pending.connection_id,
+            error_message(pending.header, STATUS_CANCELLED),
+        );
+        true
+    }
+
+    /// Fails a read or write that would go through someone else's lock.
+    pub(crate) fn check_lock_conflict(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        file_id: SmbFileId,
+        offset: u64,
+        length: u64,
+        write: bool,
+    ) -> Result<(), NtStatus> {
+        if self.locks.locks.is_empty() {
+            return Ok(());
+        }
+        let open = self.open(connection_id, header, file_id)?;
+        let Some(file) = &open.file else {
+            return Ok(());
+        };
+        let file = file_key(file)?;
+        self.locks
+            .check_io(file, file_id.volatile, offset, length, write)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: FileKey = (1, 2);
+
+    fn element(offset: u64, length: u64, flags: u32) -> SmbLockElement {
+        SmbLockElement {
+            offset,
+            length,
+            flags,
+        }
+    }
+
+    #[test]
+    fn shared_locks_coexist_until_someone_wants_exclusive() {
+        let mut locks = LockManager::default();
+        assert!(locks.try_lock(FILE, 1, &element(0, 10, SMB2_LOCKFLAG_SHARED_LOCK)));
+        assert!(locks.try_lock(FILE, 2, &element(5, 10, SMB2_LOCKFLAG_SHARED_LOCK)));
+        assert!(!locks.try_lock(FILE, 3, &element(9, 1, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        // right after the last shared lock ends is fine.
+        assert!(locks.try_lock(FILE, 3, &element(15, 1, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        // and so is a different file.
+        assert!(locks.try_lock((1, 3), 3, &element(0, 10, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+    }
+
+    #[test]
+    fn exclusive_locks_only_let_their_own_open_share() {
+        let mut locks = LockManager::default();
+        assert!(locks.try_lock(FILE, 1, &element(100, 10, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        assert!(!locks.try_lock(FILE, 2, &element(105, 1, SMB2_LOCKFLAG_SHARED_LOCK)));
+        assert!(locks.try_lock(FILE, 1, &element(105, 1, SMB2_LOCKFLAG_SHARED_LOCK)));
+        assert!(!locks.try_lock(FILE, 1, &element(105, 1, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+    }
+
+    #[test]
+    fn zero_length_and_huge_ranges() {
+        let mut locks = LockManager::default();
+        assert!(locks.try_lock(FILE, 1, &element(0, u64::MAX, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        assert!(locks.try_lock(FILE, 2, &element(50, 0, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        assert!(!locks.try_lock(
+            FILE,
+            2,
+            &element(u64::MAX - 2, 1, SMB2_LOCKFLAG_SHARED_LOCK)
+        ));
+    }
+
+    #[test]
+    fn unlocking_has_to_match_exactly() {
+        let mut locks = LockManager::default();
+        assert!(locks.try_lock(FILE, 1, &element(0, 10, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        assert_eq!(locks.unlock(FILE, 1, 0, 5), Err(STATUS_RANGE_NOT_LOCKED));
+        assert_eq!(locks.unlock(FILE, 2, 0, 10), Err(STATUS_RANGE_NOT_LOCKED));
+        assert_eq!(locks.unlock(FILE, 1, 0, 10), Ok(()));
+        assert!(locks.locks.is_empty());
+    }
+
+    #[test]
+    fn io_conflicts() {
+        let mut locks = LockManager::default();
+        assert!(locks.try_lock(FILE, 1, &element(0, 10, SMB2_LOCKFLAG_EXCLUSIVE_LOCK)));
+        assert!(locks.try_lock(FILE, 1, &element(20, 10, SMB2_LOCKFLAG_SHARED_LOCK)));
+        assert_eq!(locks.check_io(FILE, 1, 0, 10, true), Ok(()));
+        assert_eq!(
+            locks.check_io(FILE, 2, 5, 1, false),
+            Err(STATUS_FILE_LOCK_CONFLICT)
+        );
+        assert_eq!(locks.check_io(FILE, 2, 25, 1, false), Ok(()));
+        assert_eq!(
+            locks.check_io(FILE, 1, 25, 1, true),
+            Err(STATUS_FILE_LOCK_CONFLICT)
+        );
+        locks.release_open(FILE, 1);
+        assert_eq!(locks.check_io(FILE, 2, 0, 100, true), Ok(()));
+    }
+}
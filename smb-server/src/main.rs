@@ -1,93 +1,840 @@
+use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroU64;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use smb::{Smb1Header, Smb1Message};
-use smb2::message::SmbNegotiateResponse;
+use bytes::{Bytes, BytesMut};
+use smb::Smb1Message;
+use smb2::compression::{decompress_message, is_compressed};
+use smb2::encryption::{is_encrypted, EncryptionKeys};
+use smb2::message::SMB2_SHAREFLAG_ENCRYPT_DATA;
+use smb2::message::{Capabilities, Command, Dialect, HeaderFlags, SecurityMode};
+use smb2::message::{ShareType, SmbTreeConnect, SmbTreeConnectResponse, SmbTreeDisconnect};
+use smb2::message::{SmbBody, SmbErrorContext, SmbErrorResponse};
+use smb2::message::{SmbEcho, SmbLogoff, SMB2_SHARE_CAP_CONTINUOUS_AVAILABILITY};
 use smb2::message::{SmbMessage, SmbMessageHeader, SmbMessageHeaderVariant};
+use smb2::message::{SmbTransformHeader, TRANSFORM_HEADER_SIZE};
+use smb2::preauth::PreauthIntegrityHash;
+use smb2::signing::SigningKey;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream};
-use tokio::sync::Mutex;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
 
-struct Server;
+mod change_notify;
+use change_notify::ChangeNotifier;
+mod compound;
+mod config;
+use config::Config;
+mod create;
+use create::Open;
+mod durable;
+mod fs;
+mod handle_store;
+use handle_store::HandleStore;
+mod ioctl;
+mod lease;
+use lease::LeaseTable;
+mod lock;
+use lock::LockManager;
+mod negotiate;
+use negotiate::{Algorithms, DialectPolicy};
+mod oplock;
+use oplock::OplockManager;
+mod query_directory;
+mod query_info;
+mod read_write;
+mod session_setup;
+use session_setup::NtlmExchange;
+mod status;
+use status::*;
+mod users;
+use users::UserDatabase;
+
+const MAX_READ_WRITE_SIZE: u32 = 8 * 1024 * 1024;
+
+// the most a compressed PDU can decompress to: the biggest WRITE, with room
+// for its header and whatever it's compounded with.
+const MAX_DECOMPRESSED_SIZE: usize = MAX_READ_WRITE_SIZE as usize + 64 * 1024;
+
+// the most a client can send in one go: as much as it could decompress
+// to, which covers anything compressed, and a transform header if it's
+// encrypted. Checked before anything's allocated for it.
+const MAX_PDU_SIZE: usize = MAX_DECOMPRESSED_SIZE + TRANSFORM_HEADER_SIZE;
+
+// who we say we are in NEGOTIATE, and again in FSCTL_VALIDATE_NEGOTIATE_INFO.
+const SERVER_GUID: u128 = 23885548255760334674942869530154890271;
+
+// FILE_ALL_ACCESS, there's no access control yet.
+const MAXIMAL_ACCESS: u32 = 0x001F01FF;
+
+/// What a command handler hands back: either the body of the response,
+/// or why the request failed.
+type HandlerResult = Result<SmbBody, Failure>;
+
+/// The status to fail a request with, and anything that'll help
+/// the client make sense of it.
+#[derive(Debug, PartialEq)]
+struct Failure {
+    status: NtStatus,
+    error_data: Vec<SmbErrorContext>,
+}
+
+impl From<NtStatus> for Failure {
+    fn from(status: NtStatus) -> Self {
+        Self {
+            status,
+            error_data: vec![],
+        }
+    }
+}
+
+/// STATUS_BUFFER_TOO_SMALL, saying how big the buffer would have had to be.
+fn buffer_too_small(required: usize) -> Failure {
+    Failure {
+        status: STATUS_BUFFER_TOO_SMALL,
+        error_data: vec![SmbErrorContext::BufferTooSmall {
+            required: required as u32,
+        }],
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    // keyed by the lowercase share name, since share names are case insensitive.
+    shares: HashMap<String, Share>,
+    sessions: HashMap<u64, Session>,
+    next_session_id: u64,
+    /// Who clients can log on as.
+    users: UserDatabase,
+    /// Whether clients that don't log on as one of `users` get in as guests.
+    map_to_guest: bool,
+    // keyed by the volatile part of the file id.
+    opens: HashMap<u64, Open>,
+    next_file_id: u64,
+    locks: LockManager,
+    oplocks: OplockManager,
+    leases: LeaseTable,
+    notifier: ChangeNotifier,
+    /// Where persistent opens are journaled, if any share is continuously available.
+    handle_store: Option<HandleStore>,
+    dialects: DialectPolicy,
+    /// Whether we insist on signing, said in NEGOTIATE and
+    /// FSCTL_VALIDATE_NEGOTIATE_INFO.
+    security_mode: SecurityMode,
+    /// Whether every session has to be encrypted.
+    encrypt_data: bool,
+    connections: HashMap<u64, Connection>,
+    next_connection_id: u64,
+    next_async_id: u64,
+}
+
+struct Connection {
+    /// Where to send anything that isn't a direct reply, already encoded.
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    /// The address the client reached us on.
+    local_addr: Option<SocketAddr>,
+    /// None until the client has negotiated a dialect.
+    negotiated: Option<Negotiated>,
+    /// For 3.1.1, the hash over the NEGOTIATE request and response that
+    /// every session set up on the connection starts from.
+    preauth_integrity: Option<PreauthIntegrityHash>,
+}
+
+/// What was said on both sides of a connection's NEGOTIATE, so
+/// FSCTL_VALIDATE_NEGOTIATE_INFO can check nobody tampered with it.
+struct Negotiated {
+    dialect: Dialect,
+    algorithms: Algorithms,
+    capabilities: Capabilities,
+    client_capabilities: Capabilities,
+    client_guid: u128,
+    client_security_mode: SecurityMode,
+    client_dialects: Vec<Dialect>,
+}
+
+struct Share {
+    share_type: ShareType,
+    // None for IPC$, which doesn't live anywhere on disk.
+    path: Option<PathBuf>,
+    continuously_available: bool,
+    /// Whether everything done on it has to be encrypted.
+    encrypt_data: bool,
+}
+
+struct Session {
+    connection_id: u64,
+    guest: bool,
+    /// Who logged on, None for guests and anonymous sessions.
+    user: Option<String>,
+    /// For 3.1.1, the connection's hash carried on over this session's
+    /// SESSION_SETUPs, what its keys are derived from.
+    preauth_integrity: Option<PreauthIntegrityHash>,
+    /// The NTLMSSP exchange it's waiting on the AUTHENTICATE for, while
+    /// it's still being set up.
+    ntlmssp: Option<NtlmExchange>,
+    /// None for sessions without a session key, i.e. guests.
+    signing_key: Option<SigningKey>,
+    /// Whether everything sent on the session has to be signed, rather than
+    /// just what the other side chose to.
+    signing_required: bool,
+    /// None for sessions without a session key, and on connections that
+    /// didn't settle on a cipher.
+    encryption: Option<EncryptionKeys>,
+    /// Whether everything sent on the session has to be encrypted.
+    encrypt_data: bool,
+    trees: HashMap<u32, TreeConnect>,
+    next_tree_id: u32,
+}
+
+struct TreeConnect {
+    share: String,
+}
+
+/// Builds the header for a response to `request`, echoing back
+/// everything the client needs to match it up.
+fn response_header(request: &SmbMessageHeader, status: NtStatus) -> SmbMessageHeader {
+    SmbMessageHeader {
+        protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+        header_size: 64,
+        credit_charge: request.credit_charge,
+        status,
+        command: request.command,
+        // always grant at least one credit so the client can keep talking.
+        credit_request_response: request.credit_request_response.max(1),
+        // a signed request gets a signed response, if there's a key to sign it with.
+        flags: HeaderFlags::SERVER_TO_REDIR | (request.flags & HeaderFlags::SIGNED),
+        next_command: 0,
+        message_id: request.message_id,
+        variant: request.variant,
+        session_id: request.session_id,
+        signature: 0,
+    }
+}
 
 impl Server {
-    async fn handle_message(&mut self, message: &SmbMessage) {}
-    async fn handle_smb1_message(&mut self, message: &Smb1Message) -> SmbMessage {
-        match &message.body {
-            // for now we only are going to support SMB 2.???
-            smb::Smb1Body::SmbComNegotiate(a) => SmbMessage {
-                header: SmbMessageHeader {
-                    protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
-                    header_size: 64,
-                    credit_charge: 0,
-                    status: 0,
-                    command: 0,
-                    credit_request_response: 0,
-                    flags: 0x1 & 0x2,
-                    next_command: 0,
-                    message_id: 14123141,
-                    variant: SmbMessageHeaderVariant::Async {
-                        id: NonZeroU64::new(12).unwrap(),
+    fn new(config: Config) -> Self {
+        let mut shares: HashMap<_, _> = config
+            .shares
+            .into_iter()
+            .map(|share| {
+                (
+                    share.name.to_lowercase(),
+                    Share {
+                        share_type: ShareType::Disk,
+                        path: Some(share.path),
+                        continuously_available: share.continuously_available,
+                        encrypt_data: share.encrypt_data,
                     },
-                    session_id: 0,
-                    signature: 0,
-                },
-                body: smb2::message::SmbBody::NegotiateResponse(SmbNegotiateResponse {
-                    size: 65,
-                    security_mode: 0x01,
-                    dialect_rev: 0x02FF,
-                    negotiate_context_count: 0,
-                    server_guid: 23885548255760334674942869530154890271,
-                    capabilities: 0,
-                    max_transact_size: 120,
-                    max_read_size: 120,
-                    max_write_size: 120,
-                    system_time: 13364930937000000,
-                    server_start_time: 5,
-                    security_buff_offset: 0,
-                    security_buff_len: 0,
-                    neg_context_offset: 0,
-                    buf: vec![],
-                    context_list: vec![],
-                }),
+                )
+            })
+            .collect();
+        shares.insert(
+            "ipc$".into(),
+            Share {
+                share_type: ShareType::Pipe,
+                path: None,
+                continuously_available: false,
+                encrypt_data: false,
+            },
+        );
+        let handle_store = shares
+            .values()
+            .any(|share| share.continuously_available)
+            .then(|| HandleStore::open(&config.handle_store))
+            .and_then(|store| {
+                store
+                    .inspect_err(|e| println!("couldn't open persistent handle store: {e}"))
+                    .ok()
+            });
+        let users = config
+            .passwd_file
+            .map(|path| {
+                UserDatabase::load(&path)
+                    .inspect_err(|e| println!("couldn't read {}: {e}", path.display()))
+                    .unwrap_or_default()
+            })
+            .unwrap_or_default();
+        let mut server = Self {
+            shares,
+            handle_store,
+            users,
+            map_to_guest: config.map_to_guest,
+            dialects: DialectPolicy {
+                min: config.min_protocol,
+                max: config.max_protocol,
             },
+            security_mode: match config.signing_required {
+                true => SecurityMode::SIGNING_ENABLED | SecurityMode::SIGNING_REQUIRED,
+                false => SecurityMode::SIGNING_ENABLED,
+            },
+            encrypt_data: config.encrypt_data,
+            ..Default::default()
+        };
+        server.restore_persistent_opens();
+        server
+    }
+
+    fn connect(
+        &mut self,
+        sender: mpsc::UnboundedSender<Vec<u8>>,
+        local_addr: Option<SocketAddr>,
+    ) -> u64 {
+        self.next_connection_id += 1;
+        self.connections.insert(
+            self.next_connection_id,
+            Connection {
+                sender,
+                local_addr,
+                negotiated: None,
+                preauth_integrity: None,
+            },
+        );
+        self.next_connection_id
+    }
+
+    /// Cleans up after a client that hung up: its sessions are gone, and so is
+    /// everything they had open, except for durable opens it might come back for.
+    fn disconnect(&mut self, connection_id: u64) {
+        self.connections.remove(&connection_id);
+        let session_ids: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.connection_id == connection_id)
+            .map(|(&session_id, _)| session_id)
+            .collect();
+        self.orphan_durable_opens(&session_ids);
+        for session_id in session_ids {
+            self.remove_session(session_id);
+        }
+    }
+
+    /// Ends a session, closing everything opened through it.
+    fn remove_session(&mut self, session_id: u64) -> Option<Session> {
+        let mut session = self.sessions.remove(&session_id)?;
+        for tree_id in std::mem::take(&mut session.trees).into_keys() {
+            self.close_tree_opens(session_id, tree_id);
+        }
+        Some(session)
+    }
+
+    /// Sends something the client didn't directly ask for just now,
+    /// e.g. the final response to a request that went async.
+    fn send(&self, connection_id: u64, message: SmbMessage) {
+        if let Some(connection) = self.connections.get(&connection_id) {
+            // if the client's gone, so is whoever was waiting for this.
+            let _ = connection
+                .sender
+                .send(self.encode(connection_id, vec![message], false));
+        }
+    }
+
+    /// Encodes `messages` as one compounded PDU, ready to go on the wire.
+    /// If they're answering an `encrypted` request, or are on a session or
+    /// share that has to be, it's encrypted rather than signed. Big READs
+    /// are compressed in between.
+    /// Only the keys of sessions on `connection_id` get used.
+    fn encode(
+        &self,
+        connection_id: u64,
+        mut messages: Vec<SmbMessage>,
+        encrypted: bool,
+    ) -> Vec<u8> {
+        let encryption = messages.first().and_then(|message| {
+            let session = self
+                .session_on(connection_id, message.header.session_id)
+                .ok()?;
+            let keys = session.encryption.as_ref()?;
+            (encrypted || self.requires_encryption(&message.header)).then_some(keys)
+        });
+        let pdu = match encryption {
+            Some(_) => {
+                for message in &mut messages {
+                    message.header.flags.remove(HeaderFlags::SIGNED);
+                }
+                SmbMessage::compound_to_vec(&messages)
+            }
+            None => self.sign(connection_id, &mut messages),
+        };
+        let pdu = self.compress_reads(&messages, pdu);
+        match encryption {
+            Some(keys) => keys.encrypt(messages[0].header.session_id, &pdu),
+            None => pdu,
         }
     }
+
+    /// Encodes `messages` as one compounded PDU, signing the ones that were
+    /// asked for signed and the ones on sessions that sign everything.
+    fn sign(&self, connection_id: u64, messages: &mut [SmbMessage]) -> Vec<u8> {
+        let keys: Vec<_> = messages
+            .iter_mut()
+            .map(|message| {
+                let signed = message.header.flags.contains(HeaderFlags::SIGNED);
+                let key = self
+                    .session_on(connection_id, message.header.session_id)
+                    .ok()
+                    .filter(|session| signed || session.signing_required)
+                    .and_then(|session| session.signing_key.as_ref());
+                if key.is_none() {
+                    message.header.flags.remove(HeaderFlags::SIGNED);
+                }
+                key
+            })
+            .collect();
+        SmbMessage::compound_to_vec_signed(messages, &keys)
+    }
+
+    /// Checks that `raw`, the request `header` is from, is what the client
+    /// sent. Encrypted requests already have been, the rest are checked by
+    /// their signature: sessions that sign everything fail anything unsigned,
+    /// the rest only check what's signed. Nothing but an encrypted request
+    /// will do for sessions and shares that have to be encrypted.
+    fn authenticate(
+        &self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        raw: &[u8],
+        encrypted: bool,
+    ) -> Result<(), NtStatus> {
+        if encrypted {
+            return Ok(());
+        }
+        let Ok(session) = self.session_on(connection_id, header.session_id) else {
+            // it's up to the handler to say there's no such session.
+            return Ok(());
+        };
+        if self.requires_encryption(header) {
+            println!("unencrypted {:?} where it has to be", header.command);
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        let Some(key) = &session.signing_key else {
+            return Ok(());
+        };
+        let valid = match header.flags.contains(HeaderFlags::SIGNED) {
+            true => key.verify(raw),
+            false => !session.signing_required,
+        };
+        if !valid {
+            println!("bad signature on {:?}", header.command);
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        Ok(())
+    }
+
+    /// Whether what `header` is part of has to be encrypted, because its
+    /// session or share say so. Sessions are set up before there's a key to
+    /// encrypt with, so that's never the case for NEGOTIATE or SESSION_SETUP.
+    /// Async headers don't say which tree they're for, so any of the
+    /// session's will do.
+    fn requires_encryption(&self, header: &SmbMessageHeader) -> bool {
+        if matches!(header.command, Command::Negotiate | Command::SessionSetup) {
+            return false;
+        }
+        let Some(session) = self.sessions.get(&header.session_id) else {
+            return false;
+        };
+        let encrypted_share = |tree: &TreeConnect| {
+            self.shares
+                .get(&tree.share)
+                .is_some_and(|share| share.encrypt_data)
+        };
+        session.encrypt_data
+            || match header.variant {
+                SmbMessageHeaderVariant::Sync { tree_id } => {
+                    session.trees.get(&tree_id).is_some_and(encrypted_share)
+                }
+                SmbMessageHeaderVariant::Async { .. } => {
+                    session.trees.values().any(encrypted_share)
+                }
+            }
+    }
+
+    /// The PDU `buf`, behind a transform header, decrypted with the keys
+    /// of the session it's for, along with that session's id. None if it
+    /// isn't for a session on this connection that can be encrypted, or
+    /// doesn't decrypt.
+    fn decrypt(&self, connection_id: u64, buf: &[u8]) -> Option<(Bytes, u64)> {
+        let (_remaining, header) = SmbTransformHeader::parse(buf).ok()?;
+        let session = self
+            .sessions
+            .get(&header.session_id)
+            .filter(|session| session.connection_id == connection_id)?;
+        let pdu = session.encryption.as_ref()?.decrypt(buf)?;
+        Some((Bytes::from(pdu), header.session_id))
+    }
+
+    /// The PDU `buf`, behind a compression transform header, decompressed.
+    /// None if it was compressed with something the connection didn't
+    /// negotiate, or doesn't decompress.
+    fn decompress(&self, connection_id: u64, buf: &[u8]) -> Option<Bytes> {
+        let negotiated = self.connections.get(&connection_id)?.negotiated.as_ref()?;
+        let algorithms = &negotiated.algorithms;
+        let pdu = decompress_message(
+            buf,
+            &algorithms.compression,
+            algorithms.chained_compression,
+            MAX_DECOMPRESSED_SIZE,
+        )?;
+        Some(Bytes::from(pdu))
+    }
+
+    /// Turns `header` into the header of an interim STATUS_PENDING
+    /// response, with the final response to follow.
+    fn go_async(&mut self, header: &mut SmbMessageHeader) -> NonZeroU64 {
+        self.next_async_id += 1;
+        let id = NonZeroU64::new(self.next_async_id).unwrap();
+        header.flags |= HeaderFlags::ASYNC_COMMAND;
+        header.variant = SmbMessageHeaderVariant::Async { id };
+        id
+    }
+
+    /// Fails whichever async request `header` is cancelling, be it named by its
+    /// AsyncId or its MessageId. There's nothing to do if it's already finished.
+    fn cancel(&mut self, connection_id: u64, header: &SmbMessageHeader) {
+        let _ = self.cancel_lock(connection_id, header)
+            || self.cancel_notify(connection_id, header)
+            || self.cancel_create(connection_id, header);
+    }
+
+    /// `raw` is the message as it came in, for the few things that need to
+    /// see exactly what the client sent.
+    fn handle_message(
+        &mut self,
+        connection_id: u64,
+        message: &SmbMessage,
+        raw: &[u8],
+    ) -> Option<SmbMessage> {
+        if let SmbBody::Cancel(_) = message.body {
+            // the response is to the cancelled request, never the CANCEL itself.
+            self.cancel(connection_id, &message.header);
+            return None;
+        }
+        let mut header = response_header(&message.header, STATUS_SUCCESS);
+        let result = match &message.body {
+            SmbBody::Negotiate(negotiate) => self.negotiate(connection_id, negotiate, raw),
+            SmbBody::SessionSetup(session_setup) => {
+                self.session_setup(connection_id, &mut header, session_setup, raw)
+            }
+            SmbBody::Logoff(_) => self.logoff(connection_id, &message.header),
+            SmbBody::TreeConnect(tree_connect) => {
+                self.tree_connect(connection_id, &mut header, tree_connect)
+            }
+            SmbBody::TreeDisconnect(_) => self.tree_disconnect(connection_id, &message.header),
+            SmbBody::Create(create) => self.create(connection_id, &mut header, create),
+            SmbBody::Close(close) => self.close(connection_id, &message.header, close),
+            SmbBody::Read(read) => self.read(connection_id, &message.header, read),
+            SmbBody::Write(write) => self.write(connection_id, &message.header, write),
+            SmbBody::Lock(lock) => self.lock(connection_id, &mut header, lock),
+            SmbBody::Ioctl(ioctl) => self.ioctl(connection_id, &mut header, ioctl),
+            SmbBody::Echo(_) => Ok(SmbBody::EchoResponse(SmbEcho { size: 4 })),
+            SmbBody::QueryDirectory(query) => {
+                self.query_directory(connection_id, &message.header, query)
+            }
+            SmbBody::ChangeNotify(notify) => self.change_notify(connection_id, &mut header, notify),
+            SmbBody::QueryInfo(query) => self.query_info(connection_id, &mut header, query),
+            SmbBody::SetInfo(set) => self.set_info(connection_id, &message.header, set),
+            SmbBody::OplockBreak(ack) => self.oplock_break(connection_id, &message.header, ack),
+            SmbBody::LeaseBreak(ack) => self.lease_break(connection_id, ack),
+            _ => Err(STATUS_NOT_SUPPORTED.into()),
+        };
+        if !self.connections.contains_key(&connection_id) {
+            // hung up on, there's nobody left to answer.
+            return None;
+        }
+        let response = match result {
+            Ok(body) => SmbMessage { header, body },
+            Err(failure) => self.failure_message(connection_id, header, failure),
+        };
+        self.preauth_response(connection_id, &response);
+        Some(response)
+    }
+
+    /// Chains a NEGOTIATE or SESSION_SETUP response into the preauth
+    /// integrity hash it's part of, if there is one.
+    fn preauth_response(&mut self, connection_id: u64, response: &SmbMessage) {
+        let preauth_integrity = match response.header.command {
+            Command::Negotiate => self
+                .connections
+                .get_mut(&connection_id)
+                .and_then(|connection| connection.preauth_integrity.as_mut()),
+            // the response that finishes setting up the session isn't part of it,
+            // it's signed with what came before.
+            Command::SessionSetup if response.header.status == STATUS_MORE_PROCESSING_REQUIRED => {
+                self.sessions
+                    .get_mut(&response.header.session_id)
+                    .and_then(|session| session.preauth_integrity.as_mut())
+            }
+            _ => None,
+        };
+        if let Some(preauth_integrity) = preauth_integrity {
+            preauth_integrity.update(&response.to_vec());
+        }
+    }
+
+    /// The response to a request that failed, with whatever error data
+    /// went with it in the form the connection's dialect expects.
+    fn failure_message(
+        &self,
+        connection_id: u64,
+        mut header: SmbMessageHeader,
+        failure: Failure,
+    ) -> SmbMessage {
+        let contexts = self
+            .connections
+            .get(&connection_id)
+            .and_then(|connection| connection.negotiated.as_ref())
+            .is_some_and(|negotiated| negotiated.dialect >= Dialect::Smb311);
+        header.status = failure.status;
+        SmbMessage {
+            header,
+            body: SmbBody::ErrorResponse(SmbErrorResponse::new(failure.error_data, contexts)),
+        }
+    }
+
+    /// `session_id`, so long as it was set up on `connection_id`. Session
+    /// ids are easy to guess, and nothing's bound to another connection.
+    fn session_on(&self, connection_id: u64, session_id: u64) -> Result<&Session, NtStatus> {
+        self.sessions
+            .get(&session_id)
+            .filter(|session| session.connection_id == connection_id)
+            .ok_or(STATUS_USER_SESSION_DELETED)
+    }
+
+    /// The request's session, so long as it's been set up on `connection_id`.
+    fn session(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+    ) -> Result<&mut Session, NtStatus> {
+        self.sessions
+            .get_mut(&header.session_id)
+            .filter(|session| session.connection_id == connection_id)
+            .filter(|session| session.ntlmssp.is_none())
+            .ok_or(STATUS_USER_SESSION_DELETED)
+    }
+
+    /// What was negotiated on the connection `session_id` was set up on.
+    fn negotiated(&self, session_id: u64) -> Option<&Negotiated> {
+        let session = self.sessions.get(&session_id)?;
+        self.connections
+            .get(&session.connection_id)?
+            .negotiated
+            .as_ref()
+    }
+
+    fn tree_id(&self, connection_id: u64, header: &SmbMessageHeader) -> Result<u32, NtStatus> {
+        let SmbMessageHeaderVariant::Sync { tree_id } = header.variant else {
+            return Err(STATUS_NETWORK_NAME_DELETED);
+        };
+        let session = self.session_on(connection_id, header.session_id)?;
+        if !session.trees.contains_key(&tree_id) {
+            return Err(STATUS_NETWORK_NAME_DELETED);
+        }
+        Ok(tree_id)
+    }
+
+    /// The share the request's tree connect is for.
+    fn share(&self, connection_id: u64, header: &SmbMessageHeader) -> Result<&Share, NtStatus> {
+        let tree_id = self.tree_id(connection_id, header)?;
+        let tree = &self.sessions[&header.session_id].trees[&tree_id];
+        self.shares
+            .get(&tree.share)
+            .ok_or(STATUS_NETWORK_NAME_DELETED)
+    }
+
+    fn logoff(&mut self, connection_id: u64, header: &SmbMessageHeader) -> HandlerResult {
+        self.session_on(connection_id, header.session_id)?;
+        self.remove_session(header.session_id)
+            .ok_or(STATUS_USER_SESSION_DELETED)?;
+        Ok(SmbBody::LogoffResponse(SmbLogoff { size: 4 }))
+    }
+
+    fn tree_connect(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        tree_connect: &SmbTreeConnect,
+    ) -> HandlerResult {
+        // nobody gets to find out which shares there are without a session.
+        self.session(connection_id, header)?;
+        let share_name = tree_connect.share_name().to_lowercase();
+        let share = self
+            .shares
+            .get(&share_name)
+            .ok_or(STATUS_BAD_NETWORK_NAME)?;
+        if share.path.as_ref().is_some_and(|path| !path.is_dir()) {
+            println!("share {share_name} doesn't point to a directory");
+            return Err(STATUS_BAD_NETWORK_NAME.into());
+        }
+        let share_type = share.share_type;
+        let capabilities = if share.continuously_available {
+            SMB2_SHARE_CAP_CONTINUOUS_AVAILABILITY
+        } else {
+            0
+        };
+        let share_flags = if share.encrypt_data {
+            SMB2_SHAREFLAG_ENCRYPT_DATA
+        } else {
+            0
+        };
+        let encrypt_data = share.encrypt_data;
+        let session = self.session(connection_id, header)?;
+        if encrypt_data && session.encryption.is_none() {
+            println!("{share_name} has to be encrypted, and the session can't be");
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+        session.next_tree_id += 1;
+        let tree_id = session.next_tree_id;
+        session
+            .trees
+            .insert(tree_id, TreeConnect { share: share_name });
+
+        header.variant = SmbMessageHeaderVariant::Sync { tree_id };
+        Ok(SmbBody::TreeConnectResponse(SmbTreeConnectResponse {
+            size: 16,
+            share_type,
+            share_flags,
+            capabilities,
+            maximal_access: MAXIMAL_ACCESS,
+        }))
+    }
+
+    fn tree_disconnect(&mut self, connection_id: u64, header: &SmbMessageHeader) -> HandlerResult {
+        let tree_id = self.tree_id(connection_id, header)?;
+        self.close_tree_opens(header.session_id, tree_id);
+        let tree = self
+            .session(connection_id, header)?
+            .trees
+            .remove(&tree_id)
+            .ok_or(STATUS_NETWORK_NAME_DELETED)?;
+        println!("disconnected from {}", tree.share);
+        Ok(SmbBody::TreeDisconnectResponse(SmbTreeDisconnect {
+            size: 4,
+        }))
+    }
+}
+
+/// Whether `cancel` is the CANCEL for the request `pending` is
+/// the (already async) final response header of.
+fn cancels(cancel: &SmbMessageHeader, pending: &SmbMessageHeader) -> bool {
+    if cancel.flags.contains(HeaderFlags::ASYNC_COMMAND) {
+        cancel.variant == pending.variant
+    } else {
+        cancel.message_id == pending.message_id && cancel.session_id == pending.session_id
+    }
+}
+
+/// The response to a request that failed with nothing more to say than `status`.
+fn error_message(mut header: SmbMessageHeader, status: NtStatus) -> SmbMessage {
+    header.status = status;
+    SmbMessage {
+        header,
+        body: SmbBody::ErrorResponse(SmbErrorResponse::new(vec![], false)),
+    }
 }
 
-async fn handle_conn(server: Arc<Mutex<Server>>, mut socket: TcpStream) {
+async fn send_pdu(socket: &mut OwnedWriteHalf, buff: Vec<u8>) -> io::Result<()> {
+    let mut buff2 = vec![];
+    buff2.extend(u32::to_be_bytes(buff.len() as u32));
+    buff2.extend(buff);
+    socket.write_all(&buff2).await?;
+    Ok(())
+}
+
+/// Everything headed to a client goes through here, so responses
+/// to async requests can be sent whenever they're ready.
+async fn write_messages(mut socket: OwnedWriteHalf, mut pdus: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(pdu) = pdus.recv().await {
+        if send_pdu(&mut socket, pdu).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle_conn(server: Arc<Mutex<Server>>, socket: TcpStream) {
+    let local_addr = socket.local_addr().ok();
+    let (mut socket, writer) = socket.into_split();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(write_messages(writer, receiver));
+    let connection_id = server.lock().await.connect(sender.clone(), local_addr);
+
     let mut len = [0; 4];
-    let mut buf = Vec::new();
     loop {
-        socket.readable().await.unwrap();
-        socket.read(&mut len).await.expect("failed to read buffer");
-        let len = u32::from_be_bytes(len).try_into().unwrap();
-        buf.resize(len, 0);
-        socket.read(&mut buf).await.unwrap();
-        if let Ok((_remaining, message)) = SmbMessage::try_parse(&buf) {
+        if socket.read_exact(&mut len).await.is_err() {
+            // the client hung up on us.
+            break;
+        }
+        // Direct TCP's length is only 24 bits, the byte before it is always zero.
+        if len[0] != 0 {
+            println!("not a Direct TCP frame, hanging up");
+            break;
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_PDU_SIZE {
+            println!("{len} byte PDU is too big, hanging up");
+            break;
+        }
+        let mut buf = BytesMut::zeroed(len);
+        if socket.read_exact(&mut buf).await.is_err() {
+            break;
+        }
+        let mut buf = buf.freeze();
+        // the session an encrypted PDU was for, once it's been decrypted.
+        let mut encrypted = None;
+        if is_encrypted(&buf) {
+            let Some((pdu, session_id)) = server.lock().await.decrypt(connection_id, &buf) else {
+                // someone without the key, or that's been tampering.
+                println!("couldn't decrypt, hanging up");
+                break;
+            };
+            buf = pdu;
+            encrypted = Some(session_id);
+        }
+        // clients compress before they encrypt, so this comes off second.
+        if is_compressed(&buf) {
+            let Some(pdu) = server.lock().await.decompress(connection_id, &buf) else {
+                println!("couldn't decompress, hanging up");
+                break;
+            };
+            buf = pdu;
+        }
+        if buf.starts_with(&[0xFE, b'S', b'M', b'B']) {
             let mut server = server.lock().await;
-            server.handle_message(dbg!(&message)).await;
+            let responses = server.handle_compound(connection_id, &buf, encrypted);
+            if !responses.is_empty() {
+                let _ = sender.send(server.encode(connection_id, responses, encrypted.is_some()));
+            }
+            if !server.connections.contains_key(&connection_id) {
+                // we've decided this client can't be trusted any more.
+                break;
+            }
         } else if let Ok((_remaining, message)) = Smb1Message::try_parse(&buf) {
             let mut server = server.lock().await;
-            let resp = server.handle_smb1_message(dbg!(&message)).await;
-            let buff = resp.to_vec();
-            let mut buff2 = vec![];
-            buff2.extend(u32::to_be_bytes(buff.len() as u32));
-            buff2.extend(buff);
-            socket.writable().await.unwrap();
-            socket.write(&buff2).await.unwrap();
-            println!("sent response!");
+            let resp = server.handle_smb1_message(connection_id, &message);
+            let _ = sender.send(server.encode(connection_id, vec![resp], false));
         } else {
-            println!("error {:x?}", &buf);
+            // whatever this is, there's nothing in it to reply to.
+            println!("not an SMB message, hanging up {:x?}", &buf);
+            break;
         }
     }
+    server.lock().await.disconnect(connection_id);
 }
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let listener = tokio::net::TcpListener::bind("0.0.0.0:445").await?;
-    let server = Arc::new(Mutex::new(Server));
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+        None => Config::default(),
+    };
+    let server = Arc::new(Mutex::new(Server::new(config)));
+    tokio::spawn(change_notify::watch_changes(server.clone()));
+    tokio::spawn(oplock::expire_breaks(server.clone()));
+    tokio::spawn(durable::expire_durable_handles(server.clone()));
     loop {
         match listener.accept().await {
             Ok((socket, _addr)) => {
@@ -0,0 +1,509 @@
+//! NEGOTIATE, where the client and server settle on a dialect and
+//! what they'll each be able to do with it.
+
+use std::time::SystemTime;
+
+use smb::Smb1Message;
+use smb2::message::COMPRESSION_CAPABILITIES_FLAG_CHAINED;
+use smb2::message::{Capabilities, Command, Dialect, HeaderFlags, SecurityMode};
+use smb2::message::{Cipher, CompressionAlgorithm, HashAlgorithm, SigningAlgorithm};
+use smb2::message::{SmbBody, SmbMessage};
+use smb2::message::{SmbMessageHeader, SmbMessageHeaderVariant};
+use smb2::message::{SmbNegotiate, SmbNegotiateResponse};
+use smb2::message::{SmbNegotiateContext, SmbNegotiateContextData};
+use smb2::preauth::PreauthIntegrityHash;
+
+use crate::status::*;
+use crate::{error_message, fs, session_setup, HandlerResult, Negotiated, Server};
+use crate::{MAX_READ_WRITE_SIZE, SERVER_GUID};
+
+// the security buffer comes right after the response's fixed part.
+const SECURITY_BUFFER_OFFSET: u16 = 64 + 64;
+
+// dialects we're able to speak, best first.
+const SUPPORTED_DIALECTS: [Dialect; 5] = [
+    Dialect::Smb311,
+    Dialect::Smb302,
+    Dialect::Smb300,
+    Dialect::Smb210,
+    Dialect::Smb202,
+];
+
+// what 3.1.1 clients can pick from, we go with the first of these they offer.
+const HASH_ALGORITHMS: [HashAlgorithm; 1] = [HashAlgorithm::Sha512];
+const CIPHERS: [Cipher; 4] = [
+    Cipher::Aes128Gcm,
+    Cipher::Aes128Ccm,
+    Cipher::Aes256Gcm,
+    Cipher::Aes256Ccm,
+];
+const SIGNING_ALGORITHMS: [SigningAlgorithm; 3] = [
+    SigningAlgorithm::AesGmac,
+    SigningAlgorithm::AesCmac,
+    SigningAlgorithm::HmacSha256,
+];
+// everything but LZ4, which clients list in their order of preference.
+const COMPRESSION_ALGORITHMS: [CompressionAlgorithm; 4] = [
+    CompressionAlgorithm::Lz77,
+    CompressionAlgorithm::Lz77Huffman,
+    CompressionAlgorithm::Lznt1,
+    CompressionAlgorithm::PatternV1,
+];
+
+const PREAUTH_SALT_SIZE: usize = 32;
+
+// without SMB2_GLOBAL_CAP_LARGE_MTU, the most a single read or write can move.
+const SMB2_02_MAX_SIZE: u32 = 64 * 1024;
+
+/// The oldest and newest dialects clients are allowed to use.
+pub struct DialectPolicy {
+    pub min: Dialect,
+    pub max: Dialect,
+}
+
+impl Default for DialectPolicy {
+    fn default() -> Self {
+        Self {
+            min: Dialect::Smb202,
+            max: Dialect::Smb311,
+        }
+    }
+}
+
+impl DialectPolicy {
+    fn allows(&self, dialect: Dialect) -> bool {
+        (self.min..=self.max).contains(&dialect)
+    }
+
+    /// The best dialect out of `dialects` that we can speak and are allowed to.
+    pub fn select(&self, dialects: &[Dialect]) -> Option<Dialect> {
+        SUPPORTED_DIALECTS
+            .into_iter()
+            .find(|&dialect| self.allows(dialect) && dialects.contains(&dialect))
+    }
+
+    /// What to answer an SMB1 NEGOTIATE offering `dialects` with: the
+    /// wildcard if the client can follow up with an SMB2 NEGOTIATE for
+    /// something newer than 2.0.2, otherwise 2.0.2 itself.
+    fn select_smb1(&self, dialects: &[&str]) -> Option<Dialect> {
+        let newer_than_2_0_2 = SUPPORTED_DIALECTS
+            .into_iter()
+            .any(|dialect| dialect > Dialect::Smb202 && self.allows(dialect));
+        if dialects.contains(&"SMB 2.???") && newer_than_2_0_2 {
+            Some(Dialect::Wildcard)
+        } else if dialects.contains(&"SMB 2.002") && self.allows(Dialect::Smb202) {
+            Some(Dialect::Smb202)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a 3.1.1 client's negotiate contexts settled on, everything is
+/// left unset for older dialects.
+#[derive(Debug, Default, PartialEq)]
+pub struct Algorithms {
+    pub preauth_hash: Option<HashAlgorithm>,
+    /// None if the client didn't ask for encryption, or there's no cipher
+    /// we both know.
+    pub cipher: Option<Cipher>,
+    pub signing: Option<SigningAlgorithm>,
+    /// In the client's order of preference.
+    pub compression: Vec<CompressionAlgorithm>,
+    pub chained_compression: bool,
+}
+
+/// The first of `offered` that's also in `supported`, skipping ones we've never heard of.
+fn first_supported<T>(offered: &[u16], supported: &[T]) -> Option<T>
+where
+    T: TryFrom<u16> + PartialEq,
+{
+    offered
+        .iter()
+        .filter_map(|&id| T::try_from(id).ok())
+        .find(|algorithm| supported.contains(algorithm))
+}
+
+/// Picks what to use out of the contexts a 3.1.1 client sent, along with
+/// the contexts to answer them with.
+fn select_algorithms(
+    offered: &[SmbNegotiateContext],
+) -> Result<(Algorithms, Vec<SmbNegotiateContext>), NtStatus> {
+    let mut algorithms = Algorithms::default();
+    let mut contexts = vec![];
+    for context in offered {
+        let data = match &context.data {
+            SmbNegotiateContextData::PreauthIntegrityCapabilities { hash_algo, .. } => {
+                let hash = first_supported(hash_algo, &HASH_ALGORITHMS)
+                    .ok_or(STATUS_SMB_NO_PREAUTH_INTEGRITY_HASH_OVERLAP)?;
+                algorithms.preauth_hash = Some(hash);
+                let mut salt = vec![0; PREAUTH_SALT_SIZE];
+                getrandom::getrandom(&mut salt).map_err(|_| STATUS_INTERNAL_ERROR)?;
+                SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                    hash_algo_count: 1,
+                    salt_length: PREAUTH_SALT_SIZE as u16,
+                    hash_algo: vec![hash as u16],
+                    salt,
+                }
+            }
+            SmbNegotiateContextData::EncryptionCapabilities { ciphers, .. } => {
+                algorithms.cipher = first_supported(ciphers, &CIPHERS);
+                SmbNegotiateContextData::EncryptionCapabilities {
+                    cipher_count: 1,
+                    // 0 for nothing in common.
+                    ciphers: vec![algorithms.cipher.map_or(0, |cipher| cipher as u16)],
+                }
+            }
+            SmbNegotiateContextData::CompressionCapabilities {
+                flags,
+                compression_algos,
+                ..
+            } => {
+                algorithms.compression = compression_algos
+                    .iter()
+                    .filter_map(|&id| CompressionAlgorithm::try_from(id).ok())
+                    .filter(|algorithm| COMPRESSION_ALGORITHMS.contains(algorithm))
+                    .collect();
+                algorithms.chained_compression = !algorithms.compression.is_empty()
+                    && flags & COMPRESSION_CAPABILITIES_FLAG_CHAINED != 0;
+                let mut compression_algos: Vec<_> = algorithms
+                    .compression
+                    .iter()
+                    .map(|&algorithm| algorithm as u16)
+                    .collect();
+                if compression_algos.is_empty() {
+                    compression_algos.push(CompressionAlgorithm::None as u16);
+                }
+                SmbNegotiateContextData::CompressionCapabilities {
+                    compression_algo_count: compression_algos.len() as u16,
+                    flags: match algorithms.chained_compression {
+                        true => COMPRESSION_CAPABILITIES_FLAG_CHAINED,
+                        false => 0,
+                    },
+                    compression_algos,
+                }
+            }
+            SmbNegotiateContextData::SigningCapabilities { signing_algos, .. } => {
+                // AES-CMAC is what 3.x signs with when nothing else is agreed on.
+                let signing = first_supported(signing_algos, &SIGNING_ALGORITHMS)
+                    .unwrap_or(SigningAlgorithm::AesCmac);
+                algorithms.signing = Some(signing);
+                SmbNegotiateContextData::SigningCapabilities {
+                    signing_algo_count: 1,
+                    signing_algos: vec![signing as u16],
+                }
+            }
+            // nothing the server answers.
+            SmbNegotiateContextData::NetNameNegotiateContextId { .. }
+            | SmbNegotiateContextData::TransportCapabilities { .. }
+            | SmbNegotiateContextData::RdmaTransformCapabilities { .. } => continue,
+        };
+        contexts.push(SmbNegotiateContext::new(data));
+    }
+    // every 3.1.1 NEGOTIATE has to have one.
+    if algorithms.preauth_hash.is_none() {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    Ok((algorithms, contexts))
+}
+
+impl Server {
+    pub(crate) fn negotiate(
+        &mut self,
+        connection_id: u64,
+        negotiate: &SmbNegotiate,
+        raw: &[u8],
+    ) -> HandlerResult {
+        let Some(dialect) = self.dialects.select(&negotiate.dialects) else {
+            println!("no dialect in common with {:?}", negotiate.dialects);
+            return Err(STATUS_NOT_SUPPORTED.into());
+        };
+        let mut response =
+            negotiate_response(dialect, self.security_mode, self.handle_store.is_some());
+        let mut algorithms = Algorithms::default();
+        if dialect == Dialect::Smb311 {
+            let offered = negotiate
+                .negotiate_context_list
+                .as_deref()
+                .unwrap_or_default();
+            (algorithms, response.context_list) = select_algorithms(offered)?;
+        } else if dialect >= Dialect::Smb300
+            && negotiate.capabilities.contains(Capabilities::ENCRYPTION)
+        {
+            // before 3.1.1 there's no negotiating, it's AES-128-CCM or nothing.
+            response.capabilities |= Capabilities::ENCRYPTION;
+            algorithms.cipher = Some(Cipher::Aes128Ccm);
+        }
+        // a NEGOTIATE starts the hash over, the response is chained in once it's sent.
+        let preauth_integrity = (dialect == Dialect::Smb311).then(|| {
+            let mut preauth_integrity = PreauthIntegrityHash::default();
+            preauth_integrity.update(raw);
+            preauth_integrity
+        });
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.preauth_integrity = preauth_integrity;
+            connection.negotiated = Some(Negotiated {
+                dialect,
+                algorithms,
+                capabilities: response.capabilities,
+                client_capabilities: negotiate.capabilities,
+                client_guid: negotiate.client_guid,
+                client_security_mode: negotiate.security_mode,
+                client_dialects: negotiate.dialects.clone(),
+            });
+        }
+        Ok(SmbBody::NegotiateResponse(response))
+    }
+
+    /// SMB1 is only ever spoken to find out the client can do SMB2.
+    pub(crate) fn handle_smb1_message(
+        &mut self,
+        connection_id: u64,
+        message: &Smb1Message,
+    ) -> SmbMessage {
+        match &message.body {
+            smb::Smb1Body::SmbComNegotiate(negotiate) => {
+                let header = SmbMessageHeader {
+                    protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+                    header_size: 64,
+                    credit_charge: 0,
+                    status: STATUS_SUCCESS,
+                    command: Command::Negotiate,
+                    credit_request_response: 1,
+                    flags: HeaderFlags::SERVER_TO_REDIR,
+                    next_command: 0,
+                    // the SMB1 request doesn't have one, so this is the first.
+                    message_id: 0,
+                    variant: SmbMessageHeaderVariant::Sync { tree_id: 0 },
+                    session_id: 0,
+                    signature: 0,
+                };
+                let dialects: Vec<_> = negotiate
+                    .smb_data
+                    .dialects
+                    .iter()
+                    .map(|dialect| dialect.dialect_string.as_str())
+                    .collect();
+                let Some(dialect) = self.dialects.select_smb1(&dialects) else {
+                    println!("no dialect in common with {dialects:?}");
+                    return error_message(header, STATUS_NOT_SUPPORTED);
+                };
+                let response = negotiate_response(dialect, self.security_mode, false);
+                // 2.0.2 is settled on here and now, the wildcard means there's another NEGOTIATE to come.
+                if dialect == Dialect::Smb202 {
+                    if let Some(connection) = self.connections.get_mut(&connection_id) {
+                        connection.negotiated = Some(Negotiated {
+                            dialect,
+                            algorithms: Algorithms::default(),
+                            capabilities: response.capabilities,
+                            client_capabilities: Capabilities::empty(),
+                            client_guid: 0,
+                            client_security_mode: Default::default(),
+                            client_dialects: vec![dialect],
+                        });
+                    }
+                }
+                SmbMessage {
+                    header,
+                    body: SmbBody::NegotiateResponse(response),
+                }
+            }
+        }
+    }
+}
+
+/// `persistent_handles` is whether any share is continuously available.
+fn negotiate_response(
+    dialect: Dialect,
+    security_mode: SecurityMode,
+    persistent_handles: bool,
+) -> SmbNegotiateResponse {
+    let max_size = match dialect {
+        Dialect::Smb202 => SMB2_02_MAX_SIZE,
+        _ => MAX_READ_WRITE_SIZE,
+    };
+    let security_buffer = session_setup::negotiate_token();
+    SmbNegotiateResponse {
+        size: 65,
+        security_mode,
+        dialect_rev: dialect,
+        server_guid: SERVER_GUID,
+        capabilities: match dialect {
+            // 2.0.2 doesn't know about multi-credit requests, or leases.
+            Dialect::Smb202 => Capabilities::empty(),
+            Dialect::Smb210 | Dialect::Wildcard => Capabilities::LARGE_MTU | Capabilities::LEASING,
+            // directory leases and persistent handles are from 3.0 on, the
+            // latter only if there's a share that can have them.
+            _ => {
+                Capabilities::LARGE_MTU
+                    | Capabilities::LEASING
+                    | Capabilities::DIRECTORY_LEASING
+                    | if persistent_handles {
+                        Capabilities::PERSISTENT_HANDLES
+                    } else {
+                        Capabilities::empty()
+                    }
+            }
+        },
+        max_transact_size: max_size,
+        max_read_size: max_size,
+        max_write_size: max_size,
+        system_time: fs::filetime(SystemTime::now()),
+        // not something clients are meant to look at.
+        server_start_time: 0,
+        security_buff_offset: SECURITY_BUFFER_OFFSET,
+        security_buff_len: security_buffer.len() as u16,
+        buf: security_buffer,
+        context_list: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_allowed_dialect() {
+        let everything = [
+            Dialect::Smb202,
+            Dialect::Smb210,
+            Dialect::Smb300,
+            Dialect::Smb302,
+        ];
+        let policy = DialectPolicy::default();
+        assert_eq!(policy.select(&everything), Some(Dialect::Smb302));
+        assert_eq!(policy.select(&[Dialect::Smb202]), Some(Dialect::Smb202));
+        assert_eq!(
+            policy.select_smb1(&["NT LM 0.12", "SMB 2.002", "SMB 2.???"]),
+            Some(Dialect::Wildcard)
+        );
+
+        // no 2.0.2, for compliance.
+        let policy = DialectPolicy {
+            min: Dialect::Smb210,
+            max: Dialect::Smb300,
+        };
+        assert_eq!(policy.select(&everything), Some(Dialect::Smb300));
+        assert_eq!(policy.select(&[Dialect::Smb202]), None);
+        assert_eq!(policy.select_smb1(&["SMB 2.002"]), None);
+
+        // nothing but 2.0.2.
+        let policy = DialectPolicy {
+            min: Dialect::Smb202,
+            max: Dialect::Smb202,
+        };
+        assert_eq!(
+            policy.select_smb1(&["SMB 2.002", "SMB 2.???"]),
+            Some(Dialect::Smb202)
+        );
+    }
+
+    #[test]
+    fn algorithms_from_what_the_client_offered() {
+        let offered = [
+            SmbNegotiateContext::new(SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                hash_algo_count: 2,
+                salt_length: 0,
+                hash_algo: vec![0x7777, HashAlgorithm::Sha512 as u16],
+                salt: vec![],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::EncryptionCapabilities {
+                cipher_count: 2,
+                ciphers: vec![Cipher::Aes256Gcm as u16, Cipher::Aes128Gcm as u16],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::CompressionCapabilities {
+                compression_algo_count: 1,
+                flags: COMPRESSION_CAPABILITIES_FLAG_CHAINED,
+                compression_algos: vec![CompressionAlgorithm::Lz4 as u16],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::SigningCapabilities {
+                signing_algo_count: 1,
+                signing_algos: vec![0x7777],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::NetNameNegotiateContextId {
+                net_name: "server".encode_utf16().collect(),
+            }),
+        ];
+        let (algorithms, contexts) = select_algorithms(&offered).unwrap();
+        assert_eq!(
+            algorithms,
+            Algorithms {
+                preauth_hash: Some(HashAlgorithm::Sha512),
+                cipher: Some(Cipher::Aes256Gcm),
+                signing: Some(SigningAlgorithm::AesCmac),
+                compression: vec![],
+                chained_compression: false,
+            }
+        );
+        // one answer each, bar the net name.
+        assert_eq!(contexts.len(), 4);
+        let SmbNegotiateContextData::PreauthIntegrityCapabilities { salt, .. } = &contexts[0].data
+        else {
+            panic!("{:?}", contexts[0]);
+        };
+        assert_eq!(salt.len(), PREAUTH_SALT_SIZE);
+        assert_eq!(
+            contexts[2].data,
+            SmbNegotiateContextData::CompressionCapabilities {
+                compression_algo_count: 1,
+                flags: 0,
+                compression_algos: vec![CompressionAlgorithm::None as u16],
+            }
+        );
+
+        assert_eq!(
+            select_algorithms(&offered[1..]),
+            Err(STATUS_INVALID_PARAMETER)
+        );
+        let no_overlap = [SmbNegotiateContext::new(
+            SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                hash_algo_count: 1,
+                salt_length: 0,
+                hash_algo: vec![0x7777],
+                salt: vec![],
+            },
+        )];
+        assert_eq!(
+            select_algorithms(&no_overlap),
+            Err(STATUS_SMB_NO_PREAUTH_INTEGRITY_HASH_OVERLAP)
+        );
+    }
+
+    #[test]
+    fn compression_in_the_clients_order() {
+        let offered = [
+            SmbNegotiateContext::new(SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                hash_algo_count: 1,
+                salt_length: 0,
+                hash_algo: vec![HashAlgorithm::Sha512 as u16],
+                salt: vec![],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::CompressionCapabilities {
+                compression_algo_count: 4,
+                flags: COMPRESSION_CAPABILITIES_FLAG_CHAINED,
+                compression_algos: vec![
+                    CompressionAlgorithm::Lz4 as u16,
+                    CompressionAlgorithm::PatternV1 as u16,
+                    CompressionAlgorithm::Lznt1 as u16,
+                    CompressionAlgorithm::Lz77 as u16,
+                ],
+            }),
+        ];
+        let (algorithms, contexts) = select_algorithms(&offered).unwrap();
+        let compression = vec![
+            CompressionAlgorithm::PatternV1,
+            CompressionAlgorithm::Lznt1,
+            CompressionAlgorithm::Lz77,
+        ];
+        assert_eq!(algorithms.compression, compression);
+        assert!(algorithms.chained_compression);
+        assert_eq!(
+            contexts[1].data,
+            SmbNegotiateContextData::CompressionCapabilities {
+                compression_algo_count: 3,
+                flags: COMPRESSION_CAPABILITIES_FLAG_CHAINED,
+                compression_algos: compression.iter().map(|&a| a as u16).collect(),
+            }
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Oplocks, and breaking them when somebody else wants the file.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use smb2::message::SmbOplockBreak;
+use smb2::message::{Command, HeaderFlags, SmbMessage, SmbMessageHeader, SmbMessageHeaderVariant};
+use smb2::message::{CreateDisposition, OplockLevel, SmbBody, SmbCreate, SmbFileId};
+use tokio::sync::Mutex;
+
+use crate::create::Open;
+use crate::status::*;
+use crate::{cancels, error_message, HandlerResult, Server};
+
+/// How long a client gets to acknowledge a break before it's
+/// assumed to have gone away, the same as Windows.
+const OPLOCK_BREAK_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// An oplock that's been told to break, but hasn't said it has yet.
+struct Break {
+    to: OplockLevel,
+    deadline: Instant,
+}
+
+/// A CREATE that went async waiting on somebody else's oplock to break.
+struct WaitingCreate {
+    connection_id: u64,
+    /// What the final response goes out with, already async.
+    header: SmbMessageHeader,
+    tree_id: u32,
+    path: PathBuf,
+    create: SmbCreate,
+}
+
+#[derive(Default)]
+pub struct OplockManager {
+    // keyed by the volatile part of the file id of the open being broken.
+    breaking: HashMap<u64, Break>,
+    waiting: Vec<WaitingCreate>,
+}
+
+/// The best oplock that can be handed out for a new open of a file,
+/// given what's wanted and whether anybody else already has it open.
+pub fn grant(requested: OplockLevel, shared: bool) -> OplockLevel {
+    match requested {
+        // leases are handed out separately, this is for clients that can't have one.
+        OplockLevel::None | OplockLevel::Lease => OplockLevel::None,
+        OplockLevel::LevelII => OplockLevel::LevelII,
+        OplockLevel::Exclusive | OplockLevel::Batch if shared => OplockLevel::LevelII,
+        OplockLevel::Exclusive | OplockLevel::Batch => requested,
+    }
+}
+
+/// Checks once a second for oplock and lease breaks that took too long
+/// to be acknowledged.
+pub async fn expire_breaks(server: Arc<Mutex<Server>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        server.lock().await.expire_oplock_breaks(Instant::now());
+    }
+}
+
+impl Server {
+    /// Starts breaking every other oplock on `path` that's in the way of
+    /// `create`, returning whether it has to wait for any of them.
+    pub(crate) fn break_oplocks(
+        &mut self,
+        session_id: u64,
+        path: &Path,
+        create: &SmbCreate,
+    ) -> bool {
+        let overwrite = matches!(
+            create.create_disposition,
+            CreateDisposition::Supersede
+                | CreateDisposition::Overwrite
+                | CreateDisposition::OverwriteIf
+        );
+        let holders: Vec<_> = self
+            .opens
+            .iter()
+            .filter(|(_, open)| open.path == path && open.oplock_level != OplockLevel::None)
+            .map(|(&volatile, open)| (volatile, open.oplock_level))
+            .collect();
+        let mut wait = false;
+        for (volatile, level) in holders {
+            if self.oplocks.breaking.contains_key(&volatile) {
+                wait = true;
+                continue;
+            }
+            match level {
+                OplockLevel::Exclusive | OplockLevel::Batch => {
+                    // whatever gets written is about to be thrown away anyway.
+                    let to = if overwrite {
+                        OplockLevel::None
+                    } else {
+                        OplockLevel::LevelII
+                    };
+                    self.send_oplock_break(volatile, to);
+                    self.oplocks.breaking.insert(
+                        volatile,
+                        Break {
+                            to,
+                            deadline: Instant::now() + OPLOCK_BREAK_TIMEOUT,
+                        },
+                    );
+                    wait = true;
+                }
+                OplockLevel::LevelII if overwrite => self.break_to_none(volatile),
+                _ => {}
+            }
+        }
+        let requester = self.requested_lease(session_id, create).map(|(id, _)| id);
+        wait | self.break_leases(requester, path, create, overwrite)
+    }
+
+    /// Breaks the Level II oplocks and read leases of every open of `path` except
+    /// `volatile`'s, since it's just been written to and whatever they've cached is stale.
+    pub(crate) fn break_read_caching(&mut self, path: &Path, volatile: u64) {
+        let holders: Vec<_> = self
+            .opens
+            .iter()
+            .filter(|(&other, open)| {
+                other != volatile && open.path == path && open.oplock_level == OplockLevel::LevelII
+            })
+            .map(|(&other, _)| other)
+            .collect();
+        for holder in holders {
+            self.break_to_none(holder);
+        }
+        let writer = self.opens.get(&volatile).and_then(|open| open.lease);
+        self.break_read_leases(path, writer);
+    }
+
+    /// Level II oplocks are broken without waiting, the client
+    /// doesn't acknowledge them.
+    fn break_to_none(&mut self, volatile: u64) {
+        self.send_oplock_break(volatile, OplockLevel::None);
+        if let Some(open) = self.opens.get_mut(&volatile) {
+            open.oplock_level = OplockLevel::None;
+        }
+    }
+
+    fn send_oplock_break(&self, volatile: u64, to: OplockLevel) {
+        let Some(open) = self.opens.get(&volatile) else {
+            return;
+        };
+        let Some(session) = self.sessions.get(&open.session_id) else {
+            return;
+        };
+        self.send(
+            session.connection_id,
+            SmbMessage {
+                header: break_header(open),
+                body: SmbBody::OplockBreakResponse(SmbOplockBreak {
+                    size: 24,
+                    oplock_level: to,
+                    file_id: SmbFileId {
+                        persistent: volatile,
+                        volatile,
+                    },
+                }),
+            },
+        );
+    }
+
+    /// Parks a CREATE until the oplocks in its way are done breaking.
+    pub(crate) fn wait_for_oplock_breaks(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        tree_id: u32,
+        path: PathBuf,
+        create: &SmbCreate,
+    ) {
+        self.go_async(header);
+        self.oplocks.waiting.push(WaitingCreate {
+            connection_id,
+            header: SmbMessageHeader {
+                // the interim response already handed out credits.
+                credit_request_response: 0,
+                ..header.clone()
+            },
+            tree_id,
+            path,
+            create: create.clone(),
+        });
+    }
+
+    /// The client acknowledging a break we sent it.
+    pub(crate) fn oplock_break(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        ack: &SmbOplockBreak,
+    ) -> HandlerResult {
+        let volatile = ack.file_id.volatile;
+        self.open(connection_id, header, ack.file_id)?;
+        let Some(pending) = self.oplocks.breaking.remove(&volatile) else {
+            return Err(STATUS_INVALID_OPLOCK_PROTOCOL.into());
+        };
+        // it's only allowed to go as far as it was told, or further.
+        let level = match (ack.oplock_level, pending.to) {
+            (OplockLevel::None, _) => Some(OplockLevel::None),
+            (OplockLevel::LevelII, OplockLevel::LevelII) => Some(OplockLevel::LevelII),
+            _ => None,
+        };
+        let open = self.opens.get_mut(&volatile).unwrap();
+        open.oplock_level = level.unwrap_or(OplockLevel::None);
+        self.retry_waiting_creates();
+        let level = level.ok_or(STATUS_INVALID_OPLOCK_PROTOCOL)?;
+        Ok(SmbBody::OplockBreakResponse(SmbOplockBreak {
+            size: 24,
+            oplock_level: level,
+            file_id: ack.file_id,
+        }))
+    }
+
+    /// Gives up on any breaks that have run out of time, taking the
+    /// oplock away entirely.
+    pub(crate) fn expire_oplock_breaks(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .oplocks
+            .breaking
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(&volatile, _)| volatile)
+            .collect();
+        for &volatile in &expired {
+            self.oplocks.breaking.remove(&volatile);
+            if let Some(open) = self.opens.get_mut(&volatile) {
+                println!("oplock break on {:?} timed out", open.path);
+                open.oplock_level = OplockLevel::None;
+            }
+        }
+        if self.expire_lease_breaks(now) || !expired.is_empty() {
+            self.retry_waiting_creates();
+        }
+    }
+
+    /// Forgets about any break in progress on an open that's closing,
+    /// which is how a client usually answers a break on a batch oplock.
+    pub(crate) fn release_oplock(&mut self, volatile: u64) {
+        if self.oplocks.breaking.remove(&volatile).is_some() {
+            self.retry_waiting_creates();
+        }
+    }
+
+    /// Finishes every waiting CREATE that isn't waiting on anything any more.
+    pub(crate) fn retry_waiting_creates(&mut self) {
+        for waiting in std::mem::take(&mut self.oplocks.waiting) {
+            let session_id = waiting.header.session_id;
+            if self.break_oplocks(session_id, &waiting.path, &waiting.create) {
+                self.oplocks.waiting.push(waiting);
+                continue;
+            }
+            let result =
+                self.create_open(session_id, waiting.tree_id, waiting.path, &waiting.create);
+            let message = match result {
+                Ok(body) => SmbMessage {
+                    header: waiting.header,
+                    body,
+                },
+                Err(failure) => {
+                    self.failure_message(waiting.connection_id, waiting.header, failure)
+                }
+            };
+            self.send(waiting.connection_id, message);
+        }
+    }
+
+    /// Fails the waiting CREATE `cancel` is for, if that's what it's for.
+    pub(crate) fn cancel_create(&mut self, connection_id: u64, cancel: &SmbMessageHeader) -> bool {
+        let Some(i) = self.oplocks.waiting.iter().position(|waiting| {
+            waiting.connection_id == connection_id && cancels(cancel, &waiting.header)
+        }) else {
+            return false;
+        };
+        let waiting = self.oplocks.waiting.remove(i);
+        self.send(
+            waiting.connection_id,
+            error_message(waiting.header, STATUS_CANCELLED),
+        );
+        true
+    }
+}
+
+/// The header of a break notification for `open`. It goes out on the
+/// session and tree the open is on, so that it's signed or encrypted
+/// with that session's keys like everything else sent on it.
+pub(crate) fn break_header(open: &Open) -> SmbMessageHeader {
+    SmbMessageHeader {
+        protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+        header_size: 64,
+        credit_charge: 0,
+        status: STATUS_SUCCESS,
+        command: Command::OplockBreak,
+        credit_request_response: 0,
+        // only signed if the session has a key to sign it with.
+        flags: HeaderFlags::SERVER_TO_REDIR | HeaderFlags::SIGNED,
+        next_command: 0,
+        // unsolicited, so there's no request for it to be the response to.
+        message_id: u64::MAX,
+        variant: SmbMessageHeaderVariant::Sync {
+            tree_id: open.tree_id,
+        },
+        session_id: open.session_id,
+        signature: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_files_only_get_level_ii() {
+        assert_eq!(grant(OplockLevel::Batch, false), OplockLevel::Batch);
+        assert_eq!(grant(OplockLevel::Exclusive, false), OplockLevel::Exclusive);
+        assert_eq!(grant(OplockLevel::Batch, true), OplockLevel::LevelII);
+        assert_eq!(grant(OplockLevel::LevelII, true), OplockLevel::LevelII);
+        assert_eq!(grant(OplockLevel::Lease, false), OplockLevel::None);
+        assert_eq!(grant(OplockLevel::None, false), OplockLevel::None);
+    }
+}
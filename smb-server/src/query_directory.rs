@@ -0,0 +1,189 @@
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use smb2::message::{FileDirectoryEntry, FileDirectoryListBuilder, SmbBody, SmbMessageHeader};
+use smb2::message::{SmbQueryDirectory, SmbQueryDirectoryResponse, MAXIMUM_ALLOWED};
+use smb2::message::{
+    SMB2_INDEX_SPECIFIED, SMB2_REOPEN, SMB2_RESTART_SCANS, SMB2_RETURN_SINGLE_ENTRY,
+};
+
+use crate::create::READ_ACCESS;
+use crate::fs::{self, FileTimes};
+use crate::status::*;
+use crate::{HandlerResult, Server, MAX_READ_WRITE_SIZE};
+
+/// How far a client has gotten through listing a directory.
+pub struct DirectorySearch {
+    /// Everything that matched the pattern when the search started.
+    names: Vec<String>,
+    next: usize,
+}
+
+impl DirectorySearch {
+    fn new(dir: &Path, pattern: &str) -> std::io::Result<Self> {
+        let pattern: Vec<char> = match pattern {
+            // "*.*" is how DOS spelled "everything", dot or not.
+            "" | "*.*" => vec!['*'],
+            pattern => pattern.chars().collect(),
+        };
+        let mut names = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            // names that aren't valid UTF-8 can't be sent, or opened again later.
+            if let Ok(name) = entry?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        names.splice(0..0, [".".to_string(), "..".to_string()]);
+        names.retain(|name| {
+            let name: Vec<char> = name.chars().collect();
+            wildcard_match(&pattern, &name)
+        });
+        Ok(Self { names, next: 0 })
+    }
+}
+
+/// Case insensitive matching against a search pattern, including
+/// the DOS_STAR (`<`), DOS_QM (`>`) and DOS_DOT (`"`) wildcards.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*' | '<', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some(('>', rest)) => {
+            wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(rest, &name[1..]))
+        }
+        Some(('"', rest)) => match name.split_first() {
+            Some(('.', name)) => wildcard_match(rest, name),
+            Some(_) => false,
+            None => wildcard_match(rest, name),
+        },
+        Some((c, rest)) => name.split_first().is_some_and(|(n, name)| {
+            c.to_lowercase().eq(n.to_lowercase()) && wildcard_match(rest, name)
+        }),
+    }
+}
+
+fn directory_entry(
+    index: usize,
+    name: &str,
+    path: &Path,
+    metadata: &Metadata,
+) -> FileDirectoryEntry {
+    let times = FileTimes::from_metadata(metadata);
+    FileDirectoryEntry {
+        file_index: index as u32,
+        creation_time: times.creation_time,
+        last_access_time: times.last_access_time,
+        last_write_time: times.last_write_time,
+        change_time: times.change_time,
+        end_of_file: if metadata.is_dir() { 0 } else { metadata.len() },
+        allocation_size: fs::allocation_size(metadata),
+        file_attributes: fs::file_attributes(path, metadata),
+        file_id: metadata.ino() as u128,
+        file_name: name.into(),
+        ..Default::default()
+    }
+}
+
+impl Server {
+    pub(crate) fn query_directory(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        query: &SmbQueryDirectory,
+    ) -> HandlerResult {
+        let root = self
+            .share(connection_id, header)?
+            .path
+            .clone()
+            .ok_or(STATUS_INVALID_PARAMETER)?;
+        let open = self.open(connection_id, header, query.file_id)?;
+        if open.file.is_some() {
+            return Err(STATUS_INVALID_PARAMETER.into());
+        }
+        if open.desired_access & (READ_ACCESS | MAXIMUM_ALLOWED) == 0 {
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+
+        if query.flags & (SMB2_RESTART_SCANS | SMB2_REOPEN) != 0 || open.search.is_none() {
+            let search = DirectorySearch::new(&open.path, &query.file_name)
+                .map_err(|e| NtStatus::from(&e))?;
+            open.search = Some(search);
+        }
+        let search = open.search.as_mut().expect("search was just started");
+        if query.flags & SMB2_INDEX_SPECIFIED != 0 {
+            search.next = (query.file_index as usize + 1).min(search.names.len());
+        }
+
+        let max_len = query.output_buffer_length.min(MAX_READ_WRITE_SIZE) as usize;
+        let mut list = FileDirectoryListBuilder::new(query.file_information_class, max_len);
+        while let Some(name) = search.names.get(search.next) {
+            let path = match name.as_str() {
+                "." => open.path.clone(),
+                // don't let anyone peek above the share.
+                ".." if open.path == root => open.path.clone(),
+                ".." => open.path.parent().unwrap_or(&open.path).to_path_buf(),
+                name => open.path.join(name),
+            };
+            // it might have gone away since the search started.
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                search.next += 1;
+                continue;
+            };
+            if !list.push(&directory_entry(search.next, name, &path, &metadata)) {
+                break;
+            }
+            search.next += 1;
+            if query.flags & SMB2_RETURN_SINGLE_ENTRY != 0 {
+                break;
+            }
+        }
+
+        if list.is_empty() {
+            return Err(if search.names.is_empty() {
+                STATUS_NO_SUCH_FILE
+            } else if search.next >= search.names.len() {
+                STATUS_NO_MORE_FILES
+            } else {
+                // not even one entry fits in what the client gave us.
+                STATUS_INFO_LENGTH_MISMATCH
+            }
+            .into());
+        }
+        let buffer = list.finish();
+        Ok(SmbBody::QueryDirectoryResponse(SmbQueryDirectoryResponse {
+            size: 9,
+            output_buffer_offset: SmbQueryDirectoryResponse::OUTPUT_BUFFER_OFFSET,
+            output_buffer_length: buffer.len() as u32,
+            buffer,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pattern, &name)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", "anything.txt"));
+        assert!(matches("*.TXT", "notes.txt"));
+        assert!(!matches("*.txt", "notes.txt.bak"));
+        assert!(matches("n?tes.txt", "notes.txt"));
+        assert!(!matches("n?tes.txt", "ntes.txt"));
+        assert!(matches("exact", "EXACT"));
+        assert!(!matches("exact", "exactly"));
+        // what Windows sends for "*.*" and "file.???"
+        assert!(matches("<\"*", "readme"));
+        assert!(matches("file.>>>", "file.c"));
+        assert!(matches("file\">>>", "file"));
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use smb2::message::{FileAllInformation, FileBasicInformation, FileFsSectorSizeInformation};
+use smb2::message::{FileInfo, FileInfoClass, FileNetworkOpenInformation, FileStandardInformation};
+use smb2::message::{FileStreamEntry, FsInfo, FsInfoClass, InfoType, SmbBody, SmbMessageHeader};
+use smb2::message::{SmbQueryInfo, SmbQueryInfoResponse, SmbSetInfo, SmbSetInfoResponse};
+use smb2::message::{DACL_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION};
+use smb2::message::{FILE_ATTRIBUTE_READONLY, FILE_CASE_PRESERVED_NAMES};
+use smb2::message::{FILE_CASE_SENSITIVE_SEARCH, FILE_DEVICE_DISK, FILE_DISPOSITION_DELETE};
+use smb2::message::{FILE_SUPPORTS_HARD_LINKS, FILE_SUPPORTS_SPARSE_FILES, FILE_UNICODE_ON_DISK};
+use smb2::message::{MAXIMUM_ALLOWED, SSINFO_FLAGS_PARTITION_ALIGNED_ON_DEVICE};
+use smb2::message::{OWNER_SECURITY_INFORMATION, SSINFO_FLAGS_ALIGNED_DEVICE};
+
+use crate::create::{Open, DELETE_ACCESS, WRITE_ACCESS};
+use crate::fs::{self, FileTimes};
+use crate::status::*;
+use crate::{buffer_too_small, HandlerResult, Server, MAXIMAL_ACCESS};
+
+// what a "sector" is as far as clients are concerned.
+const BYTES_PER_SECTOR: u32 = 512;
+
+impl Server {
+    pub(crate) fn query_info(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        query: &SmbQueryInfo,
+    ) -> HandlerResult {
+        let root = self
+            .share(connection_id, header)?
+            .path
+            .clone()
+            .ok_or(STATUS_NOT_SUPPORTED)?;
+        let open = self.open(connection_id, header, query.file_id)?;
+        let (mut buffer, variable_length) = match query.info_type {
+            InfoType::File => {
+                let class = FileInfoClass::try_from(query.file_info_class)
+                    .map_err(|_| STATUS_INVALID_INFO_CLASS)?;
+                let file_info = file_info(&root, open, class)?;
+                let variable_length = matches!(
+                    file_info,
+                    FileInfo::All(_) | FileInfo::Stream(_) | FileInfo::NormalizedName { .. }
+                );
+                (file_info.to_vec(), variable_length)
+            }
+            InfoType::Filesystem => {
+                let class = FsInfoClass::try_from(query.file_info_class)
+                    .map_err(|_| STATUS_INVALID_INFO_CLASS)?;
+                let fs_info = fs_info(&root, class)?;
+                let variable_length =
+                    matches!(fs_info, FsInfo::Volume { .. } | FsInfo::Attribute { .. });
+                (fs_info.to_vec(), variable_length)
+            }
+            InfoType::Security => {
+                let descriptor = security_descriptor(query.additional_information);
+                if descriptor.len() > query.output_buffer_length as usize {
+                    return Err(buffer_too_small(descriptor.len()));
+                }
+                (descriptor, false)
+            }
+            InfoType::Quota => return Err(STATUS_NOT_SUPPORTED.into()),
+        };
+
+        if buffer.len() > query.output_buffer_length as usize {
+            // the fixed size classes are all or nothing, the
+            // rest send whatever fits and say there was more.
+            if !variable_length {
+                return Err(STATUS_INFO_LENGTH_MISMATCH.into());
+            }
+            buffer.truncate(query.output_buffer_length as usize);
+            header.status = STATUS_BUFFER_OVERFLOW;
+        }
+        Ok(SmbBody::QueryInfoResponse(SmbQueryInfoResponse {
+            size: 9,
+            output_buffer_offset: SmbQueryInfoResponse::OUTPUT_BUFFER_OFFSET,
+            output_buffer_length: buffer.len() as u32,
+            buffer,
+        }))
+    }
+
+    pub(crate) fn set_info(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        set: &SmbSetInfo,
+    ) -> HandlerResult {
+        let root = self
+            .share(connection_id, header)?
+            .path
+            .clone()
+            .ok_or(STATUS_NOT_SUPPORTED)?;
+        self.open(connection_id, header, set.file_id)?;
+        match set.info_type {
+            InfoType::File => {
+                let class = FileInfoClass::try_from(set.file_info_class)
+                    .map_err(|_| STATUS_INVALID_INFO_CLASS)?;
+                let (_, file_info) =
+                    FileInfo::parse(class, &set.buffer).map_err(|_| STATUS_INVALID_PARAMETER)?;
+                self.set_file_info(&root, set.file_id.volatile, file_info)?;
+            }
+            // there's no mapping Windows ACLs onto unix permissions, so
+            // pretend it worked rather than making every copy fail.
+            InfoType::Security => {}
+            InfoType::Filesystem | InfoType::Quota => return Err(STATUS_NOT_SUPPORTED.into()),
+        }
+        Ok(SmbBody::SetInfoResponse(SmbSetInfoResponse { size: 2 }))
+    }
+
+    fn set_file_info(
+        &mut self,
+        root: &Path,
+        volatile: u64,
+        file_info: FileInfo,
+    ) -> Result<(), NtStatus> {
+        let open = self
+            .opens
+            .get_mut(&volatile)
+            .expect("open was just looked up");
+        match file_info {
+            FileInfo::Basic(basic) => set_basic(open, &basic),
+            FileInfo::EndOfFile { end_of_file } => writable_file(open)?
+                .set_len(end_of_file)
+                .map_err(|e| NtStatus::from(&e)),
+            FileInfo::Allocation { allocation_size } => {
+                let file = writable_file(open)?;
+                let len = file.metadata().map_err(|e| NtStatus::from(&e))?.len();
+                // there's no preallocating, but shrinking the allocation
+                // below the end of the file cuts it short.
+                if allocation_size < len {
+                    file.set_len(allocation_size)
+                        .map_err(|e| NtStatus::from(&e))?;
+                }
+                Ok(())
+            }
+            FileInfo::Disposition { delete_pending } => set_delete_pending(open, delete_pending),
+            FileInfo::DispositionEx { flags } => {
+                set_delete_pending(open, flags & FILE_DISPOSITION_DELETE != 0)
+            }
+            FileInfo::Position {
+                current_byte_offset,
+            } => {
+                open.position = current_byte_offset;
+                Ok(())
+            }
+            // the only modes worth anything are the ones the file was opened with.
+            FileInfo::Mode { .. } => Ok(()),
+            FileInfo::Rename {
+                replace_if_exists,
+                file_name,
+                ..
+            } => {
+                let from = open.path.clone();
+                let to = link_target(root, &file_name, replace_if_exists)?;
+                std::fs::rename(&from, &to).map_err(|e| NtStatus::from(&e))?;
+                // anything open at or under the old name moved along with it.
+                for open in self.opens.values_mut() {
+                    if open.path == from {
+                        open.path = to.clone();
+                    } else if let Ok(rest) = open.path.strip_prefix(&from) {
+                        open.path = to.join(rest);
+                    }
+                }
+                Ok(())
+            }
+            FileInfo::Link {
+                replace_if_exists,
+                file_name,
+                ..
+            } => {
+                if open.file.is_none() {
+                    return Err(STATUS_FILE_IS_A_DIRECTORY);
+                }
+                let to = link_target(root, &file_name, replace_if_exists)?;
+                if to.exists() {
+                    std::fs::remove_file(&to).map_err(|e| NtStatus::from(&e))?;
+                }
+                std::fs::hard_link(&open.path, &to).map_err(|e| NtStatus::from(&e))
+            }
+            _ => Err(STATUS_INVALID_INFO_CLASS),
+        }
+    }
+}
+
+fn file_info(root: &Path, open: &Open, class: FileInfoClass) -> Result<FileInfo, NtStatus> {
+    use FileInfoClass::*;
+    let metadata = std::fs::metadata(&open.path).map_err(|e| NtStatus::from(&e))?;
+    let times = FileTimes::from_metadata(&metadata);
+    let file_attributes = fs::file_attributes(&open.path, &metadata);
+    let end_of_file = if metadata.is_dir() { 0 } else { metadata.len() };
+    let basic = self::FileBasicInformation {
+        creation_time: times.creation_time,
+        last_access_time: times.last_access_time,
+        last_write_time: times.last_write_time,
+        change_time: times.change_time,
+        file_attributes,
+    };
+    let standard = self::FileStandardInformation {
+        allocation_size: fs::allocation_size(&metadata),
+        end_of_file,
+        number_of_links: metadata.nlink() as u32,
+        delete_pending: open.delete_on_close,
+        directory: metadata.is_dir(),
+    };
+    Ok(match class {
+        FileBasicInformation => FileInfo::Basic(basic),
+        FileStandardInformation => FileInfo::Standard(standard),
+        FileInternalInformation => FileInfo::Internal {
+            index_number: metadata.ino(),
+        },
+        FileEaInformation => FileInfo::Ea { ea_size: 0 },
+        FileAccessInformation => FileInfo::Access {
+            access_flags: access_flags(open),
+        },
+        FilePositionInformation => FileInfo::Position {
+            current_byte_offset: open.position,
+        },
+        FileModeInformation => FileInfo::Mode { mode: 0 },
+        FileAlignmentInformation => FileInfo::Alignment {
+            alignment_requirement: 0,
+        },
+        FileAllInformation => FileInfo::All(self::FileAllInformation {
+            basic,
+            standard,
+            index_number: metadata.ino(),
+            ea_size: 0,
+            access_flags: access_flags(open),
+            current_byte_offset: open.position,
+            mode: 0,
+            alignment_requirement: 0,
+            file_name: format!("\\{}", fs::share_relative_name(root, &open.path)),
+        }),
+        FileStreamInformation => FileInfo::Stream(if metadata.is_dir() {
+            vec![]
+        } else {
+            vec![FileStreamEntry {
+                stream_name: "::$DATA".into(),
+                stream_size: end_of_file,
+                stream_allocation_size: fs::allocation_size(&metadata),
+            }]
+        }),
+        FileNetworkOpenInformation => FileInfo::NetworkOpen(self::FileNetworkOpenInformation {
+            creation_time: times.creation_time,
+            last_access_time: times.last_access_time,
+            last_write_time: times.last_write_time,
+            change_time: times.change_time,
+            allocation_size: fs::allocation_size(&metadata),
+            end_of_file,
+            file_attributes,
+        }),
+        FileAttributeTagInformation => FileInfo::AttributeTag {
+            file_attributes,
+            reparse_tag: 0,
+        },
+        FileNormalizedNameInformation => FileInfo::NormalizedName {
+            file_name: fs::share_relative_name(root, &open.path),
+        },
+        // the rest can only be set, not asked about.
+        FileRenameInformation
+        | FileLinkInformation
+        | FileDispositionInformation
+        | FileAllocationInformation
+        | FileEndOfFileInformation
+        | FileDispositionInformationEx => return Err(STATUS_INVALID_INFO_CLASS),
+    })
+}
+
+fn access_flags(open: &Open) -> u32 {
+    if open.desired_access & MAXIMUM_ALLOWED != 0 {
+        MAXIMAL_ACCESS
+    } else {
+        open.desired_access
+    }
+}
+
+fn fs_info(root: &Path, class: FsInfoClass) -> Result<FsInfo, NtStatus> {
+    use FsInfoClass::*;
+    let metadata = std::fs::metadata(root).map_err(|e| NtStatus::from(&e))?;
+    let stat = fs::statvfs(root).map_err(|e| NtStatus::from(&e))?;
+    let sectors_per_allocation_unit = (stat.f_frsize as u32 / BYTES_PER_SECTOR).max(1);
+    Ok(match class {
+        FileFsVolumeInformation => FsInfo::Volume {
+            volume_creation_time: FileTimes::from_metadata(&metadata).creation_time,
+            volume_serial_number: metadata.dev() as u32,
+            supports_objects: false,
+            volume_label: root
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        },
+        FileFsSizeInformation => FsInfo::Size {
+            total_allocation_units: stat.f_blocks,
+            available_allocation_units: stat.f_bavail,
+            sectors_per_allocation_unit,
+            bytes_per_sector: BYTES_PER_SECTOR,
+        },
+        FileFsDeviceInformation => FsInfo::Device {
+            device_type: FILE_DEVICE_DISK,
+            characteristics: 0,
+        },
+        FileFsAttributeInformation => FsInfo::Attribute {
+            file_system_attributes: FILE_CASE_SENSITIVE_SEARCH
+                | FILE_CASE_PRESERVED_NAMES
+                | FILE_UNICODE_ON_DISK
+                | FILE_SUPPORTS_SPARSE_FILES
+                | FILE_SUPPORTS_HARD_LINKS,
+            maximum_component_name_length: stat.f_namemax as u32,
+            // clients get suspicious of anything else.
+            file_system_name: "NTFS".into(),
+        },
+        FileFsFullSizeInformation => FsInfo::FullSize {
+            total_allocation_units: stat.f_blocks,
+            caller_available_allocation_units: stat.f_bavail,
+            actual_available_allocation_units: stat.f_bfree,
+            sectors_per_allocation_unit,
+            bytes_per_sector: BYTES_PER_SECTOR,
+        },
+        FileFsObjectIdInformation => FsInfo::ObjectId {
+            object_id: metadata.dev() as u128,
+            extended_info: [0; 48],
+        },
+        FileFsSectorSizeInformation => FsInfo::SectorSize(self::FileFsSectorSizeInformation {
+            logical_bytes_per_sector: BYTES_PER_SECTOR,
+            physical_bytes_per_sector_for_atomicity: stat.f_bsize as u32,
+            physical_bytes_per_sector_for_performance: stat.f_bsize as u32,
+            effective_physical_bytes_per_sector_for_atomicity: stat.f_bsize as u32,
+            flags: SSINFO_FLAGS_ALIGNED_DEVICE | SSINFO_FLAGS_PARTITION_ALIGNED_ON_DEVICE,
+            byte_offset_for_sector_alignment: 0,
+            byte_offset_for_partition_alignment: 0,
+        }),
+    })
+}
+
+/// A self-relative security descriptor with whichever of the owner,
+/// group and DACL were asked for: Everyone owns everything, and
+/// Everyone gets full access, since that's how it actually is.
+fn security_descriptor(additional_information: u32) -> Vec<u8> {
+    // S-1-1-0
+    const EVERYONE: [u8; 12] = [1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    const SE_DACL_PRESENT: u16 = 0x0004;
+    const SE_SELF_RELATIVE: u16 = 0x8000;
+
+    let mut control = SE_SELF_RELATIVE;
+    let mut offsets = [0u32; 4];
+    let mut out = vec![0; 20];
+    if additional_information & OWNER_SECURITY_INFORMATION != 0 {
+        offsets[0] = out.len() as u32;
+        out.extend(EVERYONE);
+    }
+    if additional_information & GROUP_SECURITY_INFORMATION != 0 {
+        offsets[1] = out.len() as u32;
+        out.extend(EVERYONE);
+    }
+    if additional_information & DACL_SECURITY_INFORMATION != 0 {
+        control |= SE_DACL_PRESENT;
+        offsets[3] = out.len() as u32;
+        let ace_size = 8 + EVERYONE.len() as u16;
+        // ACL header: revision, reserved, size, ACE count, reserved.
+        out.extend([2, 0]);
+        out.extend((8 + ace_size).to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend([0; 2]);
+        // ACCESS_ALLOWED_ACE, inherited by files and directories.
+        out.extend([0, 0x03]);
+        out.extend(ace_size.to_le_bytes());
+        out.extend(MAXIMAL_ACCESS.to_le_bytes());
+        out.extend(EVERYONE);
+    }
+    out[0] = 1;
+    out[2..4].copy_from_slice(&control.to_le_bytes());
+    for (i, offset) in offsets.into_iter().enumerate() {
+        out[4 + i * 4..8 + i * 4].copy_from_slice(&offset.to_le_bytes());
+    }
+    out
+}
+
+fn writable_file(open: &Open) -> Result<&File, NtStatus> {
+    let file = open.file.as_ref().ok_or(STATUS_INVALID_PARAMETER)?;
+    if open.desired_access & WRITE_ACCESS == 0 {
+        return Err(STATUS_ACCESS_DENIED);
+    }
+    Ok(file)
+}
+
+fn set_basic(open: &Open, basic: &FileBasicInformation) -> Result<(), NtStatus> {
+    // 0 means leave it alone, and so does -1 (which also means stop
+    // updating it for the rest of this open, which isn't a thing here).
+    let wanted = |time: u64| (time != 0 && time != u64::MAX).then(|| fs::from_filetime(time));
+    let mut times = std::fs::FileTimes::new();
+    let mut any = false;
+    if let Some(accessed) = wanted(basic.last_access_time) {
+        times = times.set_accessed(accessed);
+        any = true;
+    }
+    if let Some(modified) = wanted(basic.last_write_time) {
+        times = times.set_modified(modified);
+        any = true;
+    }
+    if any {
+        let file = match &open.file {
+            Some(file) => file.try_clone(),
+            None => File::open(&open.path),
+        }
+        .map_err(|e| NtStatus::from(&e))?;
+        file.set_times(times).map_err(|e| NtStatus::from(&e))?;
+    }
+
+    if basic.file_attributes != 0 {
+        let mut permissions = std::fs::metadata(&open.path)
+            .map_err(|e| NtStatus::from(&e))?
+            .permissions();
+        let mode = permissions.mode();
+        let readonly = basic.file_attributes & FILE_ATTRIBUTE_READONLY != 0;
+        permissions.set_mode(if readonly {
+            mode & !0o222
+        } else {
+            mode | 0o200
+        });
+        if permissions.mode() != mode {
+            std::fs::set_permissions(&open.path, permissions).map_err(|e| NtStatus::from(&e))?;
+        }
+    }
+    Ok(())
+}
+
+fn set_delete_pending(open: &mut Open, delete_pending: bool) -> Result<(), NtStatus> {
+    if open.desired_access & (DELETE_ACCESS | MAXIMUM_ALLOWED) == 0 {
+        return Err(STATUS_ACCESS_DENIED);
+    }
+    if delete_pending && open.file.is_none() {
+        let mut entries = std::fs::read_dir(&open.path).map_err(|e| NtStatus::from(&e))?;
+        if entries.next().is_some() {
+            return Err(STATUS_DIRECTORY_NOT_EMPTY);
+        }
+    }
+    open.delete_on_close = delete_pending;
+    Ok(())
+}
+
+/// Where a rename or hard link should point, making sure it's
+/// alright to put something there.
+fn link_target(root: &Path, file_name: &str, replace_if_exists: bool) -> Result<PathBuf, NtStatus> {
+    let to = fs::resolve(root, file_name.trim_start_matches('\\'))?;
+    if let Ok(metadata) = std::fs::symlink_metadata(&to) {
+        if !replace_if_exists {
+            return Err(STATUS_OBJECT_NAME_COLLISION);
+        }
+        if metadata.is_dir() {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+    }
+    Ok(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn security_descriptor_offsets() {
+        let descriptor = security_descriptor(
+            OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+        );
+        let u32_at = |at: usize| u32::from_le_bytes(descriptor[at..at + 4].try_into().unwrap());
+        // owner, group, no SACL, and the DACL after them.
+        assert_eq!(u32_at(4), 20);
+        assert_eq!(u32_at(8), 32);
+        assert_eq!(u32_at(12), 0);
+        assert_eq!(u32_at(16), 44);
+        assert_eq!(descriptor.len(), 44 + 8 + 20);
+
+        let owner_only = security_descriptor(OWNER_SECURITY_INFORMATION);
+        assert_eq!(owner_only.len(), 32);
+        assert_eq!(owner_only[2..4], 0x8000u16.to_le_bytes());
+    }
+}
@@ -0,0 +1,126 @@
+use std::os::unix::fs::FileExt;
+
+use bytes::{Bytes, BytesMut};
+use smb2::compression::compress_message;
+use smb2::message::{SmbBody, SmbMessage, SmbMessageHeader, SmbRead, SmbReadResponse};
+use smb2::message::{SmbWrite, SmbWriteResponse, SMB2_WRITEFLAG_WRITE_THROUGH};
+
+use crate::create::{READ_ACCESS, WRITE_ACCESS};
+use crate::status::*;
+use crate::{HandlerResult, Server, MAX_READ_WRITE_SIZE};
+
+// READs for any less than this aren't worth the time it takes to compress.
+const COMPRESSION_THRESHOLD: usize = 4096;
+
+impl Server {
+    pub(crate) fn read(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        read: &SmbRead,
+    ) -> HandlerResult {
+        if read.length > MAX_READ_WRITE_SIZE {
+            return Err(STATUS_INVALID_PARAMETER.into());
+        }
+        self.check_lock_conflict(
+            connection_id,
+            header,
+            read.file_id,
+            read.offset,
+            read.length as u64,
+            false,
+        )?;
+        let open = self.open(connection_id, header, read.file_id)?;
+        if open.desired_access & READ_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+        let file = open.file.as_ref().ok_or(STATUS_INVALID_DEVICE_REQUEST)?;
+
+        let mut data = BytesMut::zeroed(read.length as usize);
+        let mut filled = 0;
+        // keep going until we have everything or hit the end of the file,
+        // a short read isn't necessarily the end.
+        while filled < data.len() {
+            match file.read_at(&mut data[filled..], read.offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(NtStatus::from(&e).into()),
+            }
+        }
+        data.truncate(filled);
+        if filled == 0 && read.length > 0 || (filled as u32) < read.minimum_count {
+            return Err(STATUS_END_OF_FILE.into());
+        }
+
+        let data: Bytes = data.freeze();
+        Ok(SmbBody::ReadResponse(SmbReadResponse {
+            size: 17,
+            data_offset: SmbReadResponse::DATA_OFFSET,
+            data_length: data.len() as u32,
+            data_remaining: 0,
+            flags: 0,
+            data,
+        }))
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        connection_id: u64,
+        header: &SmbMessageHeader,
+        write: &SmbWrite,
+    ) -> HandlerResult {
+        let length = write.data.len() as u64;
+        self.check_lock_conflict(
+            connection_id,
+            header,
+            write.file_id,
+            write.offset,
+            length,
+            true,
+        )?;
+        let open = self.open(connection_id, header, write.file_id)?;
+        if open.desired_access & WRITE_ACCESS == 0 {
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+        let file = open.file.as_ref().ok_or(STATUS_INVALID_DEVICE_REQUEST)?;
+        file.write_all_at(&write.data, write.offset)
+            .map_err(|e| NtStatus::from(&e))?;
+        if write.flags & SMB2_WRITEFLAG_WRITE_THROUGH != 0 {
+            file.sync_data().map_err(|e| NtStatus::from(&e))?;
+        }
+        let path = open.path.clone();
+        self.break_read_caching(&path, write.file_id.volatile);
+        Ok(SmbBody::WriteResponse(SmbWriteResponse {
+            size: 17,
+            count: write.data.len() as u32,
+            remaining: 0,
+            write_channel_info_offset: 0,
+            write_channel_info_length: 0,
+        }))
+    }
+
+    /// `pdu`, the encoded `messages`, compressed if one of them answers a
+    /// READ with enough data to be worth it and the client negotiated
+    /// compression. Anything else, or that doesn't get any smaller, is left
+    /// as it is.
+    pub(crate) fn compress_reads(&self, messages: &[SmbMessage], pdu: Vec<u8>) -> Vec<u8> {
+        let large_read = messages.iter().any(|message| {
+            matches!(&message.body, SmbBody::ReadResponse(read) if read.data.len() >= COMPRESSION_THRESHOLD)
+        });
+        let Some(negotiated) = messages
+            .first()
+            .filter(|_| large_read)
+            .and_then(|message| self.negotiated(message.header.session_id))
+        else {
+            return pdu;
+        };
+        let algorithms = &negotiated.algorithms;
+        compress_message(
+            &pdu,
+            &algorithms.compression,
+            algorithms.chained_compression,
+        )
+        .unwrap_or(pdu)
+    }
+}
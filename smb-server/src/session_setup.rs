@@ -0,0 +1,763 @@
+//! SESSION_SETUP, where the client says who it is. NTLMSSP is the only way
+//! there is to, usually inside SPNEGO (offered in the NEGOTIATE response),
+//! and takes two goes: the client's NEGOTIATE gets a CHALLENGE back, with
+//! STATUS_MORE_PROCESSING_REQUIRED, and the AUTHENTICATE answering it is
+//! checked against the user database. A client that would rather have used
+//! something else first (e.g. Kerberos) is told NTLMSSP it is, and takes a
+//! go more to send its NEGOTIATE. Anything else, anonymous logons and
+//! users that aren't in the database get STATUS_LOGON_FAILURE, unless
+//! `map to guest` lets them in as a guest.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use smb2::encryption::EncryptionKeys;
+use smb2::message::{Dialect, HeaderFlags, SecurityMode, SmbBody, SmbMessageHeader};
+use smb2::message::{SmbSessionSetup, SmbSessionSetupResponse, SMB2_SESSION_FLAG_ENCRYPT_DATA};
+use smb2::message::{SMB2_SESSION_FLAG_IS_GUEST, SMB2_SESSION_FLAG_IS_NULL};
+use smb2::ntlmssp::{signature, Direction, NtlmMessage, ServerChallenge};
+use smb2::signing::SigningKey;
+use smb2::spnego::NTLMSSP_OID;
+use smb2::spnego::{mech_list_to_vec, NegState, NegTokenInit, NegTokenResp, SpnegoToken};
+
+use crate::status::*;
+use crate::{fs, HandlerResult, Server, Session};
+
+// who we say we are in the CHALLENGE, a server in a workgroup of its own.
+const NETBIOS_NAME: &str = "BAD-SAMBA";
+const WORKGROUP: &str = "WORKGROUP";
+
+// the security buffer comes right after the response's fixed part.
+const SECURITY_BUFFER_OFFSET: u16 = 64 + 8;
+
+/// An NTLMSSP exchange waiting on the client's AUTHENTICATE.
+pub struct NtlmExchange {
+    /// None while waiting on the client's NEGOTIATE, when it had to be
+    /// told to use NTLMSSP rather than what it offered first.
+    challenge: Option<ServerChallenge>,
+    /// The mechanism list the client's NegTokenInit offered, DER encoded,
+    /// which its mechListMIC is over. None if it didn't use SPNEGO.
+    mech_list: Option<Vec<u8>>,
+}
+
+/// What goes in the NEGOTIATE response's security buffer: NTLMSSP being
+/// the only mechanism there is to pick.
+pub fn negotiate_token() -> Vec<u8> {
+    NegTokenInit {
+        mech_types: vec![NTLMSSP_OID.to_vec()],
+        ..Default::default()
+    }
+    .to_vec()
+}
+
+impl Server {
+    pub(crate) fn session_setup(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        session_setup: &SmbSessionSetup,
+        raw: &[u8],
+    ) -> HandlerResult {
+        let token = &session_setup.buffer;
+        match SpnegoToken::parse(token) {
+            // Kerberos, or anything else the client might rather use, isn't
+            // something we can do, but NTLMSSP anywhere on its list will do.
+            Ok((_remaining, SpnegoToken::Init(init))) => {
+                let mech_list = mech_list_to_vec(&init.mech_types);
+                let ntlmssp_first = init.mech_types.first().is_some_and(|m| m == NTLMSSP_OID);
+                match &init.mech_token {
+                    Some(mech_token) if ntlmssp_first => {
+                        self.ntlm_negotiate(connection_id, header, mech_token, Some(mech_list), raw)
+                    }
+                    _ if init.mech_types.iter().any(|m| m == NTLMSSP_OID) => {
+                        self.select_ntlmssp(connection_id, header, mech_list, raw)
+                    }
+                    _ => self.unauthenticated(connection_id, header, raw),
+                }
+            }
+            Ok((_remaining, SpnegoToken::Resp(resp))) => {
+                let mech_token = resp.response_token.as_deref().unwrap_or_default();
+                let awaiting_negotiate = self
+                    .sessions
+                    .get(&header.session_id)
+                    .and_then(|session| session.ntlmssp.as_ref())
+                    .is_some_and(|exchange| exchange.challenge.is_none());
+                if awaiting_negotiate {
+                    return self.ntlm_selected_negotiate(connection_id, header, mech_token, raw);
+                }
+                let mic = resp.mech_list_mic.as_deref();
+                self.ntlm_authenticate(connection_id, header, mech_token, mic, raw)
+            }
+            // some clients send NTLMSSP without SPNEGO around it.
+            Err(_) => match NtlmMessage::parse(token) {
+                Ok((_remaining, NtlmMessage::Negotiate(_))) => {
+                    self.ntlm_negotiate(connection_id, header, token, None, raw)
+                }
+                Ok((_remaining, NtlmMessage::Authenticate(_))) => {
+                    self.ntlm_authenticate(connection_id, header, token, None, raw)
+                }
+                _ => self.unauthenticated(connection_id, header, raw),
+            },
+        }
+    }
+
+    /// A client that didn't say who it is in any way we understand, which
+    /// only gets in if guests are allowed.
+    fn unauthenticated(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        raw: &[u8],
+    ) -> HandlerResult {
+        if !self.map_to_guest {
+            println!("refusing a session setup that isn't NTLMSSP");
+            return Err(STATUS_LOGON_FAILURE.into());
+        }
+        header.session_id = self.new_session(connection_id, raw)?;
+        // guests don't have a session key to sign with.
+        self.establish(header, None, None, SMB2_SESSION_FLAG_IS_GUEST, vec![])
+    }
+
+    /// The first go at an NTLMSSP session setup, the NEGOTIATE (`token`)
+    /// getting a CHALLENGE back. `mech_list` is what the client's
+    /// NegTokenInit offered, if it came in one.
+    fn ntlm_negotiate(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        token: &[u8],
+        mech_list: Option<Vec<u8>>,
+        raw: &[u8],
+    ) -> HandlerResult {
+        let challenge = new_challenge(token).ok_or(STATUS_INVALID_PARAMETER)?;
+        header.session_id = self.new_session(connection_id, raw)?;
+        let buffer = match mech_list {
+            Some(_) => NegTokenResp {
+                neg_state: Some(NegState::AcceptIncomplete),
+                supported_mech: Some(NTLMSSP_OID.to_vec()),
+                response_token: Some(challenge.message().to_vec()),
+                mech_list_mic: None,
+            }
+            .to_vec(),
+            None => challenge.message().to_vec(),
+        };
+        self.sessions.get_mut(&header.session_id).unwrap().ntlmssp = Some(NtlmExchange {
+            challenge: Some(challenge),
+            mech_list,
+        });
+        more_processing_required(header, buffer)
+    }
+
+    /// A NegTokenInit that offers NTLMSSP, just not first or without its
+    /// NEGOTIATE, which gets told NTLMSSP is what it'll be and nothing else.
+    /// The NEGOTIATE comes next, in a NegTokenResp.
+    fn select_ntlmssp(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        mech_list: Vec<u8>,
+        raw: &[u8],
+    ) -> HandlerResult {
+        header.session_id = self.new_session(connection_id, raw)?;
+        self.sessions.get_mut(&header.session_id).unwrap().ntlmssp = Some(NtlmExchange {
+            challenge: None,
+            mech_list: Some(mech_list),
+        });
+        let buffer = NegTokenResp {
+            neg_state: Some(NegState::AcceptIncomplete),
+            supported_mech: Some(NTLMSSP_OID.to_vec()),
+            ..Default::default()
+        }
+        .to_vec();
+        more_processing_required(header, buffer)
+    }
+
+    /// The NEGOTIATE (`token`) a client sends once it's been told to use
+    /// NTLMSSP, which gets a CHALLENGE back like any other.
+    fn ntlm_selected_negotiate(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        token: &[u8],
+        raw: &[u8],
+    ) -> HandlerResult {
+        let session_id = header.session_id;
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .filter(|session| session.connection_id == connection_id)
+            .ok_or(STATUS_USER_SESSION_DELETED)?;
+        if let Some(preauth_integrity) = &mut session.preauth_integrity {
+            preauth_integrity.update(raw);
+        }
+        let Some(challenge) = new_challenge(token) else {
+            return self.logon_failure(session_id);
+        };
+        let buffer = NegTokenResp {
+            neg_state: Some(NegState::AcceptIncomplete),
+            response_token: Some(challenge.message().to_vec()),
+            ..Default::default()
+        }
+        .to_vec();
+        let exchange = session
+            .ntlmssp
+            .as_mut()
+            .ok_or(STATUS_REQUEST_NOT_ACCEPTED)?;
+        exchange.challenge = Some(challenge);
+        more_processing_required(header, buffer)
+    }
+
+    /// A session that's still being set up, its preauth integrity hash
+    /// (for 3.1.1) carried on from the connection's with `raw`, the
+    /// SESSION_SETUP request it was started by.
+    fn new_session(&mut self, connection_id: u64, raw: &[u8]) -> Result<u64, NtStatus> {
+        let connection = self
+            .connections
+            .get(&connection_id)
+            .ok_or(STATUS_CONNECTION_DISCONNECTED)?;
+        let preauth_integrity =
+            connection
+                .preauth_integrity
+                .clone()
+                .map(|mut preauth_integrity| {
+                    preauth_integrity.update(raw);
+                    preauth_integrity
+                });
+        self.next_session_id += 1;
+        let session_id = self.next_session_id;
+        self.sessions.insert(
+            session_id,
+            Session {
+                connection_id,
+                guest: false,
+                user: None,
+                preauth_integrity,
+                ntlmssp: None,
+                signing_key: None,
+                signing_required: false,
+                encryption: None,
+                encrypt_data: self.encrypt_data,
+                trees: HashMap::new(),
+                next_tree_id: 0,
+            },
+        );
+        Ok(session_id)
+    }
+
+    /// The second go at an NTLMSSP session setup, the AUTHENTICATE (`token`
+    /// as it came in) answering the CHALLENGE the session was sent, along
+    /// with the client's mechListMIC if it used SPNEGO and sent one.
+    fn ntlm_authenticate(
+        &mut self,
+        connection_id: u64,
+        header: &mut SmbMessageHeader,
+        token: &[u8],
+        mech_list_mic: Option<&[u8]>,
+        raw: &[u8],
+    ) -> HandlerResult {
+        let session_id = header.session_id;
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .filter(|session| session.connection_id == connection_id)
+            .ok_or(STATUS_USER_SESSION_DELETED)?;
+        // re-authenticating a session that's already set up isn't supported.
+        let exchange = session.ntlmssp.take().ok_or(STATUS_REQUEST_NOT_ACCEPTED)?;
+        if let Some(preauth_integrity) = &mut session.preauth_integrity {
+            preauth_integrity.update(raw);
+        }
+        let Some(challenge) = exchange.challenge else {
+            return self.logon_failure(session_id);
+        };
+        let Ok((_remaining, NtlmMessage::Authenticate(authenticate))) = NtlmMessage::parse(token)
+        else {
+            return self.logon_failure(session_id);
+        };
+        // nothing else to say once it's done, but SPNEGO needs telling that.
+        let completed = |mech_list_mic| match exchange.mech_list {
+            Some(_) => NegTokenResp {
+                neg_state: Some(NegState::AcceptCompleted),
+                mech_list_mic,
+                ..Default::default()
+            }
+            .to_vec(),
+            None => vec![],
+        };
+        if authenticate.is_anonymous() {
+            if !self.map_to_guest {
+                println!("refusing an anonymous logon");
+                return self.logon_failure(session_id);
+            }
+            let buffer = completed(None);
+            return self.establish(header, None, None, SMB2_SESSION_FLAG_IS_NULL, buffer);
+        }
+        let Some(nt_hash) = self.users.nt_hash(&authenticate.user) else {
+            if !self.map_to_guest {
+                println!("logon failure for unknown user {}", authenticate.user);
+                return self.logon_failure(session_id);
+            }
+            let buffer = completed(None);
+            return self.establish(header, None, None, SMB2_SESSION_FLAG_IS_GUEST, buffer);
+        };
+        let Some(session_key) = challenge.verify(&authenticate, token, nt_hash) else {
+            println!(
+                "logon failure for {}\\{}",
+                authenticate.domain, authenticate.user
+            );
+            return self.logon_failure(session_id);
+        };
+        // the mechListMIC, if the client sent one, says nobody talked it down
+        // to NTLMSSP, and it wants one back saying the same of us.
+        let flags = challenge.flags() & authenticate.flags;
+        let mech_list_mic = match (&exchange.mech_list, mech_list_mic) {
+            (Some(mech_list), Some(mic)) => {
+                let expected =
+                    signature(&session_key, flags, Direction::ClientToServer, 0, mech_list);
+                if expected.as_ref().map(|expected| &expected[..]) != Some(mic) {
+                    println!("bad mechListMIC from {}", authenticate.user);
+                    return self.logon_failure(session_id);
+                }
+                signature(&session_key, flags, Direction::ServerToClient, 0, mech_list)
+            }
+            _ => None,
+        };
+        println!("{} logged on", authenticate.user);
+        let buffer = completed(mech_list_mic.map(Vec::from));
+        self.establish(
+            header,
+            Some(&session_key),
+            Some(authenticate.user),
+            0,
+            buffer,
+        )
+    }
+
+    /// Gives up on the session being set up.
+    fn logon_failure(&mut self, session_id: u64) -> HandlerResult {
+        self.remove_session(session_id);
+        Err(STATUS_LOGON_FAILURE.into())
+    }
+
+    /// Finishes setting up the session `header` is for, as `user`, with the
+    /// keys derived from `session_key`, or none for guests and anonymous
+    /// sessions. `session_flags` says which of those it is, if either, and
+    /// `buffer` is the last of the security exchange, if there's any left.
+    fn establish(
+        &mut self,
+        header: &mut SmbMessageHeader,
+        session_key: Option<&[u8; 16]>,
+        user: Option<String>,
+        session_flags: u16,
+        buffer: Vec<u8>,
+    ) -> HandlerResult {
+        let session_id = header.session_id;
+        let session = &self.sessions[&session_id];
+        let negotiated = self
+            .connections
+            .get(&session.connection_id)
+            .and_then(|connection| connection.negotiated.as_ref());
+        let preauth_integrity = session.preauth_integrity.as_ref();
+        let signing_key = session_key
+            .zip(negotiated)
+            .map(|(session_key, negotiated)| {
+                SigningKey::new(
+                    negotiated.dialect,
+                    negotiated.algorithms.signing,
+                    session_key,
+                    preauth_integrity,
+                )
+            });
+        // either side can insist on it.
+        let client_security_mode = negotiated.map_or(SecurityMode::empty(), |negotiated| {
+            negotiated.client_security_mode
+        });
+        let signing_required = signing_key.is_some()
+            && (self.security_mode | client_security_mode).contains(SecurityMode::SIGNING_REQUIRED);
+        let encryption = session_key
+            .zip(negotiated)
+            .and_then(|(session_key, negotiated)| {
+                EncryptionKeys::new(
+                    negotiated.dialect,
+                    negotiated.algorithms.cipher,
+                    session_key,
+                    preauth_integrity,
+                )
+            });
+        // a 3.1.1 client checks the signature on this to know nobody
+        // tampered with what came before it.
+        let signed = signing_key.is_some()
+            && (signing_required || negotiated.is_some_and(|n| n.dialect == Dialect::Smb311));
+        if self.encrypt_data && encryption.is_none() {
+            println!("refusing a session that can't be encrypted");
+            self.remove_session(session_id);
+            return Err(STATUS_ACCESS_DENIED.into());
+        }
+
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        session.guest = session_key.is_none();
+        session.user = user;
+        session.signing_key = signing_key;
+        session.signing_required = signing_required;
+        session.encryption = encryption;
+        if signed {
+            header.flags |= HeaderFlags::SIGNED;
+        }
+        Ok(SmbBody::SessionSetupResponse(SmbSessionSetupResponse {
+            size: 9,
+            session_flags: if session.guest || !session.encrypt_data {
+                session_flags
+            } else {
+                session_flags | SMB2_SESSION_FLAG_ENCRYPT_DATA
+            },
+            security_buff_offset: if buffer.is_empty() {
+                0
+            } else {
+                SECURITY_BUFFER_OFFSET
+            },
+            security_buff_len: buffer.len() as u16,
+            buffer,
+        }))
+    }
+}
+
+/// Our CHALLENGE to the client's NEGOTIATE, `token`, if it is one.
+fn new_challenge(token: &[u8]) -> Option<ServerChallenge> {
+    let timestamp = fs::filetime(SystemTime::now());
+    ServerChallenge::new(token, NETBIOS_NAME, WORKGROUP, timestamp)
+}
+
+/// The response to a SESSION_SETUP that's not done yet, `buffer` being the
+/// next of the security exchange.
+fn more_processing_required(header: &mut SmbMessageHeader, buffer: Vec<u8>) -> HandlerResult {
+    header.status = STATUS_MORE_PROCESSING_REQUIRED;
+    Ok(SmbBody::SessionSetupResponse(SmbSessionSetupResponse {
+        size: 9,
+        session_flags: 0,
+        security_buff_offset: SECURITY_BUFFER_OFFSET,
+        security_buff_len: buffer.len() as u16,
+        buffer,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::UserDatabase;
+    use smb2::message::{Command, SmbMessageHeaderVariant};
+    use smb2::ntlmssp::{nt_hash, ntlmv2_blob, ntlmv2_response, ntowf_v2, session_base_key};
+    use smb2::ntlmssp::{NegotiateFlags, NtlmAuthenticate, NtlmChallenge, NtlmNegotiate};
+    use smb2::spnego::NegTokenResp;
+    use tokio::sync::mpsc;
+
+    // 1.2.840.113554.1.2.2, what Windows clients offer ahead of NTLMSSP.
+    const KERBEROS_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02];
+
+    fn request(session_id: u64) -> SmbMessageHeader {
+        SmbMessageHeader {
+            protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+            header_size: 64,
+            credit_charge: 1,
+            status: STATUS_SUCCESS,
+            command: Command::SessionSetup,
+            credit_request_response: 1,
+            flags: HeaderFlags::empty(),
+            next_command: 0,
+            message_id: 1,
+            variant: SmbMessageHeaderVariant::Sync { tree_id: 0 },
+            session_id,
+            signature: 0,
+        }
+    }
+
+    fn session_setup(token: Vec<u8>) -> SmbSessionSetup {
+        SmbSessionSetup {
+            size: 25,
+            flags: 0,
+            security_mode: SecurityMode::SIGNING_ENABLED,
+            capabilities: Default::default(),
+            channel: 0,
+            security_buff_offset: 88,
+            security_buff_len: token.len() as u16,
+            previous_session_id: 0,
+            buffer: token,
+        }
+    }
+
+    fn with_alice(map_to_guest: bool) -> Server {
+        Server {
+            users: UserDatabase::parse(&format!(
+                "alice:1000:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:{}:[U          ]:LCT-00000000:\n",
+                nt_hash("Password")
+                    .iter()
+                    .map(|b| format!("{b:02X}"))
+                    .collect::<String>()
+            )),
+            map_to_guest,
+            ..Default::default()
+        }
+    }
+
+    /// A logon the way a client would do it, in SPNEGO if `spnego`, with
+    /// Kerberos offered ahead of NTLMSSP if `kerberos_first`. The client's
+    /// mechListMIC can be made wrong with `bad_mic`.
+    struct LogOn {
+        user: &'static str,
+        password: &'static str,
+        spnego: bool,
+        kerberos_first: bool,
+        bad_mic: bool,
+    }
+
+    impl LogOn {
+        fn new(user: &'static str, password: &'static str) -> LogOn {
+            LogOn {
+                user,
+                password,
+                spnego: true,
+                kerberos_first: false,
+                bad_mic: false,
+            }
+        }
+
+        /// NEGOTIATE then AUTHENTICATE, returning the session the
+        /// CHALLENGE was for and how the AUTHENTICATE went.
+        fn run(&self, server: &mut Server) -> (u64, HandlerResult) {
+            let connection_id = server.connect(mpsc::unbounded_channel().0, None);
+            let negotiate = NtlmNegotiate {
+                flags: NegotiateFlags::UNICODE
+                    | NegotiateFlags::NTLM
+                    | NegotiateFlags::EXTENDED_SESSIONSECURITY,
+                domain: "".into(),
+                workstation: "".into(),
+            };
+            let mut mech_types = vec![NTLMSSP_OID.to_vec()];
+            let token = match self.spnego {
+                true if self.kerberos_first => {
+                    mech_types.insert(0, KERBEROS_OID.to_vec());
+                    NegTokenInit {
+                        mech_types: mech_types.clone(),
+                        mech_token: Some(b"an AP-REQ".to_vec()),
+                        mech_list_mic: None,
+                    }
+                    .to_vec()
+                }
+                true => NegTokenInit {
+                    mech_types: mech_types.clone(),
+                    mech_token: Some(negotiate.to_vec()),
+                    mech_list_mic: None,
+                }
+                .to_vec(),
+                false => negotiate.to_vec(),
+            };
+            let mut header = request(0);
+            let Ok(SmbBody::SessionSetupResponse(mut response)) =
+                server.session_setup(connection_id, &mut header, &session_setup(token), &[])
+            else {
+                panic!("no challenge");
+            };
+            assert_eq!(header.status, STATUS_MORE_PROCESSING_REQUIRED);
+            if self.kerberos_first {
+                // told to use NTLMSSP instead, and asked for its NEGOTIATE.
+                let (_remaining, resp) = NegTokenResp::parse(&response.buffer).unwrap();
+                assert_eq!(resp.neg_state, Some(NegState::AcceptIncomplete));
+                assert_eq!(resp.supported_mech.as_deref(), Some(NTLMSSP_OID));
+                assert_eq!(resp.response_token, None);
+                let token = NegTokenResp {
+                    response_token: Some(negotiate.to_vec()),
+                    ..Default::default()
+                }
+                .to_vec();
+                let Ok(SmbBody::SessionSetupResponse(challenge)) =
+                    server.session_setup(connection_id, &mut header, &session_setup(token), &[])
+                else {
+                    panic!("no challenge");
+                };
+                assert_eq!(header.status, STATUS_MORE_PROCESSING_REQUIRED);
+                response = challenge;
+            }
+            assert_eq!(response.security_buff_offset, 72);
+            let challenge = match self.spnego {
+                true => {
+                    let (_remaining, resp) = NegTokenResp::parse(&response.buffer).unwrap();
+                    assert_eq!(resp.neg_state, Some(NegState::AcceptIncomplete));
+                    if !self.kerberos_first {
+                        assert_eq!(resp.supported_mech.as_deref(), Some(NTLMSSP_OID));
+                    }
+                    resp.response_token.unwrap()
+                }
+                false => response.buffer,
+            };
+            let (_remaining, challenge) = NtlmChallenge::parse(&challenge).unwrap();
+            assert_eq!(challenge.target_name, NETBIOS_NAME);
+            let session_id = header.session_id;
+            // nothing but SESSION_SETUP until it's been set up.
+            assert!(server.session(connection_id, &request(session_id)).is_err());
+
+            let key = ntowf_v2(&nt_hash(self.password), self.user, WORKGROUP);
+            let blob = ntlmv2_blob(0, [0xaa; 8], &challenge.target_info);
+            let nt_response = ntlmv2_response(&key, &challenge.server_challenge, &blob);
+            let session_key = session_base_key(&key, &nt_response[..16]);
+            let anonymous = self.user.is_empty();
+            let authenticate = NtlmAuthenticate {
+                flags: challenge.flags,
+                lm_response: if anonymous { vec![] } else { vec![0; 24] },
+                nt_response: if anonymous { vec![] } else { nt_response },
+                domain: WORKGROUP.into(),
+                user: self.user.into(),
+                workstation: "CLIENT".into(),
+                encrypted_random_session_key: vec![],
+                mic: None,
+            };
+            let mech_list = mech_list_to_vec(&mech_types);
+            let mut mic = signature(
+                &session_key,
+                challenge.flags,
+                Direction::ClientToServer,
+                0,
+                &mech_list,
+            )
+            .unwrap();
+            if self.bad_mic {
+                mic[4] ^= 1;
+            }
+            let token = match self.spnego {
+                true => NegTokenResp {
+                    response_token: Some(authenticate.to_vec()),
+                    mech_list_mic: (!anonymous).then(|| mic.to_vec()),
+                    ..Default::default()
+                }
+                .to_vec(),
+                false => authenticate.to_vec(),
+            };
+            let mut header = request(session_id);
+            let result =
+                server.session_setup(connection_id, &mut header, &session_setup(token), &[]);
+            if let Ok(SmbBody::SessionSetupResponse(response)) = &result {
+                if self.spnego {
+                    let (_remaining, resp) = NegTokenResp::parse(&response.buffer).unwrap();
+                    assert_eq!(resp.neg_state, Some(NegState::AcceptCompleted));
+                    // ours, going the other way, if it's a real logon.
+                    let expected = signature(
+                        &session_key,
+                        challenge.flags,
+                        Direction::ServerToClient,
+                        0,
+                        &mech_list,
+                    );
+                    let expected = expected.filter(|_| response.session_flags == 0);
+                    assert_eq!(resp.mech_list_mic, expected.map(Vec::from));
+                }
+            }
+            (session_id, result)
+        }
+    }
+
+    #[test]
+    fn ntlmssp_round_trip() {
+        let mut server = with_alice(false);
+        for spnego in [true, false] {
+            let log_on = LogOn {
+                spnego,
+                ..LogOn::new("Alice", "Password")
+            };
+            let (session_id, result) = log_on.run(&mut server);
+            let Ok(SmbBody::SessionSetupResponse(response)) = result else {
+                panic!("didn't log on: {result:?}");
+            };
+            assert_eq!(response.session_flags, 0);
+            let connection_id = server.next_connection_id;
+            let session = server.session(connection_id, &request(session_id)).unwrap();
+            assert!(!session.guest);
+            assert_eq!(session.user.as_deref(), Some("Alice"));
+            // it's no use to anyone on any other connection.
+            let other = server.connect(mpsc::unbounded_channel().0, None);
+            assert_eq!(
+                server.session(other, &request(session_id)).err(),
+                Some(STATUS_USER_SESSION_DELETED)
+            );
+            assert_eq!(
+                server.logoff(other, &request(session_id)).err(),
+                Some(STATUS_USER_SESSION_DELETED.into())
+            );
+            assert!(server.sessions.contains_key(&session_id));
+        }
+
+        let (session_id, result) = LogOn::new("alice", "wrong").run(&mut server);
+        assert_eq!(result.err(), Some(STATUS_LOGON_FAILURE.into()));
+        assert!(!server.sessions.contains_key(&session_id));
+        let log_on = LogOn {
+            bad_mic: true,
+            ..LogOn::new("Alice", "Password")
+        };
+        let (session_id, result) = log_on.run(&mut server);
+        assert_eq!(result.err(), Some(STATUS_LOGON_FAILURE.into()));
+        assert!(!server.sessions.contains_key(&session_id));
+    }
+
+    #[test]
+    fn ntlmssp_when_kerberos_comes_first() {
+        let mut server = with_alice(false);
+        let log_on = LogOn {
+            kerberos_first: true,
+            ..LogOn::new("alice", "Password")
+        };
+        let (session_id, result) = log_on.run(&mut server);
+        let Ok(SmbBody::SessionSetupResponse(response)) = result else {
+            panic!("didn't log on: {result:?}");
+        };
+        assert_eq!(response.session_flags, 0);
+        let connection_id = server.next_connection_id;
+        let session = server.session(connection_id, &request(session_id)).unwrap();
+        assert_eq!(session.user.as_deref(), Some("alice"));
+
+        // the mechListMIC is over everything the client offered.
+        let log_on = LogOn {
+            kerberos_first: true,
+            bad_mic: true,
+            ..LogOn::new("alice", "Password")
+        };
+        let (session_id, result) = log_on.run(&mut server);
+        assert_eq!(result.err(), Some(STATUS_LOGON_FAILURE.into()));
+        assert!(!server.sessions.contains_key(&session_id));
+    }
+
+    #[test]
+    fn guests_only_if_mapped() {
+        let junk = |server: &mut Server| {
+            let connection_id = server.connect(mpsc::unbounded_channel().0, None);
+            let setup = session_setup(b"not a security blob".to_vec());
+            server.session_setup(connection_id, &mut request(0), &setup, &[])
+        };
+        let mut server = with_alice(false);
+        for user in ["mallory", ""] {
+            let (_session_id, result) = LogOn::new(user, "Password").run(&mut server);
+            assert_eq!(result.err(), Some(STATUS_LOGON_FAILURE.into()));
+        }
+        assert_eq!(junk(&mut server).err(), Some(STATUS_LOGON_FAILURE.into()));
+        assert!(server.sessions.is_empty());
+
+        let mut server = with_alice(true);
+        let log_ons = [
+            ("mallory", SMB2_SESSION_FLAG_IS_GUEST),
+            ("", SMB2_SESSION_FLAG_IS_NULL),
+        ];
+        for (user, session_flags) in log_ons {
+            let (session_id, result) = LogOn::new(user, "Password").run(&mut server);
+            let Ok(SmbBody::SessionSetupResponse(response)) = result else {
+                panic!("{user:?} didn't get in: {result:?}");
+            };
+            assert_eq!(response.session_flags, session_flags);
+            let connection_id = server.next_connection_id;
+            let session = server.session(connection_id, &request(session_id)).unwrap();
+            assert!(session.guest);
+            assert_eq!(session.user, None);
+        }
+        let Ok(SmbBody::SessionSetupResponse(response)) = junk(&mut server) else {
+            panic!("no guest session");
+        };
+        assert_eq!(response.session_flags, SMB2_SESSION_FLAG_IS_GUEST);
+        // a real user still has to get their password right.
+        let (_session_id, result) = LogOn::new("alice", "wrong").run(&mut server);
+        assert_eq!(result.err(), Some(STATUS_LOGON_FAILURE.into()));
+    }
+}
@@ -0,0 +1,3 @@
+//! The NT status codes the server hands back.
+
+pub use ntstatus::*;
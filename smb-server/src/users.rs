@@ -0,0 +1,99 @@
+//! The local users clients can log on as, kept in Samba's smbpasswd format:
+//!
+//! ```text
+//! # name:uid:LM hash:NT hash:[account flags]:LCT-last change time:
+//! alice:1000:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:8846F7EAEE8FB117AD06BDD830B7586C:[U          ]:LCT-5F5A1C2B:
+//! ```
+//!
+//! Only the NT hash is used, LM hashes being for authentication nothing
+//! should be doing any more. Disabled accounts (a `D` in the flags) aren't
+//! let in, and accounts with no password (`N`) have the hash of an empty one.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use smb2::ntlmssp::nt_hash;
+
+#[derive(Debug, Default)]
+pub struct UserDatabase {
+    // keyed by the lowercase user name, since user names are case insensitive.
+    users: HashMap<String, [u8; 16]>,
+}
+
+impl UserDatabase {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<UserDatabase> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Lines that don't make sense are skipped, like Samba does, rather
+    /// than locking everybody else out.
+    pub fn parse(text: &str) -> UserDatabase {
+        let mut users = HashMap::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split(':').collect();
+            let [name, _uid, _lm_hash, hash, flags, ..] = fields[..] else {
+                println!("smbpasswd line {}: too few fields", line_number + 1);
+                continue;
+            };
+            if flags.contains('D') {
+                continue;
+            }
+            let hash = match flags.contains('N') {
+                true => Some(nt_hash("")),
+                false => parse_hash(hash),
+            };
+            let Some(hash) = hash else {
+                println!("smbpasswd line {}: no NT hash for {name}", line_number + 1);
+                continue;
+            };
+            users.insert(name.to_lowercase(), hash);
+        }
+        UserDatabase { users }
+    }
+
+    /// The NT hash of `user`'s password, if they can log on.
+    pub fn nt_hash(&self, user: &str) -> Option<&[u8; 16]> {
+        self.users.get(&user.to_lowercase())
+    }
+}
+
+/// An NT hash as smbpasswd writes it, 32 hex digits. Accounts without
+/// one have X's instead.
+fn parse_hash(hash: &str) -> Option<[u8; 16]> {
+    if hash.len() != 32 || !hash.is_ascii() {
+        return None;
+    }
+    let mut out = [0; 16];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_smbpasswd() {
+        let users = UserDatabase::parse(
+            "# a comment\n\
+             alice:1000:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:A4F49C406510BDCAB6824EE7C30FD852:[U          ]:LCT-5F5A1C2B:\n\
+             bob:1001:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:A4F49C406510BDCAB6824EE7C30FD852:[DU         ]:LCT-5F5A1C2B:\n\
+             carol:1002:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:[NU         ]:LCT-5F5A1C2B:\n\
+             dave:1003:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX:[U          ]:LCT-5F5A1C2B:\n\
+             eve:1004\n",
+        );
+        assert_eq!(users.nt_hash("Alice"), Some(&nt_hash("Password")));
+        // disabled.
+        assert_eq!(users.nt_hash("bob"), None);
+        assert_eq!(users.nt_hash("carol"), Some(&nt_hash("")));
+        // neither a hash nor the flag saying there's no password.
+        assert_eq!(users.nt_hash("dave"), None);
+        assert_eq!(users.nt_hash("eve"), None);
+    }
+}
@@ -24,7 +24,7 @@ impl Smb1Message {
 pub struct Smb1Header {
     pub protocol: [u8; 4],
     pub command: u8,
-    pub status: u32,
+    pub status: ntstatus::NtStatus,
     pub flags: u8,
     pub flags2: u16,
     pub pid_high: u16,
@@ -54,7 +54,7 @@ impl Smb1Header {
             Smb1Header {
                 protocol: protocol.try_into().unwrap(),
                 command: command[0],
-                status: u32::from_le_bytes(status.try_into().unwrap()),
+                status: ntstatus::NtStatus(u32::from_le_bytes(status.try_into().unwrap())),
                 flags: flags[0],
                 flags2: u16::from_le_bytes(flags2.try_into().unwrap()),
                 pid_high: u16::from_le_bytes(pid_high.try_into().unwrap()),
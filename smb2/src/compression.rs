@@ -0,0 +1,368 @@
+//! Message compression, which 3.1.1 clients can negotiate to move less over
+//! slow links. A compressed message goes out behind a
+//! COMPRESSION_TRANSFORM_HEADER, either all compressed the one way or, when
+//! chained, as a run of payloads that can each be compressed differently.
+//! The algorithms are MS-XCA's.
+
+use crate::message::SMB2_COMPRESSION_FLAG_NONE;
+use crate::message::{CompressionAlgorithm, SmbCompressionChainedPayloadHeader};
+use crate::message::{SmbCompressionTransformHeader, SmbPatternV1Payload};
+use crate::message::{COMPRESSION_PROTOCOL_ID, SMB2_COMPRESSION_FLAG_CHAINED};
+
+mod lz77;
+mod lz77_huffman;
+mod lznt1;
+mod match_finder;
+
+// runs of the same byte any shorter than this aren't worth a Pattern_V1
+// payload of their own.
+const MIN_PATTERN: usize = 64;
+
+fn read_u16(data: &[u8], input: &mut usize) -> Option<u16> {
+    let value = u16::from_le_bytes(data.get(*input..*input + 2)?.try_into().unwrap());
+    *input += 2;
+    Some(value)
+}
+
+fn read_u32(data: &[u8], input: &mut usize) -> Option<u32> {
+    let value = u32::from_le_bytes(data.get(*input..*input + 4)?.try_into().unwrap());
+    *input += 4;
+    Some(value)
+}
+
+/// Copies `length` bytes from `offset` back, a byte at a time since they
+/// can overlap what's being copied. None if that's before the start, or
+/// would go past `limit`.
+fn copy_match(out: &mut Vec<u8>, offset: usize, length: usize, limit: usize) -> Option<()> {
+    if offset == 0 || offset > out.len() || out.len() + length > limit {
+        return None;
+    }
+    let start = out.len() - offset;
+    for i in start..start + length {
+        out.push(out[i]);
+    }
+    Some(())
+}
+
+/// `data` compressed with `algorithm`, or None if it's not one that compresses
+/// (NONE and Pattern_V1) or we don't have (LZ4).
+pub fn compress(algorithm: CompressionAlgorithm, data: &[u8]) -> Option<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::Lznt1 => Some(lznt1::compress(data)),
+        CompressionAlgorithm::Lz77 => Some(lz77::compress(data)),
+        CompressionAlgorithm::Lz77Huffman => Some(lz77_huffman::compress(data)),
+        CompressionAlgorithm::None
+        | CompressionAlgorithm::PatternV1
+        | CompressionAlgorithm::Lz4 => None,
+    }
+}
+
+/// `data` decompressed with `algorithm`, None unless it comes out to
+/// exactly `original_size` bytes.
+pub fn decompress(
+    algorithm: CompressionAlgorithm,
+    data: &[u8],
+    original_size: usize,
+) -> Option<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::Lznt1 => lznt1::decompress(data, original_size),
+        CompressionAlgorithm::Lz77 => lz77::decompress(data, original_size),
+        CompressionAlgorithm::Lz77Huffman => lz77_huffman::decompress(data, original_size),
+        CompressionAlgorithm::None
+        | CompressionAlgorithm::PatternV1
+        | CompressionAlgorithm::Lz4 => None,
+    }
+}
+
+/// Whether `pdu` is compressed, i.e. starts with a compression transform header.
+pub fn is_compressed(pdu: &[u8]) -> bool {
+    pdu.starts_with(&COMPRESSION_PROTOCOL_ID)
+}
+
+/// Splits `data` into the runs of one byte long enough to be a pattern, and what's between them.
+fn pattern_runs(data: &[u8]) -> Vec<(Option<u8>, &[u8])> {
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let run = data[pos..].iter().take_while(|&&b| b == data[pos]).count();
+        if run >= MIN_PATTERN {
+            if start < pos {
+                runs.push((None, &data[start..pos]));
+            }
+            runs.push((Some(data[pos]), &data[pos..pos + run]));
+            start = pos + run;
+        }
+        pos += run;
+    }
+    if start < data.len() {
+        runs.push((None, &data[start..]));
+    }
+    runs
+}
+
+/// `pdu`, a whole (possibly compounded) message, compressed with the first
+/// of `algorithms` we can compress with, chained if it was negotiated (and
+/// with Pattern_V1 for long runs of the same byte, if that was too). None if
+/// there's nothing to compress with, or it wouldn't get any smaller.
+pub fn compress_message(
+    pdu: &[u8],
+    algorithms: &[CompressionAlgorithm],
+    chained: bool,
+) -> Option<Vec<u8>> {
+    let algorithm = algorithms.iter().copied().find(|&algorithm| {
+        matches!(
+            algorithm,
+            CompressionAlgorithm::Lznt1
+                | CompressionAlgorithm::Lz77
+                | CompressionAlgorithm::Lz77Huffman
+        )
+    });
+    let mut out = vec![];
+    if !chained {
+        let algorithm = algorithm?;
+        let compressed = compress(algorithm, pdu)?;
+        out.extend(
+            SmbCompressionTransformHeader::Unchained {
+                original_compressed_segment_size: pdu.len() as u32,
+                compression_algorithm: algorithm,
+                offset: 0,
+            }
+            .to_vec(),
+        );
+        out.extend(compressed);
+    } else {
+        out.extend(
+            SmbCompressionTransformHeader::Chained {
+                original_compressed_segment_size: pdu.len() as u32,
+            }
+            .to_vec(),
+        );
+        let patterns = algorithms.contains(&CompressionAlgorithm::PatternV1);
+        let runs = match patterns {
+            true => pattern_runs(pdu),
+            false => vec![(None, pdu)],
+        };
+        for (i, (pattern, data)) in runs.into_iter().enumerate() {
+            let (payload_algorithm, payload) = match pattern {
+                Some(pattern) => (
+                    CompressionAlgorithm::PatternV1,
+                    SmbPatternV1Payload {
+                        pattern,
+                        repetitions: data.len() as u32,
+                    }
+                    .to_vec(),
+                ),
+                // what doesn't get any smaller is left as it is.
+                None => algorithm
+                    .and_then(|algorithm| {
+                        let compressed = compress(algorithm, data)?;
+                        (compressed.len() < data.len()).then_some((algorithm, compressed))
+                    })
+                    .unwrap_or((CompressionAlgorithm::None, data.to_vec())),
+            };
+            // the first payload's flags are where an unchained header's are.
+            let flags = match i {
+                0 => SMB2_COMPRESSION_FLAG_CHAINED,
+                _ => SMB2_COMPRESSION_FLAG_NONE,
+            };
+            out.extend(
+                SmbCompressionChainedPayloadHeader::new(
+                    payload_algorithm,
+                    flags,
+                    &payload,
+                    data.len(),
+                )
+                .to_vec(),
+            );
+            out.extend(payload);
+        }
+    }
+    (out.len() < pdu.len()).then_some(out)
+}
+
+/// The message `pdu` (starting at its compression transform header) was
+/// compressed from. None if it uses an algorithm that isn't one of
+/// `algorithms`, is chained when that wasn't negotiated, would come out
+/// bigger than `max_size`, or doesn't decompress.
+pub fn decompress_message(
+    pdu: &[u8],
+    algorithms: &[CompressionAlgorithm],
+    chained: bool,
+    max_size: usize,
+) -> Option<Vec<u8>> {
+    let (mut remaining, header) = SmbCompressionTransformHeader::parse(pdu).ok()?;
+    match header {
+        SmbCompressionTransformHeader::Unchained {
+            original_compressed_segment_size,
+            compression_algorithm,
+            offset,
+        } => {
+            let offset = offset as usize;
+            let original_size = original_compressed_segment_size as usize;
+            if !algorithms.contains(&compression_algorithm) || offset + original_size > max_size {
+                return None;
+            }
+            let mut out = remaining.get(..offset)?.to_vec();
+            out.extend(decompress(
+                compression_algorithm,
+                &remaining[offset..],
+                original_size,
+            )?);
+            Some(out)
+        }
+        SmbCompressionTransformHeader::Chained {
+            original_compressed_segment_size,
+        } => {
+            let original_size = original_compressed_segment_size as usize;
+            if !chained || original_size > max_size {
+                return None;
+            }
+            let mut out = Vec::with_capacity(original_size);
+            while !remaining.is_empty() {
+                let (rest, payload) = SmbCompressionChainedPayloadHeader::parse(remaining).ok()?;
+                let data = rest.get(..payload.data_length())?;
+                remaining = &rest[data.len()..];
+                let left = original_size - out.len();
+                match payload.compression_algorithm {
+                    CompressionAlgorithm::None if data.len() <= left => out.extend(data),
+                    CompressionAlgorithm::PatternV1
+                        if algorithms.contains(&CompressionAlgorithm::PatternV1) =>
+                    {
+                        let (_, pattern) = SmbPatternV1Payload::parse(data).ok()?;
+                        let repetitions = pattern.repetitions as usize;
+                        if repetitions > left {
+                            return None;
+                        }
+                        out.resize(out.len() + repetitions, pattern.pattern);
+                    }
+                    algorithm if algorithms.contains(&algorithm) => {
+                        let size = payload.original_payload_size? as usize;
+                        if size > left {
+                            return None;
+                        }
+                        out.extend(decompress(algorithm, data, size)?);
+                    }
+                    _ => return None,
+                }
+            }
+            (out.len() == original_size).then_some(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a READ response's worth of message: a header, then some text, then a
+    // run of zeroes and some more text.
+    fn message() -> Vec<u8> {
+        let mut message = vec![0xFE, b'S', b'M', b'B'];
+        message.extend(0..76);
+        message.extend(b"the quick brown fox jumps over the lazy dog. ".repeat(50));
+        message.extend([0; 10_000]);
+        message.extend(b"and again, the quick brown fox. ".repeat(20));
+        message
+    }
+
+    #[test]
+    fn every_algorithm() {
+        let message = message();
+        for algorithm in [
+            CompressionAlgorithm::Lznt1,
+            CompressionAlgorithm::Lz77,
+            CompressionAlgorithm::Lz77Huffman,
+        ] {
+            let compressed = compress(algorithm, &message).unwrap();
+            assert!(compressed.len() < message.len() / 4, "{algorithm:?}");
+            assert_eq!(
+                decompress(algorithm, &compressed, message.len()).as_ref(),
+                Some(&message),
+                "{algorithm:?}"
+            );
+        }
+        assert_eq!(compress(CompressionAlgorithm::Lz4, &message), None);
+    }
+
+    #[test]
+    fn unchained_message() {
+        let message = message();
+        let algorithms = [CompressionAlgorithm::Lz4, CompressionAlgorithm::Lz77];
+        let compressed = compress_message(&message, &algorithms, false).unwrap();
+        assert!(is_compressed(&compressed));
+        // LZ4's skipped, we don't have it.
+        assert_eq!(&compressed[8..10], [0x02, 0x00]);
+        assert_eq!(
+            decompress_message(&compressed, &algorithms, false, message.len()),
+            Some(message.clone())
+        );
+        // not something that was negotiated.
+        assert_eq!(
+            decompress_message(&compressed, &[CompressionAlgorithm::Lznt1], false, 1 << 20),
+            None
+        );
+        // bigger than we're willing to make room for.
+        assert_eq!(
+            decompress_message(&compressed, &algorithms, false, message.len() - 1),
+            None
+        );
+        // random bytes don't get any smaller.
+        let mut noise = 0x12345678u32;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                noise as u8
+            })
+            .collect();
+        assert_eq!(compress_message(&noise, &algorithms, false), None);
+    }
+
+    #[test]
+    fn chained_message() {
+        let message = message();
+        let algorithms = [
+            CompressionAlgorithm::Lz77Huffman,
+            CompressionAlgorithm::PatternV1,
+        ];
+        let compressed = compress_message(&message, &algorithms, true).unwrap();
+        let (remaining, _) = SmbCompressionTransformHeader::parse(&compressed).unwrap();
+        let mut payloads = vec![];
+        let mut remaining = remaining;
+        while !remaining.is_empty() {
+            let (rest, payload) = SmbCompressionChainedPayloadHeader::parse(remaining).unwrap();
+            remaining = &rest[payload.data_length()..];
+            payloads.push((payload.compression_algorithm, payload.flags));
+        }
+        assert_eq!(
+            payloads,
+            [
+                (
+                    CompressionAlgorithm::Lz77Huffman,
+                    SMB2_COMPRESSION_FLAG_CHAINED
+                ),
+                (CompressionAlgorithm::PatternV1, SMB2_COMPRESSION_FLAG_NONE),
+                (
+                    CompressionAlgorithm::Lz77Huffman,
+                    SMB2_COMPRESSION_FLAG_NONE
+                ),
+            ]
+        );
+        assert_eq!(
+            decompress_message(&compressed, &algorithms, true, 1 << 20),
+            Some(message.clone())
+        );
+        // chaining wasn't negotiated.
+        assert_eq!(
+            decompress_message(&compressed, &algorithms, false, 1 << 20),
+            None
+        );
+        // nor was Pattern_V1.
+        assert_eq!(
+            decompress_message(&compressed, &algorithms[..1], true, 1 << 20),
+            None
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Plain LZ77 (MS-XCA 2.3 and 2.4): literals and matches, told apart by
+//! 32 bit flag words that come before the tokens they're for.
+
+use super::match_finder::{MatchFinder, MIN_MATCH};
+use super::{copy_match, read_u16, read_u32};
+
+const WINDOW: usize = 8192;
+
+/// Keeps track of the flag bits for the tokens since the last flag word.
+struct Flags {
+    bits: u32,
+    count: u32,
+    /// Where the flag word for them goes.
+    position: usize,
+}
+
+impl Flags {
+    fn push(&mut self, out: &mut Vec<u8>, match_: bool) {
+        self.bits = (self.bits << 1) | match_ as u32;
+        self.count += 1;
+        if self.count == 32 {
+            out[self.position..self.position + 4].copy_from_slice(&self.bits.to_le_bytes());
+            self.position = out.len();
+            out.extend([0; 4]);
+            self.count = 0;
+        }
+    }
+
+    /// Writes the last flag word, with what's left of it set so the
+    /// decompressor goes looking for a match that isn't there, and stops.
+    fn finish(self, out: &mut [u8]) {
+        let unused = 32 - self.count;
+        let bits = ((self.bits as u64) << unused | ((1 << unused) - 1)) as u32;
+        out[self.position..self.position + 4].copy_from_slice(&bits.to_le_bytes());
+    }
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; 4];
+    let mut flags = Flags {
+        bits: 0,
+        count: 0,
+        position: 0,
+    };
+    // every other long match shares a byte with the one before it for its length.
+    let mut half_byte: Option<usize> = None;
+    let mut finder = MatchFinder::new(data, WINDOW);
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some((offset, length)) = finder.find(pos, WINDOW, u32::MAX as usize) else {
+            out.push(data[pos]);
+            flags.push(&mut out, false);
+            pos += 1;
+            continue;
+        };
+        let encoded_length = length - MIN_MATCH;
+        let offset = ((offset - 1) as u16) << 3;
+        out.extend((offset | encoded_length.min(7) as u16).to_le_bytes());
+        if encoded_length >= 7 {
+            let nibble = (encoded_length - 7).min(15) as u8;
+            match half_byte.take() {
+                None => {
+                    half_byte = Some(out.len());
+                    out.push(nibble);
+                }
+                Some(position) => out[position] |= nibble << 4,
+            }
+            if encoded_length >= 7 + 15 {
+                match encoded_length - (7 + 15) {
+                    more @ 0..255 => out.push(more as u8),
+                    _ => {
+                        out.push(255);
+                        match u16::try_from(encoded_length) {
+                            Ok(encoded_length) => out.extend(encoded_length.to_le_bytes()),
+                            Err(_) => {
+                                out.extend([0; 2]);
+                                out.extend((encoded_length as u32).to_le_bytes());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        flags.push(&mut out, true);
+        for pos in pos + 1..pos + length {
+            finder.insert(pos);
+        }
+        pos += length;
+    }
+    flags.finish(&mut out);
+    out
+}
+
+pub fn decompress(data: &[u8], original_size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(original_size);
+    let mut input = 0;
+    let mut flags = 0;
+    let mut flag_count = 0;
+    let mut half_byte: Option<usize> = None;
+    while out.len() < original_size {
+        if flag_count == 0 {
+            flags = read_u32(data, &mut input)?;
+            flag_count = 32;
+        }
+        flag_count -= 1;
+        if flags & (1 << flag_count) == 0 {
+            out.push(*data.get(input)?);
+            input += 1;
+            continue;
+        }
+        let token = read_u16(data, &mut input)? as usize;
+        let offset = token / 8 + 1;
+        let mut length = token % 8;
+        if length == 7 {
+            length = match half_byte.take() {
+                None => {
+                    let byte = *data.get(input)?;
+                    half_byte = Some(input);
+                    input += 1;
+                    byte % 16
+                }
+                Some(position) => data[position] / 16,
+            } as usize;
+            if length == 15 {
+                length = *data.get(input)? as usize;
+                input += 1;
+                if length == 255 {
+                    length = read_u16(data, &mut input)? as usize;
+                    if length == 0 {
+                        length = read_u32(data, &mut input)? as usize;
+                    }
+                    length = length.checked_sub(15 + 7)?;
+                }
+                length += 15;
+            }
+            length += 7;
+        }
+        copy_match(&mut out, offset, length + MIN_MATCH, original_size)?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        let data = b"abcdefghijklmnopqrstuvwxyz";
+        let mut expected = vec![0x3F, 0x00, 0x00, 0x00];
+        expected.extend(data);
+        // from MS-XCA's examples.
+        assert_eq!(compress(data), expected);
+        assert_eq!(decompress(&expected, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn long_match() {
+        let data = b"abc".repeat(100);
+        #[rustfmt::skip]
+        let expected = [
+            // flags, three literals then a match
+            0xFF, 0xFF, 0xFF, 0x1F,
+            b'a', b'b', b'c',
+            // offset 3, length 7 or more| 15 or more| 255 or more| 297 - 3
+            0x17, 0x00, 0x0F, 0xFF, 0x26, 0x01,
+        ];
+        // from MS-XCA's examples.
+        assert_eq!(compress(&data), expected);
+        assert_eq!(decompress(&expected, data.len()).unwrap(), data);
+        // more than it says it's going to be is as bad as less.
+        assert_eq!(decompress(&expected, data.len() - 1), None);
+        assert_eq!(decompress(&expected, data.len() + 1), None);
+    }
+
+    #[test]
+    fn every_length_encoding() {
+        // matches of every length that shares a half byte, and the ones long
+        // enough to need 16 and 32 bits, with literals in between.
+        let mut data = vec![];
+        for length in (3..40).chain([300, 70_000]) {
+            data.extend(b"xyz");
+            data.extend(std::iter::repeat_n(length as u8, length));
+        }
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+}
@@ -0,0 +1,423 @@
+//! LZ77+Huffman (MS-XCA 2.1 and 2.2): LZ77 literals and matches as symbols
+//! of a Huffman code that starts over every 64 KiB of output. Each block
+//! starts with the code's bit lengths, then a stream of 16 bit words read
+//! most significant bit first, with the odd byte of match length wedged in
+//! between them.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use super::match_finder::{MatchFinder, MIN_MATCH};
+use super::{copy_match, read_u16};
+
+const BLOCK_SIZE: usize = 65536;
+// 256 literals, then EOF and the matches.
+const SYMBOLS: usize = 512;
+const EOF: usize = 256;
+// the bit lengths, 4 bits a symbol.
+const TABLE_SIZE: usize = SYMBOLS / 2;
+const MAX_CODE_LENGTH: u32 = 15;
+const WINDOW: usize = 65536;
+// offsets and lengths (less 3) that go past 16 bits can't be encoded.
+const MAX_OFFSET: usize = WINDOW - 1;
+const MAX_LENGTH: usize = u16::MAX as usize + MIN_MATCH;
+
+enum Token {
+    Literal(u8),
+    Match { offset: usize, length: usize },
+    End,
+}
+
+/// How many bits of a match's offset follow its symbol, all but the
+/// highest one, which the symbol says where it is.
+fn offset_bits(offset: usize) -> u32 {
+    usize::BITS - 1 - offset.leading_zeros()
+}
+
+impl Token {
+    fn symbol(&self) -> usize {
+        match *self {
+            Token::Literal(byte) => byte as usize,
+            Token::Match { offset, length } => {
+                EOF + ((offset_bits(offset) as usize) << 4) + (length - MIN_MATCH).min(15)
+            }
+            Token::End => EOF,
+        }
+    }
+
+    /// How much output it's for.
+    fn len(&self) -> usize {
+        match *self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => length,
+            Token::End => 0,
+        }
+    }
+}
+
+fn tokens(data: &[u8]) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut finder = MatchFinder::new(data, WINDOW);
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some((offset, length)) = finder.find(pos, MAX_OFFSET, MAX_LENGTH) else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+            continue;
+        };
+        tokens.push(Token::Match { offset, length });
+        for pos in pos + 1..pos + length {
+            finder.insert(pos);
+        }
+        pos += length;
+    }
+    tokens
+}
+
+/// Each symbol's bit length in a Huffman code for `frequencies`, with no
+/// code longer than 15 bits.
+fn code_lengths(frequencies: &[u32; SYMBOLS]) -> [u8; SYMBOLS] {
+    let mut frequencies = *frequencies;
+    loop {
+        let lengths = huffman_lengths(&frequencies);
+        if lengths
+            .iter()
+            .all(|&length| length as u32 <= MAX_CODE_LENGTH)
+        {
+            return lengths;
+        }
+        // flatten things out until the tree is shallow enough, every symbol
+        // in use stays in use.
+        for frequency in frequencies.iter_mut().filter(|frequency| **frequency > 0) {
+            *frequency = (*frequency / 2).max(1);
+        }
+    }
+}
+
+fn huffman_lengths(frequencies: &[u32; SYMBOLS]) -> [u8; SYMBOLS] {
+    let mut lengths = [0; SYMBOLS];
+    let mut heap: BinaryHeap<_> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, &frequency)| frequency > 0)
+        .map(|(symbol, &frequency)| Reverse((frequency as u64, symbol)))
+        .collect();
+    if heap.len() == 1 {
+        // a code with one symbol still needs a bit to say it.
+        let Reverse((_, symbol)) = heap.pop().unwrap();
+        lengths[symbol] = 1;
+        return lengths;
+    }
+    // the symbols are the leaves, every node after them is a join.
+    let mut parents = vec![usize::MAX; SYMBOLS];
+    while let (Some(Reverse((a, a_node))), Some(Reverse((b, b_node)))) = (heap.pop(), heap.pop()) {
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[a_node] = node;
+        parents[b_node] = node;
+        heap.push(Reverse((a + b, node)));
+        if heap.len() == 1 {
+            break;
+        }
+    }
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        let mut node = symbol;
+        while parents[node] != usize::MAX {
+            node = parents[node];
+            *length += 1;
+        }
+    }
+    lengths
+}
+
+/// The canonical code for each symbol: shorter codes first, then in order
+/// of symbol. None if the lengths ask for more codes than there are.
+fn canonical_codes(lengths: &[u8; SYMBOLS]) -> Option<[u16; SYMBOLS]> {
+    let mut codes = [0; SYMBOLS];
+    let mut code = 0u32;
+    for length in 1..=MAX_CODE_LENGTH as u8 {
+        for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l == length) {
+            codes[symbol] = code as u16;
+            code += 1;
+        }
+        if code > 1 << length {
+            return None;
+        }
+        code <<= 1;
+    }
+    Some(codes)
+}
+
+/// The symbol for every 15 bit prefix a code can start with, u16::MAX
+/// for the ones it can't.
+fn decoding_table(lengths: &[u8; SYMBOLS]) -> Option<Vec<u16>> {
+    let codes = canonical_codes(lengths)?;
+    let mut table = vec![u16::MAX; 1 << MAX_CODE_LENGTH];
+    for (symbol, &length) in lengths.iter().enumerate().filter(|(_, &l)| l > 0) {
+        let unused = MAX_CODE_LENGTH - length as u32;
+        let start = (codes[symbol] as usize) << unused;
+        table[start..start + (1 << unused)].fill(symbol as u16);
+    }
+    Some(table)
+}
+
+/// Puts bits into 16 bit words, leaving room for the next word before any
+/// bytes written after the current one starts, which is where the
+/// decompressor will have already read it from by then.
+struct BitWriter {
+    current: usize,
+    next: Option<usize>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    /// The decompressor starts each block with two words read.
+    fn new(out: &mut Vec<u8>) -> Self {
+        let current = out.len();
+        out.extend([0; 4]);
+        Self {
+            current,
+            next: Some(current + 2),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, out: &mut Vec<u8>, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            if self.count == 0 && self.next.is_none() {
+                self.next = Some(out.len());
+                out.extend([0; 2]);
+            }
+            self.bits = (self.bits << 1) | ((value >> i) & 1);
+            self.count += 1;
+            if self.count == 16 {
+                out[self.current..self.current + 2]
+                    .copy_from_slice(&(self.bits as u16).to_le_bytes());
+                self.current = self.next.take().unwrap();
+                self.bits = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    fn finish(self, out: &mut [u8]) {
+        if self.count > 0 {
+            let bits = (self.bits << (16 - self.count)) as u16;
+            out[self.current..self.current + 2].copy_from_slice(&bits.to_le_bytes());
+        }
+    }
+}
+
+fn write_block(out: &mut Vec<u8>, block: &[Token]) {
+    let mut frequencies = [0; SYMBOLS];
+    for token in block {
+        frequencies[token.symbol()] += 1;
+    }
+    let lengths = code_lengths(&frequencies);
+    let codes = canonical_codes(&lengths).unwrap();
+    out.extend(lengths.chunks(2).map(|pair| pair[0] | pair[1] << 4));
+    let mut bits = BitWriter::new(out);
+    for token in block {
+        let symbol = token.symbol();
+        bits.write(out, codes[symbol] as u32, lengths[symbol] as u32);
+        let Token::Match { offset, length } = *token else {
+            continue;
+        };
+        let encoded_length = length - MIN_MATCH;
+        if encoded_length >= 15 {
+            match encoded_length - 15 {
+                more @ 0..255 => out.push(more as u8),
+                _ => {
+                    out.push(255);
+                    out.extend((encoded_length as u16).to_le_bytes());
+                }
+            }
+        }
+        let offset_bits = offset_bits(offset);
+        bits.write(out, (offset - (1 << offset_bits)) as u32, offset_bits);
+    }
+    bits.finish(out);
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut tokens = tokens(data).into_iter().peekable();
+    let mut position = 0;
+    loop {
+        // a block is over once it's written 64 KiB, even if its last match
+        // went past that, so an EOF right after a full block needs its own.
+        let block_end = position + BLOCK_SIZE;
+        let mut block = vec![];
+        while position < block_end {
+            let Some(token) = tokens.next() else {
+                break;
+            };
+            position += token.len();
+            block.push(token);
+        }
+        let last = tokens.peek().is_none() && position < block_end;
+        if last {
+            block.push(Token::End);
+        }
+        write_block(&mut out, &block);
+        if last {
+            return out;
+        }
+    }
+}
+
+/// Reads the 16 bit words a block's symbols and offsets are in, keeping
+/// at least 16 bits more than are needed yet.
+struct BitReader {
+    bits: u32,
+    extra: i32,
+}
+
+impl BitReader {
+    // past the end is zero, in case whoever compressed it didn't bother
+    // writing out words there was nothing left in.
+    fn word(data: &[u8], input: &mut usize) -> u32 {
+        let word = data
+            .get(*input..*input + 2)
+            .map_or(0, |word| u16::from_le_bytes(word.try_into().unwrap()));
+        *input += 2;
+        word as u32
+    }
+
+    fn new(data: &[u8], input: &mut usize) -> Self {
+        let high = Self::word(data, input);
+        let low = Self::word(data, input);
+        Self {
+            bits: high << 16 | low,
+            extra: 16,
+        }
+    }
+
+    fn peek(&self) -> usize {
+        (self.bits >> (32 - MAX_CODE_LENGTH)) as usize
+    }
+
+    fn consume(&mut self, count: u32, data: &[u8], input: &mut usize) {
+        self.bits <<= count;
+        self.extra -= count as i32;
+        if self.extra < 0 {
+            self.bits |= Self::word(data, input) << -self.extra;
+            self.extra += 16;
+        }
+    }
+
+    fn take(&mut self, count: u32, data: &[u8], input: &mut usize) -> usize {
+        let value = match count {
+            0 => 0,
+            _ => self.bits >> (32 - count),
+        };
+        self.consume(count, data, input);
+        value as usize
+    }
+}
+
+pub fn decompress(data: &[u8], original_size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(original_size);
+    let mut input = 0;
+    while out.len() < original_size {
+        let table = data.get(input..input + TABLE_SIZE)?;
+        input += TABLE_SIZE;
+        let mut lengths = [0; SYMBOLS];
+        for (i, byte) in table.iter().enumerate() {
+            lengths[2 * i] = byte & 0xF;
+            lengths[2 * i + 1] = byte >> 4;
+        }
+        let decoding = decoding_table(&lengths)?;
+        let mut bits = BitReader::new(data, &mut input);
+        let block_end = out.len() + BLOCK_SIZE;
+        while out.len() < block_end && out.len() < original_size {
+            let symbol = decoding[bits.peek()];
+            if symbol == u16::MAX {
+                return None;
+            }
+            let symbol = symbol as usize;
+            bits.consume(lengths[symbol] as u32, data, &mut input);
+            if symbol < EOF {
+                out.push(symbol as u8);
+                continue;
+            }
+            // short of what we were told there'd be.
+            if symbol == EOF && input >= data.len() {
+                return None;
+            }
+            let mut length = (symbol - EOF) % 16;
+            let offset_bits = ((symbol - EOF) / 16) as u32;
+            if length == 15 {
+                length = *data.get(input)? as usize;
+                input += 1;
+                if length == 255 {
+                    length = (read_u16(data, &mut input)? as usize).checked_sub(15)?;
+                }
+                length += 15;
+            }
+            let offset = bits.take(offset_bits, data, &mut input) + (1 << offset_bits);
+            copy_match(&mut out, offset, length + MIN_MATCH, original_size)?;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_literal() {
+        let mut expected = vec![0; TABLE_SIZE];
+        // 'a' and EOF both get a one bit code, 0 and 1.
+        expected[b'a' as usize / 2] = 0x10;
+        expected[EOF / 2] = 0x01;
+        expected.extend([0x00, 0x40, 0x00, 0x00]);
+        assert_eq!(compress(b"a"), expected);
+        assert_eq!(decompress(&expected, 1).unwrap(), b"a");
+    }
+
+    #[test]
+    fn blocks() {
+        let mut noise = 0x12345678u32;
+        let mut data: Vec<u8> = (0..100_000)
+            .map(|_| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                b"abcdefgh"[noise as usize % 8]
+            })
+            .collect();
+        // a match too long for a byte, and one that crosses into the next block.
+        data.extend(std::iter::repeat_n(b'z', 70_000));
+        for len in [0, 1, 3, BLOCK_SIZE, BLOCK_SIZE + 1, data.len()] {
+            let data = &data[..len];
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).as_deref(), Some(data));
+        }
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(decompress(&compressed, data.len() + 1), None);
+    }
+
+    #[test]
+    fn lengths_fit_in_four_bits() {
+        // a fibonacci distribution makes for the deepest trees.
+        let mut frequencies = [0; SYMBOLS];
+        let (mut a, mut b) = (1, 1);
+        for frequency in frequencies.iter_mut().take(30) {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+        assert!(huffman_lengths(&frequencies).iter().max() > Some(&15));
+        let lengths = code_lengths(&frequencies);
+        assert!(lengths.iter().max() <= Some(&15));
+        assert!(canonical_codes(&lengths).is_some());
+        // more codes than there's room for.
+        let mut lengths = [0; SYMBOLS];
+        lengths[..3].fill(1);
+        assert!(decoding_table(&lengths).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+//! LZNT1 (MS-XCA 2.5), NTFS's compression: 4 KiB chunks each compressed on
+//! their own, with copy tokens whose split between offset and length moves
+//! as the chunk goes on.
+
+use super::match_finder::{MatchFinder, MIN_MATCH};
+use super::{copy_match, read_u16};
+
+const CHUNK_SIZE: usize = 4096;
+
+// a chunk header is its size (less 3, header included) and these.
+const CHUNK_SIGNATURE: u16 = 0x3000;
+const CHUNK_COMPRESSED: u16 = 0x8000;
+const CHUNK_SIZE_MASK: u16 = 0x0FFF;
+
+/// How many bits of a copy token are its offset, at `pos` bytes into a
+/// chunk. Offsets can only point back into the chunk, so there are only
+/// ever as many as there need to be, and the rest are for the length.
+fn offset_bits(pos: usize) -> u32 {
+    let mut bits = 4;
+    while (1 << bits) < pos {
+        bits += 1;
+    }
+    bits
+}
+
+fn compress_chunk(chunk: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut finder = MatchFinder::new(chunk, CHUNK_SIZE);
+    let mut pos = 0;
+    while pos < chunk.len() {
+        let flags_position = out.len();
+        out.push(0);
+        for bit in 0..8 {
+            if pos >= chunk.len() {
+                break;
+            }
+            let length_bits = 16 - offset_bits(pos);
+            let max_length = (1 << length_bits) - 1 + MIN_MATCH;
+            let Some((offset, length)) = finder.find(pos, pos, max_length) else {
+                out.push(chunk[pos]);
+                pos += 1;
+                continue;
+            };
+            let token = ((offset - 1) << length_bits) | (length - MIN_MATCH);
+            out.extend((token as u16).to_le_bytes());
+            out[flags_position] |= 1 << bit;
+            for pos in pos + 1..pos + length {
+                finder.insert(pos);
+            }
+            pos += length;
+        }
+    }
+    out
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for chunk in data.chunks(CHUNK_SIZE) {
+        let compressed = compress_chunk(chunk);
+        // a chunk that doesn't get any smaller is left as it is.
+        let (flags, chunk) = match compressed.len() < chunk.len() {
+            true => (CHUNK_COMPRESSED, &compressed[..]),
+            false => (0, chunk),
+        };
+        let header = flags | CHUNK_SIGNATURE | (chunk.len() - 1) as u16;
+        out.extend(header.to_le_bytes());
+        out.extend(chunk);
+    }
+    out
+}
+
+fn decompress_chunk(chunk: &[u8], out: &mut Vec<u8>, original_size: usize) -> Option<()> {
+    let start = out.len();
+    let mut input = 0;
+    while input < chunk.len() {
+        let flags = chunk[input];
+        input += 1;
+        for bit in 0..8 {
+            if input >= chunk.len() {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                if out.len() == original_size {
+                    return None;
+                }
+                out.push(chunk[input]);
+                input += 1;
+                continue;
+            }
+            let token = read_u16(chunk, &mut input)? as usize;
+            let pos = out.len() - start;
+            let length_bits = 16 - offset_bits(pos);
+            let offset = (token >> length_bits) + 1;
+            if offset > pos {
+                return None;
+            }
+            let length = (token & ((1 << length_bits) - 1)) + MIN_MATCH;
+            copy_match(out, offset, length, original_size)?;
+        }
+    }
+    Some(())
+}
+
+pub fn decompress(data: &[u8], original_size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(original_size);
+    let mut input = 0;
+    while out.len() < original_size {
+        let header = read_u16(data, &mut input)?;
+        // a header of zero can mark the end.
+        if header == 0 {
+            break;
+        }
+        let size = (header & CHUNK_SIZE_MASK) as usize + 1;
+        let chunk = data.get(input..input + size)?;
+        input += size;
+        if header & CHUNK_COMPRESSED == 0 {
+            if out.len() + chunk.len() > original_size {
+                return None;
+            }
+            out.extend(chunk);
+        } else {
+            decompress_chunk(chunk, &mut out, original_size)?;
+        }
+    }
+    (out.len() == original_size).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        // compressible, then not, then compressible and short.
+        let mut data = b"abc".repeat(2000)[..CHUNK_SIZE].to_vec();
+        let mut noise = 0x12345678u32;
+        data.extend((0..CHUNK_SIZE).map(|_| {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            noise as u8
+        }));
+        data.extend(b"hello hello hello");
+        let compressed = compress(&data);
+        // offset 3, length 4093, in the first chunk's first token.
+        assert_eq!(
+            compressed[..8],
+            [0x05, 0xB0, 0x08, b'a', b'b', b'c', 0xFA, 0x2F]
+        );
+        let second = 2 + 6;
+        assert_eq!(compressed[second..second + 2], [0xFF, 0x3F]);
+        assert_eq!(
+            compressed[second + 2..second + 2 + CHUNK_SIZE],
+            data[CHUNK_SIZE..2 * CHUNK_SIZE]
+        );
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        assert_eq!(decompress(&compressed, data.len() - 1), None);
+    }
+
+    #[test]
+    fn offset_bits_grow_with_the_chunk() {
+        assert_eq!(offset_bits(1), 4);
+        assert_eq!(offset_bits(16), 4);
+        assert_eq!(offset_bits(17), 5);
+        assert_eq!(offset_bits(4095), 12);
+        // a copy token pointing before the start of the chunk.
+        assert_eq!(decompress(&[0x02, 0xB0, 0x01, 0x00, 0x00], 3), None);
+    }
+}
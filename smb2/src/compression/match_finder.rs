@@ -0,0 +1,88 @@
+//! Finding where what's coming up has been seen before, which every LZ77
+//! flavour does the same way and only encodes differently.
+
+const HASH_BITS: u32 = 15;
+// how far down a hash chain to look before settling for the best so far.
+const MAX_CHAIN: usize = 32;
+const NONE: u32 = u32::MAX;
+
+/// The shortest match worth a token, for every algorithm here.
+pub const MIN_MATCH: usize = 3;
+
+pub struct MatchFinder<'a> {
+    data: &'a [u8],
+    /// The last position each hash was seen at.
+    head: Vec<u32>,
+    /// For each position in the window, the one before it with the same hash.
+    prev: Vec<u32>,
+    window_mask: usize,
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let bytes = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (bytes.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+impl<'a> MatchFinder<'a> {
+    /// For matches at most `window` (a power of two) bytes back.
+    pub fn new(data: &'a [u8], window: usize) -> Self {
+        Self {
+            data,
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; window.min(data.len())],
+            window_mask: window - 1,
+        }
+    }
+
+    /// Remembers what's at `pos`, for matches further on to find.
+    pub fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = hash(&self.data[pos..]);
+        self.prev[pos & self.window_mask] = self.head[hash];
+        self.head[hash] = pos as u32;
+    }
+
+    /// The offset back and length of the longest earlier match for what's
+    /// at `pos`, within `max_offset` and `max_length`, then remembers it.
+    /// Positions have to be asked about (or inserted) in order.
+    pub fn find(
+        &mut self,
+        pos: usize,
+        max_offset: usize,
+        max_length: usize,
+    ) -> Option<(usize, usize)> {
+        let max_length = max_length.min(self.data.len() - pos);
+        if max_length < MIN_MATCH {
+            self.insert(pos);
+            return None;
+        }
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[hash(&self.data[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE {
+                break;
+            }
+            let earlier = candidate as usize;
+            // the chain only gets further away from here.
+            if earlier >= pos || pos - earlier > max_offset {
+                break;
+            }
+            let length = self.data[earlier..]
+                .iter()
+                .zip(&self.data[pos..pos + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= MIN_MATCH && best.is_none_or(|(_, best)| length > best) {
+                best = Some((pos - earlier, length));
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.prev[earlier & self.window_mask];
+        }
+        self.insert(pos);
+        best
+    }
+}
@@ -0,0 +1,271 @@
+//! Message encryption, SMB 3's answer to a network that can read as well as
+//! tamper. An encrypted message goes out behind a TRANSFORM_HEADER, which
+//! carries the nonce and the AEAD's tag in place of the message's signature.
+
+use aes::{Aes128, Aes256};
+use aes_gcm::aead::consts::{U11, U16};
+use aes_gcm::aead::{AeadInPlace, KeyInit, Nonce, Tag};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use ccm::Ccm;
+
+use crate::message::{Cipher, Dialect, SmbTransformHeader, SMB2_TRANSFORM_FLAG_ENCRYPTED};
+use crate::message::{TRANSFORM_HEADER_SIZE, TRANSFORM_PROTOCOL_ID};
+use crate::preauth::PreauthIntegrityHash;
+use crate::signing::kdf;
+
+type Aes128Ccm = Ccm<Aes128, U16, U11>;
+type Aes256Ccm = Ccm<Aes256, U16, U11>;
+
+/// How much of the transform header's nonce `cipher` uses.
+fn nonce_size(cipher: Cipher) -> usize {
+    match cipher {
+        Cipher::Aes128Ccm | Cipher::Aes256Ccm => 11,
+        Cipher::Aes128Gcm | Cipher::Aes256Gcm => 12,
+    }
+}
+
+fn seal<C: AeadInPlace + KeyInit>(key: &[u8], nonce: &[u8], aad: &[u8], buf: &mut [u8]) -> u128 {
+    let tag = C::new_from_slice(key)
+        .unwrap()
+        .encrypt_in_place_detached(Nonce::<C>::from_slice(nonce), aad, buf)
+        .unwrap();
+    u128::from_le_bytes(tag.as_slice().try_into().unwrap())
+}
+
+fn open<C: AeadInPlace + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    buf: &mut [u8],
+    tag: u128,
+) -> bool {
+    C::new_from_slice(key)
+        .unwrap()
+        .decrypt_in_place_detached(
+            Nonce::<C>::from_slice(nonce),
+            aad,
+            buf,
+            Tag::<C>::from_slice(&tag.to_le_bytes()),
+        )
+        .is_ok()
+}
+
+/// What a session's messages are encrypted with, both ways.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    cipher: Cipher,
+    /// What the server encrypts with, called the ServerOut or S2C key.
+    encryption: Vec<u8>,
+    /// What the client encrypts with, called the ServerIn or C2S key.
+    decryption: Vec<u8>,
+}
+
+/// Keys don't belong in logs.
+impl std::fmt::Debug for EncryptionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKeys")
+            .field("cipher", &self.cipher)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptionKeys {
+    /// The keys for a session set up with `session_key` on a connection that
+    /// negotiated `dialect`. `negotiated` is what ENCRYPTION_CAPABILITIES
+    /// settled on, and `preauth_integrity` the session's final preauth
+    /// integrity hash, both of which only 3.1.1 has. None if the dialect
+    /// can't encrypt, or the client and server had no cipher in common.
+    pub fn new(
+        dialect: Dialect,
+        negotiated: Option<Cipher>,
+        session_key: &[u8],
+        preauth_integrity: Option<&PreauthIntegrityHash>,
+    ) -> Option<EncryptionKeys> {
+        let (cipher, labels, context): (_, [&[u8]; 2], &[u8]) = match dialect {
+            Dialect::Smb202 | Dialect::Smb210 | Dialect::Wildcard => return None,
+            // 3.0 only ever had the one cipher.
+            Dialect::Smb300 | Dialect::Smb302 => {
+                (Cipher::Aes128Ccm, [b"SMB2AESCCM\0", b"SMB2AESCCM\0"], b"")
+            }
+            Dialect::Smb311 => (
+                negotiated?,
+                [b"SMBS2CCipherKey\0", b"SMBC2SCipherKey\0"],
+                preauth_integrity
+                    .map(|preauth_integrity| preauth_integrity.value().as_slice())
+                    .unwrap_or_default(),
+            ),
+        };
+        // 3.0 tells the two keys apart by context rather than label.
+        let contexts: [&[u8]; 2] = match dialect {
+            Dialect::Smb311 => [context, context],
+            _ => [b"ServerOut\0", b"ServerIn \0"],
+        };
+        // the 256 bit ciphers use all of the session key, the rest its first 16 bytes.
+        let (key, length) = match cipher {
+            Cipher::Aes128Ccm | Cipher::Aes128Gcm => {
+                (&session_key[..session_key.len().min(16)], 16)
+            }
+            Cipher::Aes256Ccm | Cipher::Aes256Gcm => (session_key, 32),
+        };
+        Some(EncryptionKeys {
+            cipher,
+            encryption: kdf(key, labels[0], contexts[0], length),
+            decryption: kdf(key, labels[1], contexts[1], length),
+        })
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// `pdu`, a whole (possibly compounded) message for `session_id`,
+    /// encrypted and behind a transform header, ready to go on the wire.
+    pub fn encrypt(&self, session_id: u64, pdu: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; 16];
+        // a nonce must never be used twice with the same key, and there's
+        // no counter to keep here that survives a reconnect.
+        getrandom::getrandom(&mut nonce[..nonce_size(self.cipher)])
+            .expect("the OS should have random bytes to give");
+        self.encrypt_with_nonce(session_id, pdu, nonce)
+    }
+
+    fn encrypt_with_nonce(&self, session_id: u64, pdu: &[u8], nonce: [u8; 16]) -> Vec<u8> {
+        let mut header = SmbTransformHeader {
+            signature: 0,
+            nonce,
+            original_message_size: pdu.len() as u32,
+            flags: SMB2_TRANSFORM_FLAG_ENCRYPTED,
+            session_id,
+        };
+        let aad = header.to_vec();
+        let aad = &aad[SmbTransformHeader::AAD_OFFSET..];
+        let nonce = &nonce[..nonce_size(self.cipher)];
+        let key = &self.encryption;
+        let mut ciphertext = pdu.to_vec();
+        header.signature = match self.cipher {
+            Cipher::Aes128Ccm => seal::<Aes128Ccm>(key, nonce, aad, &mut ciphertext),
+            Cipher::Aes256Ccm => seal::<Aes256Ccm>(key, nonce, aad, &mut ciphertext),
+            Cipher::Aes128Gcm => seal::<Aes128Gcm>(key, nonce, aad, &mut ciphertext),
+            Cipher::Aes256Gcm => seal::<Aes256Gcm>(key, nonce, aad, &mut ciphertext),
+        };
+        let mut out = header.to_vec();
+        out.extend(ciphertext);
+        out
+    }
+
+    /// The message `pdu` (starting at its transform header) was wrapping,
+    /// or None if it wasn't encrypted with this session's key, or has been
+    /// tampered with since.
+    pub fn decrypt(&self, pdu: &[u8]) -> Option<Vec<u8>> {
+        let (ciphertext, header) = SmbTransformHeader::parse(pdu).ok()?;
+        if header.original_message_size as usize != ciphertext.len() {
+            return None;
+        }
+        let aad = &pdu[SmbTransformHeader::AAD_OFFSET..TRANSFORM_HEADER_SIZE];
+        let nonce = &header.nonce[..nonce_size(self.cipher)];
+        let key = &self.decryption;
+        let tag = header.signature;
+        let mut plaintext = ciphertext.to_vec();
+        let opened = match self.cipher {
+            Cipher::Aes128Ccm => open::<Aes128Ccm>(key, nonce, aad, &mut plaintext, tag),
+            Cipher::Aes256Ccm => open::<Aes256Ccm>(key, nonce, aad, &mut plaintext, tag),
+            Cipher::Aes128Gcm => open::<Aes128Gcm>(key, nonce, aad, &mut plaintext, tag),
+            Cipher::Aes256Gcm => open::<Aes256Gcm>(key, nonce, aad, &mut plaintext, tag),
+        };
+        opened.then_some(plaintext)
+    }
+}
+
+/// Whether `pdu` is encrypted, i.e. starts with a transform header.
+pub fn is_encrypted(pdu: &[u8]) -> bool {
+    pdu.starts_with(&TRANSFORM_PROTOCOL_ID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // from Microsoft's "the anatomy of signing and cryptographic keys".
+    const SESSION_KEY: &str = "7cd451825d0450d235424e44ba6e78cc";
+
+    #[test]
+    fn encryption_key_derivation() {
+        assert!(EncryptionKeys::new(Dialect::Smb210, None, &hex(SESSION_KEY), None).is_none());
+        assert!(EncryptionKeys::new(Dialect::Smb311, None, &hex(SESSION_KEY), None).is_none());
+        let keys = EncryptionKeys::new(Dialect::Smb300, None, &hex(SESSION_KEY), None).unwrap();
+        assert_eq!(keys.cipher(), Cipher::Aes128Ccm);
+        assert_eq!(keys.encryption, hex("b0f0427f7ceb416d1d9dcc0cd4f99447"));
+        assert_eq!(keys.decryption, hex("fad27796665b313ebb578f388632b4f7"));
+    }
+
+    #[test]
+    fn encrypt_ccm() {
+        let keys = EncryptionKeys::new(Dialect::Smb302, None, &hex(SESSION_KEY), None).unwrap();
+        let pdu: Vec<u8> = (0..68).collect();
+        let mut nonce = [0; 16];
+        nonce[..11].copy_from_slice(&hex("a0a1a2a3a4a5a6a7a8a9aa"));
+        let encrypted = keys.encrypt_with_nonce(0x1234, &pdu, nonce);
+        let (ciphertext, header) = SmbTransformHeader::parse(&encrypted).unwrap();
+        assert_eq!(
+            header.signature.to_le_bytes().to_vec(),
+            hex("dea1dbd00b57271374eef49ef74b4a1e")
+        );
+        assert_eq!(header.original_message_size, 68);
+        assert_eq!(header.session_id, 0x1234);
+        assert_eq!(
+            ciphertext,
+            hex(concat!(
+                "23fb2a5d6a1da6caf5eca69950fb4f1bb36c7699a048a3060e6f8ceddce0b3c7",
+                "9137653a228547cf30f6be82049304ffd2c6e2bf2c9fb300eada57e7cc54fc59",
+                "ad41d1d9",
+            ))
+        );
+        // the server can't read its own messages, they're encrypted with the other key.
+        assert_eq!(keys.decrypt(&encrypted), None);
+        let client = EncryptionKeys {
+            encryption: keys.decryption.clone(),
+            decryption: keys.encryption.clone(),
+            ..keys
+        };
+        assert_eq!(client.decrypt(&encrypted), Some(pdu.clone()));
+        assert_eq!(client.decrypt(&keys.encrypt(0x1234, &pdu)), Some(pdu));
+    }
+
+    #[test]
+    fn decrypt_gcm() {
+        let mut preauth_integrity = PreauthIntegrityHash::default();
+        preauth_integrity.update(b"NEGOTIATE");
+        let session_key: Vec<u8> = (0..32).collect();
+        let keys = EncryptionKeys::new(
+            Dialect::Smb311,
+            Some(Cipher::Aes256Gcm),
+            &session_key,
+            Some(&preauth_integrity),
+        )
+        .unwrap();
+        #[rustfmt::skip]
+        let mut encrypted = hex(concat!(
+            // protocol id, signature
+            "fd534d42", "6500e401db1629fbc328a7041e036f07",
+            // nonce
+            "b0b1b2b3b4b5b6b7b8b9babb00000000",
+            // original message size, reserved, flags, session id
+            "44000000", "0000", "0100", "3412000000000000",
+            "6072572a11ad71b3b99e68b27489dd2719d4c237dd60875fa12b8f78100011ad",
+            "761d7e7649749dbecb6fb4081ea47fccae7163296e370c5797d5ff144204f77a",
+            "bd29fca6",
+        ));
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(keys.decrypt(&encrypted), Some((0..68).collect()));
+        // the session id is authenticated along with everything else.
+        encrypted[44] ^= 1;
+        assert_eq!(keys.decrypt(&encrypted), None);
+    }
+}
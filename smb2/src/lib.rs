@@ -1 +1,7 @@
+pub mod compression;
+pub mod encryption;
 pub mod message;
+pub mod ntlmssp;
+pub mod preauth;
+pub mod signing;
+pub mod spnego;
@@ -1,3 +1,4 @@
+use ::bytes::Bytes;
 use nom::bytes::complete as bytes;
 use nom::error::context;
 use nom::Parser;
@@ -5,11 +6,86 @@ use nom::Parser;
 mod header;
 pub use header::SmbMessageHeader;
 pub use header::SmbMessageHeaderVariant;
+pub use header::{Command, HeaderFlags, InvalidCommand};
 
 mod negotiate;
 pub use negotiate::SmbNegotiate;
 
 pub use negotiate::SmbNegotiateResponse;
+pub use negotiate::COMPRESSION_CAPABILITIES_FLAG_CHAINED;
+pub use negotiate::{Capabilities, Dialect, InvalidDialect, SecurityMode};
+pub use negotiate::{Cipher, CompressionAlgorithm, HashAlgorithm, SigningAlgorithm};
+pub use negotiate::{InvalidCipher, InvalidCompressionAlgorithm};
+pub use negotiate::{InvalidHashAlgorithm, InvalidSigningAlgorithm};
+pub use negotiate::{SmbNegotiateContext, SmbNegotiateContextData};
+
+mod session_setup;
+pub use session_setup::SmbLogoff;
+pub use session_setup::SmbSessionSetup;
+pub use session_setup::SmbSessionSetupResponse;
+pub use session_setup::{
+    SMB2_SESSION_FLAG_BINDING, SMB2_SESSION_FLAG_ENCRYPT_DATA, SMB2_SESSION_FLAG_IS_GUEST,
+    SMB2_SESSION_FLAG_IS_NULL,
+};
+
+mod tree_connect;
+pub use tree_connect::*;
+
+mod create;
+pub use create::*;
+
+mod echo;
+pub use echo::*;
+
+mod read_write;
+use read_write::MakePayload;
+pub use read_write::*;
+
+mod lock;
+pub use lock::*;
+
+mod ioctl;
+pub use ioctl::*;
+
+mod fsctl;
+pub use fsctl::*;
+
+mod query_directory;
+pub use query_directory::*;
+
+mod change_notify;
+pub use change_notify::*;
+
+mod query_info;
+pub use query_info::*;
+
+mod oplock_break;
+pub use oplock_break::*;
+
+mod file_info;
+pub use file_info::*;
+
+mod fs_info;
+pub use fs_info::*;
+
+mod error;
+pub use error::*;
+
+mod compound;
+pub use compound::*;
+
+mod transform;
+pub use transform::*;
+
+mod compression_transform;
+pub use compression_transform::*;
+
+/// Every SMB2 header is exactly this big, and every buffer offset
+/// on the wire is measured from the start of it.
+pub const HEADER_SIZE: usize = 64;
+
+// the only failures that still come back with the command's own response body.
+use ntstatus::{STATUS_BUFFER_OVERFLOW, STATUS_MORE_PROCESSING_REQUIRED, STATUS_SUCCESS};
 
 #[derive(Debug)]
 pub struct SmbMessage {
@@ -21,33 +97,304 @@ pub struct SmbMessage {
 pub enum SmbBody {
     Negotiate(SmbNegotiate),
     NegotiateResponse(SmbNegotiateResponse),
+    SessionSetup(SmbSessionSetup),
+    SessionSetupResponse(SmbSessionSetupResponse),
+    Logoff(SmbLogoff),
+    LogoffResponse(SmbLogoff),
+    TreeConnect(SmbTreeConnect),
+    TreeConnectResponse(SmbTreeConnectResponse),
+    TreeDisconnect(SmbTreeDisconnect),
+    TreeDisconnectResponse(SmbTreeDisconnect),
+    Create(SmbCreate),
+    CreateResponse(SmbCreateResponse),
+    Close(SmbClose),
+    CloseResponse(SmbCloseResponse),
+    Read(SmbRead),
+    ReadResponse(SmbReadResponse),
+    Write(SmbWrite),
+    WriteResponse(SmbWriteResponse),
+    Lock(SmbLock),
+    LockResponse(SmbLockResponse),
+    Ioctl(SmbIoctl),
+    IoctlResponse(SmbIoctlResponse),
+    Cancel(SmbCancel),
+    Echo(SmbEcho),
+    EchoResponse(SmbEcho),
+    QueryDirectory(SmbQueryDirectory),
+    QueryDirectoryResponse(SmbQueryDirectoryResponse),
+    ChangeNotify(SmbChangeNotify),
+    ChangeNotifyResponse(SmbChangeNotifyResponse),
+    QueryInfo(SmbQueryInfo),
+    QueryInfoResponse(SmbQueryInfoResponse),
+    SetInfo(SmbSetInfo),
+    SetInfoResponse(SmbSetInfoResponse),
+    /// The client acknowledging a break.
+    OplockBreak(SmbOplockBreak),
+    /// Either an unsolicited break notification, or the response to an acknowledgment.
+    OplockBreakResponse(SmbOplockBreak),
+    LeaseBreakNotification(SmbLeaseBreakNotification),
+    /// The client acknowledging a lease break.
+    LeaseBreak(SmbLeaseBreak),
+    LeaseBreakResponse(SmbLeaseBreak),
+    ErrorResponse(SmbErrorResponse),
 }
 
 impl SmbBody {
-    fn to_vec(self) -> Vec<u8> {
+    /// The open a request is about, for the commands that take one.
+    pub fn file_id_mut(&mut self) -> Option<&mut SmbFileId> {
+        match self {
+            SmbBody::Close(b) => Some(&mut b.file_id),
+            SmbBody::Read(b) => Some(&mut b.file_id),
+            SmbBody::Write(b) => Some(&mut b.file_id),
+            SmbBody::Lock(b) => Some(&mut b.file_id),
+            SmbBody::Ioctl(b) => Some(&mut b.file_id),
+            SmbBody::QueryDirectory(b) => Some(&mut b.file_id),
+            SmbBody::ChangeNotify(b) => Some(&mut b.file_id),
+            SmbBody::QueryInfo(b) => Some(&mut b.file_id),
+            SmbBody::SetInfo(b) => Some(&mut b.file_id),
+            SmbBody::OplockBreak(b) => Some(&mut b.file_id),
+            _ => None,
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
         match self {
             SmbBody::NegotiateResponse(b) => b.to_vec(),
-            SmbBody::Negotiate(_) => todo!(),
+            SmbBody::Negotiate(b) => b.to_vec(),
+            SmbBody::SessionSetup(b) => b.to_vec(),
+            SmbBody::SessionSetupResponse(b) => b.to_vec(),
+            SmbBody::Logoff(b) => b.to_vec(),
+            SmbBody::LogoffResponse(b) => b.to_vec(),
+            SmbBody::TreeConnect(b) => b.to_vec(),
+            SmbBody::TreeConnectResponse(b) => b.to_vec(),
+            SmbBody::TreeDisconnect(b) => b.to_vec(),
+            SmbBody::TreeDisconnectResponse(b) => b.to_vec(),
+            SmbBody::Create(b) => b.to_vec(),
+            SmbBody::CreateResponse(b) => b.to_vec(),
+            SmbBody::Close(b) => b.to_vec(),
+            SmbBody::CloseResponse(b) => b.to_vec(),
+            SmbBody::Read(b) => b.to_vec(),
+            SmbBody::ReadResponse(b) => b.to_vec(),
+            SmbBody::Write(b) => b.to_vec(),
+            SmbBody::WriteResponse(b) => b.to_vec(),
+            SmbBody::Lock(b) => b.to_vec(),
+            SmbBody::LockResponse(b) => b.to_vec(),
+            SmbBody::Ioctl(b) => b.to_vec(),
+            SmbBody::IoctlResponse(b) => b.to_vec(),
+            SmbBody::Cancel(b) => b.to_vec(),
+            SmbBody::Echo(b) => b.to_vec(),
+            SmbBody::EchoResponse(b) => b.to_vec(),
+            SmbBody::QueryDirectory(b) => b.to_vec(),
+            SmbBody::QueryDirectoryResponse(b) => b.to_vec(),
+            SmbBody::ChangeNotify(b) => b.to_vec(),
+            SmbBody::ChangeNotifyResponse(b) => b.to_vec(),
+            SmbBody::QueryInfo(b) => b.to_vec(),
+            SmbBody::QueryInfoResponse(b) => b.to_vec(),
+            SmbBody::SetInfo(b) => b.to_vec(),
+            SmbBody::SetInfoResponse(b) => b.to_vec(),
+            SmbBody::OplockBreak(b) => b.to_vec(),
+            SmbBody::OplockBreakResponse(b) => b.to_vec(),
+            SmbBody::LeaseBreakNotification(b) => b.to_vec(),
+            SmbBody::LeaseBreak(b) => b.to_vec(),
+            SmbBody::LeaseBreakResponse(b) => b.to_vec(),
+            SmbBody::ErrorResponse(b) => b.to_vec(),
         }
     }
 }
 
 impl SmbMessage {
     pub fn try_parse(body: &[u8]) -> nom::IResult<&[u8], Self, nom::error::Error<&[u8]>> {
+        Self::parse_with(body, &Bytes::copy_from_slice)
+    }
+
+    /// Like `try_parse`, except READ and WRITE payloads point into `buf`
+    /// instead of being copied out of it.
+    pub fn try_parse_shared(buf: &Bytes) -> nom::IResult<&[u8], Self, nom::error::Error<&[u8]>> {
+        Self::parse_with(buf, &|payload| buf.slice_ref(payload))
+    }
+
+    fn parse_with<'a>(
+        body: &'a [u8],
+        make_payload: MakePayload,
+    ) -> nom::IResult<&'a [u8], Self, nom::error::Error<&'a [u8]>> {
         let (remaining, header) = SmbMessageHeader::try_parse(body)?;
-        let (remaining, body) = match header.command {
-            0x0 => {
-                let (remaining, negotiate) = SmbNegotiate::parse(&remaining)?;
+        let is_response = header.flags.contains(HeaderFlags::SERVER_TO_REDIR);
+        // a few IOCTLs (e.g. copychunk) fail with their own response body,
+        // which is always bigger than an error response's 9.
+        let is_error = is_response
+            && !matches!(
+                header.status,
+                STATUS_SUCCESS | STATUS_MORE_PROCESSING_REQUIRED | STATUS_BUFFER_OVERFLOW
+            )
+            && remaining.starts_with(&9u16.to_le_bytes());
+        if is_error {
+            let (remaining, error) = SmbErrorResponse::parse(remaining, header.status)?;
+            return Ok((
+                remaining,
+                Self {
+                    header,
+                    body: SmbBody::ErrorResponse(error),
+                },
+            ));
+        }
+        let (remaining, body) = match (header.command, is_response) {
+            (Command::Negotiate, false) => {
+                let (remaining, negotiate) = SmbNegotiate::parse(remaining)?;
                 (remaining, SmbBody::Negotiate(negotiate))
             }
-
-            _ => todo! {},
+            (Command::SessionSetup, false) => {
+                let (remaining, session_setup) = SmbSessionSetup::parse(remaining)?;
+                (remaining, SmbBody::SessionSetup(session_setup))
+            }
+            (Command::SessionSetup, true) => {
+                let (remaining, session_setup) = SmbSessionSetupResponse::parse(remaining)?;
+                (remaining, SmbBody::SessionSetupResponse(session_setup))
+            }
+            (Command::Logoff, false) => {
+                let (remaining, logoff) = SmbLogoff::parse(remaining)?;
+                (remaining, SmbBody::Logoff(logoff))
+            }
+            (Command::Logoff, true) => {
+                let (remaining, logoff) = SmbLogoff::parse(remaining)?;
+                (remaining, SmbBody::LogoffResponse(logoff))
+            }
+            (Command::TreeConnect, false) => {
+                let (remaining, tree_connect) = SmbTreeConnect::parse(remaining)?;
+                (remaining, SmbBody::TreeConnect(tree_connect))
+            }
+            (Command::TreeConnect, true) => {
+                let (remaining, tree_connect) = SmbTreeConnectResponse::parse(remaining)?;
+                (remaining, SmbBody::TreeConnectResponse(tree_connect))
+            }
+            (Command::TreeDisconnect, false) => {
+                let (remaining, tree_disconnect) = SmbTreeDisconnect::parse(remaining)?;
+                (remaining, SmbBody::TreeDisconnect(tree_disconnect))
+            }
+            (Command::TreeDisconnect, true) => {
+                let (remaining, tree_disconnect) = SmbTreeDisconnect::parse(remaining)?;
+                (remaining, SmbBody::TreeDisconnectResponse(tree_disconnect))
+            }
+            (Command::Create, false) => {
+                let (remaining, create) = SmbCreate::parse(remaining)?;
+                (remaining, SmbBody::Create(create))
+            }
+            (Command::Create, true) => {
+                let (remaining, create) = SmbCreateResponse::parse(remaining)?;
+                (remaining, SmbBody::CreateResponse(create))
+            }
+            (Command::Close, false) => {
+                let (remaining, close) = SmbClose::parse(remaining)?;
+                (remaining, SmbBody::Close(close))
+            }
+            (Command::Close, true) => {
+                let (remaining, close) = SmbCloseResponse::parse(remaining)?;
+                (remaining, SmbBody::CloseResponse(close))
+            }
+            (Command::Read, false) => {
+                let (remaining, read) = SmbRead::parse(remaining)?;
+                (remaining, SmbBody::Read(read))
+            }
+            (Command::Read, true) => {
+                let (remaining, read) = SmbReadResponse::parse_with(remaining, make_payload)?;
+                (remaining, SmbBody::ReadResponse(read))
+            }
+            (Command::Write, false) => {
+                let (remaining, write) = SmbWrite::parse_with(remaining, make_payload)?;
+                (remaining, SmbBody::Write(write))
+            }
+            (Command::Write, true) => {
+                let (remaining, write) = SmbWriteResponse::parse(remaining)?;
+                (remaining, SmbBody::WriteResponse(write))
+            }
+            (Command::Lock, false) => {
+                let (remaining, lock) = SmbLock::parse(remaining)?;
+                (remaining, SmbBody::Lock(lock))
+            }
+            (Command::Lock, true) => {
+                let (remaining, lock) = SmbLockResponse::parse(remaining)?;
+                (remaining, SmbBody::LockResponse(lock))
+            }
+            (Command::Ioctl, false) => {
+                let (remaining, ioctl) = SmbIoctl::parse(remaining)?;
+                (remaining, SmbBody::Ioctl(ioctl))
+            }
+            (Command::Ioctl, true) => {
+                let (remaining, ioctl) = SmbIoctlResponse::parse(remaining)?;
+                (remaining, SmbBody::IoctlResponse(ioctl))
+            }
+            (Command::Cancel, false) => {
+                let (remaining, cancel) = SmbCancel::parse(remaining)?;
+                (remaining, SmbBody::Cancel(cancel))
+            }
+            (Command::Echo, false) => {
+                let (remaining, echo) = SmbEcho::parse(remaining)?;
+                (remaining, SmbBody::Echo(echo))
+            }
+            (Command::Echo, true) => {
+                let (remaining, echo) = SmbEcho::parse(remaining)?;
+                (remaining, SmbBody::EchoResponse(echo))
+            }
+            (Command::QueryDirectory, false) => {
+                let (remaining, query_directory) = SmbQueryDirectory::parse(remaining)?;
+                (remaining, SmbBody::QueryDirectory(query_directory))
+            }
+            (Command::QueryDirectory, true) => {
+                let (remaining, query_directory) = SmbQueryDirectoryResponse::parse(remaining)?;
+                (remaining, SmbBody::QueryDirectoryResponse(query_directory))
+            }
+            (Command::ChangeNotify, false) => {
+                let (remaining, change_notify) = SmbChangeNotify::parse(remaining)?;
+                (remaining, SmbBody::ChangeNotify(change_notify))
+            }
+            (Command::ChangeNotify, true) => {
+                let (remaining, change_notify) = SmbChangeNotifyResponse::parse(remaining)?;
+                (remaining, SmbBody::ChangeNotifyResponse(change_notify))
+            }
+            (Command::QueryInfo, false) => {
+                let (remaining, query_info) = SmbQueryInfo::parse(remaining)?;
+                (remaining, SmbBody::QueryInfo(query_info))
+            }
+            (Command::QueryInfo, true) => {
+                let (remaining, query_info) = SmbQueryInfoResponse::parse(remaining)?;
+                (remaining, SmbBody::QueryInfoResponse(query_info))
+            }
+            (Command::SetInfo, false) => {
+                let (remaining, set_info) = SmbSetInfo::parse(remaining)?;
+                (remaining, SmbBody::SetInfo(set_info))
+            }
+            (Command::SetInfo, true) => {
+                let (remaining, set_info) = SmbSetInfoResponse::parse(remaining)?;
+                (remaining, SmbBody::SetInfoResponse(set_info))
+            }
+            // oplock and lease breaks share a command, only the size tells them apart.
+            (Command::OplockBreak, false) if remaining.starts_with(&36u16.to_le_bytes()) => {
+                let (remaining, lease_break) = SmbLeaseBreak::parse(remaining)?;
+                (remaining, SmbBody::LeaseBreak(lease_break))
+            }
+            (Command::OplockBreak, false) => {
+                let (remaining, oplock_break) = SmbOplockBreak::parse(remaining)?;
+                (remaining, SmbBody::OplockBreak(oplock_break))
+            }
+            (Command::OplockBreak, true) if remaining.starts_with(&44u16.to_le_bytes()) => {
+                let (remaining, notification) = SmbLeaseBreakNotification::parse(remaining)?;
+                (remaining, SmbBody::LeaseBreakNotification(notification))
+            }
+            (Command::OplockBreak, true) if remaining.starts_with(&36u16.to_le_bytes()) => {
+                let (remaining, lease_break) = SmbLeaseBreak::parse(remaining)?;
+                (remaining, SmbBody::LeaseBreakResponse(lease_break))
+            }
+            (Command::OplockBreak, true) => {
+                let (remaining, oplock_break) = SmbOplockBreak::parse(remaining)?;
+                (remaining, SmbBody::OplockBreakResponse(oplock_break))
+            }
+            // nothing we know how to read, the caller gets to decide what to do about it.
+            _ => return fail(remaining),
         };
         Ok((remaining, Self { header, body }))
     }
-    pub fn to_vec(self) -> Vec<u8> {
-        let mut header = self.header.to_vec();
-        let mut body = self.body.to_vec();
+    pub fn to_vec(&self) -> Vec<u8> {
+        let header = self.header.to_vec();
+        let body = self.body.to_vec();
         let mut out = Vec::with_capacity(header.len() + body.len());
         out.extend(header);
         out.extend(body);
@@ -100,3 +447,60 @@ fn c_u128<'a>(
 ) -> nom::IResult<&'a [u8], u128, nom::error::Error<&'a [u8]>> {
     context(ctx, get_u128_le)(body)
 }
+
+/// Grabs the `length` byte buffer at `offset`, where `offset` is measured from
+/// the start of the SMB2 header and `body` starts right after the header.
+/// `fixed_remaining` is what was left after parsing the fixed part of the
+/// body, and is handed back as-is when the buffer is empty.
+fn header_offset_buffer<'a>(
+    ctx: &'static str,
+    body: &'a [u8],
+    fixed_remaining: &'a [u8],
+    offset: usize,
+    length: usize,
+) -> nom::IResult<&'a [u8], &'a [u8], nom::error::Error<&'a [u8]>> {
+    if length == 0 {
+        return Ok((fixed_remaining, &[]));
+    }
+    let Some(start) = offset.checked_sub(HEADER_SIZE) else {
+        return fail(body);
+    };
+    context(ctx, move |body| {
+        let (remaining, _) = bytes::take(start)(body)?;
+        bytes::take(length)(remaining)
+    })(body)
+}
+
+/// Decodes a (not NUL terminated) UTF-16LE string, like the ones
+/// used for paths and names everywhere in SMB2.
+fn parse_utf16le<'a>(
+    ctx: &'static str,
+    body: &'a [u8],
+) -> nom::IResult<&'a [u8], String, nom::error::Error<&'a [u8]>> {
+    context(
+        ctx,
+        nom::combinator::map_res(nom::multi::many0(get_u16_le), |code_points: Vec<u16>| {
+            String::from_utf16(&code_points)
+        }),
+    )(body)
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Zero pads `out` until it's `len` bytes long.
+fn pad_to(out: &mut Vec<u8>, len: usize) {
+    if out.len() < len {
+        out.resize(len, 0);
+    }
+}
+
+/// For when something points outside of the message, or otherwise
+/// doesn't add up in a way nom can't check for us.
+fn fail<T>(body: &[u8]) -> nom::IResult<&[u8], T, nom::error::Error<&[u8]>> {
+    Err(nom::Err::Error(nom::error::Error::new(
+        body,
+        nom::error::ErrorKind::Verify,
+    )))
+}
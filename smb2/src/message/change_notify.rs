@@ -0,0 +1,260 @@
+use crate::message::{c_u16, c_u32, encode_utf16le, fail, header_offset_buffer, pad_to};
+use crate::message::{parse_utf16le, SmbFileId, HEADER_SIZE};
+
+/// Watch everything under the directory, not just what's directly in it.
+pub const SMB2_WATCH_TREE: u16 = 0x0001;
+
+// which kinds of change CompletionFilter asks to hear about.
+pub const FILE_NOTIFY_CHANGE_FILE_NAME: u32 = 0x0000_0001;
+pub const FILE_NOTIFY_CHANGE_DIR_NAME: u32 = 0x0000_0002;
+pub const FILE_NOTIFY_CHANGE_ATTRIBUTES: u32 = 0x0000_0004;
+pub const FILE_NOTIFY_CHANGE_SIZE: u32 = 0x0000_0008;
+pub const FILE_NOTIFY_CHANGE_LAST_WRITE: u32 = 0x0000_0010;
+pub const FILE_NOTIFY_CHANGE_LAST_ACCESS: u32 = 0x0000_0020;
+pub const FILE_NOTIFY_CHANGE_CREATION: u32 = 0x0000_0040;
+pub const FILE_NOTIFY_CHANGE_EA: u32 = 0x0000_0080;
+pub const FILE_NOTIFY_CHANGE_SECURITY: u32 = 0x0000_0100;
+pub const FILE_NOTIFY_CHANGE_STREAM_NAME: u32 = 0x0000_0200;
+pub const FILE_NOTIFY_CHANGE_STREAM_SIZE: u32 = 0x0000_0400;
+pub const FILE_NOTIFY_CHANGE_STREAM_WRITE: u32 = 0x0000_0800;
+
+// what happened to each file in a FILE_NOTIFY_INFORMATION.
+pub const FILE_ACTION_ADDED: u32 = 0x0000_0001;
+pub const FILE_ACTION_REMOVED: u32 = 0x0000_0002;
+pub const FILE_ACTION_MODIFIED: u32 = 0x0000_0003;
+pub const FILE_ACTION_RENAMED_OLD_NAME: u32 = 0x0000_0004;
+pub const FILE_ACTION_RENAMED_NEW_NAME: u32 = 0x0000_0005;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbChangeNotify {
+    // always 32.
+    pub size: u16,
+    pub flags: u16,
+    /// The most the response's buffer is allowed to hold.
+    pub output_buffer_length: u32,
+    pub file_id: SmbFileId,
+    pub completion_filter: u32,
+}
+
+impl SmbChangeNotify {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbChangeNotify, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, completion_filter) = c_u32("Failed to get completion filter", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                flags,
+                output_buffer_length,
+                file_id,
+                completion_filter,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32);
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.output_buffer_length.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend(self.completion_filter.to_le_bytes());
+        out.extend([0; 4]);
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbChangeNotifyResponse {
+    // always 9.
+    pub size: u16,
+    pub output_buffer_offset: u16,
+    pub output_buffer_length: u32,
+    /// Encoded `FileNotifyInformation`s.
+    pub buffer: Vec<u8>,
+}
+
+impl SmbChangeNotifyResponse {
+    pub const OUTPUT_BUFFER_OFFSET: u16 = (HEADER_SIZE + 8) as u16;
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbChangeNotifyResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, output_buffer_offset) =
+            c_u16("Failed to get output buffer offset", remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get output buffer",
+            body,
+            remaining,
+            output_buffer_offset as _,
+            output_buffer_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                output_buffer_offset,
+                output_buffer_length,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.output_buffer_offset.to_le_bytes());
+        out.extend(self.output_buffer_length.to_le_bytes());
+        if self.buffer.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.output_buffer_offset as usize - HEADER_SIZE);
+            out.extend(&self.buffer);
+        }
+        out
+    }
+}
+
+/// One change to one file, named relative to the directory being watched.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileNotifyInformation {
+    /// One of the `FILE_ACTION_*` values.
+    pub action: u32,
+    pub file_name: String,
+}
+
+impl FileNotifyInformation {
+    /// Encodes `changes` as the chained list the client expects,
+    /// with each entry starting on a 4 byte boundary.
+    pub fn list_to_vec(changes: &[FileNotifyInformation]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut previous: Option<usize> = None;
+        for change in changes {
+            let start = out.len().next_multiple_of(4);
+            pad_to(&mut out, start);
+            if let Some(previous) = previous {
+                let next_entry_offset = (start - previous) as u32;
+                out[previous..previous + 4].copy_from_slice(&next_entry_offset.to_le_bytes());
+            }
+            let file_name = encode_utf16le(&change.file_name);
+            out.extend(0u32.to_le_bytes());
+            out.extend(change.action.to_le_bytes());
+            out.extend((file_name.len() as u32).to_le_bytes());
+            out.extend(file_name);
+            previous = Some(start);
+        }
+        out
+    }
+
+    pub fn parse_list(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], Vec<FileNotifyInformation>, nom::error::Error<&[u8]>> {
+        let mut changes = vec![];
+        let mut entry = body;
+        loop {
+            let (remaining, next_entry_offset) = c_u32("Failed to get next entry offset", entry)?;
+            let (remaining, action) = c_u32("Failed to get action", remaining)?;
+            let (remaining, file_name_length) = c_u32("Failed to get file name length", remaining)?;
+            let Some(file_name) = remaining.get(..file_name_length as usize) else {
+                return fail(remaining);
+            };
+            let (_, file_name) = parse_utf16le("Failed to get file name", file_name)?;
+            changes.push(Self { action, file_name });
+            if next_entry_offset == 0 {
+                return Ok((&remaining[file_name_length as usize..], changes));
+            }
+            let Some(next_entry) = entry.get(next_entry_offset as usize..) else {
+                return fail(entry);
+            };
+            entry = next_entry;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_change_notify() {
+        #[rustfmt::skip]
+        let smb_change_notify = [
+            // size    | flags
+            0x20, 0x00, 0x01, 0x00,
+            // output buffer length
+            0x00, 0x10, 0x00, 0x00,
+            // file id
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // completion filter
+            0x17, 0x00, 0x00, 0x00,
+            // reserved
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let (_, notify) = SmbChangeNotify::parse(&smb_change_notify).unwrap();
+        assert_eq!(
+            notify,
+            SmbChangeNotify {
+                size: 32,
+                flags: SMB2_WATCH_TREE,
+                output_buffer_length: 0x1000,
+                file_id: SmbFileId {
+                    persistent: 5,
+                    volatile: 5,
+                },
+                completion_filter: FILE_NOTIFY_CHANGE_FILE_NAME
+                    | FILE_NOTIFY_CHANGE_DIR_NAME
+                    | FILE_NOTIFY_CHANGE_ATTRIBUTES
+                    | FILE_NOTIFY_CHANGE_LAST_WRITE,
+            }
+        );
+        assert_eq!(notify.to_vec(), smb_change_notify);
+    }
+
+    #[test]
+    fn notify_information_list() {
+        let changes = vec![
+            FileNotifyInformation {
+                action: FILE_ACTION_RENAMED_OLD_NAME,
+                file_name: "a".into(),
+            },
+            FileNotifyInformation {
+                action: FILE_ACTION_RENAMED_NEW_NAME,
+                file_name: "dir\\bc".into(),
+            },
+        ];
+        let encoded = FileNotifyInformation::list_to_vec(&changes);
+        #[rustfmt::skip]
+        let expected = [
+            // next entry offset
+            0x10, 0x00, 0x00, 0x00,
+            // action
+            0x04, 0x00, 0x00, 0x00,
+            // file name length
+            0x02, 0x00, 0x00, 0x00,
+            // "a" | padding
+            0x61, 0x00, 0x00, 0x00,
+            // next entry offset
+            0x00, 0x00, 0x00, 0x00,
+            // action
+            0x05, 0x00, 0x00, 0x00,
+            // file name length
+            0x0C, 0x00, 0x00, 0x00,
+            // "dir\bc"
+            0x64, 0x00, 0x69, 0x00, 0x72, 0x00, 0x5C, 0x00, 0x62, 0x00, 0x63, 0x00,
+        ];
+        assert_eq!(encoded, expected);
+        assert_eq!(
+            FileNotifyInformation::parse_list(&encoded).unwrap().1,
+            changes
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use crate::message::{pad_to, SmbMessage, HEADER_SIZE};
+use crate::signing::SigningKey;
+
+/// The FileId related operations use to mean "whatever the last one used".
+pub const RELATED_FILE_ID: u64 = u64::MAX;
+
+// where NextCommand lives in the header.
+const NEXT_COMMAND_OFFSET: usize = 20;
+
+/// A NextCommand that doesn't point at another message in the same PDU,
+/// along with the rest of the PDU starting at the message it's in.
+#[derive(Debug, PartialEq)]
+pub struct InvalidNextCommand<'a>(pub &'a [u8]);
+
+/// The messages packed into one compounded PDU, each starting where the
+/// NextCommand of the one before it says. A PDU that isn't compounded is
+/// just the one message.
+pub struct CompoundMembers<'a> {
+    remaining: &'a [u8],
+}
+
+pub fn compound_members(buf: &[u8]) -> CompoundMembers<'_> {
+    CompoundMembers { remaining: buf }
+}
+
+impl<'a> Iterator for CompoundMembers<'a> {
+    type Item = Result<&'a [u8], InvalidNextCommand<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let Some(next_command) = self
+            .remaining
+            .get(NEXT_COMMAND_OFFSET..NEXT_COMMAND_OFFSET + 4)
+            .map(|next_command| u32::from_le_bytes(next_command.try_into().unwrap()) as usize)
+        else {
+            // too short to be a message at all, let whoever parses it say so.
+            return Some(Ok(std::mem::take(&mut self.remaining)));
+        };
+        if next_command == 0 {
+            return Some(Ok(std::mem::take(&mut self.remaining)));
+        }
+        // every message but the last starts on an 8 byte boundary, and has a header.
+        if next_command % 8 != 0
+            || next_command < HEADER_SIZE
+            || next_command > self.remaining.len()
+        {
+            return Some(Err(InvalidNextCommand(std::mem::take(&mut self.remaining))));
+        }
+        let (member, remaining) = self.remaining.split_at(next_command);
+        self.remaining = remaining;
+        Some(Ok(member))
+    }
+}
+
+impl SmbMessage {
+    /// Encodes `messages` as one compounded PDU, padding each but the last
+    /// out to 8 bytes and pointing its NextCommand at the one after.
+    pub fn compound_to_vec(messages: &[SmbMessage]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut previous: Option<usize> = None;
+        for message in messages {
+            let start = out.len().next_multiple_of(8);
+            pad_to(&mut out, start);
+            if let Some(previous) = previous {
+                let next_command = (start - previous) as u32;
+                out[previous + NEXT_COMMAND_OFFSET..previous + NEXT_COMMAND_OFFSET + 4]
+                    .copy_from_slice(&next_command.to_le_bytes());
+            }
+            out.extend(message.to_vec());
+            previous = Some(start);
+        }
+        out
+    }
+
+    /// Like [`SmbMessage::compound_to_vec`], signing each message that has a
+    /// key in `keys` once it's been padded out to where the next one starts.
+    pub fn compound_to_vec_signed(
+        messages: &[SmbMessage],
+        keys: &[Option<&SigningKey>],
+    ) -> Vec<u8> {
+        let mut out = Self::compound_to_vec(messages);
+        let lengths: Vec<_> = compound_members(&out)
+            .map(|member| member.map_or_else(|e| e.0.len(), <[u8]>::len))
+            .collect();
+        let mut start = 0;
+        for (len, key) in lengths.into_iter().zip(keys) {
+            if let Some(key) = key {
+                key.sign(&mut out[start..start + len]);
+            }
+            start += len;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::SmbMessageHeaderVariant;
+    use crate::message::{Command, HeaderFlags, SmbBody, SmbEcho, SmbMessageHeader};
+
+    fn echo(message_id: u64) -> SmbMessage {
+        SmbMessage {
+            header: SmbMessageHeader {
+                protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+                header_size: 64,
+                credit_charge: 0,
+                status: ntstatus::STATUS_SUCCESS,
+                command: Command::Echo,
+                credit_request_response: 1,
+                flags: HeaderFlags::empty(),
+                next_command: 0,
+                message_id,
+                variant: SmbMessageHeaderVariant::Sync { tree_id: 0 },
+                session_id: 0,
+                signature: 0,
+            },
+            body: SmbBody::Echo(SmbEcho { size: 4 }),
+        }
+    }
+
+    #[test]
+    fn compounds_split_where_they_were_joined() {
+        let encoded = SmbMessage::compound_to_vec(&[echo(1), echo(2), echo(3)]);
+        // 68 bytes each, padded out to 72 for all but the last.
+        assert_eq!(encoded.len(), 72 + 72 + 68);
+        let members: Vec<_> = compound_members(&encoded).map(Result::unwrap).collect();
+        assert_eq!(members.len(), 3);
+        for (i, member) in members.into_iter().enumerate() {
+            let (_, message) = SmbMessage::try_parse(member).unwrap();
+            assert_eq!(message.header.message_id, i as u64 + 1);
+            assert_eq!(message.header.next_command, if i == 2 { 0 } else { 72 });
+        }
+    }
+
+    #[test]
+    fn compound_members_signed_separately() {
+        use crate::message::Dialect;
+        let key = SigningKey::new(Dialect::Smb300, None, &[7; 16], None);
+        let encoded = SmbMessage::compound_to_vec_signed(
+            &[echo(1), echo(2), echo(3)],
+            &[Some(&key), None, Some(&key)],
+        );
+        let members: Vec<_> = compound_members(&encoded).map(Result::unwrap).collect();
+        // the padding is signed along with the message it follows.
+        assert_eq!(members[0].len(), 72);
+        assert!(key.verify(members[0]));
+        assert!(!key.verify(members[1]));
+        assert_eq!(&members[1][48..64], [0; 16]);
+        assert!(key.verify(members[2]));
+    }
+
+    #[test]
+    fn misaligned_next_command() {
+        let mut encoded = echo(1).to_vec();
+        encoded[NEXT_COMMAND_OFFSET] = 66;
+        encoded.extend([0; 8]);
+        let mut members = compound_members(&encoded);
+        assert_eq!(members.next(), Some(Err(InvalidNextCommand(&encoded[..]))));
+        assert_eq!(members.next(), None);
+    }
+}
@@ -0,0 +1,330 @@
+use nom::bytes::complete as bytes;
+use nom::combinator::{map_res, peek, verify};
+use nom::error::context;
+use nom::sequence::preceded;
+use nom::Parser;
+
+use crate::message::{c_u16, c_u32, get_u16_le, CompressionAlgorithm};
+
+/// What a compressed message starts with instead of 0xFE 'SMB'.
+pub const COMPRESSION_PROTOCOL_ID: [u8; 4] = [0xFC, b'S', b'M', b'B'];
+
+pub const SMB2_COMPRESSION_FLAG_NONE: u16 = 0x0000;
+/// Set in the first payload header of a chained message, which is where
+/// an unchained one has its flags, so that's how the two are told apart.
+pub const SMB2_COMPRESSION_FLAG_CHAINED: u16 = 0x0001;
+
+/// The part of a compressed message before its data, which depends on
+/// whether it's chained.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmbCompressionTransformHeader {
+    /// The first `offset` bytes after the header are left as they are, the
+    /// rest is compressed with the one algorithm.
+    Unchained {
+        /// How big the compressed part is once it's decompressed.
+        original_compressed_segment_size: u32,
+        compression_algorithm: CompressionAlgorithm,
+        offset: u32,
+    },
+    /// A run of payloads follows, each with its own header and algorithm.
+    Chained {
+        /// How big the whole message is once it's decompressed.
+        original_compressed_segment_size: u32,
+    },
+}
+
+impl SmbCompressionTransformHeader {
+    pub const UNCHAINED_SIZE: usize = 16;
+    pub const CHAINED_SIZE: usize = 8;
+
+    /// Gives back everything after the header, which for a chained message
+    /// starts with the first payload header.
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbCompressionTransformHeader, nom::error::Error<&[u8]>> {
+        let (remaining, _) = context(
+            "Not a compression transform header",
+            bytes::tag(COMPRESSION_PROTOCOL_ID),
+        )
+        .parse(body)?;
+        let (remaining, original_compressed_segment_size) =
+            c_u32("Failed to get original compressed segment size", remaining)?;
+        // the flags come after the algorithm either way.
+        let (_, flags) = context(
+            "Failed to get flags",
+            peek(preceded(bytes::take(2usize), get_u16_le)),
+        )(remaining)?;
+        if flags & SMB2_COMPRESSION_FLAG_CHAINED != 0 {
+            return Ok((
+                remaining,
+                Self::Chained {
+                    original_compressed_segment_size,
+                },
+            ));
+        }
+        let (remaining, compression_algorithm) = context(
+            "Invalid compression algorithm",
+            map_res(
+                |body| c_u16("Failed to get compression algorithm", body),
+                CompressionAlgorithm::try_from,
+            ),
+        )(remaining)?;
+        let (remaining, _flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, offset) = c_u32("Failed to get offset", remaining)?;
+        Ok((
+            remaining,
+            Self::Unchained {
+                original_compressed_segment_size,
+                compression_algorithm,
+                offset,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend(COMPRESSION_PROTOCOL_ID);
+        match self {
+            Self::Unchained {
+                original_compressed_segment_size,
+                compression_algorithm,
+                offset,
+            } => {
+                out.extend(original_compressed_segment_size.to_le_bytes());
+                out.extend((*compression_algorithm as u16).to_le_bytes());
+                out.extend(SMB2_COMPRESSION_FLAG_NONE.to_le_bytes());
+                out.extend(offset.to_le_bytes());
+            }
+            Self::Chained {
+                original_compressed_segment_size,
+            } => out.extend(original_compressed_segment_size.to_le_bytes()),
+        }
+        out
+    }
+}
+
+/// Whether a payload compressed with `algorithm` says how big it was
+/// beforehand. Those left as they are, or that are a pattern, don't.
+fn has_original_payload_size(algorithm: CompressionAlgorithm) -> bool {
+    !matches!(
+        algorithm,
+        CompressionAlgorithm::None | CompressionAlgorithm::PatternV1
+    )
+}
+
+/// What comes before each payload of a chained message.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmbCompressionChainedPayloadHeader {
+    pub compression_algorithm: CompressionAlgorithm,
+    pub flags: u16,
+    /// How much follows this field, OriginalPayloadSize included.
+    pub length: u32,
+    /// How big the payload is once it's decompressed, for the algorithms
+    /// that actually compress.
+    pub original_payload_size: Option<u32>,
+}
+
+impl SmbCompressionChainedPayloadHeader {
+    /// The header for `data` compressed with `algorithm`, from `original_size` bytes.
+    pub fn new(
+        compression_algorithm: CompressionAlgorithm,
+        flags: u16,
+        data: &[u8],
+        original_size: usize,
+    ) -> Self {
+        let original_payload_size =
+            has_original_payload_size(compression_algorithm).then_some(original_size as u32);
+        Self {
+            compression_algorithm,
+            flags,
+            length: data.len() as u32 + original_payload_size.map_or(0, |_| 4),
+            original_payload_size,
+        }
+    }
+
+    /// How much of what follows the header is the payload itself.
+    pub fn data_length(&self) -> usize {
+        self.length as usize - self.original_payload_size.map_or(0, |_| 4)
+    }
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbCompressionChainedPayloadHeader, nom::error::Error<&[u8]>> {
+        let (remaining, compression_algorithm) = context(
+            "Invalid compression algorithm",
+            map_res(
+                |body| c_u16("Failed to get compression algorithm", body),
+                CompressionAlgorithm::try_from,
+            ),
+        )(body)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, length) = c_u32("Failed to get length", remaining)?;
+        let (remaining, original_payload_size) =
+            match has_original_payload_size(compression_algorithm) {
+                true => {
+                    let (remaining, size) = context(
+                        "Length too short for original payload size",
+                        verify(
+                            |body| c_u32("Failed to get original payload size", body),
+                            |_| length >= 4,
+                        ),
+                    )(remaining)?;
+                    (remaining, Some(size))
+                }
+                false => (remaining, None),
+            };
+        Ok((
+            remaining,
+            Self {
+                compression_algorithm,
+                flags,
+                length,
+                original_payload_size,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend((self.compression_algorithm as u16).to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.length.to_le_bytes());
+        if let Some(original_payload_size) = self.original_payload_size {
+            out.extend(original_payload_size.to_le_bytes());
+        }
+        out
+    }
+}
+
+/// A Pattern_V1 payload, one byte over and over.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmbPatternV1Payload {
+    pub pattern: u8,
+    pub repetitions: u32,
+}
+
+impl SmbPatternV1Payload {
+    pub const SIZE: usize = 8;
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbPatternV1Payload, nom::error::Error<&[u8]>> {
+        let (remaining, pattern) = context("Failed to get pattern", bytes::take(1usize))(body)?;
+        let (remaining, _reserved) =
+            context("Failed to get reserved", bytes::take(3usize))(remaining)?;
+        let (remaining, repetitions) = c_u32("Failed to get repetitions", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                pattern: pattern[0],
+                repetitions,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![self.pattern, 0, 0, 0];
+        out.extend(self.repetitions.to_le_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchained_header() {
+        #[rustfmt::skip]
+        let header = [
+            // protocol id
+            0xFC, b'S', b'M', b'B',
+            // original compressed segment size
+            0x00, 0x10, 0x00, 0x00,
+            // compression algorithm (LZ77)| flags
+            0x02, 0x00, 0x00, 0x00,
+            // offset
+            0x50, 0x00, 0x00, 0x00,
+            // the start of the data
+            0xFE,
+        ];
+        let (remaining, parsed) = SmbCompressionTransformHeader::parse(&header).unwrap();
+        assert_eq!(remaining, [0xFE]);
+        assert_eq!(
+            parsed,
+            SmbCompressionTransformHeader::Unchained {
+                original_compressed_segment_size: 0x1000,
+                compression_algorithm: CompressionAlgorithm::Lz77,
+                offset: 0x50,
+            }
+        );
+        assert_eq!(parsed.to_vec(), header[..16]);
+    }
+
+    #[test]
+    fn chained_header() {
+        #[rustfmt::skip]
+        let message = [
+            // protocol id
+            0xFC, b'S', b'M', b'B',
+            // original compressed segment size
+            0x48, 0x10, 0x00, 0x00,
+            // compression algorithm (NONE)| flags (chained)
+            0x00, 0x00, 0x01, 0x00,
+            // length
+            0x02, 0x00, 0x00, 0x00,
+            0xFE, b'S',
+            // compression algorithm (LZNT1)| flags
+            0x01, 0x00, 0x00, 0x00,
+            // length, original payload size
+            0x05, 0x00, 0x00, 0x00, 0x46, 0x00, 0x00, 0x00,
+            0xAA,
+            // compression algorithm (Pattern_V1)| flags
+            0x04, 0x00, 0x00, 0x00,
+            // length
+            0x08, 0x00, 0x00, 0x00,
+            // pattern| reserved| repetitions
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        ];
+        let (remaining, header) = SmbCompressionTransformHeader::parse(&message).unwrap();
+        assert_eq!(
+            header,
+            SmbCompressionTransformHeader::Chained {
+                original_compressed_segment_size: 0x1048,
+            }
+        );
+        assert_eq!(header.to_vec(), message[..8]);
+
+        let (remaining, payload) = SmbCompressionChainedPayloadHeader::parse(remaining).unwrap();
+        assert_eq!(payload.compression_algorithm, CompressionAlgorithm::None);
+        assert_eq!(payload.flags, SMB2_COMPRESSION_FLAG_CHAINED);
+        assert_eq!(payload.data_length(), 2);
+        let remaining = &remaining[2..];
+
+        let (remaining, payload) = SmbCompressionChainedPayloadHeader::parse(remaining).unwrap();
+        assert_eq!(
+            payload,
+            SmbCompressionChainedPayloadHeader::new(
+                CompressionAlgorithm::Lznt1,
+                SMB2_COMPRESSION_FLAG_NONE,
+                &[0xAA],
+                0x46
+            )
+        );
+        assert_eq!(payload.to_vec(), message[18..30]);
+        let remaining = &remaining[1..];
+
+        let (remaining, payload) = SmbCompressionChainedPayloadHeader::parse(remaining).unwrap();
+        assert_eq!(payload.data_length(), SmbPatternV1Payload::SIZE);
+        let (remaining, pattern) = SmbPatternV1Payload::parse(remaining).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            pattern,
+            SmbPatternV1Payload {
+                pattern: 0,
+                repetitions: 0x1000,
+            }
+        );
+        assert_eq!(pattern.to_vec(), message[39..]);
+    }
+}
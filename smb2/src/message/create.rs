@@ -0,0 +1,1238 @@
+use nom::bytes::complete::take;
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::number::complete::le_u8;
+use ntstatus::NtStatus;
+
+use crate::message::{
+    c_u128, c_u16, c_u32, c_u64, encode_utf16le, fail, header_offset_buffer, pad_to, parse_utf16le,
+    HEADER_SIZE,
+};
+
+/// The handle the server hands out on CREATE, and that pretty much
+/// every other command takes to say which open it's talking about.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct SmbFileId {
+    pub persistent: u64,
+    pub volatile: u64,
+}
+
+impl SmbFileId {
+    /// The FileId used by related compound requests to mean
+    /// "whatever the previous request opened".
+    pub const ANY: SmbFileId = SmbFileId {
+        persistent: u64::MAX,
+        volatile: u64::MAX,
+    };
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbFileId, nom::error::Error<&[u8]>> {
+        let (remaining, persistent) = c_u64("Failed to get persistent file id", body)?;
+        let (remaining, volatile) = c_u64("Failed to get volatile file id", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                persistent,
+                volatile,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        out.extend(self.persistent.to_le_bytes());
+        out.extend(self.volatile.to_le_bytes());
+        out
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum OplockLevel {
+    None = 0x00,
+    LevelII = 0x01,
+    Exclusive = 0x08,
+    Batch = 0x09,
+    Lease = 0xFF,
+}
+
+#[derive(Debug)]
+pub struct InvalidOplockLevel;
+
+impl TryFrom<u8> for OplockLevel {
+    type Error = InvalidOplockLevel;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::None),
+            0x01 => Ok(Self::LevelII),
+            0x08 => Ok(Self::Exclusive),
+            0x09 => Ok(Self::Batch),
+            0xFF => Ok(Self::Lease),
+            _ => Err(InvalidOplockLevel),
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImpersonationLevel {
+    Anonymous = 0,
+    Identification = 1,
+    Impersonation = 2,
+    Delegate = 3,
+}
+
+#[derive(Debug)]
+pub struct InvalidImpersonationLevel;
+
+impl TryFrom<u32> for ImpersonationLevel {
+    type Error = InvalidImpersonationLevel;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Anonymous),
+            1 => Ok(Self::Identification),
+            2 => Ok(Self::Impersonation),
+            3 => Ok(Self::Delegate),
+            _ => Err(InvalidImpersonationLevel),
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CreateDisposition {
+    /// Replace the file if it exists, create it if it doesn't.
+    Supersede = 0,
+    /// Open the file if it exists, fail if it doesn't.
+    Open = 1,
+    /// Fail if the file exists, create it if it doesn't.
+    Create = 2,
+    /// Open the file if it exists, create it if it doesn't.
+    OpenIf = 3,
+    /// Truncate the file if it exists, fail if it doesn't.
+    Overwrite = 4,
+    /// Truncate the file if it exists, create it if it doesn't.
+    OverwriteIf = 5,
+}
+
+#[derive(Debug)]
+pub struct InvalidCreateDisposition;
+
+impl TryFrom<u32> for CreateDisposition {
+    type Error = InvalidCreateDisposition;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Supersede),
+            1 => Ok(Self::Open),
+            2 => Ok(Self::Create),
+            3 => Ok(Self::OpenIf),
+            4 => Ok(Self::Overwrite),
+            5 => Ok(Self::OverwriteIf),
+            _ => Err(InvalidCreateDisposition),
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CreateAction {
+    Superseded = 0,
+    Opened = 1,
+    Created = 2,
+    Overwritten = 3,
+}
+
+#[derive(Debug)]
+pub struct InvalidCreateAction;
+
+impl TryFrom<u32> for CreateAction {
+    type Error = InvalidCreateAction;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Superseded),
+            1 => Ok(Self::Opened),
+            2 => Ok(Self::Created),
+            3 => Ok(Self::Overwritten),
+            _ => Err(InvalidCreateAction),
+        }
+    }
+}
+
+pub const FILE_SHARE_READ: u32 = 0x0000_0001;
+pub const FILE_SHARE_WRITE: u32 = 0x0000_0002;
+pub const FILE_SHARE_DELETE: u32 = 0x0000_0004;
+
+pub const FILE_DIRECTORY_FILE: u32 = 0x0000_0001;
+pub const FILE_WRITE_THROUGH: u32 = 0x0000_0002;
+pub const FILE_SEQUENTIAL_ONLY: u32 = 0x0000_0004;
+pub const FILE_NO_INTERMEDIATE_BUFFERING: u32 = 0x0000_0008;
+pub const FILE_NON_DIRECTORY_FILE: u32 = 0x0000_0040;
+pub const FILE_NO_EA_KNOWLEDGE: u32 = 0x0000_0200;
+pub const FILE_RANDOM_ACCESS: u32 = 0x0000_0800;
+pub const FILE_DELETE_ON_CLOSE: u32 = 0x0000_1000;
+pub const FILE_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+
+pub const FILE_ATTRIBUTE_READONLY: u32 = 0x0000_0001;
+pub const FILE_ATTRIBUTE_HIDDEN: u32 = 0x0000_0002;
+pub const FILE_ATTRIBUTE_SYSTEM: u32 = 0x0000_0004;
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x0000_0010;
+pub const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x0000_0020;
+pub const FILE_ATTRIBUTE_NORMAL: u32 = 0x0000_0080;
+pub const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x0000_0100;
+pub const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x0000_0200;
+pub const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x0000_0400;
+
+pub const FILE_READ_DATA: u32 = 0x0000_0001;
+pub const FILE_WRITE_DATA: u32 = 0x0000_0002;
+pub const FILE_APPEND_DATA: u32 = 0x0000_0004;
+pub const FILE_READ_EA: u32 = 0x0000_0008;
+pub const FILE_WRITE_EA: u32 = 0x0000_0010;
+pub const FILE_EXECUTE: u32 = 0x0000_0020;
+pub const FILE_READ_ATTRIBUTES: u32 = 0x0000_0080;
+pub const FILE_WRITE_ATTRIBUTES: u32 = 0x0000_0100;
+pub const DELETE: u32 = 0x0001_0000;
+pub const READ_CONTROL: u32 = 0x0002_0000;
+pub const WRITE_DAC: u32 = 0x0004_0000;
+pub const WRITE_OWNER: u32 = 0x0008_0000;
+pub const SYNCHRONIZE: u32 = 0x0010_0000;
+pub const MAXIMUM_ALLOWED: u32 = 0x0200_0000;
+pub const GENERIC_ALL: u32 = 0x1000_0000;
+pub const GENERIC_EXECUTE: u32 = 0x2000_0000;
+pub const GENERIC_WRITE: u32 = 0x4000_0000;
+pub const GENERIC_READ: u32 = 0x8000_0000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmbCreate {
+    // always 57.
+    pub size: u16,
+    pub security_flags: u8,
+    pub requested_oplock_level: OplockLevel,
+    pub impersonation_level: ImpersonationLevel,
+    pub smb_create_flags: u64,
+    pub desired_access: u32,
+    pub file_attributes: u32,
+    pub share_access: u32,
+    pub create_disposition: CreateDisposition,
+    pub create_options: u32,
+    pub name_offset: u16,
+    pub name_length: u16,
+    pub create_contexts_offset: u32,
+    pub create_contexts_length: u32,
+    /// Relative to the share root, using `\` as the separator.
+    pub name: String,
+    pub create_contexts: Vec<SmbCreateContext>,
+}
+
+impl SmbCreate {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbCreate, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, security_flags) = le_u8(remaining)?;
+        let (remaining, requested_oplock_level) = context(
+            "Failed to get requested oplock level",
+            map_res(le_u8, OplockLevel::try_from),
+        )(remaining)?;
+        let (remaining, impersonation_level) = context(
+            "Failed to get impersonation level",
+            map_res(
+                |body| c_u32("Failed to get impersonation level", body),
+                ImpersonationLevel::try_from,
+            ),
+        )(remaining)?;
+        let (remaining, smb_create_flags) = c_u64("Failed to get create flags", remaining)?;
+        let (remaining, _reserved) = c_u64("Failed to get reserved", remaining)?;
+        let (remaining, desired_access) = c_u32("Failed to get desired access", remaining)?;
+        let (remaining, file_attributes) = c_u32("Failed to get file attributes", remaining)?;
+        let (remaining, share_access) = c_u32("Failed to get share access", remaining)?;
+        let (remaining, create_disposition) = context(
+            "Failed to get create disposition",
+            map_res(
+                |body| c_u32("Failed to get create disposition", body),
+                CreateDisposition::try_from,
+            ),
+        )(remaining)?;
+        let (remaining, create_options) = c_u32("Failed to get create options", remaining)?;
+        let (remaining, name_offset) = c_u16("Failed to get name offset", remaining)?;
+        let (remaining, name_length) = c_u16("Failed to get name length", remaining)?;
+        let (remaining, create_contexts_offset) =
+            c_u32("Failed to get create contexts offset", remaining)?;
+        let (remaining, create_contexts_length) =
+            c_u32("Failed to get create contexts length", remaining)?;
+
+        let (after_name, name) = header_offset_buffer(
+            "Failed to get name",
+            body,
+            remaining,
+            name_offset as _,
+            name_length as _,
+        )?;
+        let (_, name) = parse_utf16le("Failed to decode name", name)?;
+        let (remaining, create_contexts) = header_offset_buffer(
+            "Failed to get create contexts",
+            body,
+            after_name,
+            create_contexts_offset as _,
+            create_contexts_length as _,
+        )?;
+        let (_, create_contexts) = SmbCreateContext::parse_list(create_contexts, false)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                security_flags,
+                requested_oplock_level,
+                impersonation_level,
+                smb_create_flags,
+                desired_access,
+                file_attributes,
+                share_access,
+                create_disposition,
+                create_options,
+                name_offset,
+                name_length,
+                create_contexts_offset,
+                create_contexts_length,
+                name,
+                create_contexts,
+            },
+        ))
+    }
+
+    /// The lease the client's asking for, if it's asking for one.
+    pub fn requested_lease(&self) -> Option<&SmbLease> {
+        if self.requested_oplock_level != OplockLevel::Lease {
+            return None;
+        }
+        self.create_contexts
+            .iter()
+            .find_map(|create_context| match create_context {
+                SmbCreateContext::Lease(lease) => Some(lease),
+                _ => None,
+            })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(56 + self.name_length as usize);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.security_flags);
+        out.push(self.requested_oplock_level as u8);
+        out.extend((self.impersonation_level as u32).to_le_bytes());
+        out.extend(self.smb_create_flags.to_le_bytes());
+        out.extend([0; 8]);
+        out.extend(self.desired_access.to_le_bytes());
+        out.extend(self.file_attributes.to_le_bytes());
+        out.extend(self.share_access.to_le_bytes());
+        out.extend((self.create_disposition as u32).to_le_bytes());
+        out.extend(self.create_options.to_le_bytes());
+        out.extend(self.name_offset.to_le_bytes());
+        out.extend(self.name_length.to_le_bytes());
+        out.extend(self.create_contexts_offset.to_le_bytes());
+        out.extend(self.create_contexts_length.to_le_bytes());
+        if self.name_length > 0 {
+            pad_to(&mut out, self.name_offset as usize - HEADER_SIZE);
+            out.extend(encode_utf16le(&self.name));
+        } else {
+            // the buffer is always at least a byte, even with an empty name.
+            out.push(0);
+        }
+        if !self.create_contexts.is_empty() {
+            pad_to(&mut out, self.create_contexts_offset as usize - HEADER_SIZE);
+            out.extend(SmbCreateContext::list_to_vec(&self.create_contexts));
+        }
+        out
+    }
+}
+
+pub const SMB2_CREATE_FLAG_REPARSEPOINT: u8 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbCreateResponse {
+    // always 89.
+    pub size: u16,
+    pub oplock_level: OplockLevel,
+    pub flags: u8,
+    pub create_action: CreateAction,
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+    pub allocation_size: u64,
+    pub end_of_file: u64,
+    pub file_attributes: u32,
+    pub file_id: SmbFileId,
+    pub create_contexts_offset: u32,
+    pub create_contexts_length: u32,
+    pub create_contexts: Vec<SmbCreateContext>,
+}
+
+impl SmbCreateResponse {
+    /// Where the create contexts go when the response has any.
+    pub const CREATE_CONTEXTS_OFFSET: u32 = (HEADER_SIZE + 88) as u32;
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbCreateResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, oplock_level) = context(
+            "Failed to get oplock level",
+            map_res(le_u8, OplockLevel::try_from),
+        )(remaining)?;
+        let (remaining, flags) = le_u8(remaining)?;
+        let (remaining, create_action) = context(
+            "Failed to get create action",
+            map_res(
+                |body| c_u32("Failed to get create action", body),
+                CreateAction::try_from,
+            ),
+        )(remaining)?;
+        let (remaining, creation_time) = c_u64("Failed to get creation time", remaining)?;
+        let (remaining, last_access_time) = c_u64("Failed to get last access time", remaining)?;
+        let (remaining, last_write_time) = c_u64("Failed to get last write time", remaining)?;
+        let (remaining, change_time) = c_u64("Failed to get change time", remaining)?;
+        let (remaining, allocation_size) = c_u64("Failed to get allocation size", remaining)?;
+        let (remaining, end_of_file) = c_u64("Failed to get end of file", remaining)?;
+        let (remaining, file_attributes) = c_u32("Failed to get file attributes", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, create_contexts_offset) =
+            c_u32("Failed to get create contexts offset", remaining)?;
+        let (remaining, create_contexts_length) =
+            c_u32("Failed to get create contexts length", remaining)?;
+        let (remaining, create_contexts) = header_offset_buffer(
+            "Failed to get create contexts",
+            body,
+            remaining,
+            create_contexts_offset as _,
+            create_contexts_length as _,
+        )?;
+        let (_, create_contexts) = SmbCreateContext::parse_list(create_contexts, true)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                oplock_level,
+                flags,
+                create_action,
+                creation_time,
+                last_access_time,
+                last_write_time,
+                change_time,
+                allocation_size,
+                end_of_file,
+                file_attributes,
+                file_id,
+                create_contexts_offset,
+                create_contexts_length,
+                create_contexts,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(88 + self.create_contexts_length as usize);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.oplock_level as u8);
+        out.push(self.flags);
+        out.extend((self.create_action as u32).to_le_bytes());
+        out.extend(self.creation_time.to_le_bytes());
+        out.extend(self.last_access_time.to_le_bytes());
+        out.extend(self.last_write_time.to_le_bytes());
+        out.extend(self.change_time.to_le_bytes());
+        out.extend(self.allocation_size.to_le_bytes());
+        out.extend(self.end_of_file.to_le_bytes());
+        out.extend(self.file_attributes.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend(self.file_id.to_vec());
+        out.extend(self.create_contexts_offset.to_le_bytes());
+        out.extend(self.create_contexts_length.to_le_bytes());
+        if self.create_contexts.is_empty() {
+            // the buffer is always at least a byte.
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.create_contexts_offset as usize - HEADER_SIZE);
+            out.extend(SmbCreateContext::list_to_vec(&self.create_contexts));
+        }
+        out
+    }
+}
+
+/// One entry of a FILE_FULL_EA_INFORMATION list, i.e. an extended attribute.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileFullEaInformation {
+    pub flags: u8,
+    /// EA names are plain ASCII, no UTF-16 here.
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+pub const FILE_NEED_EA: u8 = 0x80;
+
+impl FileFullEaInformation {
+    pub fn parse_list(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], Vec<FileFullEaInformation>, nom::error::Error<&[u8]>> {
+        let mut entries = vec![];
+        let mut rest = body;
+        while !rest.is_empty() {
+            let (remaining, next_entry_offset) = c_u32("Failed to get next entry offset", rest)?;
+            let (remaining, flags) = le_u8(remaining)?;
+            let (remaining, name_length) = le_u8(remaining)?;
+            let (remaining, value_length) = c_u16("Failed to get EA value length", remaining)?;
+            let (remaining, name) = context(
+                "Failed to get EA name",
+                map_res(take(name_length), std::str::from_utf8),
+            )(remaining)?;
+            // skip the NUL terminator on the name.
+            let (remaining, _) = take(1usize)(remaining)?;
+            let (_, value) = take(value_length)(remaining)?;
+            entries.push(Self {
+                flags,
+                name: name.into(),
+                value: value.to_vec(),
+            });
+            if next_entry_offset == 0 {
+                break;
+            }
+            rest = match rest.get(next_entry_offset as usize..) {
+                Some(rest) => rest,
+                None => return fail(rest),
+            };
+        }
+        Ok((&[], entries))
+    }
+
+    pub fn list_to_vec(entries: &[FileFullEaInformation]) -> Vec<u8> {
+        let mut out = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            let start = out.len();
+            let len = 8 + entry.name.len() + 1 + entry.value.len();
+            // every entry but the last is padded out to 4 bytes.
+            let next_entry_offset = if i + 1 == entries.len() {
+                0
+            } else {
+                len.next_multiple_of(4) as u32
+            };
+            out.extend(next_entry_offset.to_le_bytes());
+            out.push(entry.flags);
+            out.push(entry.name.len() as u8);
+            out.extend((entry.value.len() as u16).to_le_bytes());
+            out.extend(entry.name.as_bytes());
+            out.push(0);
+            out.extend(&entry.value);
+            pad_to(&mut out, start + next_entry_offset as usize);
+        }
+        out
+    }
+}
+
+pub const SMB2_LEASE_NONE: u32 = 0x00;
+pub const SMB2_LEASE_READ_CACHING: u32 = 0x01;
+pub const SMB2_LEASE_HANDLE_CACHING: u32 = 0x02;
+pub const SMB2_LEASE_WRITE_CACHING: u32 = 0x04;
+
+pub const SMB2_LEASE_FLAG_BREAK_IN_PROGRESS: u32 = 0x02;
+pub const SMB2_LEASE_FLAG_PARENT_LEASE_KEY_SET: u32 = 0x04;
+
+/// SMB2_CREATE_REQUEST_LEASE(_V2) and SMB2_CREATE_RESPONSE_LEASE(_V2),
+/// which look exactly the same on the way in and on the way out.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmbLease {
+    V1 {
+        key: u128,
+        state: u32,
+        flags: u32,
+        duration: u64,
+    },
+    V2 {
+        key: u128,
+        state: u32,
+        flags: u32,
+        duration: u64,
+        parent_key: u128,
+        epoch: u16,
+    },
+}
+
+impl SmbLease {
+    pub fn key(&self) -> u128 {
+        match self {
+            Self::V1 { key, .. } | Self::V2 { key, .. } => *key,
+        }
+    }
+
+    pub fn state(&self) -> u32 {
+        match self {
+            Self::V1 { state, .. } | Self::V2 { state, .. } => *state,
+        }
+    }
+
+    fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLease, nom::error::Error<&[u8]>> {
+        let (remaining, key) = c_u128("Failed to get lease key", body)?;
+        let (remaining, state) = c_u32("Failed to get lease state", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get lease flags", remaining)?;
+        let (remaining, duration) = c_u64("Failed to get lease duration", remaining)?;
+        if remaining.is_empty() {
+            return Ok((
+                remaining,
+                Self::V1 {
+                    key,
+                    state,
+                    flags,
+                    duration,
+                },
+            ));
+        }
+        let (remaining, parent_key) = c_u128("Failed to get parent lease key", remaining)?;
+        let (remaining, epoch) = c_u16("Failed to get lease epoch", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((
+            remaining,
+            Self::V2 {
+                key,
+                state,
+                flags,
+                duration,
+                parent_key,
+                epoch,
+            },
+        ))
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(52);
+        match self {
+            Self::V1 {
+                key,
+                state,
+                flags,
+                duration,
+            } => {
+                out.extend(key.to_le_bytes());
+                out.extend(state.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out.extend(duration.to_le_bytes());
+            }
+            Self::V2 {
+                key,
+                state,
+                flags,
+                duration,
+                parent_key,
+                epoch,
+            } => {
+                out.extend(key.to_le_bytes());
+                out.extend(state.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out.extend(duration.to_le_bytes());
+                out.extend(parent_key.to_le_bytes());
+                out.extend(epoch.to_le_bytes());
+                out.extend([0; 2]);
+            }
+        }
+        out
+    }
+}
+
+pub const SMB2_DHANDLE_FLAG_PERSISTENT: u32 = 0x02;
+
+/// The create contexts, named after the tag they're sent with.
+/// A few tags mean different things in a request and a response,
+/// so those get a variant for each.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmbCreateContext {
+    /// ExtA: extended attributes to set on the new file.
+    EaBuffer(Vec<FileFullEaInformation>),
+    /// SecD: a self-relative security descriptor for the new file.
+    SdBuffer(Vec<u8>),
+    /// DHnQ, sent by the client.
+    DurableHandleRequest,
+    /// DHnQ, sent back by the server.
+    DurableHandleResponse,
+    /// DHnC
+    DurableHandleReconnect { file_id: SmbFileId },
+    /// DH2Q, sent by the client.
+    DurableHandleRequestV2 {
+        timeout: u32,
+        flags: u32,
+        create_guid: u128,
+    },
+    /// DH2Q, sent back by the server.
+    DurableHandleResponseV2 { timeout: u32, flags: u32 },
+    /// DH2C
+    DurableHandleReconnectV2 {
+        file_id: SmbFileId,
+        create_guid: u128,
+        flags: u32,
+    },
+    /// MxAc, sent by the client.
+    QueryMaximalAccessRequest { timestamp: Option<u64> },
+    /// MxAc, sent back by the server.
+    QueryMaximalAccessResponse {
+        query_status: NtStatus,
+        maximal_access: u32,
+    },
+    /// QFid, sent by the client (there's nothing in it).
+    QueryOnDiskId,
+    /// QFid, sent back by the server.
+    QueryOnDiskIdResponse { disk_file_id: u64, volume_id: u64 },
+    /// RqLs, both ways.
+    Lease(SmbLease),
+    /// AlSi
+    AllocationSize(u64),
+    /// TWrp: open the file as it was at this point in time.
+    TimewarpToken(u64),
+    /// Anything we don't understand (e.g. the GUID named ones), kept around as-is.
+    Unknown { name: Vec<u8>, data: Vec<u8> },
+}
+
+impl SmbCreateContext {
+    /// Parses a chained create context list. The same tags are used both
+    /// ways, so `response` says which side of the exchange we're looking at.
+    pub fn parse_list(
+        body: &[u8],
+        response: bool,
+    ) -> nom::IResult<&[u8], Vec<SmbCreateContext>, nom::error::Error<&[u8]>> {
+        let mut contexts = vec![];
+        let mut rest = body;
+        while !rest.is_empty() {
+            let (_, next) = c_u32("Failed to get next create context", rest)?;
+            let this = match next {
+                0 => rest,
+                next => match rest.get(..next as usize) {
+                    Some(this) => this,
+                    None => return fail(rest),
+                },
+            };
+            let (_, create_context) = Self::parse(this, response)?;
+            contexts.push(create_context);
+            if next == 0 {
+                break;
+            }
+            rest = &rest[next as usize..];
+        }
+        Ok((&[], contexts))
+    }
+
+    fn parse(
+        body: &[u8],
+        response: bool,
+    ) -> nom::IResult<&[u8], SmbCreateContext, nom::error::Error<&[u8]>> {
+        let (remaining, _next) = c_u32("Failed to get next create context", body)?;
+        let (remaining, name_offset) = c_u16("Failed to get name offset", remaining)?;
+        let (remaining, name_length) = c_u16("Failed to get name length", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, data_offset) = c_u16("Failed to get data offset", remaining)?;
+        let (_, data_length) = c_u32("Failed to get data length", remaining)?;
+        // both offsets are measured from the start of this context.
+        let (_, name) = context(
+            "Failed to get create context name",
+            nom::sequence::preceded(take(name_offset), take(name_length)),
+        )(body)?;
+        let (_, data) = match data_length {
+            0 => (body, &[] as &[u8]),
+            _ => context(
+                "Failed to get create context data",
+                nom::sequence::preceded(take(data_offset), take(data_length)),
+            )(body)?,
+        };
+
+        let create_context = match (name, response) {
+            (b"ExtA", _) => Self::EaBuffer(FileFullEaInformation::parse_list(data)?.1),
+            (b"SecD", _) => Self::SdBuffer(data.to_vec()),
+            (b"DHnQ", false) => Self::DurableHandleRequest,
+            (b"DHnQ", true) => Self::DurableHandleResponse,
+            (b"DHnC", _) => Self::DurableHandleReconnect {
+                file_id: SmbFileId::parse(data)?.1,
+            },
+            (b"DH2Q", false) => {
+                let (remaining, timeout) = c_u32("Failed to get durable timeout", data)?;
+                let (remaining, flags) = c_u32("Failed to get durable flags", remaining)?;
+                let (remaining, _reserved) = c_u64("Failed to get reserved", remaining)?;
+                let (_, create_guid) = c_u128("Failed to get create guid", remaining)?;
+                Self::DurableHandleRequestV2 {
+                    timeout,
+                    flags,
+                    create_guid,
+                }
+            }
+            (b"DH2Q", true) => {
+                let (remaining, timeout) = c_u32("Failed to get durable timeout", data)?;
+                let (_, flags) = c_u32("Failed to get durable flags", remaining)?;
+                Self::DurableHandleResponseV2 { timeout, flags }
+            }
+            (b"DH2C", _) => {
+                let (remaining, file_id) = SmbFileId::parse(data)?;
+                let (remaining, create_guid) = c_u128("Failed to get create guid", remaining)?;
+                let (_, flags) = c_u32("Failed to get durable flags", remaining)?;
+                Self::DurableHandleReconnectV2 {
+                    file_id,
+                    create_guid,
+                    flags,
+                }
+            }
+            (b"MxAc", false) => Self::QueryMaximalAccessRequest {
+                timestamp: match data {
+                    [] => None,
+                    data => Some(c_u64("Failed to get timestamp", data)?.1),
+                },
+            },
+            (b"MxAc", true) => {
+                let (remaining, query_status) = c_u32("Failed to get query status", data)?;
+                let (_, maximal_access) = c_u32("Failed to get maximal access", remaining)?;
+                Self::QueryMaximalAccessResponse {
+                    query_status: NtStatus(query_status),
+                    maximal_access,
+                }
+            }
+            (b"QFid", false) => Self::QueryOnDiskId,
+            (b"QFid", true) => {
+                let (remaining, disk_file_id) = c_u64("Failed to get disk file id", data)?;
+                let (_, volume_id) = c_u64("Failed to get volume id", remaining)?;
+                Self::QueryOnDiskIdResponse {
+                    disk_file_id,
+                    volume_id,
+                }
+            }
+            (b"RqLs", _) => Self::Lease(SmbLease::parse(data)?.1),
+            (b"AlSi", _) => Self::AllocationSize(c_u64("Failed to get allocation size", data)?.1),
+            (b"TWrp", _) => Self::TimewarpToken(c_u64("Failed to get timestamp", data)?.1),
+            _ => Self::Unknown {
+                name: name.to_vec(),
+                data: data.to_vec(),
+            },
+        };
+        Ok((&[], create_context))
+    }
+
+    fn name(&self) -> &[u8] {
+        match self {
+            Self::EaBuffer(_) => b"ExtA",
+            Self::SdBuffer(_) => b"SecD",
+            Self::DurableHandleRequest | Self::DurableHandleResponse => b"DHnQ",
+            Self::DurableHandleReconnect { .. } => b"DHnC",
+            Self::DurableHandleRequestV2 { .. } | Self::DurableHandleResponseV2 { .. } => b"DH2Q",
+            Self::DurableHandleReconnectV2 { .. } => b"DH2C",
+            Self::QueryMaximalAccessRequest { .. } | Self::QueryMaximalAccessResponse { .. } => {
+                b"MxAc"
+            }
+            Self::QueryOnDiskId | Self::QueryOnDiskIdResponse { .. } => b"QFid",
+            Self::Lease(_) => b"RqLs",
+            Self::AllocationSize(_) => b"AlSi",
+            Self::TimewarpToken(_) => b"TWrp",
+            Self::Unknown { name, .. } => name,
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        match self {
+            Self::EaBuffer(entries) => FileFullEaInformation::list_to_vec(entries),
+            Self::SdBuffer(descriptor) => descriptor.clone(),
+            Self::DurableHandleRequest => vec![0; 16],
+            Self::DurableHandleResponse => vec![0; 8],
+            Self::DurableHandleReconnect { file_id } => file_id.to_vec(),
+            Self::DurableHandleRequestV2 {
+                timeout,
+                flags,
+                create_guid,
+            } => {
+                let mut out = Vec::with_capacity(32);
+                out.extend(timeout.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out.extend([0; 8]);
+                out.extend(create_guid.to_le_bytes());
+                out
+            }
+            Self::DurableHandleResponseV2 { timeout, flags } => {
+                let mut out = Vec::with_capacity(8);
+                out.extend(timeout.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out
+            }
+            Self::DurableHandleReconnectV2 {
+                file_id,
+                create_guid,
+                flags,
+            } => {
+                let mut out = file_id.to_vec();
+                out.extend(create_guid.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out
+            }
+            Self::QueryMaximalAccessRequest { timestamp } => timestamp
+                .map(|timestamp| timestamp.to_le_bytes().to_vec())
+                .unwrap_or_default(),
+            Self::QueryMaximalAccessResponse {
+                query_status,
+                maximal_access,
+            } => {
+                let mut out = Vec::with_capacity(8);
+                out.extend(query_status.0.to_le_bytes());
+                out.extend(maximal_access.to_le_bytes());
+                out
+            }
+            Self::QueryOnDiskId => vec![],
+            Self::QueryOnDiskIdResponse {
+                disk_file_id,
+                volume_id,
+            } => {
+                let mut out = Vec::with_capacity(32);
+                out.extend(disk_file_id.to_le_bytes());
+                out.extend(volume_id.to_le_bytes());
+                out.extend([0; 16]);
+                out
+            }
+            Self::Lease(lease) => lease.to_vec(),
+            Self::AllocationSize(size) => size.to_le_bytes().to_vec(),
+            Self::TimewarpToken(timestamp) => timestamp.to_le_bytes().to_vec(),
+            Self::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let name = self.name();
+        let data = self.data();
+        // the name sits right after the 16 byte context header,
+        // and the data after that, 8 byte aligned.
+        let data_offset = if data.is_empty() {
+            0
+        } else {
+            (16 + name.len()).next_multiple_of(8)
+        };
+        let mut out = Vec::with_capacity(data_offset + data.len());
+        out.extend(0u32.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend([0; 2]);
+        out.extend((data_offset as u16).to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(name);
+        pad_to(&mut out, data_offset);
+        out.extend(data);
+        out
+    }
+
+    /// Encodes a chain of contexts, filling in each one's `Next` field.
+    pub fn list_to_vec(contexts: &[SmbCreateContext]) -> Vec<u8> {
+        let mut out = vec![];
+        for (i, create_context) in contexts.iter().enumerate() {
+            let start = out.len();
+            out.extend(create_context.to_vec());
+            if i + 1 != contexts.len() {
+                // every context starts 8 byte aligned.
+                let next = (out.len() - start).next_multiple_of(8);
+                pad_to(&mut out, start + next);
+                out[start..start + 4].copy_from_slice(&(next as u32).to_le_bytes());
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbClose {
+    // always 24.
+    pub size: u16,
+    pub flags: u16,
+    pub file_id: SmbFileId,
+}
+
+/// Ask for the file's attributes to be sent back in the close response.
+pub const SMB2_CLOSE_FLAG_POSTQUERY_ATTRIB: u16 = 0x0001;
+
+impl SmbClose {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbClose, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                flags,
+                file_id,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend(self.file_id.to_vec());
+        out
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct SmbCloseResponse {
+    // always 60.
+    pub size: u16,
+    pub flags: u16,
+    // everything below is only filled in with SMB2_CLOSE_FLAG_POSTQUERY_ATTRIB.
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+    pub allocation_size: u64,
+    pub end_of_file: u64,
+    pub file_attributes: u32,
+}
+
+impl SmbCloseResponse {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbCloseResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        let (remaining, creation_time) = c_u64("Failed to get creation time", remaining)?;
+        let (remaining, last_access_time) = c_u64("Failed to get last access time", remaining)?;
+        let (remaining, last_write_time) = c_u64("Failed to get last write time", remaining)?;
+        let (remaining, change_time) = c_u64("Failed to get change time", remaining)?;
+        let (remaining, allocation_size) = c_u64("Failed to get allocation size", remaining)?;
+        let (remaining, end_of_file) = c_u64("Failed to get end of file", remaining)?;
+        let (remaining, file_attributes) = c_u32("Failed to get file attributes", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                flags,
+                creation_time,
+                last_access_time,
+                last_write_time,
+                change_time,
+                allocation_size,
+                end_of_file,
+                file_attributes,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(60);
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend(self.creation_time.to_le_bytes());
+        out.extend(self.last_access_time.to_le_bytes());
+        out.extend(self.last_write_time.to_le_bytes());
+        out.extend(self.change_time.to_le_bytes());
+        out.extend(self.allocation_size.to_le_bytes());
+        out.extend(self.end_of_file.to_le_bytes());
+        out.extend(self.file_attributes.to_le_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_create() {
+        #[rustfmt::skip]
+        let smb_create = [
+            // size    | sec flags | oplock level
+            0x39, 0x00, 0x00, 0x09,
+            // impersonation level
+            0x02, 0x00, 0x00, 0x00,
+            // create flags
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // reserved
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // desired access
+            0x89, 0x00, 0x12, 0x00,
+            // file attributes
+            0x00, 0x00, 0x00, 0x00,
+            // share access
+            0x07, 0x00, 0x00, 0x00,
+            // create disposition
+            0x01, 0x00, 0x00, 0x00,
+            // create options
+            0x40, 0x00, 0x00, 0x00,
+            // name offset | name length
+            0x78, 0x00, 0x0a, 0x00,
+            // contexts offset
+            0x88, 0x00, 0x00, 0x00,
+            // contexts length
+            0x34, 0x00, 0x00, 0x00,
+            // a.txt
+            b'a', 0x00, b'.', 0x00, b't', 0x00, b'x', 0x00, b't', 0x00,
+            // padding
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+
+            // MxAc: next
+            0x18, 0x00, 0x00, 0x00,
+            // name offset | name length
+            0x10, 0x00, 0x04, 0x00,
+            // reserved | data offset
+            0x00, 0x00, 0x00, 0x00,
+            // data length
+            0x00, 0x00, 0x00, 0x00,
+            b'M', b'x', b'A', b'c',
+            // padding
+            0x00, 0x00, 0x00, 0x00,
+
+            // something we don't know about: next
+            0x00, 0x00, 0x00, 0x00,
+            // name offset | name length
+            0x10, 0x00, 0x04, 0x00,
+            // reserved | data offset
+            0x00, 0x00, 0x18, 0x00,
+            // data length
+            0x04, 0x00, 0x00, 0x00,
+            b'W', b'h', b'o', b'?',
+            // padding
+            0x00, 0x00, 0x00, 0x00,
+            0xde, 0xad, 0xbe, 0xef,
+        ];
+
+        let (_, create) = SmbCreate::parse(&smb_create).unwrap();
+        assert_eq!(
+            create,
+            SmbCreate {
+                size: 0x39,
+                security_flags: 0,
+                requested_oplock_level: OplockLevel::Batch,
+                impersonation_level: ImpersonationLevel::Impersonation,
+                smb_create_flags: 0,
+                desired_access: 0x00120089,
+                file_attributes: 0,
+                share_access: FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                create_disposition: CreateDisposition::Open,
+                create_options: FILE_NON_DIRECTORY_FILE,
+                name_offset: 0x78,
+                name_length: 0x0a,
+                create_contexts_offset: 0x88,
+                create_contexts_length: 0x34,
+                name: "a.txt".into(),
+                create_contexts: vec![
+                    SmbCreateContext::QueryMaximalAccessRequest { timestamp: None },
+                    SmbCreateContext::Unknown {
+                        name: b"Who?".to_vec(),
+                        data: vec![0xde, 0xad, 0xbe, 0xef],
+                    },
+                ],
+            }
+        );
+        assert_eq!(create.to_vec(), smb_create);
+    }
+
+    #[test]
+    fn create_context_round_trip() {
+        let contexts = vec![
+            SmbCreateContext::EaBuffer(vec![
+                FileFullEaInformation {
+                    flags: 0,
+                    name: "user.a".into(),
+                    value: b"hello".to_vec(),
+                },
+                FileFullEaInformation {
+                    flags: FILE_NEED_EA,
+                    name: "b".into(),
+                    value: vec![],
+                },
+            ]),
+            SmbCreateContext::SdBuffer(vec![1, 0, 4, 0x80]),
+            SmbCreateContext::DurableHandleRequest,
+            SmbCreateContext::DurableHandleReconnect {
+                file_id: SmbFileId {
+                    persistent: 1,
+                    volatile: 2,
+                },
+            },
+            SmbCreateContext::DurableHandleRequestV2 {
+                timeout: 60_000,
+                flags: SMB2_DHANDLE_FLAG_PERSISTENT,
+                create_guid: 0x1234,
+            },
+            SmbCreateContext::DurableHandleReconnectV2 {
+                file_id: SmbFileId {
+                    persistent: 3,
+                    volatile: 4,
+                },
+                create_guid: 0x1234,
+                flags: 0,
+            },
+            SmbCreateContext::QueryMaximalAccessRequest {
+                timestamp: Some(42),
+            },
+            SmbCreateContext::QueryOnDiskId,
+            SmbCreateContext::Lease(SmbLease::V1 {
+                key: 7,
+                state: SMB2_LEASE_READ_CACHING | SMB2_LEASE_HANDLE_CACHING,
+                flags: 0,
+                duration: 0,
+            }),
+            SmbCreateContext::Lease(SmbLease::V2 {
+                key: 8,
+                state: SMB2_LEASE_READ_CACHING,
+                flags: SMB2_LEASE_FLAG_PARENT_LEASE_KEY_SET,
+                duration: 0,
+                parent_key: 9,
+                epoch: 1,
+            }),
+            SmbCreateContext::AllocationSize(4096),
+            SmbCreateContext::TimewarpToken(132_000_000_000_000_000),
+        ];
+        let encoded = SmbCreateContext::list_to_vec(&contexts);
+        assert_eq!(
+            SmbCreateContext::parse_list(&encoded, false),
+            Ok((&[] as &[u8], contexts))
+        );
+
+        let contexts = vec![
+            SmbCreateContext::DurableHandleResponse,
+            SmbCreateContext::DurableHandleResponseV2 {
+                timeout: 60_000,
+                flags: 0,
+            },
+            SmbCreateContext::QueryMaximalAccessResponse {
+                query_status: ntstatus::STATUS_SUCCESS,
+                maximal_access: 0x001f01ff,
+            },
+            SmbCreateContext::QueryOnDiskIdResponse {
+                disk_file_id: 1234,
+                volume_id: 5678,
+            },
+        ];
+        let encoded = SmbCreateContext::list_to_vec(&contexts);
+        assert_eq!(
+            SmbCreateContext::parse_list(&encoded, true),
+            Ok((&[] as &[u8], contexts))
+        );
+    }
+
+    #[test]
+    fn create_response_round_trip() {
+        let response = SmbCreateResponse {
+            size: 89,
+            oplock_level: OplockLevel::None,
+            flags: 0,
+            create_action: CreateAction::Created,
+            creation_time: 1,
+            last_access_time: 2,
+            last_write_time: 3,
+            change_time: 4,
+            allocation_size: 4096,
+            end_of_file: 5,
+            file_attributes: FILE_ATTRIBUTE_ARCHIVE,
+            file_id: SmbFileId {
+                persistent: 6,
+                volatile: 7,
+            },
+            create_contexts_offset: SmbCreateResponse::CREATE_CONTEXTS_OFFSET,
+            create_contexts_length: 32,
+            create_contexts: vec![SmbCreateContext::QueryMaximalAccessResponse {
+                query_status: ntstatus::STATUS_SUCCESS,
+                maximal_access: 0x001f01ff,
+            }],
+        };
+        let encoded = response.to_vec();
+        assert_eq!(encoded.len(), 88 + 32);
+        assert_eq!(
+            SmbCreateResponse::parse(&encoded),
+            Ok((&[] as &[u8], response))
+        );
+    }
+}
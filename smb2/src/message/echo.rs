@@ -0,0 +1,60 @@
+use crate::message::c_u16;
+
+/// Both the request and the response are just a size and some padding.
+#[derive(Debug, PartialEq)]
+pub struct SmbEcho {
+    // always 4.
+    pub size: u16,
+}
+
+impl SmbEcho {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbEcho, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+}
+
+/// Which request to cancel is entirely up to the header: the AsyncId if
+/// it went async, otherwise the MessageId. There's never a response.
+#[derive(Debug, PartialEq)]
+pub struct SmbCancel {
+    // always 4.
+    pub size: u16,
+}
+
+impl SmbCancel {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbCancel, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_echo() {
+        let smb_echo = [0x04, 0x00, 0x00, 0x00];
+        let (remaining, echo) = SmbEcho::parse(&smb_echo).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(echo, SmbEcho { size: 4 });
+        assert_eq!(echo.to_vec(), smb_echo);
+    }
+}
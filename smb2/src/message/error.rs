@@ -0,0 +1,372 @@
+use crate::message::SymbolicLinkReparseBuffer;
+use crate::message::{c_u16, c_u32, encode_utf16le, fail, pad_to, parse_utf16le};
+use nom::bytes::complete::take;
+use nom::number::complete::le_u8;
+
+// the statuses whose error data means something without an error context saying what.
+use ntstatus::{NtStatus, STATUS_BUFFER_TOO_SMALL, STATUS_STOPPED_ON_SYMLINK};
+
+/// SMB2_ERROR_ID_DEFAULT, whose data is interpreted according to the status.
+pub const SMB2_ERROR_ID_DEFAULT: u32 = 0x0000_0000;
+/// SMB2_ERROR_ID_SHARE_REDIRECT, for STATUS_BAD_NETWORK_NAME on a scale out share.
+pub const SMB2_ERROR_ID_SHARE_REDIRECT: u32 = 0x7264_5253;
+
+// SymLinkErrorTag, "SYML".
+const SYMLINK_ERROR_TAG: u32 = 0x4C4D_5953;
+// where UnparsedPathLength goes in the reparse buffer, in place of its reserved field.
+const UNPARSED_PATH_LENGTH_OFFSET: usize = 6;
+
+// NotificationType for a share redirect, SHARE_MOVE_NOTIFICATION.
+const SHARE_MOVE_NOTIFICATION: u32 = 3;
+// the Type of each MOVE_DST_IPADDR.
+const MOVE_DST_IPADDR_V4: u32 = 1;
+const MOVE_DST_IPADDR_V6: u32 = 2;
+
+/// What the server sends back in place of the usual response body
+/// when a request fails (or, like STATUS_NO_MORE_FILES, ends early).
+#[derive(Debug, PartialEq, Default)]
+pub struct SmbErrorResponse {
+    // always 9.
+    pub size: u16,
+    /// 0 before 3.1.1, which only had room for the one bit of error data,
+    /// sent without an error context around it.
+    pub error_context_count: u8,
+    pub byte_count: u32,
+    pub error_data: Vec<SmbErrorContext>,
+}
+
+/// Something that explains a failure further than its status does.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmbErrorContext {
+    /// STATUS_STOPPED_ON_SYMLINK: the client's to follow the link itself.
+    SymbolicLink {
+        /// How many bytes of the path, in UTF-16, come after the link.
+        unparsed_path_length: u16,
+        link: SymbolicLinkReparseBuffer,
+    },
+    /// STATUS_BUFFER_TOO_SMALL: how big the buffer needed to be.
+    BufferTooSmall { required: u32 },
+    /// The share has moved to another node of a scale out cluster.
+    ShareRedirect {
+        resource_name: String,
+        addresses: Vec<std::net::IpAddr>,
+    },
+    /// Anything else, kept as it was.
+    Unknown { error_id: u32, data: Vec<u8> },
+}
+
+impl SmbErrorContext {
+    fn error_id(&self) -> u32 {
+        match self {
+            Self::ShareRedirect { .. } => SMB2_ERROR_ID_SHARE_REDIRECT,
+            Self::Unknown { error_id, .. } => *error_id,
+            _ => SMB2_ERROR_ID_DEFAULT,
+        }
+    }
+
+    /// Reads error data, which is made sense of by `error_id` if it has
+    /// one, or by the status the request failed with if not.
+    fn parse(error_id: u32, status: NtStatus, data: &[u8]) -> Self {
+        let parsed = match (error_id, status) {
+            (SMB2_ERROR_ID_SHARE_REDIRECT, _) => Self::parse_share_redirect(data),
+            (SMB2_ERROR_ID_DEFAULT, STATUS_STOPPED_ON_SYMLINK) => Self::parse_symbolic_link(data),
+            (SMB2_ERROR_ID_DEFAULT, STATUS_BUFFER_TOO_SMALL) => {
+                c_u32("Failed to get required buffer length", data)
+                    .map(|(remaining, required)| (remaining, Self::BufferTooSmall { required }))
+            }
+            _ => fail(data),
+        };
+        match parsed {
+            Ok((_, context)) => context,
+            Err(_) => Self::Unknown {
+                error_id,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    fn parse_symbolic_link(data: &[u8]) -> nom::IResult<&[u8], Self, nom::error::Error<&[u8]>> {
+        let (remaining, symlink_length) = c_u32("Failed to get symlink length", data)?;
+        let (_, symlink) = take(symlink_length)(remaining)?;
+        let (reparse_buffer, tag) = c_u32("Failed to get symlink error tag", symlink)?;
+        if tag != SYMLINK_ERROR_TAG {
+            return fail(symlink);
+        }
+        let (_, unparsed_path_length) = c_u16(
+            "Failed to get unparsed path length",
+            reparse_buffer
+                .get(UNPARSED_PATH_LENGTH_OFFSET..)
+                .unwrap_or_default(),
+        )?;
+        let (remaining, link) = SymbolicLinkReparseBuffer::parse(reparse_buffer)?;
+        Ok((
+            remaining,
+            Self::SymbolicLink {
+                unparsed_path_length,
+                link,
+            },
+        ))
+    }
+
+    fn parse_share_redirect(data: &[u8]) -> nom::IResult<&[u8], Self, nom::error::Error<&[u8]>> {
+        let (remaining, _size) = c_u32("Failed to get structure size", data)?;
+        let (remaining, _notification_type) = c_u32("Failed to get notification type", remaining)?;
+        let (remaining, resource_name_offset) =
+            c_u32("Failed to get resource name offset", remaining)?;
+        let (remaining, resource_name_length) =
+            c_u32("Failed to get resource name length", remaining)?;
+        let (remaining, _flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, _target_type) = c_u16("Failed to get target type", remaining)?;
+        let (mut remaining, address_count) = c_u32("Failed to get address count", remaining)?;
+        let mut addresses = vec![];
+        for _ in 0..address_count {
+            let (rest, kind) = c_u32("Failed to get address type", remaining)?;
+            let (rest, _reserved) = c_u32("Failed to get reserved", rest)?;
+            let (rest, address) = take(16usize)(rest)?;
+            addresses.push(match kind {
+                MOVE_DST_IPADDR_V4 => {
+                    std::net::IpAddr::from(<[u8; 4]>::try_from(&address[..4]).unwrap())
+                }
+                MOVE_DST_IPADDR_V6 => {
+                    std::net::IpAddr::from(<[u8; 16]>::try_from(address).unwrap())
+                }
+                _ => return fail(remaining),
+            });
+            remaining = rest;
+        }
+        let start = resource_name_offset as usize;
+        let Some(resource_name) = data.get(start..start + resource_name_length as usize) else {
+            return fail(remaining);
+        };
+        let (_, resource_name) = parse_utf16le("Failed to get resource name", resource_name)?;
+        Ok((
+            &data[start + resource_name_length as usize..],
+            Self::ShareRedirect {
+                resource_name,
+                addresses,
+            },
+        ))
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        match self {
+            Self::SymbolicLink {
+                unparsed_path_length,
+                link,
+            } => {
+                let mut reparse_buffer = link.to_vec();
+                reparse_buffer[UNPARSED_PATH_LENGTH_OFFSET..UNPARSED_PATH_LENGTH_OFFSET + 2]
+                    .copy_from_slice(&unparsed_path_length.to_le_bytes());
+                let mut out = Vec::with_capacity(8 + reparse_buffer.len());
+                out.extend((4 + reparse_buffer.len() as u32).to_le_bytes());
+                out.extend(SYMLINK_ERROR_TAG.to_le_bytes());
+                out.extend(reparse_buffer);
+                out
+            }
+            Self::BufferTooSmall { required } => required.to_le_bytes().to_vec(),
+            Self::ShareRedirect {
+                resource_name,
+                addresses,
+            } => {
+                let resource_name = encode_utf16le(resource_name);
+                let resource_name_offset = 24 + 24 * addresses.len();
+                let size = resource_name_offset + resource_name.len();
+                let mut out = Vec::with_capacity(size);
+                out.extend((size as u32).to_le_bytes());
+                out.extend(SHARE_MOVE_NOTIFICATION.to_le_bytes());
+                out.extend((resource_name_offset as u32).to_le_bytes());
+                out.extend((resource_name.len() as u32).to_le_bytes());
+                // flags | target type, which is always 0 for IP addresses.
+                out.extend([0; 4]);
+                out.extend((addresses.len() as u32).to_le_bytes());
+                for address in addresses {
+                    let (kind, mut octets) = match address {
+                        std::net::IpAddr::V4(v4) => (MOVE_DST_IPADDR_V4, v4.octets().to_vec()),
+                        std::net::IpAddr::V6(v6) => (MOVE_DST_IPADDR_V6, v6.octets().to_vec()),
+                    };
+                    octets.resize(16, 0);
+                    out.extend(kind.to_le_bytes());
+                    out.extend([0; 4]);
+                    out.extend(octets);
+                }
+                out.extend(resource_name);
+                out
+            }
+            Self::Unknown { data, .. } => data.clone(),
+        }
+    }
+}
+
+impl SmbErrorResponse {
+    /// An error response carrying `error_data`. `contexts` is whether the
+    /// connection is 3.1.1, which wraps each in an error context; before
+    /// that there's only room for one.
+    pub fn new(mut error_data: Vec<SmbErrorContext>, contexts: bool) -> Self {
+        if !contexts {
+            error_data.truncate(1);
+        }
+        let mut response = Self {
+            size: 9,
+            error_context_count: if contexts { error_data.len() as u8 } else { 0 },
+            byte_count: 0,
+            error_data,
+        };
+        response.byte_count = response.error_data_to_vec().len() as u32;
+        response
+    }
+
+    /// `status` is what the response's header says, since that's what
+    /// decides what error data without an error context means.
+    pub fn parse(
+        body: &[u8],
+        status: NtStatus,
+    ) -> nom::IResult<&[u8], SmbErrorResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, error_context_count) = le_u8(remaining)?;
+        let (remaining, _reserved) = le_u8(remaining)?;
+        let (remaining, byte_count) = c_u32("Failed to get byte count", remaining)?;
+        let (remaining, data) = take(byte_count)(remaining)?;
+        let error_data = if data.is_empty() {
+            vec![]
+        } else if error_context_count == 0 {
+            vec![SmbErrorContext::parse(SMB2_ERROR_ID_DEFAULT, status, data)]
+        } else {
+            let mut error_data = vec![];
+            let mut context = data;
+            for _ in 0..error_context_count {
+                let (rest, error_data_length) = c_u32("Failed to get error data length", context)?;
+                let (rest, error_id) = c_u32("Failed to get error id", rest)?;
+                let (rest, context_data) = take(error_data_length)(rest)?;
+                error_data.push(SmbErrorContext::parse(error_id, status, context_data));
+                // each one starts on an 8 byte boundary.
+                let next = (data.len() - rest.len()).next_multiple_of(8);
+                context = data.get(next..).unwrap_or_default();
+            }
+            error_data
+        };
+        Ok((
+            remaining,
+            Self {
+                size,
+                error_context_count,
+                byte_count,
+                error_data,
+            },
+        ))
+    }
+
+    fn error_data_to_vec(&self) -> Vec<u8> {
+        if self.error_context_count == 0 {
+            return self
+                .error_data
+                .first()
+                .map(SmbErrorContext::to_vec)
+                .unwrap_or_default();
+        }
+        let mut out = vec![];
+        for context in &self.error_data {
+            let start = out.len().next_multiple_of(8);
+            pad_to(&mut out, start);
+            let data = context.to_vec();
+            out.extend((data.len() as u32).to_le_bytes());
+            out.extend(context.error_id().to_le_bytes());
+            out.extend(data);
+        }
+        out
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let error_data = self.error_data_to_vec();
+        let mut out = Vec::with_capacity(9 + error_data.len());
+        out.extend(self.size.to_le_bytes());
+        out.push(self.error_context_count);
+        out.push(0);
+        out.extend(self.byte_count.to_le_bytes());
+        if error_data.is_empty() {
+            // there's always at least one byte of error data, even if it means nothing.
+            out.push(0);
+        } else {
+            out.extend(error_data);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::SYMLINK_FLAG_RELATIVE;
+
+    #[test]
+    fn example_buffer_too_small() {
+        #[rustfmt::skip]
+        let smb_error = [
+            // size    | context count | reserved
+            0x09, 0x00, 0x00, 0x00,
+            // byte count
+            0x04, 0x00, 0x00, 0x00,
+            // required buffer length
+            0x90, 0x00, 0x00, 0x00,
+        ];
+        let (_, error) = SmbErrorResponse::parse(&smb_error, STATUS_BUFFER_TOO_SMALL).unwrap();
+        assert_eq!(
+            error,
+            SmbErrorResponse::new(
+                vec![SmbErrorContext::BufferTooSmall { required: 0x90 }],
+                false
+            )
+        );
+        assert_eq!(error.to_vec(), smb_error);
+    }
+
+    #[test]
+    fn symlink_in_an_error_context() {
+        let error = SmbErrorResponse::new(
+            vec![SmbErrorContext::SymbolicLink {
+                unparsed_path_length: 10,
+                link: SymbolicLinkReparseBuffer {
+                    substitute_name: "..\\elsewhere".into(),
+                    print_name: "..\\elsewhere".into(),
+                    flags: SYMLINK_FLAG_RELATIVE,
+                },
+            }],
+            true,
+        );
+        let encoded = error.to_vec();
+        assert_eq!(encoded[2], 1);
+        // error data length | error id
+        assert_eq!(encoded[8..12], (error.byte_count - 8).to_le_bytes());
+        assert_eq!(encoded[12..16], SMB2_ERROR_ID_DEFAULT.to_le_bytes());
+        // symlink length | "SYML"
+        assert_eq!(encoded[16..20], (error.byte_count - 12).to_le_bytes());
+        assert_eq!(encoded[20..24], SYMLINK_ERROR_TAG.to_le_bytes());
+        // unparsed path length, where the reparse buffer would have reserved.
+        assert_eq!(encoded[30..32], 10u16.to_le_bytes());
+        let (_, parsed) = SmbErrorResponse::parse(&encoded, STATUS_STOPPED_ON_SYMLINK).unwrap();
+        assert_eq!(parsed, error);
+    }
+
+    #[test]
+    fn share_redirect_and_unknown_contexts() {
+        let error = SmbErrorResponse::new(
+            vec![
+                SmbErrorContext::ShareRedirect {
+                    resource_name: "public".into(),
+                    addresses: vec![
+                        std::net::Ipv4Addr::new(10, 0, 0, 2).into(),
+                        std::net::Ipv6Addr::LOCALHOST.into(),
+                    ],
+                },
+                SmbErrorContext::Unknown {
+                    error_id: 0x1234,
+                    data: vec![1, 2, 3],
+                },
+            ],
+            true,
+        );
+        let encoded = error.to_vec();
+        let (_, parsed) =
+            SmbErrorResponse::parse(&encoded, ntstatus::STATUS_BAD_NETWORK_NAME).unwrap();
+        assert_eq!(parsed, error);
+    }
+}
@@ -0,0 +1,666 @@
+use nom::bytes::complete::take;
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u32, c_u64, encode_utf16le, fail, pad_to, parse_utf16le};
+
+/// The FileInformationClass values QUERY_INFO and SET_INFO understand
+/// for SMB2_0_INFO_FILE.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileInfoClass {
+    FileBasicInformation = 0x04,
+    FileStandardInformation = 0x05,
+    FileInternalInformation = 0x06,
+    FileEaInformation = 0x07,
+    FileAccessInformation = 0x08,
+    FileRenameInformation = 0x0A,
+    FileLinkInformation = 0x0B,
+    FileDispositionInformation = 0x0D,
+    FilePositionInformation = 0x0E,
+    FileModeInformation = 0x10,
+    FileAlignmentInformation = 0x11,
+    FileAllInformation = 0x12,
+    FileAllocationInformation = 0x13,
+    FileEndOfFileInformation = 0x14,
+    FileStreamInformation = 0x16,
+    FileNetworkOpenInformation = 0x22,
+    FileAttributeTagInformation = 0x23,
+    FileNormalizedNameInformation = 0x30,
+    FileDispositionInformationEx = 0x40,
+}
+
+#[derive(Debug)]
+pub struct InvalidFileInfoClass;
+
+impl TryFrom<u8> for FileInfoClass {
+    type Error = InvalidFileInfoClass;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x04 => Ok(Self::FileBasicInformation),
+            0x05 => Ok(Self::FileStandardInformation),
+            0x06 => Ok(Self::FileInternalInformation),
+            0x07 => Ok(Self::FileEaInformation),
+            0x08 => Ok(Self::FileAccessInformation),
+            0x0A => Ok(Self::FileRenameInformation),
+            0x0B => Ok(Self::FileLinkInformation),
+            0x0D => Ok(Self::FileDispositionInformation),
+            0x0E => Ok(Self::FilePositionInformation),
+            0x10 => Ok(Self::FileModeInformation),
+            0x11 => Ok(Self::FileAlignmentInformation),
+            0x12 => Ok(Self::FileAllInformation),
+            0x13 => Ok(Self::FileAllocationInformation),
+            0x14 => Ok(Self::FileEndOfFileInformation),
+            0x16 => Ok(Self::FileStreamInformation),
+            0x22 => Ok(Self::FileNetworkOpenInformation),
+            0x23 => Ok(Self::FileAttributeTagInformation),
+            0x30 => Ok(Self::FileNormalizedNameInformation),
+            0x40 => Ok(Self::FileDispositionInformationEx),
+            _ => Err(InvalidFileInfoClass),
+        }
+    }
+}
+
+pub const FILE_DISPOSITION_DELETE: u32 = 0x0000_0001;
+pub const FILE_DISPOSITION_POSIX_SEMANTICS: u32 = 0x0000_0002;
+pub const FILE_DISPOSITION_ON_CLOSE: u32 = 0x0000_0008;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileBasicInformation {
+    /// Zero (or -1) in a SET_INFO means "leave it alone".
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+    /// Zero in a SET_INFO means "leave them alone".
+    pub file_attributes: u32,
+}
+
+impl FileBasicInformation {
+    fn parse(body: &[u8]) -> nom::IResult<&[u8], FileBasicInformation, nom::error::Error<&[u8]>> {
+        let (remaining, creation_time) = c_u64("Failed to get creation time", body)?;
+        let (remaining, last_access_time) = c_u64("Failed to get last access time", remaining)?;
+        let (remaining, last_write_time) = c_u64("Failed to get last write time", remaining)?;
+        let (remaining, change_time) = c_u64("Failed to get change time", remaining)?;
+        let (remaining, file_attributes) = c_u32("Failed to get file attributes", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                creation_time,
+                last_access_time,
+                last_write_time,
+                change_time,
+                file_attributes,
+            },
+        ))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.creation_time.to_le_bytes());
+        out.extend(self.last_access_time.to_le_bytes());
+        out.extend(self.last_write_time.to_le_bytes());
+        out.extend(self.change_time.to_le_bytes());
+        out.extend(self.file_attributes.to_le_bytes());
+        out.extend([0; 4]);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileStandardInformation {
+    pub allocation_size: u64,
+    pub end_of_file: u64,
+    pub number_of_links: u32,
+    pub delete_pending: bool,
+    pub directory: bool,
+}
+
+impl FileStandardInformation {
+    fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], FileStandardInformation, nom::error::Error<&[u8]>> {
+        let (remaining, allocation_size) = c_u64("Failed to get allocation size", body)?;
+        let (remaining, end_of_file) = c_u64("Failed to get end of file", remaining)?;
+        let (remaining, number_of_links) = c_u32("Failed to get number of links", remaining)?;
+        let (remaining, delete_pending) = le_u8(remaining)?;
+        let (remaining, directory) = le_u8(remaining)?;
+        let (remaining, _reserved) = take(2usize)(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                allocation_size,
+                end_of_file,
+                number_of_links,
+                delete_pending: delete_pending != 0,
+                directory: directory != 0,
+            },
+        ))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.allocation_size.to_le_bytes());
+        out.extend(self.end_of_file.to_le_bytes());
+        out.extend(self.number_of_links.to_le_bytes());
+        out.push(self.delete_pending as u8);
+        out.push(self.directory as u8);
+        out.extend([0; 2]);
+    }
+}
+
+/// Everything FileAllInformation bundles together.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileAllInformation {
+    pub basic: FileBasicInformation,
+    pub standard: FileStandardInformation,
+    pub index_number: u64,
+    pub ea_size: u32,
+    pub access_flags: u32,
+    pub current_byte_offset: u64,
+    pub mode: u32,
+    pub alignment_requirement: u32,
+    pub file_name: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileNetworkOpenInformation {
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+    pub allocation_size: u64,
+    pub end_of_file: u64,
+    pub file_attributes: u32,
+}
+
+/// One of the entries in FileStreamInformation.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileStreamEntry {
+    /// e.g. `::$DATA` for the unnamed data stream.
+    pub stream_name: String,
+    pub stream_size: u64,
+    pub stream_allocation_size: u64,
+}
+
+/// The file information classes, for both asking about a file and
+/// changing it. FileRename and FileLink use the SMB2 layout, with
+/// a 64 bit RootDirectory.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FileInfo {
+    Basic(FileBasicInformation),
+    Standard(FileStandardInformation),
+    Internal {
+        index_number: u64,
+    },
+    Ea {
+        ea_size: u32,
+    },
+    Access {
+        access_flags: u32,
+    },
+    Rename {
+        replace_if_exists: bool,
+        root_directory: u64,
+        file_name: String,
+    },
+    Link {
+        replace_if_exists: bool,
+        root_directory: u64,
+        file_name: String,
+    },
+    Disposition {
+        delete_pending: bool,
+    },
+    Position {
+        current_byte_offset: u64,
+    },
+    Mode {
+        mode: u32,
+    },
+    Alignment {
+        alignment_requirement: u32,
+    },
+    All(FileAllInformation),
+    Allocation {
+        allocation_size: u64,
+    },
+    EndOfFile {
+        end_of_file: u64,
+    },
+    Stream(Vec<FileStreamEntry>),
+    NetworkOpen(FileNetworkOpenInformation),
+    AttributeTag {
+        file_attributes: u32,
+        reparse_tag: u32,
+    },
+    NormalizedName {
+        file_name: String,
+    },
+    DispositionEx {
+        flags: u32,
+    },
+}
+
+impl FileInfo {
+    pub fn class(&self) -> FileInfoClass {
+        use FileInfoClass::*;
+        match self {
+            Self::Basic(_) => FileBasicInformation,
+            Self::Standard(_) => FileStandardInformation,
+            Self::Internal { .. } => FileInternalInformation,
+            Self::Ea { .. } => FileEaInformation,
+            Self::Access { .. } => FileAccessInformation,
+            Self::Rename { .. } => FileRenameInformation,
+            Self::Link { .. } => FileLinkInformation,
+            Self::Disposition { .. } => FileDispositionInformation,
+            Self::Position { .. } => FilePositionInformation,
+            Self::Mode { .. } => FileModeInformation,
+            Self::Alignment { .. } => FileAlignmentInformation,
+            Self::All(_) => FileAllInformation,
+            Self::Allocation { .. } => FileAllocationInformation,
+            Self::EndOfFile { .. } => FileEndOfFileInformation,
+            Self::Stream(_) => FileStreamInformation,
+            Self::NetworkOpen(_) => FileNetworkOpenInformation,
+            Self::AttributeTag { .. } => FileAttributeTagInformation,
+            Self::NormalizedName { .. } => FileNormalizedNameInformation,
+            Self::DispositionEx { .. } => FileDispositionInformationEx,
+        }
+    }
+
+    pub fn parse(
+        class: FileInfoClass,
+        body: &[u8],
+    ) -> nom::IResult<&[u8], FileInfo, nom::error::Error<&[u8]>> {
+        use FileInfoClass::*;
+        Ok(match class {
+            FileBasicInformation => {
+                let (remaining, basic) = self::FileBasicInformation::parse(body)?;
+                (remaining, Self::Basic(basic))
+            }
+            FileStandardInformation => {
+                let (remaining, standard) = self::FileStandardInformation::parse(body)?;
+                (remaining, Self::Standard(standard))
+            }
+            FileInternalInformation => {
+                let (remaining, index_number) = c_u64("Failed to get index number", body)?;
+                (remaining, Self::Internal { index_number })
+            }
+            FileEaInformation => {
+                let (remaining, ea_size) = c_u32("Failed to get EA size", body)?;
+                (remaining, Self::Ea { ea_size })
+            }
+            FileAccessInformation => {
+                let (remaining, access_flags) = c_u32("Failed to get access flags", body)?;
+                (remaining, Self::Access { access_flags })
+            }
+            FileRenameInformation | FileLinkInformation => {
+                let (remaining, replace_if_exists) = le_u8(body)?;
+                let (remaining, _reserved) = take(7usize)(remaining)?;
+                let (remaining, root_directory) = c_u64("Failed to get root directory", remaining)?;
+                let (remaining, file_name) = parse_file_name(remaining)?;
+                let replace_if_exists = replace_if_exists != 0;
+                (
+                    remaining,
+                    if class == FileRenameInformation {
+                        Self::Rename {
+                            replace_if_exists,
+                            root_directory,
+                            file_name,
+                        }
+                    } else {
+                        Self::Link {
+                            replace_if_exists,
+                            root_directory,
+                            file_name,
+                        }
+                    },
+                )
+            }
+            FileDispositionInformation => {
+                let (remaining, delete_pending) = le_u8(body)?;
+                (
+                    remaining,
+                    Self::Disposition {
+                        delete_pending: delete_pending != 0,
+                    },
+                )
+            }
+            FilePositionInformation => {
+                let (remaining, current_byte_offset) = c_u64("Failed to get byte offset", body)?;
+                (
+                    remaining,
+                    Self::Position {
+                        current_byte_offset,
+                    },
+                )
+            }
+            FileModeInformation => {
+                let (remaining, mode) = c_u32("Failed to get mode", body)?;
+                (remaining, Self::Mode { mode })
+            }
+            FileAlignmentInformation => {
+                let (remaining, alignment_requirement) =
+                    c_u32("Failed to get alignment requirement", body)?;
+                (
+                    remaining,
+                    Self::Alignment {
+                        alignment_requirement,
+                    },
+                )
+            }
+            FileAllInformation => {
+                let (remaining, basic) = self::FileBasicInformation::parse(body)?;
+                let (remaining, standard) = self::FileStandardInformation::parse(remaining)?;
+                let (remaining, index_number) = c_u64("Failed to get index number", remaining)?;
+                let (remaining, ea_size) = c_u32("Failed to get EA size", remaining)?;
+                let (remaining, access_flags) = c_u32("Failed to get access flags", remaining)?;
+                let (remaining, current_byte_offset) =
+                    c_u64("Failed to get byte offset", remaining)?;
+                let (remaining, mode) = c_u32("Failed to get mode", remaining)?;
+                let (remaining, alignment_requirement) =
+                    c_u32("Failed to get alignment requirement", remaining)?;
+                let (remaining, file_name) = parse_file_name(remaining)?;
+                (
+                    remaining,
+                    Self::All(self::FileAllInformation {
+                        basic,
+                        standard,
+                        index_number,
+                        ea_size,
+                        access_flags,
+                        current_byte_offset,
+                        mode,
+                        alignment_requirement,
+                        file_name,
+                    }),
+                )
+            }
+            FileAllocationInformation => {
+                let (remaining, allocation_size) = c_u64("Failed to get allocation size", body)?;
+                (remaining, Self::Allocation { allocation_size })
+            }
+            FileEndOfFileInformation => {
+                let (remaining, end_of_file) = c_u64("Failed to get end of file", body)?;
+                (remaining, Self::EndOfFile { end_of_file })
+            }
+            FileStreamInformation => (&[] as &[u8], Self::Stream(parse_streams(body)?.1)),
+            FileNetworkOpenInformation => {
+                let (remaining, creation_time) = c_u64("Failed to get creation time", body)?;
+                let (remaining, last_access_time) =
+                    c_u64("Failed to get last access time", remaining)?;
+                let (remaining, last_write_time) =
+                    c_u64("Failed to get last write time", remaining)?;
+                let (remaining, change_time) = c_u64("Failed to get change time", remaining)?;
+                let (remaining, allocation_size) =
+                    c_u64("Failed to get allocation size", remaining)?;
+                let (remaining, end_of_file) = c_u64("Failed to get end of file", remaining)?;
+                let (remaining, file_attributes) =
+                    c_u32("Failed to get file attributes", remaining)?;
+                let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+                (
+                    remaining,
+                    Self::NetworkOpen(self::FileNetworkOpenInformation {
+                        creation_time,
+                        last_access_time,
+                        last_write_time,
+                        change_time,
+                        allocation_size,
+                        end_of_file,
+                        file_attributes,
+                    }),
+                )
+            }
+            FileAttributeTagInformation => {
+                let (remaining, file_attributes) = c_u32("Failed to get file attributes", body)?;
+                let (remaining, reparse_tag) = c_u32("Failed to get reparse tag", remaining)?;
+                (
+                    remaining,
+                    Self::AttributeTag {
+                        file_attributes,
+                        reparse_tag,
+                    },
+                )
+            }
+            FileNormalizedNameInformation => {
+                let (remaining, file_name) = parse_file_name(body)?;
+                (remaining, Self::NormalizedName { file_name })
+            }
+            FileDispositionInformationEx => {
+                let (remaining, flags) = c_u32("Failed to get disposition flags", body)?;
+                (remaining, Self::DispositionEx { flags })
+            }
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Self::Basic(basic) => basic.encode(&mut out),
+            Self::Standard(standard) => standard.encode(&mut out),
+            Self::Internal { index_number } => out.extend(index_number.to_le_bytes()),
+            Self::Ea { ea_size } => out.extend(ea_size.to_le_bytes()),
+            Self::Access { access_flags } => out.extend(access_flags.to_le_bytes()),
+            Self::Rename {
+                replace_if_exists,
+                root_directory,
+                file_name,
+            }
+            | Self::Link {
+                replace_if_exists,
+                root_directory,
+                file_name,
+            } => {
+                out.push(*replace_if_exists as u8);
+                out.extend([0; 7]);
+                out.extend(root_directory.to_le_bytes());
+                encode_file_name(&mut out, file_name);
+            }
+            Self::Disposition { delete_pending } => out.push(*delete_pending as u8),
+            Self::Position {
+                current_byte_offset,
+            } => out.extend(current_byte_offset.to_le_bytes()),
+            Self::Mode { mode } => out.extend(mode.to_le_bytes()),
+            Self::Alignment {
+                alignment_requirement,
+            } => out.extend(alignment_requirement.to_le_bytes()),
+            Self::All(all) => {
+                all.basic.encode(&mut out);
+                all.standard.encode(&mut out);
+                out.extend(all.index_number.to_le_bytes());
+                out.extend(all.ea_size.to_le_bytes());
+                out.extend(all.access_flags.to_le_bytes());
+                out.extend(all.current_byte_offset.to_le_bytes());
+                out.extend(all.mode.to_le_bytes());
+                out.extend(all.alignment_requirement.to_le_bytes());
+                encode_file_name(&mut out, &all.file_name);
+            }
+            Self::Allocation { allocation_size } => out.extend(allocation_size.to_le_bytes()),
+            Self::EndOfFile { end_of_file } => out.extend(end_of_file.to_le_bytes()),
+            Self::Stream(streams) => {
+                for (i, stream) in streams.iter().enumerate() {
+                    let start = out.len();
+                    let stream_name = encode_utf16le(&stream.stream_name);
+                    out.extend(0u32.to_le_bytes());
+                    out.extend((stream_name.len() as u32).to_le_bytes());
+                    out.extend(stream.stream_size.to_le_bytes());
+                    out.extend(stream.stream_allocation_size.to_le_bytes());
+                    out.extend(stream_name);
+                    if i + 1 != streams.len() {
+                        // every entry starts 8 byte aligned.
+                        let next = (out.len() - start).next_multiple_of(8);
+                        pad_to(&mut out, start + next);
+                        out[start..start + 4].copy_from_slice(&(next as u32).to_le_bytes());
+                    }
+                }
+            }
+            Self::NetworkOpen(network_open) => {
+                out.extend(network_open.creation_time.to_le_bytes());
+                out.extend(network_open.last_access_time.to_le_bytes());
+                out.extend(network_open.last_write_time.to_le_bytes());
+                out.extend(network_open.change_time.to_le_bytes());
+                out.extend(network_open.allocation_size.to_le_bytes());
+                out.extend(network_open.end_of_file.to_le_bytes());
+                out.extend(network_open.file_attributes.to_le_bytes());
+                out.extend([0; 4]);
+            }
+            Self::AttributeTag {
+                file_attributes,
+                reparse_tag,
+            } => {
+                out.extend(file_attributes.to_le_bytes());
+                out.extend(reparse_tag.to_le_bytes());
+            }
+            Self::NormalizedName { file_name } => encode_file_name(&mut out, file_name),
+            Self::DispositionEx { flags } => out.extend(flags.to_le_bytes()),
+        }
+        out
+    }
+}
+
+/// A FileNameLength followed by that many bytes of name.
+fn parse_file_name(body: &[u8]) -> nom::IResult<&[u8], String, nom::error::Error<&[u8]>> {
+    let (remaining, file_name_length) = c_u32("Failed to get file name length", body)?;
+    let (remaining, file_name) = take(file_name_length)(remaining)?;
+    let (_, file_name) = parse_utf16le("Failed to decode file name", file_name)?;
+    Ok((remaining, file_name))
+}
+
+fn encode_file_name(out: &mut Vec<u8>, file_name: &str) {
+    let file_name = encode_utf16le(file_name);
+    out.extend((file_name.len() as u32).to_le_bytes());
+    out.extend(file_name);
+}
+
+fn parse_streams(
+    body: &[u8],
+) -> nom::IResult<&[u8], Vec<FileStreamEntry>, nom::error::Error<&[u8]>> {
+    let mut streams = vec![];
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (remaining, next_entry_offset) = c_u32("Failed to get next entry offset", rest)?;
+        let (remaining, stream_name_length) = c_u32("Failed to get stream name length", remaining)?;
+        let (remaining, stream_size) = c_u64("Failed to get stream size", remaining)?;
+        let (remaining, stream_allocation_size) =
+            c_u64("Failed to get stream allocation size", remaining)?;
+        let (_, stream_name) = take(stream_name_length)(remaining)?;
+        let (_, stream_name) = parse_utf16le("Failed to decode stream name", stream_name)?;
+        streams.push(FileStreamEntry {
+            stream_name,
+            stream_size,
+            stream_allocation_size,
+        });
+        if next_entry_offset == 0 {
+            break;
+        }
+        rest = match rest.get(next_entry_offset as usize..) {
+            Some(rest) => rest,
+            None => return fail(rest),
+        };
+    }
+    Ok((&[], streams))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_file_basic_information() {
+        #[rustfmt::skip]
+        let file_basic_information = [
+            // creation time
+            0x00, 0x80, 0x3E, 0xD5, 0xDE, 0xB1, 0x9D, 0x01,
+            // last access time
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // last write time
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // change time
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // attributes             | reserved
+            0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (_, file_info) =
+            FileInfo::parse(FileInfoClass::FileBasicInformation, &file_basic_information).unwrap();
+        assert_eq!(
+            file_info,
+            FileInfo::Basic(FileBasicInformation {
+                creation_time: 0x019DB1DED53E8000,
+                last_access_time: 1,
+                last_write_time: 2,
+                change_time: 3,
+                file_attributes: 0x20,
+            })
+        );
+        assert_eq!(file_info.to_vec(), file_basic_information);
+    }
+
+    #[test]
+    fn example_file_rename_information() {
+        #[rustfmt::skip]
+        let file_rename_information = [
+            // replace | reserved
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // root directory
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // name length
+            0x0A, 0x00, 0x00, 0x00,
+            // a\b.c
+            b'a', 0x00, b'\\', 0x00, b'b', 0x00, b'.', 0x00, b'c', 0x00,
+        ];
+        let (_, file_info) = FileInfo::parse(
+            FileInfoClass::FileRenameInformation,
+            &file_rename_information,
+        )
+        .unwrap();
+        assert_eq!(
+            file_info,
+            FileInfo::Rename {
+                replace_if_exists: true,
+                root_directory: 0,
+                file_name: "a\\b.c".into(),
+            }
+        );
+        assert_eq!(file_info.to_vec(), file_rename_information);
+    }
+
+    #[test]
+    fn variable_length_classes_round_trip() {
+        let infos = [
+            FileInfo::All(FileAllInformation {
+                basic: FileBasicInformation {
+                    creation_time: 10,
+                    file_attributes: 0x10,
+                    ..Default::default()
+                },
+                standard: FileStandardInformation {
+                    number_of_links: 1,
+                    directory: true,
+                    ..Default::default()
+                },
+                index_number: 1234,
+                access_flags: 0x001F01FF,
+                file_name: "\\dir".into(),
+                ..Default::default()
+            }),
+            FileInfo::Stream(vec![
+                FileStreamEntry {
+                    stream_name: "::$DATA".into(),
+                    stream_size: 5,
+                    stream_allocation_size: 4096,
+                },
+                FileStreamEntry {
+                    stream_name: ":extra:$DATA".into(),
+                    stream_size: 1,
+                    stream_allocation_size: 8,
+                },
+            ]),
+            FileInfo::NormalizedName {
+                file_name: "dir\\file".into(),
+            },
+        ];
+        for info in infos {
+            let encoded = info.to_vec();
+            let (_, parsed) = FileInfo::parse(info.class(), &encoded).unwrap();
+            assert_eq!(parsed, info);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use nom::bytes::complete::take;
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u128, c_u32, c_u64, encode_utf16le, parse_utf16le};
+
+/// The FsInformationClass values QUERY_INFO understands for SMB2_0_INFO_FILESYSTEM.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FsInfoClass {
+    FileFsVolumeInformation = 0x01,
+    FileFsSizeInformation = 0x03,
+    FileFsDeviceInformation = 0x04,
+    FileFsAttributeInformation = 0x05,
+    FileFsFullSizeInformation = 0x07,
+    FileFsObjectIdInformation = 0x08,
+    FileFsSectorSizeInformation = 0x0B,
+}
+
+#[derive(Debug)]
+pub struct InvalidFsInfoClass;
+
+impl TryFrom<u8> for FsInfoClass {
+    type Error = InvalidFsInfoClass;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::FileFsVolumeInformation),
+            0x03 => Ok(Self::FileFsSizeInformation),
+            0x04 => Ok(Self::FileFsDeviceInformation),
+            0x05 => Ok(Self::FileFsAttributeInformation),
+            0x07 => Ok(Self::FileFsFullSizeInformation),
+            0x08 => Ok(Self::FileFsObjectIdInformation),
+            0x0B => Ok(Self::FileFsSectorSizeInformation),
+            _ => Err(InvalidFsInfoClass),
+        }
+    }
+}
+
+pub const FILE_DEVICE_DISK: u32 = 0x0000_0007;
+
+pub const FILE_CASE_SENSITIVE_SEARCH: u32 = 0x0000_0001;
+pub const FILE_CASE_PRESERVED_NAMES: u32 = 0x0000_0002;
+pub const FILE_UNICODE_ON_DISK: u32 = 0x0000_0004;
+pub const FILE_PERSISTENT_ACLS: u32 = 0x0000_0008;
+pub const FILE_SUPPORTS_SPARSE_FILES: u32 = 0x0000_0040;
+pub const FILE_SUPPORTS_REPARSE_POINTS: u32 = 0x0000_0080;
+pub const FILE_SUPPORTS_HARD_LINKS: u32 = 0x0040_0000;
+
+pub const SSINFO_FLAGS_ALIGNED_DEVICE: u32 = 0x0000_0001;
+pub const SSINFO_FLAGS_PARTITION_ALIGNED_ON_DEVICE: u32 = 0x0000_0002;
+pub const SSINFO_FLAGS_NO_SEEK_PENALTY: u32 = 0x0000_0004;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileFsSectorSizeInformation {
+    pub logical_bytes_per_sector: u32,
+    pub physical_bytes_per_sector_for_atomicity: u32,
+    pub physical_bytes_per_sector_for_performance: u32,
+    pub effective_physical_bytes_per_sector_for_atomicity: u32,
+    pub flags: u32,
+    pub byte_offset_for_sector_alignment: u32,
+    pub byte_offset_for_partition_alignment: u32,
+}
+
+/// The filesystem information classes, about the volume a share lives on.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FsInfo {
+    Volume {
+        volume_creation_time: u64,
+        volume_serial_number: u32,
+        supports_objects: bool,
+        volume_label: String,
+    },
+    Size {
+        total_allocation_units: u64,
+        available_allocation_units: u64,
+        sectors_per_allocation_unit: u32,
+        bytes_per_sector: u32,
+    },
+    Device {
+        device_type: u32,
+        characteristics: u32,
+    },
+    Attribute {
+        file_system_attributes: u32,
+        maximum_component_name_length: u32,
+        file_system_name: String,
+    },
+    FullSize {
+        total_allocation_units: u64,
+        caller_available_allocation_units: u64,
+        actual_available_allocation_units: u64,
+        sectors_per_allocation_unit: u32,
+        bytes_per_sector: u32,
+    },
+    ObjectId {
+        object_id: u128,
+        extended_info: [u8; 48],
+    },
+    SectorSize(FileFsSectorSizeInformation),
+}
+
+impl FsInfo {
+    pub fn class(&self) -> FsInfoClass {
+        use FsInfoClass::*;
+        match self {
+            Self::Volume { .. } => FileFsVolumeInformation,
+            Self::Size { .. } => FileFsSizeInformation,
+            Self::Device { .. } => FileFsDeviceInformation,
+            Self::Attribute { .. } => FileFsAttributeInformation,
+            Self::FullSize { .. } => FileFsFullSizeInformation,
+            Self::ObjectId { .. } => FileFsObjectIdInformation,
+            Self::SectorSize(_) => FileFsSectorSizeInformation,
+        }
+    }
+
+    pub fn parse(
+        class: FsInfoClass,
+        body: &[u8],
+    ) -> nom::IResult<&[u8], FsInfo, nom::error::Error<&[u8]>> {
+        use FsInfoClass::*;
+        Ok(match class {
+            FileFsVolumeInformation => {
+                let (remaining, volume_creation_time) =
+                    c_u64("Failed to get volume creation time", body)?;
+                let (remaining, volume_serial_number) =
+                    c_u32("Failed to get volume serial number", remaining)?;
+                let (remaining, volume_label_length) =
+                    c_u32("Failed to get volume label length", remaining)?;
+                let (remaining, supports_objects) = le_u8(remaining)?;
+                let (remaining, _reserved) = le_u8(remaining)?;
+                let (remaining, volume_label) = take(volume_label_length)(remaining)?;
+                let (_, volume_label) =
+                    parse_utf16le("Failed to decode volume label", volume_label)?;
+                (
+                    remaining,
+                    Self::Volume {
+                        volume_creation_time,
+                        volume_serial_number,
+                        supports_objects: supports_objects != 0,
+                        volume_label,
+                    },
+                )
+            }
+            FileFsSizeInformation => {
+                let (remaining, total_allocation_units) =
+                    c_u64("Failed to get total allocation units", body)?;
+                let (remaining, available_allocation_units) =
+                    c_u64("Failed to get available allocation units", remaining)?;
+                let (remaining, sectors_per_allocation_unit) =
+                    c_u32("Failed to get sectors per allocation unit", remaining)?;
+                let (remaining, bytes_per_sector) =
+                    c_u32("Failed to get bytes per sector", remaining)?;
+                (
+                    remaining,
+                    Self::Size {
+                        total_allocation_units,
+                        available_allocation_units,
+                        sectors_per_allocation_unit,
+                        bytes_per_sector,
+                    },
+                )
+            }
+            FileFsDeviceInformation => {
+                let (remaining, device_type) = c_u32("Failed to get device type", body)?;
+                let (remaining, characteristics) =
+                    c_u32("Failed to get characteristics", remaining)?;
+                (
+                    remaining,
+                    Self::Device {
+                        device_type,
+                        characteristics,
+                    },
+                )
+            }
+            FileFsAttributeInformation => {
+                let (remaining, file_system_attributes) =
+                    c_u32("Failed to get file system attributes", body)?;
+                let (remaining, maximum_component_name_length) =
+                    c_u32("Failed to get maximum component name length", remaining)?;
+                let (remaining, file_system_name_length) =
+                    c_u32("Failed to get file system name length", remaining)?;
+                let (remaining, file_system_name) = take(file_system_name_length)(remaining)?;
+                let (_, file_system_name) =
+                    parse_utf16le("Failed to decode file system name", file_system_name)?;
+                (
+                    remaining,
+                    Self::Attribute {
+                        file_system_attributes,
+                        maximum_component_name_length,
+                        file_system_name,
+                    },
+                )
+            }
+            FileFsFullSizeInformation => {
+                let (remaining, total_allocation_units) =
+                    c_u64("Failed to get total allocation units", body)?;
+                let (remaining, caller_available_allocation_units) =
+                    c_u64("Failed to get caller available allocation units", remaining)?;
+                let (remaining, actual_available_allocation_units) =
+                    c_u64("Failed to get actual available allocation units", remaining)?;
+                let (remaining, sectors_per_allocation_unit) =
+                    c_u32("Failed to get sectors per allocation unit", remaining)?;
+                let (remaining, bytes_per_sector) =
+                    c_u32("Failed to get bytes per sector", remaining)?;
+                (
+                    remaining,
+                    Self::FullSize {
+                        total_allocation_units,
+                        caller_available_allocation_units,
+                        actual_available_allocation_units,
+                        sectors_per_allocation_unit,
+                        bytes_per_sector,
+                    },
+                )
+            }
+            FileFsObjectIdInformation => {
+                let (remaining, object_id) = c_u128("Failed to get object id", body)?;
+                let (remaining, extended_info) = take(48usize)(remaining)?;
+                (
+                    remaining,
+                    Self::ObjectId {
+                        object_id,
+                        extended_info: extended_info.try_into().unwrap(),
+                    },
+                )
+            }
+            FileFsSectorSizeInformation => {
+                let (remaining, logical_bytes_per_sector) =
+                    c_u32("Failed to get logical bytes per sector", body)?;
+                let (remaining, physical_bytes_per_sector_for_atomicity) =
+                    c_u32("Failed to get physical bytes per sector", remaining)?;
+                let (remaining, physical_bytes_per_sector_for_performance) =
+                    c_u32("Failed to get physical bytes per sector", remaining)?;
+                let (remaining, effective_physical_bytes_per_sector_for_atomicity) =
+                    c_u32("Failed to get physical bytes per sector", remaining)?;
+                let (remaining, flags) = c_u32("Failed to get sector size flags", remaining)?;
+                let (remaining, byte_offset_for_sector_alignment) =
+                    c_u32("Failed to get sector alignment", remaining)?;
+                let (remaining, byte_offset_for_partition_alignment) =
+                    c_u32("Failed to get partition alignment", remaining)?;
+                (
+                    remaining,
+                    Self::SectorSize(self::FileFsSectorSizeInformation {
+                        logical_bytes_per_sector,
+                        physical_bytes_per_sector_for_atomicity,
+                        physical_bytes_per_sector_for_performance,
+                        effective_physical_bytes_per_sector_for_atomicity,
+                        flags,
+                        byte_offset_for_sector_alignment,
+                        byte_offset_for_partition_alignment,
+                    }),
+                )
+            }
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Self::Volume {
+                volume_creation_time,
+                volume_serial_number,
+                supports_objects,
+                volume_label,
+            } => {
+                let volume_label = encode_utf16le(volume_label);
+                out.extend(volume_creation_time.to_le_bytes());
+                out.extend(volume_serial_number.to_le_bytes());
+                out.extend((volume_label.len() as u32).to_le_bytes());
+                out.push(*supports_objects as u8);
+                out.push(0);
+                out.extend(volume_label);
+            }
+            Self::Size {
+                total_allocation_units,
+                available_allocation_units,
+                sectors_per_allocation_unit,
+                bytes_per_sector,
+            } => {
+                out.extend(total_allocation_units.to_le_bytes());
+                out.extend(available_allocation_units.to_le_bytes());
+                out.extend(sectors_per_allocation_unit.to_le_bytes());
+                out.extend(bytes_per_sector.to_le_bytes());
+            }
+            Self::Device {
+                device_type,
+                characteristics,
+            } => {
+                out.extend(device_type.to_le_bytes());
+                out.extend(characteristics.to_le_bytes());
+            }
+            Self::Attribute {
+                file_system_attributes,
+                maximum_component_name_length,
+                file_system_name,
+            } => {
+                let file_system_name = encode_utf16le(file_system_name);
+                out.extend(file_system_attributes.to_le_bytes());
+                out.extend(maximum_component_name_length.to_le_bytes());
+                out.extend((file_system_name.len() as u32).to_le_bytes());
+                out.extend(file_system_name);
+            }
+            Self::FullSize {
+                total_allocation_units,
+                caller_available_allocation_units,
+                actual_available_allocation_units,
+                sectors_per_allocation_unit,
+                bytes_per_sector,
+            } => {
+                out.extend(total_allocation_units.to_le_bytes());
+                out.extend(caller_available_allocation_units.to_le_bytes());
+                out.extend(actual_available_allocation_units.to_le_bytes());
+                out.extend(sectors_per_allocation_unit.to_le_bytes());
+                out.extend(bytes_per_sector.to_le_bytes());
+            }
+            Self::ObjectId {
+                object_id,
+                extended_info,
+            } => {
+                out.extend(object_id.to_le_bytes());
+                out.extend(extended_info);
+            }
+            Self::SectorSize(sector_size) => {
+                out.extend(sector_size.logical_bytes_per_sector.to_le_bytes());
+                out.extend(
+                    sector_size
+                        .physical_bytes_per_sector_for_atomicity
+                        .to_le_bytes(),
+                );
+                out.extend(
+                    sector_size
+                        .physical_bytes_per_sector_for_performance
+                        .to_le_bytes(),
+                );
+                out.extend(
+                    sector_size
+                        .effective_physical_bytes_per_sector_for_atomicity
+                        .to_le_bytes(),
+                );
+                out.extend(sector_size.flags.to_le_bytes());
+                out.extend(sector_size.byte_offset_for_sector_alignment.to_le_bytes());
+                out.extend(
+                    sector_size
+                        .byte_offset_for_partition_alignment
+                        .to_le_bytes(),
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_fs_attribute_information() {
+        #[rustfmt::skip]
+        let fs_attribute_information = [
+            // attributes
+            0x47, 0x00, 0x00, 0x00,
+            // max component length
+            0xFF, 0x00, 0x00, 0x00,
+            // name length
+            0x08, 0x00, 0x00, 0x00,
+            // NTFS
+            b'N', 0x00, b'T', 0x00, b'F', 0x00, b'S', 0x00,
+        ];
+        let (_, fs_info) = FsInfo::parse(
+            FsInfoClass::FileFsAttributeInformation,
+            &fs_attribute_information,
+        )
+        .unwrap();
+        assert_eq!(
+            fs_info,
+            FsInfo::Attribute {
+                file_system_attributes: FILE_CASE_SENSITIVE_SEARCH
+                    | FILE_CASE_PRESERVED_NAMES
+                    | FILE_UNICODE_ON_DISK
+                    | FILE_SUPPORTS_SPARSE_FILES,
+                maximum_component_name_length: 255,
+                file_system_name: "NTFS".into(),
+            }
+        );
+        assert_eq!(fs_info.to_vec(), fs_attribute_information);
+    }
+
+    #[test]
+    fn every_class_round_trips() {
+        let infos = [
+            FsInfo::Volume {
+                volume_creation_time: 5,
+                volume_serial_number: 0xDEADBEEF,
+                supports_objects: false,
+                volume_label: "share".into(),
+            },
+            FsInfo::Size {
+                total_allocation_units: 100,
+                available_allocation_units: 50,
+                sectors_per_allocation_unit: 8,
+                bytes_per_sector: 512,
+            },
+            FsInfo::Device {
+                device_type: FILE_DEVICE_DISK,
+                characteristics: 0,
+            },
+            FsInfo::FullSize {
+                total_allocation_units: 100,
+                caller_available_allocation_units: 40,
+                actual_available_allocation_units: 50,
+                sectors_per_allocation_unit: 8,
+                bytes_per_sector: 512,
+            },
+            FsInfo::ObjectId {
+                object_id: 42,
+                extended_info: [0; 48],
+            },
+            FsInfo::SectorSize(FileFsSectorSizeInformation {
+                logical_bytes_per_sector: 512,
+                physical_bytes_per_sector_for_atomicity: 4096,
+                physical_bytes_per_sector_for_performance: 4096,
+                effective_physical_bytes_per_sector_for_atomicity: 4096,
+                flags: SSINFO_FLAGS_ALIGNED_DEVICE,
+                byte_offset_for_sector_alignment: 0,
+                byte_offset_for_partition_alignment: 0,
+            }),
+        ];
+        for info in infos {
+            let (_, parsed) = FsInfo::parse(info.class(), &info.to_vec()).unwrap();
+            assert_eq!(parsed, info);
+        }
+    }
+}
@@ -0,0 +1,571 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use nom::bytes::complete::take;
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::multi::count;
+
+use crate::message::{c_u128, c_u16, c_u32, c_u64, encode_utf16le, fail, parse_utf16le};
+use crate::message::{Capabilities, Dialect, SecurityMode};
+
+// the control codes IOCTL knows about.
+pub const FSCTL_GET_REPARSE_POINT: u32 = 0x0009_00A8;
+pub const FSCTL_SET_SPARSE: u32 = 0x0009_00C4;
+pub const FSCTL_QUERY_ALLOCATED_RANGES: u32 = 0x0009_40CF;
+pub const FSCTL_SET_ZERO_DATA: u32 = 0x0009_80C8;
+pub const FSCTL_PIPE_TRANSCEIVE: u32 = 0x0011_C017;
+pub const FSCTL_SRV_REQUEST_RESUME_KEY: u32 = 0x0014_0078;
+pub const FSCTL_QUERY_NETWORK_INTERFACE_INFO: u32 = 0x0014_01FC;
+pub const FSCTL_VALIDATE_NEGOTIATE_INFO: u32 = 0x0014_0204;
+pub const FSCTL_SRV_COPYCHUNK: u32 = 0x0014_40F2;
+pub const FSCTL_SRV_COPYCHUNK_WRITE: u32 = 0x0014_80F2;
+
+/// FSCTL_VALIDATE_NEGOTIATE_INFO's input: what the client
+/// thinks it said in its NEGOTIATE.
+#[derive(Debug, PartialEq)]
+pub struct ValidateNegotiateInfo {
+    pub capabilities: Capabilities,
+    pub guid: u128,
+    pub security_mode: SecurityMode,
+    /// Like NEGOTIATE's, only the dialects we know of.
+    pub dialects: Vec<Dialect>,
+}
+
+impl ValidateNegotiateInfo {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], ValidateNegotiateInfo, nom::error::Error<&[u8]>> {
+        let (remaining, capabilities) = c_u32("Failed to get capabilities", body)?;
+        let (remaining, guid) = c_u128("Failed to get guid", remaining)?;
+        let (remaining, security_mode) = c_u16("Failed to get security mode", remaining)?;
+        let (remaining, dialect_count) = c_u16("Failed to get dialect count", remaining)?;
+        let (remaining, dialects) = count(
+            |body| c_u16("Failed to get dialect", body),
+            dialect_count as _,
+        )(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                capabilities: Capabilities::from_bits_retain(capabilities),
+                guid,
+                security_mode: SecurityMode::from_bits_retain(security_mode),
+                dialects: dialects
+                    .into_iter()
+                    .filter_map(|dialect| Dialect::try_from(dialect).ok())
+                    .collect(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + 2 * self.dialects.len());
+        out.extend(self.capabilities.bits().to_le_bytes());
+        out.extend(self.guid.to_le_bytes());
+        out.extend(self.security_mode.bits().to_le_bytes());
+        out.extend((self.dialects.len() as u16).to_le_bytes());
+        for &dialect in &self.dialects {
+            out.extend((dialect as u16).to_le_bytes());
+        }
+        out
+    }
+}
+
+/// ...and what the server actually said back.
+#[derive(Debug, PartialEq)]
+pub struct ValidateNegotiateInfoResponse {
+    pub capabilities: Capabilities,
+    pub guid: u128,
+    pub security_mode: SecurityMode,
+    pub dialect: Dialect,
+}
+
+impl ValidateNegotiateInfoResponse {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], ValidateNegotiateInfoResponse, nom::error::Error<&[u8]>> {
+        let (remaining, capabilities) = c_u32("Failed to get capabilities", body)?;
+        let (remaining, guid) = c_u128("Failed to get guid", remaining)?;
+        let (remaining, security_mode) = c_u16("Failed to get security mode", remaining)?;
+        let (remaining, dialect) = context(
+            "Invalid dialect",
+            map_res(
+                |body| c_u16("Failed to get dialect", body),
+                Dialect::try_from,
+            ),
+        )(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                capabilities: Capabilities::from_bits_retain(capabilities),
+                guid,
+                security_mode: SecurityMode::from_bits_retain(security_mode),
+                dialect,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend(self.capabilities.bits().to_le_bytes());
+        out.extend(self.guid.to_le_bytes());
+        out.extend(self.security_mode.bits().to_le_bytes());
+        out.extend((self.dialect as u16).to_le_bytes());
+        out
+    }
+}
+
+pub const RSS_CAPABLE: u32 = 0x0000_0001;
+pub const RDMA_CAPABLE: u32 = 0x0000_0002;
+
+// address families as Windows numbers them.
+const AF_INET: u16 = 0x0002;
+const AF_INET6: u16 = 0x0017;
+
+/// One entry of the FSCTL_QUERY_NETWORK_INTERFACE_INFO output.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NetworkInterfaceInfo {
+    pub if_index: u32,
+    pub capability: u32,
+    /// In bits per second.
+    pub link_speed: u64,
+    pub address: SocketAddr,
+}
+
+impl NetworkInterfaceInfo {
+    // the fixed part, then a 128 byte SOCKADDR_STORAGE.
+    const SIZE: usize = 24 + 128;
+
+    /// Encodes `interfaces` as the chained list the client expects.
+    pub fn list_to_vec(interfaces: &[NetworkInterfaceInfo]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE * interfaces.len());
+        for (i, interface) in interfaces.iter().enumerate() {
+            let next = if i + 1 == interfaces.len() {
+                0
+            } else {
+                Self::SIZE as u32
+            };
+            out.extend(next.to_le_bytes());
+            out.extend(interface.if_index.to_le_bytes());
+            out.extend(interface.capability.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(interface.link_speed.to_le_bytes());
+            let start = out.len();
+            match interface.address {
+                SocketAddr::V4(address) => {
+                    out.extend(AF_INET.to_le_bytes());
+                    out.extend(address.port().to_be_bytes());
+                    out.extend(address.ip().octets());
+                }
+                SocketAddr::V6(address) => {
+                    out.extend(AF_INET6.to_le_bytes());
+                    out.extend(address.port().to_be_bytes());
+                    out.extend(address.flowinfo().to_be_bytes());
+                    out.extend(address.ip().octets());
+                    out.extend(address.scope_id().to_le_bytes());
+                }
+            }
+            out.resize(start + 128, 0);
+        }
+        out
+    }
+
+    pub fn parse_list(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], Vec<NetworkInterfaceInfo>, nom::error::Error<&[u8]>> {
+        let mut interfaces = vec![];
+        let mut entry = body;
+        loop {
+            let (remaining, next) = c_u32("Failed to get next", entry)?;
+            let (remaining, if_index) = c_u32("Failed to get interface index", remaining)?;
+            let (remaining, capability) = c_u32("Failed to get capability", remaining)?;
+            let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+            let (remaining, link_speed) = c_u64("Failed to get link speed", remaining)?;
+            let (remaining, sockaddr) = take(128usize)(remaining)?;
+            let (sockaddr, family) = c_u16("Failed to get address family", sockaddr)?;
+            let port = u16::from_be_bytes([sockaddr[0], sockaddr[1]]);
+            let ip = match family {
+                AF_INET => IpAddr::V4(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(&sockaddr[2..6]).unwrap(),
+                )),
+                AF_INET6 => IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&sockaddr[6..22]).unwrap(),
+                )),
+                _ => return fail(sockaddr),
+            };
+            interfaces.push(Self {
+                if_index,
+                capability,
+                link_speed,
+                address: SocketAddr::new(ip, port),
+            });
+            if next == 0 {
+                return Ok((remaining, interfaces));
+            }
+            let Some(next_entry) = entry.get(next as usize..) else {
+                return fail(entry);
+            };
+            entry = next_entry;
+        }
+    }
+}
+
+/// An opaque key naming an open, handed out by FSCTL_SRV_REQUEST_RESUME_KEY
+/// so the client can later use that open as a copychunk source.
+pub type ResumeKey = [u8; 24];
+
+#[derive(Debug, PartialEq)]
+pub struct SrvRequestResumeKey {
+    pub resume_key: ResumeKey,
+}
+
+impl SrvRequestResumeKey {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SrvRequestResumeKey, nom::error::Error<&[u8]>> {
+        let (remaining, resume_key) = take(24usize)(body)?;
+        let (remaining, context_length) = c_u32("Failed to get context length", remaining)?;
+        let (remaining, _context) = take(context_length)(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                resume_key: resume_key.try_into().unwrap(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(28);
+        out.extend(self.resume_key);
+        // no context.
+        out.extend(0u32.to_le_bytes());
+        out
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SrvCopychunk {
+    pub source_offset: u64,
+    pub target_offset: u64,
+    pub length: u32,
+}
+
+/// FSCTL_SRV_COPYCHUNK(_WRITE)'s input.
+#[derive(Debug, PartialEq)]
+pub struct SrvCopychunkCopy {
+    pub source_key: ResumeKey,
+    pub chunks: Vec<SrvCopychunk>,
+}
+
+impl SrvCopychunkCopy {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SrvCopychunkCopy, nom::error::Error<&[u8]>> {
+        let (remaining, source_key) = take(24usize)(body)?;
+        let (remaining, chunk_count) = c_u32("Failed to get chunk count", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        let (remaining, chunks) = count(
+            |body| {
+                let (remaining, source_offset) = c_u64("Failed to get source offset", body)?;
+                let (remaining, target_offset) = c_u64("Failed to get target offset", remaining)?;
+                let (remaining, length) = c_u32("Failed to get length", remaining)?;
+                let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+                Ok((
+                    remaining,
+                    SrvCopychunk {
+                        source_offset,
+                        target_offset,
+                        length,
+                    },
+                ))
+            },
+            chunk_count as _,
+        )(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                source_key: source_key.try_into().unwrap(),
+                chunks,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + 24 * self.chunks.len());
+        out.extend(self.source_key);
+        out.extend((self.chunks.len() as u32).to_le_bytes());
+        out.extend([0; 4]);
+        for chunk in &self.chunks {
+            out.extend(chunk.source_offset.to_le_bytes());
+            out.extend(chunk.target_offset.to_le_bytes());
+            out.extend(chunk.length.to_le_bytes());
+            out.extend([0; 4]);
+        }
+        out
+    }
+}
+
+/// How far a copychunk got. When it fails with STATUS_INVALID_PARAMETER,
+/// these are the server's limits instead.
+#[derive(Debug, PartialEq, Default)]
+pub struct SrvCopychunkResponse {
+    pub chunks_written: u32,
+    pub chunk_bytes_written: u32,
+    pub total_bytes_written: u32,
+}
+
+impl SrvCopychunkResponse {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SrvCopychunkResponse, nom::error::Error<&[u8]>> {
+        let (remaining, chunks_written) = c_u32("Failed to get chunks written", body)?;
+        let (remaining, chunk_bytes_written) =
+            c_u32("Failed to get chunk bytes written", remaining)?;
+        let (remaining, total_bytes_written) =
+            c_u32("Failed to get total bytes written", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                chunks_written,
+                chunk_bytes_written,
+                total_bytes_written,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(12);
+        out.extend(self.chunks_written.to_le_bytes());
+        out.extend(self.chunk_bytes_written.to_le_bytes());
+        out.extend(self.total_bytes_written.to_le_bytes());
+        out
+    }
+}
+
+/// FSCTL_SET_ZERO_DATA's input: zero everything in `file_offset..beyond_final_zero`.
+#[derive(Debug, PartialEq)]
+pub struct FileZeroDataInformation {
+    pub file_offset: u64,
+    pub beyond_final_zero: u64,
+}
+
+impl FileZeroDataInformation {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], FileZeroDataInformation, nom::error::Error<&[u8]>> {
+        let (remaining, file_offset) = c_u64("Failed to get file offset", body)?;
+        let (remaining, beyond_final_zero) = c_u64("Failed to get beyond final zero", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                file_offset,
+                beyond_final_zero,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        out.extend(self.file_offset.to_le_bytes());
+        out.extend(self.beyond_final_zero.to_le_bytes());
+        out
+    }
+}
+
+/// A range of a file, used both for the range FSCTL_QUERY_ALLOCATED_RANGES
+/// should look at and the allocated ranges it finds there.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FileAllocatedRangeBuffer {
+    pub file_offset: u64,
+    pub length: u64,
+}
+
+impl FileAllocatedRangeBuffer {
+    pub const SIZE: usize = 16;
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], FileAllocatedRangeBuffer, nom::error::Error<&[u8]>> {
+        let (remaining, file_offset) = c_u64("Failed to get file offset", body)?;
+        let (remaining, length) = c_u64("Failed to get length", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                file_offset,
+                length,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE);
+        out.extend(self.file_offset.to_le_bytes());
+        out.extend(self.length.to_le_bytes());
+        out
+    }
+}
+
+pub const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+/// The substitute name is relative to the directory the link is in.
+pub const SYMLINK_FLAG_RELATIVE: u32 = 0x0000_0001;
+
+/// The reparse data FSCTL_GET_REPARSE_POINT hands back for a symlink.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SymbolicLinkReparseBuffer {
+    pub substitute_name: String,
+    pub print_name: String,
+    pub flags: u32,
+}
+
+impl SymbolicLinkReparseBuffer {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SymbolicLinkReparseBuffer, nom::error::Error<&[u8]>> {
+        let (remaining, reparse_tag) = c_u32("Failed to get reparse tag", body)?;
+        if reparse_tag != IO_REPARSE_TAG_SYMLINK {
+            return fail(body);
+        }
+        let (remaining, reparse_data_length) =
+            c_u16("Failed to get reparse data length", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, data) = take(reparse_data_length)(remaining)?;
+        let (path_buffer, substitute_name_offset) =
+            c_u16("Failed to get substitute name offset", data)?;
+        let (path_buffer, substitute_name_length) =
+            c_u16("Failed to get substitute name length", path_buffer)?;
+        let (path_buffer, print_name_offset) =
+            c_u16("Failed to get print name offset", path_buffer)?;
+        let (path_buffer, print_name_length) =
+            c_u16("Failed to get print name length", path_buffer)?;
+        let (path_buffer, flags) = c_u32("Failed to get flags", path_buffer)?;
+        let name = |offset: u16, length: u16| {
+            let (_, name) =
+                match path_buffer.get(offset as usize..offset as usize + length as usize) {
+                    Some(name) => parse_utf16le("Failed to get name", name)?,
+                    None => fail(path_buffer)?,
+                };
+            Ok(name)
+        };
+        Ok((
+            remaining,
+            Self {
+                substitute_name: name(substitute_name_offset, substitute_name_length)?,
+                print_name: name(print_name_offset, print_name_length)?,
+                flags,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let substitute_name = encode_utf16le(&self.substitute_name);
+        let print_name = encode_utf16le(&self.print_name);
+        let reparse_data_length = 12 + substitute_name.len() + print_name.len();
+        let mut out = Vec::with_capacity(8 + reparse_data_length);
+        out.extend(IO_REPARSE_TAG_SYMLINK.to_le_bytes());
+        out.extend((reparse_data_length as u16).to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(0u16.to_le_bytes());
+        out.extend((substitute_name.len() as u16).to_le_bytes());
+        out.extend((substitute_name.len() as u16).to_le_bytes());
+        out.extend((print_name.len() as u16).to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(substitute_name);
+        out.extend(print_name);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_negotiate_info() {
+        #[rustfmt::skip]
+        let input = [
+            // capabilities
+            0x7F, 0x00, 0x00, 0x00,
+            // guid
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // security mode | dialect count
+            0x01, 0x00, 0x02, 0x00,
+            // dialects
+            0x02, 0x02, 0x10, 0x02,
+        ];
+        let (_, info) = ValidateNegotiateInfo::parse(&input).unwrap();
+        assert_eq!(
+            info,
+            ValidateNegotiateInfo {
+                capabilities: Capabilities::from_bits_retain(0x7F),
+                guid: 1,
+                security_mode: SecurityMode::SIGNING_ENABLED,
+                dialects: vec![Dialect::Smb202, Dialect::Smb210],
+            }
+        );
+        assert_eq!(info.to_vec(), input);
+    }
+
+    #[test]
+    fn network_interfaces_round_trip() {
+        let interfaces = vec![
+            NetworkInterfaceInfo {
+                if_index: 1,
+                capability: RSS_CAPABLE,
+                link_speed: 1_000_000_000,
+                address: "192.168.1.2:445".parse().unwrap(),
+            },
+            NetworkInterfaceInfo {
+                if_index: 2,
+                capability: 0,
+                link_speed: 10_000_000_000,
+                address: "[fe80::1]:445".parse().unwrap(),
+            },
+        ];
+        let encoded = NetworkInterfaceInfo::list_to_vec(&interfaces);
+        assert_eq!(encoded.len(), 2 * 152);
+        assert_eq!(&encoded[24..30], [0x02, 0x00, 0x01, 0xBD, 192, 168]);
+        assert_eq!(
+            NetworkInterfaceInfo::parse_list(&encoded).unwrap().1,
+            interfaces
+        );
+    }
+
+    #[test]
+    fn copychunk_copy() {
+        let copy = SrvCopychunkCopy {
+            source_key: [3; 24],
+            chunks: vec![
+                SrvCopychunk {
+                    source_offset: 0,
+                    target_offset: 0x1000,
+                    length: 0x1000,
+                },
+                SrvCopychunk {
+                    source_offset: 0x1000,
+                    target_offset: 0,
+                    length: 12,
+                },
+            ],
+        };
+        let encoded = copy.to_vec();
+        assert_eq!(encoded.len(), 32 + 2 * 24);
+        assert_eq!(SrvCopychunkCopy::parse(&encoded).unwrap().1, copy);
+    }
+
+    #[test]
+    fn symlink_reparse_buffer() {
+        let symlink = SymbolicLinkReparseBuffer {
+            substitute_name: "dir\\target".into(),
+            print_name: "dir\\target".into(),
+            flags: SYMLINK_FLAG_RELATIVE,
+        };
+        let encoded = symlink.to_vec();
+        assert_eq!(encoded[..4], IO_REPARSE_TAG_SYMLINK.to_le_bytes());
+        assert_eq!(
+            u16::from_le_bytes([encoded[4], encoded[5]]) as usize,
+            encoded.len() - 8
+        );
+        assert_eq!(
+            SymbolicLinkReparseBuffer::parse(&encoded).unwrap().1,
+            symlink
+        );
+    }
+}
@@ -1,15 +1,17 @@
 use nom::bytes::complete as bytes;
+use nom::combinator::map_res;
 use nom::Parser;
+use ntstatus::NtStatus;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SmbMessageHeader {
     pub protocol_id: u32,
     pub header_size: u16,
     pub credit_charge: u16,
-    pub status: u32,
-    pub command: u16,
+    pub status: NtStatus,
+    pub command: Command,
     pub credit_request_response: u16,
-    pub flags: u32,
+    pub flags: HeaderFlags,
     pub next_command: u32,
     pub message_id: u64,
     pub variant: SmbMessageHeaderVariant,
@@ -17,22 +19,99 @@ pub struct SmbMessageHeader {
     pub signature: u128,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SmbMessageHeaderVariant {
     Sync { tree_id: u32 },
     Async { id: std::num::NonZeroU64 },
 }
 
+/// What a message is asking for, or answering.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Command {
+    Negotiate = 0x0,
+    SessionSetup = 0x1,
+    Logoff = 0x2,
+    TreeConnect = 0x3,
+    TreeDisconnect = 0x4,
+    Create = 0x5,
+    Close = 0x6,
+    Flush = 0x7,
+    Read = 0x8,
+    Write = 0x9,
+    Lock = 0xA,
+    Ioctl = 0xB,
+    Cancel = 0xC,
+    Echo = 0xD,
+    QueryDirectory = 0xE,
+    ChangeNotify = 0xF,
+    QueryInfo = 0x10,
+    SetInfo = 0x11,
+    /// Oplock and lease breaks, along with their acknowledgments.
+    OplockBreak = 0x12,
+}
+
+#[derive(Debug)]
+pub struct InvalidCommand;
+
+impl TryFrom<u16> for Command {
+    type Error = InvalidCommand;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(Self::Negotiate),
+            0x1 => Ok(Self::SessionSetup),
+            0x2 => Ok(Self::Logoff),
+            0x3 => Ok(Self::TreeConnect),
+            0x4 => Ok(Self::TreeDisconnect),
+            0x5 => Ok(Self::Create),
+            0x6 => Ok(Self::Close),
+            0x7 => Ok(Self::Flush),
+            0x8 => Ok(Self::Read),
+            0x9 => Ok(Self::Write),
+            0xA => Ok(Self::Lock),
+            0xB => Ok(Self::Ioctl),
+            0xC => Ok(Self::Cancel),
+            0xD => Ok(Self::Echo),
+            0xE => Ok(Self::QueryDirectory),
+            0xF => Ok(Self::ChangeNotify),
+            0x10 => Ok(Self::QueryInfo),
+            0x11 => Ok(Self::SetInfo),
+            0x12 => Ok(Self::OplockBreak),
+            _ => Err(InvalidCommand),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct HeaderFlags: u32 {
+        /// Set on everything the server sends back.
+        const SERVER_TO_REDIR = 0x0000_0001;
+        /// The header has an AsyncId instead of a TreeId.
+        const ASYNC_COMMAND = 0x0000_0002;
+        /// Uses the session, tree connect and file of the message before
+        /// it in the same compound.
+        const RELATED_OPERATIONS = 0x0000_0004;
+        const SIGNED = 0x0000_0008;
+        /// 3.1.1's I/O priority, a value rather than a flag.
+        const PRIORITY_MASK = 0x0000_0070;
+        const DFS_OPERATIONS = 0x1000_0000;
+        /// A request the client is sending again after losing its connection.
+        const REPLAY_OPERATION = 0x2000_0000;
+    }
+}
+
 impl SmbMessageHeader {
-    pub fn to_vec(self) -> Vec<u8> {
+    pub fn to_vec(&self) -> Vec<u8> {
         let mut out = Vec::with_capacity(std::mem::size_of::<Self>());
         out.extend(self.protocol_id.to_le_bytes());
         out.extend(self.header_size.to_le_bytes());
         out.extend(self.credit_charge.to_le_bytes());
-        out.extend(self.status.to_le_bytes());
-        out.extend(self.command.to_le_bytes());
+        out.extend(self.status.0.to_le_bytes());
+        out.extend((self.command as u16).to_le_bytes());
         out.extend(self.credit_request_response.to_le_bytes());
-        out.extend(self.flags.to_le_bytes());
+        out.extend(self.flags.bits().to_le_bytes());
         out.extend(self.next_command.to_le_bytes());
         out.extend(self.message_id.to_le_bytes());
         match self.variant {
@@ -50,7 +129,7 @@ impl SmbMessageHeader {
     }
     fn parse_variant<'a>(
         body: &'a [u8],
-    ) -> nom::IResult<&[u8], SmbMessageHeaderVariant, nom::error::Error<&[u8]>> {
+    ) -> nom::IResult<&'a [u8], SmbMessageHeaderVariant, nom::error::Error<&'a [u8]>> {
         let get_sync = |body: &'a [u8]| {
             // if the body starts with 4 zeros, the next 4 bytes
             // is the tree id for the sync variant.
@@ -78,14 +157,19 @@ impl SmbMessageHeader {
 
         let (remaining, protocol_id) = c_u32("Failed to get protocol id", body)?;
         let (remaining, header_size) = nom::combinator::verify(
-            |remaining| dbg!(c_u16("Failed to get message header size", remaining)),
+            |remaining| c_u16("Failed to get message header size", remaining),
             |&s| s == 64,
         )(remaining)?;
         let (remaining, credit_charge) = c_u16("Failed to get credit charge", remaining)?;
         let (remaining, status) =
             c_u32("Failed to get (ChannelSequence,Reserved)/Charge", remaining)?;
-        println!("{status}");
-        let (remaining, command) = c_u16("Failed to get command", remaining)?;
+        let (remaining, command) = context(
+            "Invalid command",
+            map_res(
+                |body| c_u16("Failed to get command", body),
+                Command::try_from,
+            ),
+        )(remaining)?;
         let (remaining, credit_request_response) =
             c_u16("Failed to get credit request/response", remaining)?;
         let (remaining, flags) = c_u32("Failed to get credit header flags", remaining)?;
@@ -101,10 +185,10 @@ impl SmbMessageHeader {
                 protocol_id,
                 header_size,
                 credit_charge,
-                status,
+                status: NtStatus(status),
                 command,
                 credit_request_response,
-                flags,
+                flags: HeaderFlags::from_bits_retain(flags),
                 next_command,
                 message_id,
                 session_id,
@@ -121,6 +205,7 @@ mod tests {
     #[test]
     fn parse_protocol_id() {
         let mut header = [0; 64];
+        header[4] = 64;
         header[0] = 0xFE;
         header[1] = b'S';
         header[2] = b'M';
@@ -133,6 +218,7 @@ mod tests {
     #[test]
     fn valid_tree_id() {
         let mut header = [0; 64];
+        header[4] = 64;
         header[37] = 0x04;
         assert_eq!(
             SmbMessageHeader::try_parse(&header).unwrap().1.variant,
@@ -142,8 +228,29 @@ mod tests {
         );
     }
     #[test]
+    fn command_and_flags() {
+        let mut header = [0; 64];
+        header[4] = 64;
+        header[12] = 0x12;
+        header[16] = 0x05;
+        header[19] = 0x20;
+        let (_, parsed) = SmbMessageHeader::try_parse(&header).unwrap();
+        assert_eq!(parsed.command, Command::OplockBreak);
+        assert_eq!(
+            parsed.flags,
+            HeaderFlags::SERVER_TO_REDIR
+                | HeaderFlags::RELATED_OPERATIONS
+                | HeaderFlags::REPLAY_OPERATION
+        );
+        assert_eq!(parsed.to_vec(), header);
+
+        header[12] = 0x13;
+        assert!(SmbMessageHeader::try_parse(&header).is_err());
+    }
+    #[test]
     fn valid_async_id() {
         let mut header = [0; 64];
+        header[4] = 64;
         header[32] = 0xFF;
         assert_eq!(
             SmbMessageHeader::try_parse(&header).unwrap().1.variant,
@@ -0,0 +1,271 @@
+use crate::message::{c_u16, c_u32, header_offset_buffer, pad_to, SmbFileId, HEADER_SIZE};
+
+/// Set in the IOCTL flags when `ctl_code` is an FSCTL, which is
+/// the only kind SMB2 actually allows.
+pub const SMB2_0_IOCTL_IS_FSCTL: u32 = 0x0000_0001;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbIoctl {
+    // always 57.
+    pub size: u16,
+    /// One of the `FSCTL_*` codes.
+    pub ctl_code: u32,
+    /// `SmbFileId::ANY` for codes that aren't about a particular open.
+    pub file_id: SmbFileId,
+    pub input_offset: u32,
+    pub input_count: u32,
+    pub max_input_response: u32,
+    pub output_offset: u32,
+    pub output_count: u32,
+    /// How much the response's output buffer is allowed to hold.
+    pub max_output_response: u32,
+    pub flags: u32,
+    pub input: Vec<u8>,
+    /// Only ever sent for FSCTL_PIPE_PEEK and friends, which we don't do.
+    pub output: Vec<u8>,
+}
+
+impl SmbIoctl {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbIoctl, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, ctl_code) = c_u32("Failed to get ctl code", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, input_offset) = c_u32("Failed to get input offset", remaining)?;
+        let (remaining, input_count) = c_u32("Failed to get input count", remaining)?;
+        let (remaining, max_input_response) = c_u32("Failed to get max input response", remaining)?;
+        let (remaining, output_offset) = c_u32("Failed to get output offset", remaining)?;
+        let (remaining, output_count) = c_u32("Failed to get output count", remaining)?;
+        let (remaining, max_output_response) =
+            c_u32("Failed to get max output response", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, _reserved2) = c_u32("Failed to get reserved2", remaining)?;
+        let (_, input) = header_offset_buffer(
+            "Failed to get input buffer",
+            body,
+            remaining,
+            input_offset as _,
+            input_count as _,
+        )?;
+        let (_, output) = header_offset_buffer(
+            "Failed to get output buffer",
+            body,
+            remaining,
+            output_offset as _,
+            output_count as _,
+        )?;
+        Ok((
+            &[],
+            Self {
+                size,
+                ctl_code,
+                file_id,
+                input_offset,
+                input_count,
+                max_input_response,
+                output_offset,
+                output_count,
+                max_output_response,
+                flags,
+                input: input.to_vec(),
+                output: output.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(56 + self.input.len() + self.output.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.ctl_code.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend(self.input_offset.to_le_bytes());
+        out.extend(self.input_count.to_le_bytes());
+        out.extend(self.max_input_response.to_le_bytes());
+        out.extend(self.output_offset.to_le_bytes());
+        out.extend(self.output_count.to_le_bytes());
+        out.extend(self.max_output_response.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend([0; 4]);
+        encode_buffers(
+            &mut out,
+            (self.input_offset, &self.input),
+            (self.output_offset, &self.output),
+        );
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbIoctlResponse {
+    // always 49.
+    pub size: u16,
+    pub ctl_code: u32,
+    pub file_id: SmbFileId,
+    pub input_offset: u32,
+    pub input_count: u32,
+    pub output_offset: u32,
+    pub output_count: u32,
+    pub flags: u32,
+    /// Almost always empty, servers aren't meant to echo the input back.
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+}
+
+impl SmbIoctlResponse {
+    /// Right after the fixed part of the response, which is
+    /// where both buffers start when there's no input echoed back.
+    pub const BUFFER_OFFSET: u32 = (HEADER_SIZE + 48) as u32;
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbIoctlResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, ctl_code) = c_u32("Failed to get ctl code", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, input_offset) = c_u32("Failed to get input offset", remaining)?;
+        let (remaining, input_count) = c_u32("Failed to get input count", remaining)?;
+        let (remaining, output_offset) = c_u32("Failed to get output offset", remaining)?;
+        let (remaining, output_count) = c_u32("Failed to get output count", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, _reserved2) = c_u32("Failed to get reserved2", remaining)?;
+        let (_, input) = header_offset_buffer(
+            "Failed to get input buffer",
+            body,
+            remaining,
+            input_offset as _,
+            input_count as _,
+        )?;
+        let (_, output) = header_offset_buffer(
+            "Failed to get output buffer",
+            body,
+            remaining,
+            output_offset as _,
+            output_count as _,
+        )?;
+        Ok((
+            &[],
+            Self {
+                size,
+                ctl_code,
+                file_id,
+                input_offset,
+                input_count,
+                output_offset,
+                output_count,
+                flags,
+                input: input.to_vec(),
+                output: output.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(48 + self.input.len() + self.output.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.ctl_code.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend(self.input_offset.to_le_bytes());
+        out.extend(self.input_count.to_le_bytes());
+        out.extend(self.output_offset.to_le_bytes());
+        out.extend(self.output_count.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend([0; 4]);
+        encode_buffers(
+            &mut out,
+            (self.input_offset, &self.input),
+            (self.output_offset, &self.output),
+        );
+        out
+    }
+}
+
+/// Lays out the input and output buffers at their (header relative) offsets.
+fn encode_buffers(out: &mut Vec<u8>, input: (u32, &[u8]), output: (u32, &[u8])) {
+    if input.1.is_empty() && output.1.is_empty() {
+        out.push(0);
+        return;
+    }
+    for (offset, buffer) in [input, output] {
+        if !buffer.is_empty() {
+            pad_to(out, offset as usize - HEADER_SIZE);
+            out.extend(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::FSCTL_VALIDATE_NEGOTIATE_INFO;
+
+    #[test]
+    fn example_ioctl() {
+        #[rustfmt::skip]
+        let smb_ioctl = [
+            // size    | reserved
+            0x39, 0x00, 0x00, 0x00,
+            // ctl code
+            0x04, 0x02, 0x14, 0x00,
+            // file id
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            // input offset
+            0x78, 0x00, 0x00, 0x00,
+            // input count
+            0x04, 0x00, 0x00, 0x00,
+            // max input response
+            0x00, 0x00, 0x00, 0x00,
+            // output offset
+            0x78, 0x00, 0x00, 0x00,
+            // output count
+            0x00, 0x00, 0x00, 0x00,
+            // max output response
+            0x18, 0x00, 0x00, 0x00,
+            // flags
+            0x01, 0x00, 0x00, 0x00,
+            // reserved2
+            0x00, 0x00, 0x00, 0x00,
+            // input
+            0x01, 0x02, 0x03, 0x04,
+        ];
+        let (_, ioctl) = SmbIoctl::parse(&smb_ioctl).unwrap();
+        assert_eq!(
+            ioctl,
+            SmbIoctl {
+                size: 57,
+                ctl_code: FSCTL_VALIDATE_NEGOTIATE_INFO,
+                file_id: SmbFileId::ANY,
+                input_offset: 0x78,
+                input_count: 4,
+                max_input_response: 0,
+                output_offset: 0x78,
+                output_count: 0,
+                max_output_response: 24,
+                flags: SMB2_0_IOCTL_IS_FSCTL,
+                input: vec![1, 2, 3, 4],
+                output: vec![],
+            }
+        );
+        assert_eq!(ioctl.to_vec(), smb_ioctl);
+    }
+
+    #[test]
+    fn ioctl_response_round_trip() {
+        let response = SmbIoctlResponse {
+            size: 49,
+            ctl_code: FSCTL_VALIDATE_NEGOTIATE_INFO,
+            file_id: SmbFileId::ANY,
+            input_offset: SmbIoctlResponse::BUFFER_OFFSET,
+            input_count: 0,
+            output_offset: SmbIoctlResponse::BUFFER_OFFSET,
+            output_count: 3,
+            flags: 0,
+            input: vec![],
+            output: vec![7, 8, 9],
+        };
+        let encoded = response.to_vec();
+        assert_eq!(encoded.len(), 48 + 3);
+        assert_eq!(SmbIoctlResponse::parse(&encoded).unwrap().1, response);
+    }
+}
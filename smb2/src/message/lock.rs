@@ -0,0 +1,166 @@
+use nom::multi::count;
+
+use crate::message::{c_u16, c_u32, c_u64, SmbFileId};
+
+pub const SMB2_LOCKFLAG_SHARED_LOCK: u32 = 0x0000_0001;
+pub const SMB2_LOCKFLAG_EXCLUSIVE_LOCK: u32 = 0x0000_0002;
+pub const SMB2_LOCKFLAG_UNLOCK: u32 = 0x0000_0004;
+pub const SMB2_LOCKFLAG_FAIL_IMMEDIATELY: u32 = 0x0000_0010;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SmbLockElement {
+    pub offset: u64,
+    pub length: u64,
+    pub flags: u32,
+}
+
+impl SmbLockElement {
+    fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLockElement, nom::error::Error<&[u8]>> {
+        let (remaining, offset) = c_u64("Failed to get lock offset", body)?;
+        let (remaining, length) = c_u64("Failed to get lock length", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get lock flags", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                offset,
+                length,
+                flags,
+            },
+        ))
+    }
+
+    fn to_vec(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend(self.offset.to_le_bytes());
+        out.extend(self.length.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend([0; 4]);
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbLock {
+    // always 48.
+    pub size: u16,
+    /// Only the bottom 4 bits are sent.
+    pub lock_sequence_number: u8,
+    /// Which of the (up to 64) lock sequence slots this is for,
+    /// or 0 if the client isn't keeping track.
+    pub lock_sequence_index: u32,
+    pub file_id: SmbFileId,
+    pub locks: Vec<SmbLockElement>,
+}
+
+impl SmbLock {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLock, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, lock_count) = c_u16("Failed to get lock count", remaining)?;
+        let (remaining, lock_sequence) = c_u32("Failed to get lock sequence", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, locks) = count(SmbLockElement::parse, lock_count as usize)(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                lock_sequence_number: (lock_sequence & 0xF) as u8,
+                lock_sequence_index: lock_sequence >> 4,
+                file_id,
+                locks,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + 24 * self.locks.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend((self.locks.len() as u16).to_le_bytes());
+        let lock_sequence =
+            (self.lock_sequence_index << 4) | (self.lock_sequence_number & 0xF) as u32;
+        out.extend(lock_sequence.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        for lock in &self.locks {
+            out.extend(lock.to_vec());
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbLockResponse {
+    // always 4.
+    pub size: u16,
+}
+
+impl SmbLockResponse {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLockResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_lock() {
+        #[rustfmt::skip]
+        let smb_lock = [
+            // size    | lock count
+            0x30, 0x00, 0x02, 0x00,
+            // lock sequence: number 3, index 2
+            0x23, 0x00, 0x00, 0x00,
+            // file id
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // offset
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // length
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // exclusive, fail immediately | reserved
+            0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // offset
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // length
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // shared, fail immediately | reserved
+            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (_, lock) = SmbLock::parse(&smb_lock).unwrap();
+        assert_eq!(
+            lock,
+            SmbLock {
+                size: 48,
+                lock_sequence_number: 3,
+                lock_sequence_index: 2,
+                file_id: SmbFileId {
+                    persistent: 7,
+                    volatile: 7
+                },
+                locks: vec![
+                    SmbLockElement {
+                        offset: 0x1000,
+                        length: 0x100,
+                        flags: SMB2_LOCKFLAG_EXCLUSIVE_LOCK | SMB2_LOCKFLAG_FAIL_IMMEDIATELY,
+                    },
+                    SmbLockElement {
+                        offset: 0,
+                        length: 0x10,
+                        flags: SMB2_LOCKFLAG_SHARED_LOCK | SMB2_LOCKFLAG_FAIL_IMMEDIATELY,
+                    },
+                ],
+            }
+        );
+        assert_eq!(lock.to_vec(), smb_lock);
+    }
+}
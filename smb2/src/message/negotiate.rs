@@ -1,14 +1,12 @@
-use nom::{
-    bytes::complete::take,
-    combinator::{map, verify},
-    error::context,
-    multi::count,
-    Parser,
-};
+use nom::{bytes::complete::take, combinator::map, error::context, multi::count, Parser};
 
-use crate::message::{c_u128, c_u16, c_u32};
+use crate::message::{c_u128, c_u16, c_u32, fail, pad_to, HEADER_SIZE};
 
-use self::negotiate_context::SmbNegotiateContext;
+pub use self::negotiate_context::COMPRESSION_CAPABILITIES_FLAG_CHAINED;
+pub use self::negotiate_context::{Cipher, CompressionAlgorithm, HashAlgorithm, SigningAlgorithm};
+pub use self::negotiate_context::{InvalidCipher, InvalidCompressionAlgorithm};
+pub use self::negotiate_context::{InvalidHashAlgorithm, InvalidSigningAlgorithm};
+pub use self::negotiate_context::{SmbNegotiateContext, SmbNegotiateContextData};
 
 mod negotiate_context;
 
@@ -18,42 +16,53 @@ pub struct SmbNegotiate {
     // we don't include the dialect.
     pub size: u16,
     pub dialect_count: u16,
-    pub security_mode: SmbSecurityMode,
-    pub capabilities: u32,
+    pub security_mode: SecurityMode,
+    pub capabilities: Capabilities,
     pub client_guid: u128,
     pub dependant_field: DialectDependantField,
-    pub dialects: Vec<u16>,
+    /// The dialects offered that we know of, ones newer than 3.1.1 are left out.
+    pub dialects: Vec<Dialect>,
     pub negotiate_context_list: Option<Vec<SmbNegotiateContext>>,
 }
 
 #[derive(Debug)]
 pub struct SmbNegotiateResponse {
     pub size: u16,
-    pub security_mode: u16,
-    pub dialect_rev: u16,
-    pub negotiate_context_count: u16,
+    pub security_mode: SecurityMode,
+    pub dialect_rev: Dialect,
     pub server_guid: u128,
-    pub capabilities: u32,
+    pub capabilities: Capabilities,
     pub max_transact_size: u32,
     pub max_read_size: u32,
     pub max_write_size: u32,
     pub system_time: u64,
-    pub server_start_time: u32,
+    pub server_start_time: u64,
     pub security_buff_offset: u16,
     pub security_buff_len: u16,
-    pub neg_context_offset: u32,
     pub buf: Vec<u8>,
-    pub context_list: Vec<u8>,
+    /// Only for 3.1.1, NegotiateContextOffset and NegotiateContextCount
+    /// are worked out from these when encoding.
+    pub context_list: Vec<SmbNegotiateContext>,
 }
 impl SmbNegotiateResponse {
-    pub fn to_vec(self) -> Vec<u8> {
+    // the fixed part of the response, before the security buffer.
+    const FIXED_SIZE: usize = 64;
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        // the contexts start on an 8 byte boundary after the security buffer,
+        // counting from the start of the header.
+        let context_start = (Self::FIXED_SIZE + self.buf.len()).next_multiple_of(8);
+        let neg_context_offset = match self.context_list.is_empty() {
+            true => 0,
+            false => (HEADER_SIZE + context_start) as u32,
+        };
         let mut out = Vec::with_capacity(std::mem::size_of::<Self>());
         out.extend(self.size.to_le_bytes());
-        out.extend(self.security_mode.to_le_bytes());
-        out.extend(self.dialect_rev.to_le_bytes());
-        out.extend(self.negotiate_context_count.to_le_bytes());
+        out.extend(self.security_mode.bits().to_le_bytes());
+        out.extend((self.dialect_rev as u16).to_le_bytes());
+        out.extend((self.context_list.len() as u16).to_le_bytes());
         out.extend(self.server_guid.to_le_bytes());
-        out.extend(self.capabilities.to_le_bytes());
+        out.extend(self.capabilities.bits().to_le_bytes());
         out.extend(self.max_transact_size.to_le_bytes());
         out.extend(self.max_read_size.to_le_bytes());
         out.extend(self.max_write_size.to_le_bytes());
@@ -61,35 +70,75 @@ impl SmbNegotiateResponse {
         out.extend(self.server_start_time.to_le_bytes());
         out.extend(self.security_buff_offset.to_le_bytes());
         out.extend(self.security_buff_len.to_le_bytes());
-        out.extend(self.neg_context_offset.to_le_bytes());
-        out.extend(self.buf);
-        out.extend(self.context_list);
+        out.extend(neg_context_offset.to_le_bytes());
+        out.extend(&self.buf);
+        if !self.context_list.is_empty() {
+            pad_to(&mut out, context_start);
+            out.extend(SmbNegotiateContext::list_to_vec(&self.context_list));
+        }
         out
     }
 }
 
+/// The revisions of SMB2 there are.
 #[repr(u16)]
-#[derive(Debug, PartialEq, Eq)]
-pub enum SmbSecurityMode {
-    SigningEnabled = 0x01,
-    SigningRequired = 0x02,
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum Dialect {
+    Smb202 = 0x0202,
+    Smb210 = 0x0210,
+    /// SMB2_WILDCARD_REVISION, the answer to an SMB1 NEGOTIATE offering
+    /// "SMB 2.???", telling the client to follow up with an SMB2 one.
+    Wildcard = 0x02FF,
+    Smb300 = 0x0300,
+    Smb302 = 0x0302,
+    Smb311 = 0x0311,
 }
 
 #[derive(Debug)]
-pub struct OutOfRange;
+pub struct InvalidDialect;
 
-impl TryFrom<u16> for SmbSecurityMode {
-    type Error = OutOfRange;
+impl TryFrom<u16> for Dialect {
+    type Error = InvalidDialect;
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            0x01 => Ok(Self::SigningEnabled),
-            0x02 => Ok(Self::SigningRequired),
-            _ => Err(OutOfRange),
+            0x0202 => Ok(Self::Smb202),
+            0x0210 => Ok(Self::Smb210),
+            0x02FF => Ok(Self::Wildcard),
+            0x0300 => Ok(Self::Smb300),
+            0x0302 => Ok(Self::Smb302),
+            0x0311 => Ok(Self::Smb311),
+            _ => Err(InvalidDialect),
         }
     }
 }
 
+bitflags::bitflags! {
+    /// Whether a side of the connection can sign messages, or insists on it.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct SecurityMode: u16 {
+        const SIGNING_ENABLED = 0x0001;
+        const SIGNING_REQUIRED = 0x0002;
+    }
+}
+
+bitflags::bitflags! {
+    /// The SMB2_GLOBAL_CAP_* a client or server supports.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct Capabilities: u32 {
+        const DFS = 0x0000_0001;
+        /// From 2.1 on.
+        const LEASING = 0x0000_0002;
+        /// Reads and writes bigger than 64k, i.e. multi-credit requests.
+        const LARGE_MTU = 0x0000_0004;
+        const MULTI_CHANNEL = 0x0000_0008;
+        const PERSISTENT_HANDLES = 0x0000_0010;
+        const DIRECTORY_LEASING = 0x0000_0020;
+        const ENCRYPTION = 0x0000_0040;
+        const NOTIFICATIONS = 0x0000_0080;
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum DialectDependantField {
     NegContext { offset: u32, count: u16 },
@@ -100,14 +149,7 @@ impl SmbNegotiate {
     pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbNegotiate, nom::error::Error<&[u8]>> {
         let (remaining, structure_size) = c_u16("Failed to get structure size", body)?;
         let (remaining, dialect_count) = c_u16("Failed to get dialect count", remaining)?;
-        let (remaining, security_mode) = context(
-            "Failed to get security mode",
-            verify(
-                take(2usize).map(|bytes: &[u8]| u16::from_le_bytes(bytes.try_into().unwrap())),
-                |&i| (0x01..=0x02).contains(&i),
-            ),
-        )(remaining)?;
-        let security_mode = SmbSecurityMode::try_from(security_mode).unwrap();
+        let (remaining, security_mode) = c_u16("Failed to get security mode", remaining)?;
         let (remaining, _) = c_u16("Failed to get padding", remaining)?;
         let (remaining, capabilities) = c_u32("Failed to get capabilities", remaining)?;
         let (remaining, client_guid) = c_u128("Failed to get client_guid", remaining)?;
@@ -122,8 +164,12 @@ impl SmbNegotiate {
                 dialect_count as _,
             ),
         )(remaining)?;
+        let dialects: Vec<_> = dialects
+            .into_iter()
+            .filter_map(|dialect| Dialect::try_from(dialect).ok())
+            .collect();
 
-        let dependant_field = if dialects.contains(&0x0311) {
+        let dependant_field = if dialects.contains(&Dialect::Smb311) {
             let (remaining, offset) = map(take(4usize), |bytes: &[u8]| {
                 u32::from_le_bytes(bytes.try_into().unwrap())
             })(dependant_field)?;
@@ -140,12 +186,17 @@ impl SmbNegotiate {
             // sigh, this field is defined as an offset from the header.
             // hence why - 64
             DialectDependantField::NegContext { offset, count } => {
-                context(
-                    "Failed to parse negotiate context list",
-                    nom::multi::count(negotiate_context::SmbNegotiateContext::parse, count as _),
-                )
+                let Some(contexts) = (offset as usize)
+                    .checked_sub(HEADER_SIZE)
+                    .and_then(|offset| body.get(offset..))
+                else {
+                    return fail(body);
+                };
+                context("Failed to parse negotiate context list", |contexts| {
+                    SmbNegotiateContext::parse_list(contexts, count as _)
+                })
                 .map(Some)
-                .parse(&body[(offset as usize - 64)..])?
+                .parse(contexts)?
                 .1
             }
             DialectDependantField::ClientStartTime(_) => None,
@@ -155,8 +206,8 @@ impl SmbNegotiate {
             Self {
                 size: structure_size,
                 dialect_count,
-                security_mode,
-                capabilities,
+                security_mode: SecurityMode::from_bits_retain(security_mode),
+                capabilities: Capabilities::from_bits_retain(capabilities),
                 client_guid,
                 dependant_field,
                 dialects,
@@ -164,6 +215,42 @@ impl SmbNegotiate {
             },
         ))
     }
+
+    /// DialectCount, and for 3.1.1 NegotiateContextOffset and
+    /// NegotiateContextCount, are worked out from `dialects` and
+    /// `negotiate_context_list`. Otherwise, ClientStartTime comes from
+    /// `dependant_field`.
+    pub fn to_vec(&self) -> Vec<u8> {
+        // the fixed part of the request, before the dialects.
+        const FIXED_SIZE: usize = 36;
+        // the contexts start on an 8 byte boundary after the dialects,
+        // counting from the start of the header.
+        let context_start = (FIXED_SIZE + 2 * self.dialects.len()).next_multiple_of(8);
+        let mut out = Vec::with_capacity(context_start);
+        out.extend(self.size.to_le_bytes());
+        out.extend((self.dialects.len() as u16).to_le_bytes());
+        out.extend(self.security_mode.bits().to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.capabilities.bits().to_le_bytes());
+        out.extend(self.client_guid.to_le_bytes());
+        match (&self.negotiate_context_list, &self.dependant_field) {
+            (Some(contexts), _) => {
+                out.extend(((HEADER_SIZE + context_start) as u32).to_le_bytes());
+                out.extend((contexts.len() as u16).to_le_bytes());
+                out.extend([0; 2]);
+            }
+            (None, DialectDependantField::ClientStartTime(time)) => out.extend(time.to_le_bytes()),
+            (None, DialectDependantField::NegContext { .. }) => out.extend([0; 8]),
+        }
+        for dialect in &self.dialects {
+            out.extend((*dialect as u16).to_le_bytes());
+        }
+        if let Some(contexts) = &self.negotiate_context_list {
+            pad_to(&mut out, context_start);
+            out.extend(SmbNegotiateContext::list_to_vec(contexts));
+        }
+        out
+    }
 }
 
 #[cfg(test)]
@@ -201,14 +288,143 @@ mod tests {
                 SmbNegotiate {
                     size: 0x24,
                     dialect_count: 0x02,
-                    security_mode: SmbSecurityMode::SigningEnabled,
-                    capabilities: 0,
+                    security_mode: SecurityMode::SIGNING_ENABLED,
+                    capabilities: Capabilities::empty(),
                     client_guid: 1,
                     dependant_field: DialectDependantField::ClientStartTime(0),
-                    dialects: vec![0x0202, 0x0103],
+                    // 0x0103 isn't a dialect.
+                    dialects: vec![Dialect::Smb202],
                     negotiate_context_list: None
                 }
             ))
         )
     }
+
+    #[rustfmt::skip]
+    const NEGOTIATE_WITH_CONTEXTS: [u8; 78] = [
+        // size    | dialect count
+        0x24, 0x00, 0x01, 0x00,
+        // sec mode| reserved
+        0x01, 0x00, 0x00, 0x00,
+        // capabilities
+        0x00, 0x00, 0x00, 0x00,
+        // guid
+        0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        // context offset      | context count | reserved
+        0x68, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        // dialects| padding
+        0x11, 0x03, 0x00, 0x00,
+
+        // preauth | data length
+        0x01, 0x00, 0x0A, 0x00,
+        // reserved
+        0x00, 0x00, 0x00, 0x00,
+        // hash algo count | salt length | SHA-512
+        0x01, 0x00, 0x04, 0x00, 0x01, 0x00,
+        // salt
+        0xAA, 0xBB, 0xCC, 0xDD,
+        // padding, up to 8 bytes
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+
+        // encryption | data length
+        0x02, 0x00, 0x06, 0x00,
+        // reserved
+        0x00, 0x00, 0x00, 0x00,
+        // cipher count | AES-128-GCM | AES-128-CCM
+        0x02, 0x00, 0x02, 0x00, 0x01, 0x00,
+    ];
+
+    #[test]
+    fn negotiate_contexts() {
+        let smb_negotiate = NEGOTIATE_WITH_CONTEXTS;
+
+        let contexts = vec![
+            SmbNegotiateContext::new(SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                hash_algo_count: 1,
+                salt_length: 4,
+                hash_algo: vec![HashAlgorithm::Sha512 as u16],
+                salt: vec![0xAA, 0xBB, 0xCC, 0xDD],
+            }),
+            SmbNegotiateContext::new(SmbNegotiateContextData::EncryptionCapabilities {
+                cipher_count: 2,
+                ciphers: vec![Cipher::Aes128Gcm as u16, Cipher::Aes128Ccm as u16],
+            }),
+        ];
+        let (_, negotiate) = SmbNegotiate::parse(&smb_negotiate).unwrap();
+        assert_eq!(negotiate.dialects, vec![Dialect::Smb311]);
+        assert_eq!(
+            negotiate.dependant_field,
+            DialectDependantField::NegContext {
+                offset: 0x68,
+                count: 2
+            }
+        );
+        assert_eq!(negotiate.negotiate_context_list.as_ref(), Some(&contexts));
+        assert_eq!(
+            SmbNegotiateContext::list_to_vec(&contexts),
+            &smb_negotiate[40..]
+        );
+    }
+
+    #[test]
+    fn negotiate_round_trip() {
+        let negotiate = SmbNegotiate {
+            size: 36,
+            dialect_count: 3,
+            security_mode: SecurityMode::SIGNING_REQUIRED,
+            capabilities: Capabilities::LEASING | Capabilities::LARGE_MTU,
+            client_guid: 0x1234,
+            dependant_field: DialectDependantField::ClientStartTime(0xABCD),
+            dialects: vec![Dialect::Smb202, Dialect::Smb210, Dialect::Smb302],
+            negotiate_context_list: None,
+        };
+        let encoded = negotiate.to_vec();
+        assert_eq!(encoded.len(), 36 + 3 * 2);
+        assert_eq!(SmbNegotiate::parse(&encoded), Ok((&[] as _, negotiate)));
+
+        // the contexts go back where they were found, padding and all.
+        let (_, negotiate) = SmbNegotiate::parse(&NEGOTIATE_WITH_CONTEXTS).unwrap();
+        assert_eq!(negotiate.to_vec(), NEGOTIATE_WITH_CONTEXTS);
+    }
+
+    #[test]
+    fn response_context_offset() {
+        let mut response = SmbNegotiateResponse {
+            size: 65,
+            security_mode: SecurityMode::SIGNING_ENABLED,
+            dialect_rev: Dialect::Smb311,
+            server_guid: 0,
+            capabilities: Capabilities::empty(),
+            max_transact_size: 0,
+            max_read_size: 0,
+            max_write_size: 0,
+            system_time: 0,
+            server_start_time: 0,
+            security_buff_offset: 128,
+            security_buff_len: 3,
+            buf: vec![1, 2, 3],
+            context_list: vec![],
+        };
+        let encoded = response.to_vec();
+        assert_eq!(encoded.len(), 64 + 3);
+        // no contexts, no offset or count.
+        assert_eq!(&encoded[6..8], [0, 0]);
+        assert_eq!(&encoded[60..64], [0, 0, 0, 0]);
+
+        let signing = SmbNegotiateContext::new(SmbNegotiateContextData::SigningCapabilities {
+            signing_algo_count: 1,
+            signing_algos: vec![SigningAlgorithm::AesGmac as u16],
+        });
+        response.context_list = vec![signing];
+        let encoded = response.to_vec();
+        assert_eq!(&encoded[6..8], [1, 0]);
+        // the security buffer ends at 131 from the header, padded out to 136.
+        assert_eq!(&encoded[60..64], 136u32.to_le_bytes());
+        assert_eq!(&encoded[67..72], [0; 5]);
+        let (_, contexts) = SmbNegotiateContext::parse_list(&encoded[72..], 1).unwrap();
+        assert_eq!(contexts, response.context_list);
+    }
 }
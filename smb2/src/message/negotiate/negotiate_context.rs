@@ -3,7 +3,7 @@ use nom::combinator::verify;
 use nom::multi::count;
 use nom::Parser;
 
-use crate::message::{get_u16_le, get_u32_le};
+use crate::message::{get_u16_le, get_u32_le, pad_to};
 
 type Utf16String = Vec<u16>;
 
@@ -29,8 +29,12 @@ impl SmbNegotiateContext {
         .parse(body)?;
 
         let (remaining, data_length) = get_u16_le(remaining)?;
+        let (remaining, _reserved) = get_u32_le(remaining)?;
+        let (after, data) = take(data_length)(remaining)?;
+        // everything below only looks at this context's data.
+        let remaining = data;
         use SmbNegotiateContextTypes::*;
-        let (remaining, context) = match discriminant {
+        let (_remaining, context) = match discriminant {
             PreauthIntegrityCapabilies => {
                 let (remaining, hash_algo_count) = get_u16_le(remaining)?;
                 let (remaining, salt_length) = get_u16_le(remaining)?;
@@ -44,12 +48,12 @@ impl SmbNegotiateContext {
                 let (remaining, salt) = take(salt_length)(remaining)?;
                 (
                     remaining,
-                    dbg!(SmbNegotiateContextData::PreauthIntegrityCapabilities {
+                    SmbNegotiateContextData::PreauthIntegrityCapabilities {
                         hash_algo_count,
                         salt_length,
                         hash_algo: hash_algorithms.collect(),
                         salt: salt.to_vec(),
-                    }),
+                    },
                 )
             }
             EncryptionCapabilities => {
@@ -148,7 +152,7 @@ impl SmbNegotiateContext {
             }
         };
         Ok((
-            remaining,
+            after,
             SmbNegotiateContext {
                 context_type: discriminant as u16,
                 data_length,
@@ -230,3 +234,232 @@ pub enum SmbNegotiateContextData {
         signing_algos: Vec<u16>,
     },
 }
+
+impl SmbNegotiateContext {
+    /// A context carrying `data`, with its type and length filled in to match.
+    pub fn new(data: SmbNegotiateContextData) -> Self {
+        Self {
+            context_type: data.context_type() as u16,
+            data_length: data.to_vec().len() as u16,
+            data,
+        }
+    }
+
+    /// Parses `count` contexts, each starting on an 8 byte boundary from
+    /// the start of `body`.
+    pub fn parse_list(
+        body: &[u8],
+        count: usize,
+    ) -> nom::IResult<&[u8], Vec<SmbNegotiateContext>, nom::error::Error<&[u8]>> {
+        let mut contexts = Vec::with_capacity(count);
+        let mut remaining = body;
+        for i in 0..count {
+            if i != 0 {
+                let consumed = body.len() - remaining.len();
+                (remaining, _) = take(consumed.next_multiple_of(8) - consumed)(remaining)?;
+            }
+            let context;
+            (remaining, context) = Self::parse(remaining)?;
+            contexts.push(context);
+        }
+        Ok((remaining, contexts))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let data = self.data.to_vec();
+        let mut out = Vec::with_capacity(8 + data.len());
+        out.extend((self.data.context_type() as u16).to_le_bytes());
+        out.extend((data.len() as u16).to_le_bytes());
+        // reserved
+        out.extend(0u32.to_le_bytes());
+        out.extend(data);
+        out
+    }
+
+    /// Encodes `contexts` back to back, padding each but the last out to 8 bytes.
+    pub fn list_to_vec(contexts: &[SmbNegotiateContext]) -> Vec<u8> {
+        let mut out = vec![];
+        for context in contexts {
+            let start = out.len().next_multiple_of(8);
+            pad_to(&mut out, start);
+            out.extend(context.to_vec());
+        }
+        out
+    }
+}
+
+impl SmbNegotiateContextData {
+    fn context_type(&self) -> SmbNegotiateContextTypes {
+        use SmbNegotiateContextTypes::*;
+        match self {
+            Self::PreauthIntegrityCapabilities { .. } => PreauthIntegrityCapabilies,
+            Self::EncryptionCapabilities { .. } => EncryptionCapabilities,
+            Self::CompressionCapabilities { .. } => CompressionCapabilities,
+            Self::NetNameNegotiateContextId { .. } => NetNameNegotiateContextId,
+            Self::TransportCapabilities { .. } => TransportCapabilities,
+            Self::RdmaTransformCapabilities { .. } => RdmaTranformCapabilities,
+            Self::SigningCapabilities { .. } => SigningCapabilities,
+        }
+    }
+
+    /// The context's data, the counts are taken from the lists rather than
+    /// the count fields so the two can't disagree.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Self::PreauthIntegrityCapabilities {
+                hash_algo, salt, ..
+            } => {
+                out.extend((hash_algo.len() as u16).to_le_bytes());
+                out.extend((salt.len() as u16).to_le_bytes());
+                out.extend(hash_algo.iter().flat_map(|algo| algo.to_le_bytes()));
+                out.extend(salt);
+            }
+            Self::EncryptionCapabilities { ciphers, .. } => {
+                out.extend((ciphers.len() as u16).to_le_bytes());
+                out.extend(ciphers.iter().flat_map(|cipher| cipher.to_le_bytes()));
+            }
+            Self::CompressionCapabilities {
+                flags,
+                compression_algos,
+                ..
+            } => {
+                out.extend((compression_algos.len() as u16).to_le_bytes());
+                // padding
+                out.extend(0u16.to_le_bytes());
+                out.extend(flags.to_le_bytes());
+                out.extend(compression_algos.iter().flat_map(|algo| algo.to_le_bytes()));
+            }
+            Self::NetNameNegotiateContextId { net_name } => {
+                out.extend(
+                    net_name
+                        .iter()
+                        .flat_map(|code_point| code_point.to_le_bytes()),
+                );
+            }
+            Self::TransportCapabilities { flags } => {
+                out.extend(flags.to_le_bytes());
+            }
+            Self::RdmaTransformCapabilities { transform_ids, .. } => {
+                out.extend((transform_ids.len() as u16).to_le_bytes());
+                // reserved
+                out.extend([0; 6]);
+                out.extend(transform_ids.iter().flat_map(|id| id.to_le_bytes()));
+            }
+            Self::SigningCapabilities { signing_algos, .. } => {
+                out.extend((signing_algos.len() as u16).to_le_bytes());
+                out.extend(signing_algos.iter().flat_map(|algo| algo.to_le_bytes()));
+            }
+        }
+        out
+    }
+}
+
+/// SMB2_COMPRESSION_CAPABILITIES_FLAG_CHAINED, for when both sides can
+/// compress a message in more than one piece.
+pub const COMPRESSION_CAPABILITIES_FLAG_CHAINED: u32 = 0x0000_0001;
+
+/// What the preauth integrity hash is computed with.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HashAlgorithm {
+    Sha512 = 0x0001,
+}
+
+#[derive(Debug)]
+pub struct InvalidHashAlgorithm;
+
+impl TryFrom<u16> for HashAlgorithm {
+    type Error = InvalidHashAlgorithm;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x0001 => Ok(Self::Sha512),
+            _ => Err(InvalidHashAlgorithm),
+        }
+    }
+}
+
+/// What encrypted messages are encrypted with.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Cipher {
+    Aes128Ccm = 0x0001,
+    Aes128Gcm = 0x0002,
+    Aes256Ccm = 0x0003,
+    Aes256Gcm = 0x0004,
+}
+
+#[derive(Debug)]
+pub struct InvalidCipher;
+
+impl TryFrom<u16> for Cipher {
+    type Error = InvalidCipher;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x0001 => Ok(Self::Aes128Ccm),
+            0x0002 => Ok(Self::Aes128Gcm),
+            0x0003 => Ok(Self::Aes256Ccm),
+            0x0004 => Ok(Self::Aes256Gcm),
+            _ => Err(InvalidCipher),
+        }
+    }
+}
+
+/// What signed messages are signed with.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SigningAlgorithm {
+    HmacSha256 = 0x0000,
+    AesCmac = 0x0001,
+    AesGmac = 0x0002,
+}
+
+#[derive(Debug)]
+pub struct InvalidSigningAlgorithm;
+
+impl TryFrom<u16> for SigningAlgorithm {
+    type Error = InvalidSigningAlgorithm;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x0000 => Ok(Self::HmacSha256),
+            0x0001 => Ok(Self::AesCmac),
+            0x0002 => Ok(Self::AesGmac),
+            _ => Err(InvalidSigningAlgorithm),
+        }
+    }
+}
+
+/// What compressed messages are compressed with, from MS-XCA.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CompressionAlgorithm {
+    None = 0x0000,
+    Lznt1 = 0x0001,
+    Lz77 = 0x0002,
+    Lz77Huffman = 0x0003,
+    /// Only ever one of the payloads in a chained message.
+    PatternV1 = 0x0004,
+    Lz4 = 0x0005,
+}
+
+#[derive(Debug)]
+pub struct InvalidCompressionAlgorithm;
+
+impl TryFrom<u16> for CompressionAlgorithm {
+    type Error = InvalidCompressionAlgorithm;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x0000 => Ok(Self::None),
+            0x0001 => Ok(Self::Lznt1),
+            0x0002 => Ok(Self::Lz77),
+            0x0003 => Ok(Self::Lz77Huffman),
+            0x0004 => Ok(Self::PatternV1),
+            0x0005 => Ok(Self::Lz4),
+            _ => Err(InvalidCompressionAlgorithm),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u128, c_u16, c_u32, c_u64, OplockLevel, SmbFileId};
+
+/// The server's break notification, the client's acknowledgment
+/// and the server's response to that are all laid out the same.
+#[derive(Debug, PartialEq)]
+pub struct SmbOplockBreak {
+    // always 24.
+    pub size: u16,
+    /// What the oplock is being broken to, or what it was broken to.
+    pub oplock_level: OplockLevel,
+    pub file_id: SmbFileId,
+}
+
+impl SmbOplockBreak {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbOplockBreak, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, oplock_level) = context(
+            "Failed to get oplock level",
+            map_res(le_u8, OplockLevel::try_from),
+        )(remaining)?;
+        let (remaining, _reserved) = le_u8(remaining)?;
+        let (remaining, _reserved2) = c_u32("Failed to get reserved2", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                oplock_level,
+                file_id,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.oplock_level as u8);
+        out.extend([0; 5]);
+        out.extend(self.file_id.to_vec());
+        out
+    }
+}
+
+/// Set when the client has to acknowledge the break, i.e. whenever
+/// it's losing write or handle caching.
+pub const SMB2_NOTIFY_BREAK_LEASE_FLAG_ACK_REQUIRED: u32 = 0x01;
+
+/// What the server sends when a lease has to give up some of its caching.
+#[derive(Debug, PartialEq)]
+pub struct SmbLeaseBreakNotification {
+    // always 44.
+    pub size: u16,
+    /// Only meaningful for v2 leases, 0 otherwise.
+    pub new_epoch: u16,
+    pub flags: u32,
+    pub lease_key: u128,
+    pub current_lease_state: u32,
+    pub new_lease_state: u32,
+}
+
+impl SmbLeaseBreakNotification {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbLeaseBreakNotification, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, new_epoch) = c_u16("Failed to get new epoch", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, lease_key) = c_u128("Failed to get lease key", remaining)?;
+        let (remaining, current_lease_state) =
+            c_u32("Failed to get current lease state", remaining)?;
+        let (remaining, new_lease_state) = c_u32("Failed to get new lease state", remaining)?;
+        let (remaining, _break_reason) = c_u32("Failed to get break reason", remaining)?;
+        let (remaining, _access_mask_hint) = c_u32("Failed to get access mask hint", remaining)?;
+        let (remaining, _share_mask_hint) = c_u32("Failed to get share mask hint", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                new_epoch,
+                flags,
+                lease_key,
+                current_lease_state,
+                new_lease_state,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(44);
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.new_epoch.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.lease_key.to_le_bytes());
+        out.extend(self.current_lease_state.to_le_bytes());
+        out.extend(self.new_lease_state.to_le_bytes());
+        // break reason, access mask hint and share mask hint, all unused.
+        out.extend([0; 12]);
+        out
+    }
+}
+
+/// The client's acknowledgment of a lease break, and the server's
+/// response to it, which look the same.
+#[derive(Debug, PartialEq)]
+pub struct SmbLeaseBreak {
+    // always 36.
+    pub size: u16,
+    pub lease_key: u128,
+    pub lease_state: u32,
+}
+
+impl SmbLeaseBreak {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLeaseBreak, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, _flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, lease_key) = c_u128("Failed to get lease key", remaining)?;
+        let (remaining, lease_state) = c_u32("Failed to get lease state", remaining)?;
+        let (remaining, _lease_duration) = c_u64("Failed to get lease duration", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                lease_key,
+                lease_state,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(36);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 6]);
+        out.extend(self.lease_key.to_le_bytes());
+        out.extend(self.lease_state.to_le_bytes());
+        out.extend([0; 8]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_oplock_break() {
+        #[rustfmt::skip]
+        let smb_oplock_break = [
+            // size    | level| reserved
+            0x18, 0x00, 0x01, 0x00,
+            // reserved2
+            0x00, 0x00, 0x00, 0x00,
+            // file id
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (remaining, oplock_break) = SmbOplockBreak::parse(&smb_oplock_break).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            oplock_break,
+            SmbOplockBreak {
+                size: 24,
+                oplock_level: OplockLevel::LevelII,
+                file_id: SmbFileId {
+                    persistent: 7,
+                    volatile: 7,
+                },
+            }
+        );
+        assert_eq!(oplock_break.to_vec(), smb_oplock_break);
+    }
+
+    #[test]
+    fn example_lease_break() {
+        #[rustfmt::skip]
+        let smb_lease_break_notification = [
+            // size    | new epoch
+            0x2C, 0x00, 0x03, 0x00,
+            // flags
+            0x01, 0x00, 0x00, 0x00,
+            // lease key
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
+            // current lease state
+            0x07, 0x00, 0x00, 0x00,
+            // new lease state
+            0x03, 0x00, 0x00, 0x00,
+            // break reason
+            0x00, 0x00, 0x00, 0x00,
+            // access mask hint
+            0x00, 0x00, 0x00, 0x00,
+            // share mask hint
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let (remaining, notification) =
+            SmbLeaseBreakNotification::parse(&smb_lease_break_notification).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            notification,
+            SmbLeaseBreakNotification {
+                size: 44,
+                new_epoch: 3,
+                flags: SMB2_NOTIFY_BREAK_LEASE_FLAG_ACK_REQUIRED,
+                lease_key: 0x100F0E0D0C0B0A090807060504030201,
+                current_lease_state: 0x7,
+                new_lease_state: 0x3,
+            }
+        );
+        assert_eq!(notification.to_vec(), smb_lease_break_notification);
+
+        let ack = SmbLeaseBreak {
+            size: 36,
+            lease_key: notification.lease_key,
+            lease_state: notification.new_lease_state,
+        };
+        let encoded = ack.to_vec();
+        assert_eq!(encoded.len(), 36);
+        assert_eq!(SmbLeaseBreak::parse(&encoded).unwrap().1, ack);
+    }
+}
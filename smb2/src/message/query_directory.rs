@@ -0,0 +1,526 @@
+use nom::bytes::complete::take;
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::number::complete::le_u8;
+
+use crate::message::{
+    c_u128, c_u16, c_u32, c_u64, encode_utf16le, fail, header_offset_buffer, pad_to, parse_utf16le,
+    SmbFileId, HEADER_SIZE,
+};
+
+/// The FileInformationClass values QUERY_DIRECTORY understands.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileDirectoryInformationClass {
+    FileDirectoryInformation = 0x01,
+    FileFullDirectoryInformation = 0x02,
+    FileBothDirectoryInformation = 0x03,
+    FileNamesInformation = 0x0C,
+    FileIdBothDirectoryInformation = 0x25,
+    FileIdFullDirectoryInformation = 0x26,
+    FileIdExtdDirectoryInformation = 0x3C,
+}
+
+#[derive(Debug)]
+pub struct InvalidFileDirectoryInformationClass;
+
+impl TryFrom<u8> for FileDirectoryInformationClass {
+    type Error = InvalidFileDirectoryInformationClass;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::FileDirectoryInformation),
+            0x02 => Ok(Self::FileFullDirectoryInformation),
+            0x03 => Ok(Self::FileBothDirectoryInformation),
+            0x0C => Ok(Self::FileNamesInformation),
+            0x25 => Ok(Self::FileIdBothDirectoryInformation),
+            0x26 => Ok(Self::FileIdFullDirectoryInformation),
+            0x3C => Ok(Self::FileIdExtdDirectoryInformation),
+            _ => Err(InvalidFileDirectoryInformationClass),
+        }
+    }
+}
+
+pub const SMB2_RESTART_SCANS: u8 = 0x01;
+pub const SMB2_RETURN_SINGLE_ENTRY: u8 = 0x02;
+pub const SMB2_INDEX_SPECIFIED: u8 = 0x04;
+pub const SMB2_REOPEN: u8 = 0x10;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbQueryDirectory {
+    // always 33.
+    pub size: u16,
+    pub file_information_class: FileDirectoryInformationClass,
+    pub flags: u8,
+    /// Where to resume from, only meaningful with SMB2_INDEX_SPECIFIED.
+    pub file_index: u32,
+    pub file_id: SmbFileId,
+    pub file_name_offset: u16,
+    pub file_name_length: u16,
+    pub output_buffer_length: u32,
+    /// The search pattern, e.g. `*` or `*.txt`.
+    pub file_name: String,
+}
+
+impl SmbQueryDirectory {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbQueryDirectory, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, file_information_class) = context(
+            "Failed to get file information class",
+            map_res(le_u8, FileDirectoryInformationClass::try_from),
+        )(remaining)?;
+        let (remaining, flags) = le_u8(remaining)?;
+        let (remaining, file_index) = c_u32("Failed to get file index", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, file_name_offset) = c_u16("Failed to get file name offset", remaining)?;
+        let (remaining, file_name_length) = c_u16("Failed to get file name length", remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, file_name) = header_offset_buffer(
+            "Failed to get file name",
+            body,
+            remaining,
+            file_name_offset as _,
+            file_name_length as _,
+        )?;
+        let (_, file_name) = parse_utf16le("Failed to decode file name", file_name)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                file_information_class,
+                flags,
+                file_index,
+                file_id,
+                file_name_offset,
+                file_name_length,
+                output_buffer_length,
+                file_name,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.file_name_length as usize);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.file_information_class as u8);
+        out.push(self.flags);
+        out.extend(self.file_index.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend(self.file_name_offset.to_le_bytes());
+        out.extend(self.file_name_length.to_le_bytes());
+        out.extend(self.output_buffer_length.to_le_bytes());
+        if self.file_name_length == 0 {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.file_name_offset as usize - HEADER_SIZE);
+            out.extend(encode_utf16le(&self.file_name));
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbQueryDirectoryResponse {
+    // always 9.
+    pub size: u16,
+    pub output_buffer_offset: u16,
+    pub output_buffer_length: u32,
+    /// The directory entries, in whatever format was asked for.
+    /// See `FileDirectoryEntry` for making sense of them.
+    pub buffer: Vec<u8>,
+}
+
+impl SmbQueryDirectoryResponse {
+    /// Where the entries go when there are any.
+    pub const OUTPUT_BUFFER_OFFSET: u16 = (HEADER_SIZE + 8) as u16;
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbQueryDirectoryResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, output_buffer_offset) =
+            c_u16("Failed to get output buffer offset", remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get output buffer",
+            body,
+            remaining,
+            output_buffer_offset as _,
+            output_buffer_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                output_buffer_offset,
+                output_buffer_length,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.output_buffer_offset.to_le_bytes());
+        out.extend(self.output_buffer_length.to_le_bytes());
+        if self.buffer.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.output_buffer_offset as usize - HEADER_SIZE);
+            out.extend(&self.buffer);
+        }
+        out
+    }
+}
+
+/// Everything any of the directory information classes can say about a file.
+/// Each class only puts some of this on the wire; the rest is ignored
+/// when encoding, and left at its default when parsing.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileDirectoryEntry {
+    pub file_index: u32,
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    pub change_time: u64,
+    pub end_of_file: u64,
+    pub allocation_size: u64,
+    pub file_attributes: u32,
+    pub ea_size: u32,
+    /// Only in FileIdExtdDirectoryInformation, which reuses the EA size
+    /// slot's neighbour for it.
+    pub reparse_point_tag: u32,
+    /// The 8.3 name, if there is one. At most 12 characters.
+    pub short_name: String,
+    /// 64 bits for most classes, the full 128 for FileIdExtdDirectoryInformation.
+    pub file_id: u128,
+    pub file_name: String,
+}
+
+impl FileDirectoryEntry {
+    pub fn to_vec(&self, class: FileDirectoryInformationClass) -> Vec<u8> {
+        use FileDirectoryInformationClass::*;
+        let file_name = encode_utf16le(&self.file_name);
+        let mut out = Vec::with_capacity(104 + file_name.len());
+        // NextEntryOffset, filled in when the entry gets chained.
+        out.extend(0u32.to_le_bytes());
+        out.extend(self.file_index.to_le_bytes());
+        if class == FileNamesInformation {
+            out.extend((file_name.len() as u32).to_le_bytes());
+            out.extend(file_name);
+            return out;
+        }
+        out.extend(self.creation_time.to_le_bytes());
+        out.extend(self.last_access_time.to_le_bytes());
+        out.extend(self.last_write_time.to_le_bytes());
+        out.extend(self.change_time.to_le_bytes());
+        out.extend(self.end_of_file.to_le_bytes());
+        out.extend(self.allocation_size.to_le_bytes());
+        out.extend(self.file_attributes.to_le_bytes());
+        out.extend((file_name.len() as u32).to_le_bytes());
+        match class {
+            FileDirectoryInformation => {}
+            FileFullDirectoryInformation => out.extend(self.ea_size.to_le_bytes()),
+            FileIdFullDirectoryInformation => {
+                out.extend(self.ea_size.to_le_bytes());
+                out.extend([0; 4]);
+                out.extend((self.file_id as u64).to_le_bytes());
+            }
+            FileBothDirectoryInformation | FileIdBothDirectoryInformation => {
+                out.extend(self.ea_size.to_le_bytes());
+                let short_name = encode_utf16le(&self.short_name);
+                let short_name = &short_name[..short_name.len().min(24)];
+                out.push(short_name.len() as u8);
+                out.push(0);
+                let mut padded_short_name = [0; 24];
+                padded_short_name[..short_name.len()].copy_from_slice(short_name);
+                out.extend(padded_short_name);
+                if class == FileIdBothDirectoryInformation {
+                    out.extend([0; 2]);
+                    out.extend((self.file_id as u64).to_le_bytes());
+                }
+            }
+            FileIdExtdDirectoryInformation => {
+                out.extend(self.ea_size.to_le_bytes());
+                out.extend(self.reparse_point_tag.to_le_bytes());
+                out.extend(self.file_id.to_le_bytes());
+            }
+            FileNamesInformation => unreachable!(),
+        }
+        out.extend(file_name);
+        out
+    }
+
+    /// Parses a whole chain of entries, following NextEntryOffset.
+    pub fn parse_list(
+        body: &[u8],
+        class: FileDirectoryInformationClass,
+    ) -> nom::IResult<&[u8], Vec<FileDirectoryEntry>, nom::error::Error<&[u8]>> {
+        let mut entries = vec![];
+        let mut rest = body;
+        while !rest.is_empty() {
+            let (_, next_entry_offset) = c_u32("Failed to get next entry offset", rest)?;
+            let (_, entry) = Self::parse(rest, class)?;
+            entries.push(entry);
+            if next_entry_offset == 0 {
+                break;
+            }
+            rest = match rest.get(next_entry_offset as usize..) {
+                Some(rest) => rest,
+                None => return fail(rest),
+            };
+        }
+        Ok((&[], entries))
+    }
+
+    fn parse(
+        body: &[u8],
+        class: FileDirectoryInformationClass,
+    ) -> nom::IResult<&[u8], FileDirectoryEntry, nom::error::Error<&[u8]>> {
+        use FileDirectoryInformationClass::*;
+        let mut entry = Self::default();
+        let (remaining, _next_entry_offset) = c_u32("Failed to get next entry offset", body)?;
+        let (remaining, file_index) = c_u32("Failed to get file index", remaining)?;
+        entry.file_index = file_index;
+        if class == FileNamesInformation {
+            let (remaining, file_name_length) = c_u32("Failed to get name length", remaining)?;
+            let (remaining, file_name) = take(file_name_length)(remaining)?;
+            entry.file_name = parse_utf16le("Failed to decode file name", file_name)?.1;
+            return Ok((remaining, entry));
+        }
+        let (remaining, creation_time) = c_u64("Failed to get creation time", remaining)?;
+        let (remaining, last_access_time) = c_u64("Failed to get last access time", remaining)?;
+        let (remaining, last_write_time) = c_u64("Failed to get last write time", remaining)?;
+        let (remaining, change_time) = c_u64("Failed to get change time", remaining)?;
+        let (remaining, end_of_file) = c_u64("Failed to get end of file", remaining)?;
+        let (remaining, allocation_size) = c_u64("Failed to get allocation size", remaining)?;
+        let (remaining, file_attributes) = c_u32("Failed to get file attributes", remaining)?;
+        let (mut remaining, file_name_length) = c_u32("Failed to get name length", remaining)?;
+        entry.creation_time = creation_time;
+        entry.last_access_time = last_access_time;
+        entry.last_write_time = last_write_time;
+        entry.change_time = change_time;
+        entry.end_of_file = end_of_file;
+        entry.allocation_size = allocation_size;
+        entry.file_attributes = file_attributes;
+        if class != FileDirectoryInformation {
+            let (rest, ea_size) = c_u32("Failed to get EA size", remaining)?;
+            entry.ea_size = ea_size;
+            remaining = rest;
+        }
+        match class {
+            FileIdFullDirectoryInformation => {
+                let (rest, _reserved) = c_u32("Failed to get reserved", remaining)?;
+                let (rest, file_id) = c_u64("Failed to get file id", rest)?;
+                entry.file_id = file_id as u128;
+                remaining = rest;
+            }
+            FileBothDirectoryInformation | FileIdBothDirectoryInformation => {
+                let (rest, short_name_length) = le_u8(remaining)?;
+                let (rest, _reserved) = le_u8(rest)?;
+                let (rest, short_name) = take(24usize)(rest)?;
+                let short_name = short_name
+                    .get(..short_name_length as usize)
+                    .map_or(Ok((&[] as &[u8], String::new())), |short_name| {
+                        parse_utf16le("Failed to decode short name", short_name)
+                    })?;
+                entry.short_name = short_name.1;
+                remaining = rest;
+                if class == FileIdBothDirectoryInformation {
+                    let (rest, _reserved) = c_u16("Failed to get reserved", remaining)?;
+                    let (rest, file_id) = c_u64("Failed to get file id", rest)?;
+                    entry.file_id = file_id as u128;
+                    remaining = rest;
+                }
+            }
+            FileIdExtdDirectoryInformation => {
+                let (rest, reparse_point_tag) = c_u32("Failed to get reparse tag", remaining)?;
+                let (rest, file_id) = c_u128("Failed to get file id", rest)?;
+                entry.reparse_point_tag = reparse_point_tag;
+                entry.file_id = file_id;
+                remaining = rest;
+            }
+            _ => {}
+        }
+        let (remaining, file_name) = take(file_name_length)(remaining)?;
+        entry.file_name = parse_utf16le("Failed to decode file name", file_name)?.1;
+        Ok((remaining, entry))
+    }
+}
+
+/// Chains directory entries together, keeping the whole thing under
+/// the size the client said it could take.
+pub struct FileDirectoryListBuilder {
+    class: FileDirectoryInformationClass,
+    max_len: usize,
+    out: Vec<u8>,
+    last_entry: Option<usize>,
+}
+
+impl FileDirectoryListBuilder {
+    pub fn new(class: FileDirectoryInformationClass, max_len: usize) -> Self {
+        Self {
+            class,
+            max_len,
+            out: vec![],
+            last_entry: None,
+        }
+    }
+
+    /// Adds an entry, returning false (and leaving the list alone)
+    /// if there isn't room for it.
+    pub fn push(&mut self, entry: &FileDirectoryEntry) -> bool {
+        let encoded = entry.to_vec(self.class);
+        // every entry starts 8 byte aligned.
+        let start = self.out.len().next_multiple_of(8);
+        if start + encoded.len() > self.max_len {
+            return false;
+        }
+        if let Some(last_entry) = self.last_entry {
+            let next_entry_offset = (start - last_entry) as u32;
+            self.out[last_entry..last_entry + 4].copy_from_slice(&next_entry_offset.to_le_bytes());
+        }
+        pad_to(&mut self.out, start);
+        self.out.extend(encoded);
+        self.last_entry = Some(start);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_entry.is_none()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_query_directory() {
+        #[rustfmt::skip]
+        let smb_query_directory = [
+            // size    | class | flags
+            0x21, 0x00, 0x25, 0x01,
+            // file index
+            0x00, 0x00, 0x00, 0x00,
+            // file id
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // name offset | name length
+            0x60, 0x00, 0x02, 0x00,
+            // output buffer length
+            0x00, 0x00, 0x01, 0x00,
+            // *
+            b'*', 0x00,
+        ];
+        let (_, query_directory) = SmbQueryDirectory::parse(&smb_query_directory).unwrap();
+        assert_eq!(
+            query_directory,
+            SmbQueryDirectory {
+                size: 33,
+                file_information_class:
+                    FileDirectoryInformationClass::FileIdBothDirectoryInformation,
+                flags: SMB2_RESTART_SCANS,
+                file_index: 0,
+                file_id: SmbFileId {
+                    persistent: 1,
+                    volatile: 1
+                },
+                file_name_offset: 0x60,
+                file_name_length: 2,
+                output_buffer_length: 0x10000,
+                file_name: "*".into(),
+            }
+        );
+        assert_eq!(query_directory.to_vec(), smb_query_directory);
+    }
+
+    #[test]
+    fn entries_round_trip_in_every_class() {
+        use FileDirectoryInformationClass::*;
+        let entries = [
+            FileDirectoryEntry {
+                file_index: 0,
+                creation_time: 1,
+                last_access_time: 2,
+                last_write_time: 3,
+                change_time: 4,
+                end_of_file: 5,
+                allocation_size: 4096,
+                file_attributes: 0x20,
+                ea_size: 0,
+                reparse_point_tag: 0,
+                short_name: "A~1.TXT".into(),
+                file_id: 77,
+                file_name: "a long name.txt".into(),
+            },
+            FileDirectoryEntry {
+                file_name: "b".into(),
+                file_attributes: 0x10,
+                file_id: 78,
+                ..Default::default()
+            },
+        ];
+        for class in [
+            FileDirectoryInformation,
+            FileFullDirectoryInformation,
+            FileBothDirectoryInformation,
+            FileNamesInformation,
+            FileIdBothDirectoryInformation,
+            FileIdFullDirectoryInformation,
+            FileIdExtdDirectoryInformation,
+        ] {
+            let mut builder = FileDirectoryListBuilder::new(class, 0x10000);
+            assert!(entries.iter().all(|entry| builder.push(entry)));
+            let encoded = builder.finish();
+
+            let first_len = entries[0].to_vec(class).len();
+            let next_entry_offset = u32::from_le_bytes(encoded[..4].try_into().unwrap());
+            assert_eq!(next_entry_offset as usize, first_len.next_multiple_of(8));
+
+            let (_, parsed) = FileDirectoryEntry::parse_list(&encoded, class).unwrap();
+            assert_eq!(parsed.len(), 2, "{class:?}");
+            assert_eq!(parsed[0].file_name, entries[0].file_name, "{class:?}");
+            assert_eq!(parsed[1].file_name, entries[1].file_name, "{class:?}");
+            if class != FileNamesInformation {
+                assert_eq!(parsed[0].end_of_file, 5, "{class:?}");
+                assert_eq!(parsed[1].file_attributes, 0x10, "{class:?}");
+            }
+            if matches!(
+                class,
+                FileBothDirectoryInformation | FileIdBothDirectoryInformation
+            ) {
+                assert_eq!(parsed[0].short_name, "A~1.TXT");
+            }
+            if matches!(
+                class,
+                FileIdBothDirectoryInformation
+                    | FileIdFullDirectoryInformation
+                    | FileIdExtdDirectoryInformation
+            ) {
+                assert_eq!(parsed[1].file_id, 78);
+            }
+        }
+    }
+
+    #[test]
+    fn list_builder_stops_when_full() {
+        let entry = FileDirectoryEntry {
+            file_name: "x".into(),
+            ..Default::default()
+        };
+        let class = FileDirectoryInformationClass::FileNamesInformation;
+        // 14 bytes each, so the second one starts at 16 and ends at 30.
+        let mut builder = FileDirectoryListBuilder::new(class, 29);
+        assert!(builder.push(&entry));
+        assert!(!builder.push(&entry));
+        assert_eq!(builder.finish().len(), 14);
+    }
+}
@@ -0,0 +1,339 @@
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u16, c_u32, header_offset_buffer, pad_to, SmbFileId, HEADER_SIZE};
+
+/// What QUERY_INFO and SET_INFO are asking about. The info class
+/// that comes with it means something different for each.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InfoType {
+    File = 0x01,
+    Filesystem = 0x02,
+    Security = 0x03,
+    Quota = 0x04,
+}
+
+#[derive(Debug)]
+pub struct InvalidInfoType;
+
+impl TryFrom<u8> for InfoType {
+    type Error = InvalidInfoType;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::File),
+            0x02 => Ok(Self::Filesystem),
+            0x03 => Ok(Self::Security),
+            0x04 => Ok(Self::Quota),
+            _ => Err(InvalidInfoType),
+        }
+    }
+}
+
+// which parts of a security descriptor to get or set, in AdditionalInformation.
+pub const OWNER_SECURITY_INFORMATION: u32 = 0x0000_0001;
+pub const GROUP_SECURITY_INFORMATION: u32 = 0x0000_0002;
+pub const DACL_SECURITY_INFORMATION: u32 = 0x0000_0004;
+pub const SACL_SECURITY_INFORMATION: u32 = 0x0000_0008;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbQueryInfo {
+    // always 41.
+    pub size: u16,
+    pub info_type: InfoType,
+    /// A `FileInfoClass` or `FsInfoClass`, depending on `info_type`.
+    pub file_info_class: u8,
+    pub output_buffer_length: u32,
+    pub input_buffer_offset: u16,
+    pub input_buffer_length: u32,
+    pub additional_information: u32,
+    pub flags: u32,
+    pub file_id: SmbFileId,
+    /// Only used for quota and FileFullEaInformation queries.
+    pub input_buffer: Vec<u8>,
+}
+
+impl SmbQueryInfo {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbQueryInfo, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, info_type) = context(
+            "Failed to get info type",
+            map_res(le_u8, InfoType::try_from),
+        )(remaining)?;
+        let (remaining, file_info_class) = le_u8(remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, input_buffer_offset) =
+            c_u16("Failed to get input buffer offset", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, input_buffer_length) =
+            c_u32("Failed to get input buffer length", remaining)?;
+        let (remaining, additional_information) =
+            c_u32("Failed to get additional information", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, input_buffer) = header_offset_buffer(
+            "Failed to get input buffer",
+            body,
+            remaining,
+            input_buffer_offset as _,
+            input_buffer_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                info_type,
+                file_info_class,
+                output_buffer_length,
+                input_buffer_offset,
+                input_buffer_length,
+                additional_information,
+                flags,
+                file_id,
+                input_buffer: input_buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40 + self.input_buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.push(self.info_type as u8);
+        out.push(self.file_info_class);
+        out.extend(self.output_buffer_length.to_le_bytes());
+        out.extend(self.input_buffer_offset.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.input_buffer_length.to_le_bytes());
+        out.extend(self.additional_information.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        if self.input_buffer.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.input_buffer_offset as usize - HEADER_SIZE);
+            out.extend(&self.input_buffer);
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbQueryInfoResponse {
+    // always 9.
+    pub size: u16,
+    pub output_buffer_offset: u16,
+    pub output_buffer_length: u32,
+    /// An encoded `FileInfo`, `FsInfo` or security descriptor.
+    pub buffer: Vec<u8>,
+}
+
+impl SmbQueryInfoResponse {
+    pub const OUTPUT_BUFFER_OFFSET: u16 = (HEADER_SIZE + 8) as u16;
+
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbQueryInfoResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, output_buffer_offset) =
+            c_u16("Failed to get output buffer offset", remaining)?;
+        let (remaining, output_buffer_length) =
+            c_u32("Failed to get output buffer length", remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get output buffer",
+            body,
+            remaining,
+            output_buffer_offset as _,
+            output_buffer_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                output_buffer_offset,
+                output_buffer_length,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.output_buffer_offset.to_le_bytes());
+        out.extend(self.output_buffer_length.to_le_bytes());
+        if self.buffer.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.output_buffer_offset as usize - HEADER_SIZE);
+            out.extend(&self.buffer);
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbSetInfo {
+    // always 33.
+    pub size: u16,
+    pub info_type: InfoType,
+    /// A `FileInfoClass` or `FsInfoClass`, depending on `info_type`.
+    pub file_info_class: u8,
+    pub buffer_length: u32,
+    pub buffer_offset: u16,
+    pub additional_information: u32,
+    pub file_id: SmbFileId,
+    pub buffer: Vec<u8>,
+}
+
+impl SmbSetInfo {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbSetInfo, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, info_type) = context(
+            "Failed to get info type",
+            map_res(le_u8, InfoType::try_from),
+        )(remaining)?;
+        let (remaining, file_info_class) = le_u8(remaining)?;
+        let (remaining, buffer_length) = c_u32("Failed to get buffer length", remaining)?;
+        let (remaining, buffer_offset) = c_u16("Failed to get buffer offset", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, additional_information) =
+            c_u32("Failed to get additional information", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get buffer",
+            body,
+            remaining,
+            buffer_offset as _,
+            buffer_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                info_type,
+                file_info_class,
+                buffer_length,
+                buffer_offset,
+                additional_information,
+                file_id,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.push(self.info_type as u8);
+        out.push(self.file_info_class);
+        out.extend(self.buffer_length.to_le_bytes());
+        out.extend(self.buffer_offset.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.additional_information.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        if self.buffer.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.buffer_offset as usize - HEADER_SIZE);
+            out.extend(&self.buffer);
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbSetInfoResponse {
+    // always 2.
+    pub size: u16,
+}
+
+impl SmbSetInfoResponse {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbSetInfoResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.size.to_le_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{FileInfo, FileInfoClass};
+
+    #[test]
+    fn example_query_info() {
+        #[rustfmt::skip]
+        let smb_query_info = [
+            // size    | type | class
+            0x29, 0x00, 0x01, 0x12,
+            // output buffer length
+            0x00, 0x10, 0x00, 0x00,
+            // input offset | reserved
+            0x68, 0x00, 0x00, 0x00,
+            // input length
+            0x00, 0x00, 0x00, 0x00,
+            // additional information
+            0x00, 0x00, 0x00, 0x00,
+            // flags
+            0x00, 0x00, 0x00, 0x00,
+            // file id
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // no input
+            0x00,
+        ];
+        let (_, query_info) = SmbQueryInfo::parse(&smb_query_info).unwrap();
+        assert_eq!(
+            query_info,
+            SmbQueryInfo {
+                size: 41,
+                info_type: InfoType::File,
+                file_info_class: FileInfoClass::FileAllInformation as u8,
+                output_buffer_length: 0x1000,
+                input_buffer_offset: 0x68,
+                input_buffer_length: 0,
+                additional_information: 0,
+                flags: 0,
+                file_id: SmbFileId {
+                    persistent: 5,
+                    volatile: 5
+                },
+                input_buffer: vec![],
+            }
+        );
+        assert_eq!(query_info.to_vec(), smb_query_info);
+    }
+
+    #[test]
+    fn example_set_info() {
+        #[rustfmt::skip]
+        let smb_set_info = [
+            // size    | type | class
+            0x21, 0x00, 0x01, 0x14,
+            // buffer length
+            0x08, 0x00, 0x00, 0x00,
+            // offset  | reserved
+            0x60, 0x00, 0x00, 0x00,
+            // additional information
+            0x00, 0x00, 0x00, 0x00,
+            // file id
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // end of file
+            0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (_, set_info) = SmbSetInfo::parse(&smb_set_info).unwrap();
+        assert_eq!(set_info.info_type, InfoType::File);
+        assert_eq!(set_info.buffer_length, 8);
+        let class = FileInfoClass::try_from(set_info.file_info_class).unwrap();
+        let (_, file_info) = FileInfo::parse(class, &set_info.buffer).unwrap();
+        assert_eq!(file_info, FileInfo::EndOfFile { end_of_file: 0x400 });
+        assert_eq!(set_info.to_vec(), smb_set_info);
+    }
+}
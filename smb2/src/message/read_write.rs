@@ -0,0 +1,516 @@
+use bytes::Bytes;
+use nom::combinator::map_res;
+use nom::error::context;
+use nom::multi::many0;
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u16, c_u32, c_u64, header_offset_buffer, pad_to, SmbFileId, HEADER_SIZE};
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SmbChannel {
+    None = 0,
+    RdmaV1 = 1,
+    RdmaV1Invalidate = 2,
+    RdmaTransform = 3,
+}
+
+#[derive(Debug)]
+pub struct InvalidChannel;
+
+impl TryFrom<u32> for SmbChannel {
+    type Error = InvalidChannel;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::RdmaV1),
+            2 => Ok(Self::RdmaV1Invalidate),
+            3 => Ok(Self::RdmaTransform),
+            _ => Err(InvalidChannel),
+        }
+    }
+}
+
+/// SMB_DIRECT_BUFFER_DESCRIPTOR_V1, i.e. where on the client an RDMA
+/// transfer should land (or come from).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SmbDirectBufferDescriptor {
+    pub offset: u64,
+    pub token: u32,
+    pub length: u32,
+}
+
+impl SmbDirectBufferDescriptor {
+    fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbDirectBufferDescriptor, nom::error::Error<&[u8]>> {
+        let (remaining, offset) = c_u64("Failed to get buffer descriptor offset", body)?;
+        let (remaining, token) = c_u32("Failed to get buffer descriptor token", remaining)?;
+        let (remaining, length) = c_u32("Failed to get buffer descriptor length", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                offset,
+                token,
+                length,
+            },
+        ))
+    }
+
+    fn parse_list(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], Vec<SmbDirectBufferDescriptor>, nom::error::Error<&[u8]>> {
+        context("Failed to get channel info", many0(Self::parse))(body)
+    }
+
+    fn list_to_vec(descriptors: &[SmbDirectBufferDescriptor]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 * descriptors.len());
+        for descriptor in descriptors {
+            out.extend(descriptor.offset.to_le_bytes());
+            out.extend(descriptor.token.to_le_bytes());
+            out.extend(descriptor.length.to_le_bytes());
+        }
+        out
+    }
+}
+
+/// Turns a slice of the message into a payload. When the message came from
+/// a `Bytes`, this can point straight into it instead of copying.
+pub(crate) type MakePayload<'p> = &'p dyn Fn(&[u8]) -> Bytes;
+
+pub const SMB2_READFLAG_READ_UNBUFFERED: u8 = 0x01;
+pub const SMB2_READFLAG_REQUEST_COMPRESSED: u8 = 0x02;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbRead {
+    // always 49.
+    pub size: u16,
+    // how far into the response the client would like the data to start.
+    pub padding: u8,
+    pub flags: u8,
+    pub length: u32,
+    pub offset: u64,
+    pub file_id: SmbFileId,
+    pub minimum_count: u32,
+    pub channel: SmbChannel,
+    pub remaining_bytes: u32,
+    pub read_channel_info_offset: u16,
+    pub read_channel_info_length: u16,
+    pub read_channel_info: Vec<SmbDirectBufferDescriptor>,
+}
+
+impl SmbRead {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbRead, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, padding) = le_u8(remaining)?;
+        let (remaining, flags) = le_u8(remaining)?;
+        let (remaining, length) = c_u32("Failed to get length", remaining)?;
+        let (remaining, offset) = c_u64("Failed to get offset", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, minimum_count) = c_u32("Failed to get minimum count", remaining)?;
+        let (remaining, channel) = parse_channel(remaining)?;
+        let (remaining, remaining_bytes) = c_u32("Failed to get remaining bytes", remaining)?;
+        let (remaining, read_channel_info_offset) =
+            c_u16("Failed to get read channel info offset", remaining)?;
+        let (remaining, read_channel_info_length) =
+            c_u16("Failed to get read channel info length", remaining)?;
+        let (remaining, read_channel_info) = header_offset_buffer(
+            "Failed to get read channel info",
+            body,
+            remaining,
+            read_channel_info_offset as _,
+            read_channel_info_length as _,
+        )?;
+        let (_, read_channel_info) = SmbDirectBufferDescriptor::parse_list(read_channel_info)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                padding,
+                flags,
+                length,
+                offset,
+                file_id,
+                minimum_count,
+                channel,
+                remaining_bytes,
+                read_channel_info_offset,
+                read_channel_info_length,
+                read_channel_info,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(49);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.padding);
+        out.push(self.flags);
+        out.extend(self.length.to_le_bytes());
+        out.extend(self.offset.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend(self.minimum_count.to_le_bytes());
+        out.extend((self.channel as u32).to_le_bytes());
+        out.extend(self.remaining_bytes.to_le_bytes());
+        out.extend(self.read_channel_info_offset.to_le_bytes());
+        out.extend(self.read_channel_info_length.to_le_bytes());
+        if self.read_channel_info.is_empty() {
+            // the buffer is always at least a byte.
+            out.push(0);
+        } else {
+            pad_to(
+                &mut out,
+                self.read_channel_info_offset as usize - HEADER_SIZE,
+            );
+            out.extend(SmbDirectBufferDescriptor::list_to_vec(
+                &self.read_channel_info,
+            ));
+        }
+        out
+    }
+}
+
+pub const SMB2_READFLAG_RESPONSE_RDMA_TRANSFORM: u32 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbReadResponse {
+    // always 17.
+    pub size: u16,
+    pub data_offset: u8,
+    pub data_length: u32,
+    pub data_remaining: u32,
+    pub flags: u32,
+    pub data: Bytes,
+}
+
+impl SmbReadResponse {
+    /// Where the data goes if there's no reason to put it anywhere else.
+    pub const DATA_OFFSET: u8 = (HEADER_SIZE + 16) as u8;
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbReadResponse, nom::error::Error<&[u8]>> {
+        Self::parse_with(body, &Bytes::copy_from_slice)
+    }
+
+    pub(crate) fn parse_with<'a>(
+        body: &'a [u8],
+        make_payload: MakePayload,
+    ) -> nom::IResult<&'a [u8], SmbReadResponse, nom::error::Error<&'a [u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, data_offset) = le_u8(remaining)?;
+        let (remaining, _reserved) = le_u8(remaining)?;
+        let (remaining, data_length) = c_u32("Failed to get data length", remaining)?;
+        let (remaining, data_remaining) = c_u32("Failed to get data remaining", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (remaining, data) = header_offset_buffer(
+            "Failed to get data",
+            body,
+            remaining,
+            data_offset as _,
+            data_length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                data_offset,
+                data_length,
+                data_remaining,
+                flags,
+                data: make_payload(data),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.data.len());
+        out.extend(self.size.to_le_bytes());
+        out.push(self.data_offset);
+        out.push(0);
+        out.extend(self.data_length.to_le_bytes());
+        out.extend(self.data_remaining.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        if self.data.is_empty() {
+            out.push(0);
+        } else {
+            pad_to(&mut out, self.data_offset as usize - HEADER_SIZE);
+            out.extend_from_slice(&self.data);
+        }
+        out
+    }
+}
+
+pub const SMB2_WRITEFLAG_WRITE_THROUGH: u32 = 0x01;
+pub const SMB2_WRITEFLAG_WRITE_UNBUFFERED: u32 = 0x02;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbWrite {
+    // always 49.
+    pub size: u16,
+    pub data_offset: u16,
+    pub length: u32,
+    pub offset: u64,
+    pub file_id: SmbFileId,
+    pub channel: SmbChannel,
+    pub remaining_bytes: u32,
+    pub write_channel_info_offset: u16,
+    pub write_channel_info_length: u16,
+    pub flags: u32,
+    pub write_channel_info: Vec<SmbDirectBufferDescriptor>,
+    pub data: Bytes,
+}
+
+impl SmbWrite {
+    /// Where the data goes if there's no reason to put it anywhere else.
+    pub const DATA_OFFSET: u16 = (HEADER_SIZE + 48) as u16;
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbWrite, nom::error::Error<&[u8]>> {
+        Self::parse_with(body, &Bytes::copy_from_slice)
+    }
+
+    pub(crate) fn parse_with<'a>(
+        body: &'a [u8],
+        make_payload: MakePayload,
+    ) -> nom::IResult<&'a [u8], SmbWrite, nom::error::Error<&'a [u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, data_offset) = c_u16("Failed to get data offset", remaining)?;
+        let (remaining, length) = c_u32("Failed to get length", remaining)?;
+        let (remaining, offset) = c_u64("Failed to get offset", remaining)?;
+        let (remaining, file_id) = SmbFileId::parse(remaining)?;
+        let (remaining, channel) = parse_channel(remaining)?;
+        let (remaining, remaining_bytes) = c_u32("Failed to get remaining bytes", remaining)?;
+        let (remaining, write_channel_info_offset) =
+            c_u16("Failed to get write channel info offset", remaining)?;
+        let (remaining, write_channel_info_length) =
+            c_u16("Failed to get write channel info length", remaining)?;
+        let (remaining, flags) = c_u32("Failed to get flags", remaining)?;
+        let (_, write_channel_info) = header_offset_buffer(
+            "Failed to get write channel info",
+            body,
+            remaining,
+            write_channel_info_offset as _,
+            write_channel_info_length as _,
+        )?;
+        let (_, write_channel_info) = SmbDirectBufferDescriptor::parse_list(write_channel_info)?;
+        let (remaining, data) = header_offset_buffer(
+            "Failed to get data",
+            body,
+            remaining,
+            data_offset as _,
+            length as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                data_offset,
+                length,
+                offset,
+                file_id,
+                channel,
+                remaining_bytes,
+                write_channel_info_offset,
+                write_channel_info_length,
+                flags,
+                write_channel_info,
+                data: make_payload(data),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(48 + self.data.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.data_offset.to_le_bytes());
+        out.extend(self.length.to_le_bytes());
+        out.extend(self.offset.to_le_bytes());
+        out.extend(self.file_id.to_vec());
+        out.extend((self.channel as u32).to_le_bytes());
+        out.extend(self.remaining_bytes.to_le_bytes());
+        out.extend(self.write_channel_info_offset.to_le_bytes());
+        out.extend(self.write_channel_info_length.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        if !self.write_channel_info.is_empty() {
+            pad_to(
+                &mut out,
+                self.write_channel_info_offset as usize - HEADER_SIZE,
+            );
+            out.extend(SmbDirectBufferDescriptor::list_to_vec(
+                &self.write_channel_info,
+            ));
+        }
+        if self.data.is_empty() {
+            pad_to(&mut out, 49);
+        } else {
+            pad_to(&mut out, self.data_offset as usize - HEADER_SIZE);
+            out.extend_from_slice(&self.data);
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbWriteResponse {
+    // always 17.
+    pub size: u16,
+    pub count: u32,
+    pub remaining: u32,
+    pub write_channel_info_offset: u16,
+    pub write_channel_info_length: u16,
+}
+
+impl SmbWriteResponse {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbWriteResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, count) = c_u32("Failed to get count", remaining)?;
+        let (remaining, remaining_count) = c_u32("Failed to get remaining", remaining)?;
+        let (remaining, write_channel_info_offset) =
+            c_u16("Failed to get write channel info offset", remaining)?;
+        let (remaining, write_channel_info_length) =
+            c_u16("Failed to get write channel info length", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                count,
+                remaining: remaining_count,
+                write_channel_info_offset,
+                write_channel_info_length,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(17);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(self.count.to_le_bytes());
+        out.extend(self.remaining.to_le_bytes());
+        out.extend(self.write_channel_info_offset.to_le_bytes());
+        out.extend(self.write_channel_info_length.to_le_bytes());
+        // the buffer is always at least a byte.
+        out.push(0);
+        out
+    }
+}
+
+fn parse_channel(body: &[u8]) -> nom::IResult<&[u8], SmbChannel, nom::error::Error<&[u8]>> {
+    context(
+        "Failed to get channel",
+        map_res(
+            |body| c_u32("Failed to get channel", body),
+            SmbChannel::try_from,
+        ),
+    )(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_read() {
+        #[rustfmt::skip]
+        let smb_read = [
+            // size    | padding | flags
+            0x31, 0x00, 0x50, 0x01,
+            // length
+            0x00, 0x00, 0x01, 0x00,
+            // offset
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // file id
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // minimum count
+            0x00, 0x00, 0x00, 0x00,
+            // channel
+            0x00, 0x00, 0x00, 0x00,
+            // remaining bytes
+            0x00, 0x00, 0x00, 0x00,
+            // channel info offset | channel info length
+            0x00, 0x00, 0x00, 0x00,
+            // buffer
+            0x00,
+        ];
+        let (_, read) = SmbRead::parse(&smb_read).unwrap();
+        assert_eq!(
+            read,
+            SmbRead {
+                size: 49,
+                padding: 0x50,
+                flags: SMB2_READFLAG_READ_UNBUFFERED,
+                length: 0x10000,
+                offset: 0x1000,
+                file_id: SmbFileId {
+                    persistent: 1,
+                    volatile: 2
+                },
+                minimum_count: 0,
+                channel: SmbChannel::None,
+                remaining_bytes: 0,
+                read_channel_info_offset: 0,
+                read_channel_info_length: 0,
+                read_channel_info: vec![],
+            }
+        );
+        assert_eq!(read.to_vec(), smb_read);
+    }
+
+    #[test]
+    fn write_payload_is_shared() {
+        let write = SmbWrite {
+            size: 49,
+            data_offset: SmbWrite::DATA_OFFSET,
+            length: 5,
+            offset: 10,
+            file_id: SmbFileId {
+                persistent: 1,
+                volatile: 2,
+            },
+            channel: SmbChannel::None,
+            remaining_bytes: 0,
+            write_channel_info_offset: 0,
+            write_channel_info_length: 0,
+            flags: SMB2_WRITEFLAG_WRITE_THROUGH,
+            write_channel_info: vec![],
+            data: Bytes::from_static(b"hello"),
+        };
+        let encoded = Bytes::from(write.to_vec());
+        assert_eq!(encoded.len(), 48 + 5);
+
+        let (_, parsed) = SmbWrite::parse_with(&encoded, &|data| encoded.slice_ref(data)).unwrap();
+        assert_eq!(parsed, write);
+        // the payload should point right into the buffer it was parsed from.
+        assert_eq!(parsed.data.as_ptr(), encoded[48..].as_ptr());
+    }
+
+    #[test]
+    fn read_response_round_trip() {
+        let response = SmbReadResponse {
+            size: 17,
+            data_offset: SmbReadResponse::DATA_OFFSET,
+            data_length: 3,
+            data_remaining: 0,
+            flags: 0,
+            data: Bytes::from_static(&[1, 2, 3]),
+        };
+        let encoded = response.to_vec();
+        assert_eq!(
+            SmbReadResponse::parse(&encoded),
+            Ok((&[] as &[u8], response))
+        );
+
+        let response = SmbWriteResponse {
+            size: 17,
+            count: 3,
+            remaining: 0,
+            write_channel_info_offset: 0,
+            write_channel_info_length: 0,
+        };
+        assert_eq!(
+            SmbWriteResponse::parse(&response.to_vec()),
+            Ok((&[0u8] as &[u8], response))
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use nom::number::complete::le_u8;
+
+use crate::message::{c_u16, c_u32, c_u64, header_offset_buffer, Capabilities, SecurityMode};
+
+#[derive(Debug, PartialEq)]
+pub struct SmbSessionSetup {
+    // always 25, no matter how big the security buffer is.
+    pub size: u16,
+    pub flags: u8,
+    pub security_mode: SecurityMode,
+    pub capabilities: Capabilities,
+    pub channel: u32,
+    pub security_buff_offset: u16,
+    pub security_buff_len: u16,
+    pub previous_session_id: u64,
+    pub buffer: Vec<u8>,
+}
+
+/// Set on a session setup request when the client is binding an existing
+/// session to a new connection (multichannel).
+pub const SMB2_SESSION_FLAG_BINDING: u8 = 0x01;
+
+impl SmbSessionSetup {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbSessionSetup, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, flags) = le_u8(remaining)?;
+        let (remaining, security_mode) = le_u8(remaining)?;
+        let (remaining, capabilities) = c_u32("Failed to get capabilities", remaining)?;
+        let (remaining, channel) = c_u32("Failed to get channel", remaining)?;
+        let (remaining, security_buff_offset) =
+            c_u16("Failed to get security buffer offset", remaining)?;
+        let (remaining, security_buff_len) =
+            c_u16("Failed to get security buffer length", remaining)?;
+        let (remaining, previous_session_id) =
+            c_u64("Failed to get previous session id", remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get security buffer",
+            body,
+            remaining,
+            security_buff_offset as _,
+            security_buff_len as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                flags,
+                security_mode: SecurityMode::from_bits_retain(security_mode.into()),
+                capabilities: Capabilities::from_bits_retain(capabilities),
+                channel,
+                security_buff_offset,
+                security_buff_len,
+                previous_session_id,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.push(self.flags);
+        out.push(self.security_mode.bits() as u8);
+        out.extend(self.capabilities.bits().to_le_bytes());
+        out.extend(self.channel.to_le_bytes());
+        out.extend(self.security_buff_offset.to_le_bytes());
+        out.extend(self.security_buff_len.to_le_bytes());
+        out.extend(self.previous_session_id.to_le_bytes());
+        out.extend(&self.buffer);
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbSessionSetupResponse {
+    // always 9.
+    pub size: u16,
+    pub session_flags: u16,
+    pub security_buff_offset: u16,
+    pub security_buff_len: u16,
+    pub buffer: Vec<u8>,
+}
+
+pub const SMB2_SESSION_FLAG_IS_GUEST: u16 = 0x0001;
+pub const SMB2_SESSION_FLAG_IS_NULL: u16 = 0x0002;
+pub const SMB2_SESSION_FLAG_ENCRYPT_DATA: u16 = 0x0004;
+
+impl SmbSessionSetupResponse {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbSessionSetupResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, session_flags) = c_u16("Failed to get session flags", remaining)?;
+        let (remaining, security_buff_offset) =
+            c_u16("Failed to get security buffer offset", remaining)?;
+        let (remaining, security_buff_len) =
+            c_u16("Failed to get security buffer length", remaining)?;
+        let (remaining, buffer) = header_offset_buffer(
+            "Failed to get security buffer",
+            body,
+            remaining,
+            security_buff_offset as _,
+            security_buff_len as _,
+        )?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                session_flags,
+                security_buff_offset,
+                security_buff_len,
+                buffer: buffer.to_vec(),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.buffer.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.session_flags.to_le_bytes());
+        out.extend(self.security_buff_offset.to_le_bytes());
+        out.extend(self.security_buff_len.to_le_bytes());
+        out.extend(&self.buffer);
+        out
+    }
+}
+
+/// Both the request and the response are just a size and some padding.
+#[derive(Debug, PartialEq)]
+pub struct SmbLogoff {
+    // always 4.
+    pub size: u16,
+}
+
+impl SmbLogoff {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbLogoff, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_session_setup() {
+        #[rustfmt::skip]
+        let smb_session_setup = [
+            // size    | flags| sec mode
+            0x19, 0x00, 0x00, 0x01,
+            // capabilities
+            0x01, 0x00, 0x00, 0x00,
+            // channel
+            0x00, 0x00, 0x00, 0x00,
+            // buffer offset | buffer length
+            0x58, 0x00, 0x04, 0x00,
+            // previous session id
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            // security buffer (not a real SPNEGO token)
+            0x60, 0x48, 0x06, 0x06,
+        ];
+
+        let (remaining, session_setup) = SmbSessionSetup::parse(&smb_session_setup).unwrap();
+        assert_eq!(remaining, &[] as &[u8]);
+        assert_eq!(
+            session_setup,
+            SmbSessionSetup {
+                size: 0x19,
+                flags: 0,
+                security_mode: SecurityMode::SIGNING_ENABLED,
+                capabilities: Capabilities::DFS,
+                channel: 0,
+                security_buff_offset: 0x58,
+                security_buff_len: 4,
+                previous_session_id: 0,
+                buffer: vec![0x60, 0x48, 0x06, 0x06],
+            }
+        );
+        assert_eq!(session_setup.to_vec(), smb_session_setup);
+    }
+
+    #[test]
+    fn example_session_setup_response() {
+        #[rustfmt::skip]
+        let smb_session_setup_response = [
+            // size    | session flags
+            0x09, 0x00, 0x01, 0x00,
+            // buffer offset | buffer length
+            0x48, 0x00, 0x02, 0x00,
+            // security buffer
+            0xa1, 0x07,
+        ];
+
+        let (remaining, response) =
+            SmbSessionSetupResponse::parse(&smb_session_setup_response).unwrap();
+        assert_eq!(remaining, &[] as &[u8]);
+        assert_eq!(
+            response,
+            SmbSessionSetupResponse {
+                size: 9,
+                session_flags: SMB2_SESSION_FLAG_IS_GUEST,
+                security_buff_offset: 0x48,
+                security_buff_len: 2,
+                buffer: vec![0xa1, 0x07],
+            }
+        );
+        assert_eq!(response.to_vec(), smb_session_setup_response);
+    }
+
+    #[test]
+    fn security_buffer_out_of_range() {
+        #[rustfmt::skip]
+        let smb_session_setup_response = [
+            0x09, 0x00, 0x00, 0x00,
+            // points past the end of the message
+            0x48, 0x00, 0x10, 0x00,
+        ];
+        assert!(SmbSessionSetupResponse::parse(&smb_session_setup_response).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use nom::bytes::complete as bytes;
+use nom::error::context;
+use nom::Parser;
+
+use crate::message::{c_u128, c_u16, c_u32, c_u64};
+
+/// What an encrypted message starts with instead of 0xFE 'SMB'.
+pub const TRANSFORM_PROTOCOL_ID: [u8; 4] = [0xFD, b'S', b'M', b'B'];
+
+pub const TRANSFORM_HEADER_SIZE: usize = 52;
+
+/// The only flag there is, and the only one 3.1.1 allows.
+pub const SMB2_TRANSFORM_FLAG_ENCRYPTED: u16 = 0x0001;
+
+/// Wraps one encrypted message, or compound of them, all for the same session.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmbTransformHeader {
+    /// The AEAD's tag over the encrypted message and the rest of this header.
+    pub signature: u128,
+    /// Only the first 11 (CCM) or 12 (GCM) bytes are used, the rest are zero.
+    pub nonce: [u8; 16],
+    /// How big the message is once it's decrypted.
+    pub original_message_size: u32,
+    /// Called EncryptionAlgorithm in 3.0 and 3.0.2, where 0x0001 meant AES-128-CCM.
+    pub flags: u16,
+    pub session_id: u64,
+}
+
+impl SmbTransformHeader {
+    // where the part of the header that's authenticated starts, everything
+    // from the nonce on.
+    pub const AAD_OFFSET: usize = 20;
+
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbTransformHeader, nom::error::Error<&[u8]>> {
+        let (remaining, _) =
+            context("Not a transform header", bytes::tag(TRANSFORM_PROTOCOL_ID)).parse(body)?;
+        let (remaining, signature) = c_u128("Failed to get signature", remaining)?;
+        let (remaining, nonce) = context("Failed to get nonce", bytes::take(16usize))(remaining)?;
+        let (remaining, original_message_size) =
+            c_u32("Failed to get original message size", remaining)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, session_id) = c_u64("Failed to get session id", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                signature,
+                nonce: nonce.try_into().unwrap(),
+                original_message_size,
+                flags,
+                session_id,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(TRANSFORM_HEADER_SIZE);
+        out.extend(TRANSFORM_PROTOCOL_ID);
+        out.extend(self.signature.to_le_bytes());
+        out.extend(self.nonce);
+        out.extend(self.original_message_size.to_le_bytes());
+        // reserved
+        out.extend([0; 2]);
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.session_id.to_le_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_header() {
+        #[rustfmt::skip]
+        let transform_header = [
+            // protocol id
+            0xFD, b'S', b'M', b'B',
+            // signature
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
+            // nonce, 12 bytes for GCM
+            0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+            0xA8, 0xA9, 0xAA, 0xAB, 0x00, 0x00, 0x00, 0x00,
+            // original message size
+            0x44, 0x00, 0x00, 0x00,
+            // reserved| flags
+            0x00, 0x00, 0x01, 0x00,
+            // session id
+            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (remaining, header) = SmbTransformHeader::parse(&transform_header).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            header,
+            SmbTransformHeader {
+                signature: 0x100F0E0D0C0B0A090807060504030201,
+                nonce: [
+                    0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0, 0,
+                    0, 0
+                ],
+                original_message_size: 0x44,
+                flags: SMB2_TRANSFORM_FLAG_ENCRYPTED,
+                session_id: 0x11,
+            }
+        );
+        assert_eq!(header.to_vec(), transform_header);
+        assert!(SmbTransformHeader::parse(&[0xFE, b'S', b'M', b'B']).is_err());
+    }
+}
@@ -0,0 +1,525 @@
+use nom::bytes::complete::take;
+use nom::error::context;
+use nom::multi::count;
+use nom::number::complete::le_u8;
+
+use crate::message::{
+    c_u16, c_u32, encode_utf16le, fail, header_offset_buffer, pad_to, parse_utf16le, HEADER_SIZE,
+};
+
+pub const SMB2_TREE_CONNECT_FLAG_CLUSTER_RECONNECT: u16 = 0x0001;
+pub const SMB2_TREE_CONNECT_FLAG_REDIRECT_TO_OWNER: u16 = 0x0002;
+pub const SMB2_TREE_CONNECT_FLAG_EXTENSION_PRESENT: u16 = 0x0004;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbTreeConnect {
+    // always 9.
+    pub size: u16,
+    pub flags: u16,
+    pub path_offset: u16,
+    pub path_length: u16,
+    /// The full `\\server\share` path, decoded from UTF-16.
+    pub path: String,
+    /// Only there when `SMB2_TREE_CONNECT_FLAG_EXTENSION_PRESENT` is set (3.1.1 only).
+    pub extension: Option<SmbTreeConnectExtension>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SmbTreeConnectExtension {
+    // measured from the start of the tree connect request, not the header.
+    pub context_offset: u32,
+    pub context_count: u16,
+    pub contexts: Vec<SmbTreeConnectContext>,
+}
+
+// the extension sits right after the fixed part of the request.
+const EXTENSION_OFFSET: usize = 8;
+
+impl SmbTreeConnect {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbTreeConnect, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, flags) = c_u16("Failed to get flags", remaining)?;
+        let (remaining, path_offset) = c_u16("Failed to get path offset", remaining)?;
+        let (remaining, path_length) = c_u16("Failed to get path length", remaining)?;
+
+        if flags & SMB2_TREE_CONNECT_FLAG_EXTENSION_PRESENT == 0 {
+            let (remaining, path) = header_offset_buffer(
+                "Failed to get path",
+                body,
+                remaining,
+                path_offset as _,
+                path_length as _,
+            )?;
+            let (_, path) = parse_utf16le("Failed to decode path", path)?;
+            return Ok((
+                remaining,
+                Self {
+                    size,
+                    flags,
+                    path_offset,
+                    path_length,
+                    path,
+                    extension: None,
+                },
+            ));
+        }
+
+        let (remaining, context_offset) = c_u32("Failed to get context offset", remaining)?;
+        let (remaining, context_count) = c_u16("Failed to get context count", remaining)?;
+        let (remaining, _reserved) = take(10usize)(remaining)?;
+        // with the extension present, the path offset is measured from the
+        // start of the extension rather than from the header.
+        let (remaining, path) = header_offset_buffer(
+            "Failed to get path",
+            body,
+            remaining,
+            path_offset as usize + EXTENSION_OFFSET + HEADER_SIZE,
+            path_length as _,
+        )?;
+        let (_, path) = parse_utf16le("Failed to decode path", path)?;
+        let (remaining, contexts) = if context_count > 0 {
+            let Some(contexts) = body.get(context_offset as usize..) else {
+                return fail(body);
+            };
+            context(
+                "Failed to parse tree connect contexts",
+                count(SmbTreeConnectContext::parse, context_count as _),
+            )(contexts)?
+        } else {
+            (remaining, vec![])
+        };
+        Ok((
+            remaining,
+            Self {
+                size,
+                flags,
+                path_offset,
+                path_length,
+                path,
+                extension: Some(SmbTreeConnectExtension {
+                    context_offset,
+                    context_count,
+                    contexts,
+                }),
+            },
+        ))
+    }
+
+    /// The share part of `\\server\share`.
+    pub fn share_name(&self) -> &str {
+        self.path
+            .trim_start_matches('\\')
+            .split_once('\\')
+            .map(|(_server, share)| share)
+            .unwrap_or(&self.path)
+    }
+
+    /// The offsets and lengths are worked out from what gets written, the
+    /// parsed `path_offset`, `path_length` and `context_offset` are ignored.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let path = encode_utf16le(&self.path);
+        let mut out = Vec::with_capacity(8 + path.len());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        match &self.extension {
+            None => {
+                // the path follows the fixed part of the request.
+                out.extend(((HEADER_SIZE + 8) as u16).to_le_bytes());
+                out.extend((path.len() as u16).to_le_bytes());
+                out.extend(path);
+            }
+            Some(extension) => {
+                // the path follows the extension, the contexts follow the path.
+                let path_start = EXTENSION_OFFSET + 16;
+                let context_offset = if extension.contexts.is_empty() {
+                    0
+                } else {
+                    (path_start + path.len()).next_multiple_of(8)
+                };
+                out.extend(((path_start - EXTENSION_OFFSET) as u16).to_le_bytes());
+                out.extend((path.len() as u16).to_le_bytes());
+                out.extend((context_offset as u32).to_le_bytes());
+                out.extend((extension.contexts.len() as u16).to_le_bytes());
+                out.extend([0; 10]);
+                out.extend(path);
+                for tree_context in &extension.contexts {
+                    // every context starts 8 byte aligned.
+                    let aligned = out.len().next_multiple_of(8);
+                    pad_to(&mut out, aligned);
+                    out.extend(tree_context.to_vec());
+                }
+            }
+        }
+        out
+    }
+}
+
+pub const SMB2_REMOTED_IDENTITY_TREE_CONNECT_CONTEXT_ID: u16 = 0x0001;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbTreeConnectContext {
+    pub data_length: u16,
+    pub data: SmbTreeConnectContextData,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SmbTreeConnectContextData {
+    RemotedIdentity(RemotedIdentity),
+    // there's only one context type defined right now, but that's no
+    // reason to throw the whole request away when a new one shows up.
+    Unknown { context_type: u16, data: Vec<u8> },
+}
+
+/// The identity a remote client (e.g. a cluster node) is connecting on behalf of.
+/// Every field other than the ticket type and size is an offset into `ticket`,
+/// which holds the whole structure as it came off the wire.
+#[derive(Debug, PartialEq)]
+pub struct RemotedIdentity {
+    pub ticket_type: u16,
+    pub ticket_size: u16,
+    pub user: u16,
+    pub user_name: u16,
+    pub domain: u16,
+    pub groups: u16,
+    pub restricted_groups: u16,
+    pub privileges: u16,
+    pub primary_group: u16,
+    pub owner: u16,
+    pub default_dacl: u16,
+    pub device_groups: u16,
+    pub user_claims: u16,
+    pub device_claims: u16,
+    pub ticket: Vec<u8>,
+}
+
+impl RemotedIdentity {
+    fn parse(ticket: &[u8]) -> nom::IResult<&[u8], RemotedIdentity, nom::error::Error<&[u8]>> {
+        let (remaining, fields) = count(
+            |body| c_u16("Failed to get remoted identity field", body),
+            14,
+        )(ticket)?;
+        Ok((
+            remaining,
+            Self {
+                ticket_type: fields[0],
+                ticket_size: fields[1],
+                user: fields[2],
+                user_name: fields[3],
+                domain: fields[4],
+                groups: fields[5],
+                restricted_groups: fields[6],
+                privileges: fields[7],
+                primary_group: fields[8],
+                owner: fields[9],
+                default_dacl: fields[10],
+                device_groups: fields[11],
+                user_claims: fields[12],
+                device_claims: fields[13],
+                ticket: ticket.to_vec(),
+            },
+        ))
+    }
+}
+
+impl SmbTreeConnectContext {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbTreeConnectContext, nom::error::Error<&[u8]>> {
+        let (remaining, context_type) = c_u16("Failed to get context type", body)?;
+        let (remaining, data_length) = c_u16("Failed to get context data length", remaining)?;
+        let (remaining, _reserved) = c_u32("Failed to get reserved", remaining)?;
+        let (remaining, data) = take(data_length)(remaining)?;
+        let data = match context_type {
+            SMB2_REMOTED_IDENTITY_TREE_CONNECT_CONTEXT_ID => {
+                SmbTreeConnectContextData::RemotedIdentity(RemotedIdentity::parse(data)?.1)
+            }
+            _ => SmbTreeConnectContextData::Unknown {
+                context_type,
+                data: data.to_vec(),
+            },
+        };
+        // the next context starts 8 byte aligned; the last one may not be padded.
+        let padding = (8 - (8 + data_length as usize) % 8) % 8;
+        let remaining = &remaining[padding.min(remaining.len())..];
+        Ok((remaining, Self { data_length, data }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let (context_type, data) = match &self.data {
+            SmbTreeConnectContextData::RemotedIdentity(identity) => (
+                SMB2_REMOTED_IDENTITY_TREE_CONNECT_CONTEXT_ID,
+                &identity.ticket[..],
+            ),
+            SmbTreeConnectContextData::Unknown { context_type, data } => (*context_type, &data[..]),
+        };
+        let mut out = Vec::with_capacity(8 + data.len());
+        out.extend(context_type.to_le_bytes());
+        out.extend(self.data_length.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend(data);
+        out
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShareType {
+    Disk = 0x01,
+    Pipe = 0x02,
+    Print = 0x03,
+}
+
+#[derive(Debug)]
+pub struct InvalidShareType;
+
+impl TryFrom<u8> for ShareType {
+    type Error = InvalidShareType;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::Disk),
+            0x02 => Ok(Self::Pipe),
+            0x03 => Ok(Self::Print),
+            _ => Err(InvalidShareType),
+        }
+    }
+}
+
+pub const SMB2_SHAREFLAG_MANUAL_CACHING: u32 = 0x0000_0000;
+pub const SMB2_SHAREFLAG_AUTO_CACHING: u32 = 0x0000_0010;
+pub const SMB2_SHAREFLAG_VDO_CACHING: u32 = 0x0000_0020;
+pub const SMB2_SHAREFLAG_NO_CACHING: u32 = 0x0000_0030;
+pub const SMB2_SHAREFLAG_DFS: u32 = 0x0000_0001;
+pub const SMB2_SHAREFLAG_DFS_ROOT: u32 = 0x0000_0002;
+pub const SMB2_SHAREFLAG_RESTRICT_EXCLUSIVE_OPENS: u32 = 0x0000_0100;
+pub const SMB2_SHAREFLAG_FORCE_SHARED_DELETE: u32 = 0x0000_0200;
+pub const SMB2_SHAREFLAG_ALLOW_NAMESPACE_CACHING: u32 = 0x0000_0400;
+pub const SMB2_SHAREFLAG_ACCESS_BASED_DIRECTORY_ENUM: u32 = 0x0000_0800;
+pub const SMB2_SHAREFLAG_FORCE_LEVELII_OPLOCK: u32 = 0x0000_1000;
+pub const SMB2_SHAREFLAG_ENABLE_HASH_V1: u32 = 0x0000_2000;
+pub const SMB2_SHAREFLAG_ENABLE_HASH_V2: u32 = 0x0000_4000;
+pub const SMB2_SHAREFLAG_ENCRYPT_DATA: u32 = 0x0000_8000;
+pub const SMB2_SHAREFLAG_IDENTITY_REMOTING: u32 = 0x0004_0000;
+pub const SMB2_SHAREFLAG_COMPRESS_DATA: u32 = 0x0010_0000;
+
+pub const SMB2_SHARE_CAP_DFS: u32 = 0x0000_0008;
+pub const SMB2_SHARE_CAP_CONTINUOUS_AVAILABILITY: u32 = 0x0000_0010;
+pub const SMB2_SHARE_CAP_SCALEOUT: u32 = 0x0000_0020;
+pub const SMB2_SHARE_CAP_CLUSTER: u32 = 0x0000_0040;
+pub const SMB2_SHARE_CAP_ASYMMETRIC: u32 = 0x0000_0080;
+pub const SMB2_SHARE_CAP_REDIRECT_TO_OWNER: u32 = 0x0000_0100;
+
+#[derive(Debug, PartialEq)]
+pub struct SmbTreeConnectResponse {
+    // always 16.
+    pub size: u16,
+    pub share_type: ShareType,
+    pub share_flags: u32,
+    pub capabilities: u32,
+    pub maximal_access: u32,
+}
+
+impl SmbTreeConnectResponse {
+    pub fn parse(
+        body: &[u8],
+    ) -> nom::IResult<&[u8], SmbTreeConnectResponse, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, share_type) = context(
+            "Failed to get share type",
+            nom::combinator::map_res(le_u8, ShareType::try_from),
+        )(remaining)?;
+        let (remaining, _reserved) = le_u8(remaining)?;
+        let (remaining, share_flags) = c_u32("Failed to get share flags", remaining)?;
+        let (remaining, capabilities) = c_u32("Failed to get capabilities", remaining)?;
+        let (remaining, maximal_access) = c_u32("Failed to get maximal access", remaining)?;
+        Ok((
+            remaining,
+            Self {
+                size,
+                share_type,
+                share_flags,
+                capabilities,
+                maximal_access,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        out.extend(self.size.to_le_bytes());
+        out.push(self.share_type as u8);
+        out.push(0);
+        out.extend(self.share_flags.to_le_bytes());
+        out.extend(self.capabilities.to_le_bytes());
+        out.extend(self.maximal_access.to_le_bytes());
+        out
+    }
+}
+
+/// Both the request and the response are just a size and some padding.
+#[derive(Debug, PartialEq)]
+pub struct SmbTreeDisconnect {
+    // always 4.
+    pub size: u16,
+}
+
+impl SmbTreeDisconnect {
+    pub fn parse(body: &[u8]) -> nom::IResult<&[u8], SmbTreeDisconnect, nom::error::Error<&[u8]>> {
+        let (remaining, size) = c_u16("Failed to get structure size", body)?;
+        let (remaining, _reserved) = c_u16("Failed to get reserved", remaining)?;
+        Ok((remaining, Self { size }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4);
+        out.extend(self.size.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_tree_connect() {
+        #[rustfmt::skip]
+        let smb_tree_connect = [
+            // size    | flags
+            0x09, 0x00, 0x00, 0x00,
+            // path offset | path length
+            0x48, 0x00, 0x1a, 0x00,
+            // \\srv\share
+            b'\\', 0x00, b'\\', 0x00, b's', 0x00, b'r', 0x00,
+            b'v', 0x00, b'\\', 0x00, b's', 0x00, b'h', 0x00,
+            b'a', 0x00, b'r', 0x00, b'e', 0x00, b'$', 0x00,
+            b'x', 0x00,
+        ];
+        let (remaining, tree_connect) = SmbTreeConnect::parse(&smb_tree_connect).unwrap();
+        assert_eq!(remaining, &[] as &[u8]);
+        assert_eq!(
+            tree_connect,
+            SmbTreeConnect {
+                size: 9,
+                flags: 0,
+                path_offset: 0x48,
+                path_length: 0x1a,
+                path: r"\\srv\share$x".into(),
+                extension: None,
+            }
+        );
+        assert_eq!(tree_connect.share_name(), "share$x");
+        assert_eq!(tree_connect.to_vec(), smb_tree_connect);
+    }
+
+    #[test]
+    fn tree_connect_with_extension() {
+        #[rustfmt::skip]
+        let smb_tree_connect = [
+            // size    | flags (extension present)
+            0x09, 0x00, 0x04, 0x00,
+            // path offset (from the extension) | path length
+            0x10, 0x00, 0x0a, 0x00,
+            // context offset (from the request)
+            0x28, 0x00, 0x00, 0x00,
+            // context count | reserved
+            0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            // \\a\b
+            b'\\', 0x00, b'\\', 0x00, b'a', 0x00, b'\\', 0x00,
+            b'b', 0x00,
+            // padding
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // context type | data length
+            0x99, 0x00, 0x02, 0x00,
+            // reserved
+            0x00, 0x00, 0x00, 0x00,
+            // data
+            0xaa, 0xbb,
+        ];
+        let (_, tree_connect) = SmbTreeConnect::parse(&smb_tree_connect).unwrap();
+        assert_eq!(tree_connect.path, r"\\a\b");
+        assert_eq!(tree_connect.share_name(), "b");
+        assert_eq!(
+            tree_connect.extension,
+            Some(SmbTreeConnectExtension {
+                context_offset: 0x28,
+                context_count: 1,
+                contexts: vec![SmbTreeConnectContext {
+                    data_length: 2,
+                    data: SmbTreeConnectContextData::Unknown {
+                        context_type: 0x99,
+                        data: vec![0xaa, 0xbb]
+                    }
+                }]
+            })
+        );
+        assert_eq!(tree_connect.to_vec(), smb_tree_connect);
+    }
+
+    #[test]
+    fn tree_connect_offsets_come_from_what_is_written() {
+        let tree_connect = SmbTreeConnect {
+            size: 9,
+            flags: 0,
+            path_offset: 0,
+            path_length: 0,
+            path: r"\\a\b".into(),
+            extension: None,
+        };
+        let out = tree_connect.to_vec();
+        assert_eq!(&out[4..8], &[0x48, 0x00, 0x0a, 0x00]);
+        let (_, parsed) = SmbTreeConnect::parse(&out).unwrap();
+        assert_eq!(parsed.path, r"\\a\b");
+    }
+
+    #[test]
+    fn tree_connect_context_offset_out_of_range() {
+        #[rustfmt::skip]
+        let smb_tree_connect = [
+            // size    | flags (extension present)
+            0x09, 0x00, 0x04, 0x00,
+            // path offset (from the extension) | path length
+            0x10, 0x00, 0x0a, 0x00,
+            // context offset (past the end of the request)
+            0x00, 0x01, 0x00, 0x00,
+            // context count | reserved
+            0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            // \\a\b
+            b'\\', 0x00, b'\\', 0x00, b'a', 0x00, b'\\', 0x00,
+            b'b', 0x00,
+        ];
+        assert!(SmbTreeConnect::parse(&smb_tree_connect).is_err());
+    }
+
+    #[test]
+    fn example_tree_connect_response() {
+        #[rustfmt::skip]
+        let smb_tree_connect_response = [
+            // size    | type | reserved
+            0x10, 0x00, 0x01, 0x00,
+            // share flags
+            0x00, 0x08, 0x00, 0x00,
+            // capabilities
+            0x00, 0x00, 0x00, 0x00,
+            // maximal access
+            0xff, 0x01, 0x1f, 0x00,
+        ];
+        let (_, response) = SmbTreeConnectResponse::parse(&smb_tree_connect_response).unwrap();
+        assert_eq!(
+            response,
+            SmbTreeConnectResponse {
+                size: 16,
+                share_type: ShareType::Disk,
+                share_flags: SMB2_SHAREFLAG_ACCESS_BASED_DIRECTORY_ENUM,
+                capabilities: 0,
+                maximal_access: 0x001f01ff,
+            }
+        );
+        assert_eq!(response.to_vec(), smb_tree_connect_response);
+    }
+}
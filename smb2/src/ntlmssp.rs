@@ -0,0 +1,994 @@
+//! NTLMSSP, the challenge/response authentication carried in SESSION_SETUP's
+//! security buffer. The client's NEGOTIATE says what it can do, the server's
+//! CHALLENGE hands it something to prove it knows the user's password with,
+//! and its AUTHENTICATE is that proof. Only NTLMv2 responses are accepted,
+//! NTLMv1 having been broken for a long time now.
+
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use nom::bytes::complete::{tag, take};
+use nom::error::context;
+use nom::number::complete::{le_u16, le_u32};
+
+pub const NTLMSSP_SIGNATURE: [u8; 8] = *b"NTLMSSP\0";
+
+const NEGOTIATE_MESSAGE: u32 = 1;
+const CHALLENGE_MESSAGE: u32 = 2;
+const AUTHENTICATE_MESSAGE: u32 = 3;
+
+// where things live in an AUTHENTICATE, when the client sent a MIC.
+const MIC_OFFSET: usize = 72;
+const MIC_SIZE: usize = 16;
+const PAYLOAD_WITH_MIC: usize = MIC_OFFSET + MIC_SIZE;
+
+// the NTProofStr, then a blob of at least its fixed 28 bytes.
+const NT_PROOF_STR_SIZE: usize = 16;
+const BLOB_AV_PAIRS_OFFSET: usize = 28;
+
+// what the signing and sealing keys for each direction are made from.
+const CLIENT_SIGNING_MAGIC: &[u8] = b"session key to client-to-server signing key magic constant\0";
+const SERVER_SIGNING_MAGIC: &[u8] = b"session key to server-to-client signing key magic constant\0";
+const CLIENT_SEALING_MAGIC: &[u8] = b"session key to client-to-server sealing key magic constant\0";
+const SERVER_SEALING_MAGIC: &[u8] = b"session key to server-to-client sealing key magic constant\0";
+
+// what we say we are: Windows 7's version, and the NTLMSSP revision everything speaks.
+const VERSION: [u8; 8] = [6, 1, 0xB0, 0x1D, 0, 0, 0, 0x0F];
+
+bitflags::bitflags! {
+    /// The NTLMSSP_NEGOTIATE_* flags, what each side can do.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct NegotiateFlags: u32 {
+        const UNICODE = 0x0000_0001;
+        const OEM = 0x0000_0002;
+        const REQUEST_TARGET = 0x0000_0004;
+        const SIGN = 0x0000_0010;
+        const SEAL = 0x0000_0020;
+        const LM_KEY = 0x0000_0080;
+        const NTLM = 0x0000_0200;
+        const ANONYMOUS = 0x0000_0800;
+        const OEM_DOMAIN_SUPPLIED = 0x0000_1000;
+        const OEM_WORKSTATION_SUPPLIED = 0x0000_2000;
+        const ALWAYS_SIGN = 0x0000_8000;
+        const TARGET_TYPE_DOMAIN = 0x0001_0000;
+        const TARGET_TYPE_SERVER = 0x0002_0000;
+        const EXTENDED_SESSIONSECURITY = 0x0008_0000;
+        const IDENTIFY = 0x0010_0000;
+        const REQUEST_NON_NT_SESSION_KEY = 0x0040_0000;
+        const TARGET_INFO = 0x0080_0000;
+        const VERSION = 0x0200_0000;
+        const KEY_128 = 0x2000_0000;
+        /// The exported session key is picked by the client, and sent
+        /// encrypted with the one both sides worked out.
+        const KEY_EXCH = 0x4000_0000;
+        const KEY_56 = 0x8000_0000;
+    }
+}
+
+/// Flags the server goes along with if the client asks for them.
+const OPTIONAL_FLAGS: NegotiateFlags = NegotiateFlags::UNICODE
+    .union(NegotiateFlags::SIGN)
+    .union(NegotiateFlags::SEAL)
+    .union(NegotiateFlags::ALWAYS_SIGN)
+    .union(NegotiateFlags::EXTENDED_SESSIONSECURITY)
+    .union(NegotiateFlags::VERSION)
+    .union(NegotiateFlags::KEY_128)
+    .union(NegotiateFlags::KEY_EXCH)
+    .union(NegotiateFlags::KEY_56);
+
+pub const MSV_AV_EOL: u16 = 0x0000;
+pub const MSV_AV_NB_COMPUTER_NAME: u16 = 0x0001;
+pub const MSV_AV_NB_DOMAIN_NAME: u16 = 0x0002;
+pub const MSV_AV_DNS_COMPUTER_NAME: u16 = 0x0003;
+pub const MSV_AV_DNS_DOMAIN_NAME: u16 = 0x0004;
+pub const MSV_AV_FLAGS: u16 = 0x0006;
+pub const MSV_AV_TIMESTAMP: u16 = 0x0007;
+
+/// In an MsvAvFlags pair, the AUTHENTICATE carries a MIC.
+pub const MSV_AV_FLAG_MIC_PRESENT: u32 = 0x0000_0002;
+
+/// One of the AV pairs in a CHALLENGE's target info, which the client
+/// copies into its NTLMv2 response (adding some of its own).
+#[derive(Debug, PartialEq, Clone)]
+pub struct AvPair {
+    pub id: u16,
+    pub value: Vec<u8>,
+}
+
+impl AvPair {
+    /// A pair holding a name, which are always UTF-16LE.
+    pub fn name(id: u16, name: &str) -> AvPair {
+        AvPair {
+            id,
+            value: encode_utf16le(name),
+        }
+    }
+}
+
+/// A list of AV pairs, up to the MsvAvEOL that ends it.
+pub fn parse_av_pairs(body: &[u8]) -> nom::IResult<&[u8], Vec<AvPair>, nom::error::Error<&[u8]>> {
+    let mut pairs = vec![];
+    let mut remaining = body;
+    loop {
+        let (rest, id) = context("Failed to get AV pair id", le_u16)(remaining)?;
+        let (rest, len) = context("Failed to get AV pair length", le_u16)(rest)?;
+        let (rest, value) = context("Failed to get AV pair value", take(len))(rest)?;
+        remaining = rest;
+        if id == MSV_AV_EOL {
+            return Ok((remaining, pairs));
+        }
+        pairs.push(AvPair {
+            id,
+            value: value.to_vec(),
+        });
+    }
+}
+
+/// Encodes `pairs`, with the MsvAvEOL that ends them.
+pub fn av_pairs_to_vec(pairs: &[AvPair]) -> Vec<u8> {
+    let mut out = vec![];
+    for pair in pairs {
+        out.extend(pair.id.to_le_bytes());
+        out.extend((pair.value.len() as u16).to_le_bytes());
+        out.extend(&pair.value);
+    }
+    out.extend(MSV_AV_EOL.to_le_bytes());
+    out.extend([0; 2]);
+    out
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NtlmNegotiate {
+    pub flags: NegotiateFlags,
+    /// Both of these are only there if the flags say so, and always OEM.
+    pub domain: String,
+    pub workstation: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NtlmChallenge {
+    pub flags: NegotiateFlags,
+    pub target_name: String,
+    pub server_challenge: [u8; 8],
+    pub target_info: Vec<AvPair>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NtlmAuthenticate {
+    pub flags: NegotiateFlags,
+    pub lm_response: Vec<u8>,
+    pub nt_response: Vec<u8>,
+    pub domain: String,
+    pub user: String,
+    pub workstation: String,
+    pub encrypted_random_session_key: Vec<u8>,
+    /// Only there if the payload starts late enough to leave room for it,
+    /// whether it's meant to be is up to the NTLMv2 response.
+    pub mic: Option<[u8; 16]>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NtlmMessage {
+    Negotiate(NtlmNegotiate),
+    Challenge(NtlmChallenge),
+    Authenticate(NtlmAuthenticate),
+}
+
+impl NtlmMessage {
+    /// Every NTLMSSP message takes up the whole of the buffer it's in,
+    /// the offsets in it being from its start.
+    pub fn parse(message: &[u8]) -> nom::IResult<&[u8], NtlmMessage, nom::error::Error<&[u8]>> {
+        let (_remaining, message_type) = message_type(message)?;
+        match message_type {
+            NEGOTIATE_MESSAGE => {
+                NtlmNegotiate::parse(message).map(|(r, m)| (r, NtlmMessage::Negotiate(m)))
+            }
+            CHALLENGE_MESSAGE => {
+                NtlmChallenge::parse(message).map(|(r, m)| (r, NtlmMessage::Challenge(m)))
+            }
+            AUTHENTICATE_MESSAGE => {
+                NtlmAuthenticate::parse(message).map(|(r, m)| (r, NtlmMessage::Authenticate(m)))
+            }
+            _ => fail(message),
+        }
+    }
+}
+
+fn message_type(message: &[u8]) -> nom::IResult<&[u8], u32, nom::error::Error<&[u8]>> {
+    let (remaining, _) = context(
+        "Failed to get NTLMSSP signature",
+        tag(&NTLMSSP_SIGNATURE[..]),
+    )(message)?;
+    context("Failed to get message type", le_u32)(remaining)
+}
+
+/// Checks `message` is an NTLMSSP message of `expected` type.
+fn expect_type(message: &[u8], expected: u32) -> nom::IResult<&[u8], (), nom::error::Error<&[u8]>> {
+    let (remaining, message_type) = message_type(message)?;
+    if message_type != expected {
+        return fail(message);
+    }
+    Ok((remaining, ()))
+}
+
+/// Where a field is in its message, and what's there.
+type Field<'a> = (usize, &'a [u8]);
+
+/// A variable length field, described by its length, its maximum length
+/// (which nobody uses) and its offset from the start of `message`. Comes
+/// back with the offset, so where the payload starts can be worked out.
+fn field<'a>(
+    ctx: &'static str,
+    message: &'a [u8],
+    body: &'a [u8],
+) -> nom::IResult<&'a [u8], Field<'a>, nom::error::Error<&'a [u8]>> {
+    let (remaining, len) = context(ctx, le_u16)(body)?;
+    let (remaining, _max_len) = context(ctx, le_u16)(remaining)?;
+    let (remaining, offset) = context(ctx, le_u32)(remaining)?;
+    let (offset, len) = (offset as usize, len as usize);
+    match message.get(offset..).and_then(|rest| rest.get(..len)) {
+        Some(value) => Ok((remaining, (offset, value))),
+        None => fail(body),
+    }
+}
+
+/// Appends the descriptor for a field whose `value` goes at the end of
+/// `payload`, which starts `payload_offset` bytes into the message.
+fn field_to_vec(out: &mut Vec<u8>, payload: &mut Vec<u8>, payload_offset: usize, value: &[u8]) {
+    out.extend((value.len() as u16).to_le_bytes());
+    out.extend((value.len() as u16).to_le_bytes());
+    out.extend(((payload_offset + payload.len()) as u32).to_le_bytes());
+    payload.extend(value);
+}
+
+/// Strings are UTF-16LE if the flags say so, and otherwise OEM, which
+/// is taken to be Latin-1.
+fn decode_string(flags: NegotiateFlags, value: &[u8]) -> String {
+    if flags.contains(NegotiateFlags::UNICODE) {
+        let code_points: Vec<u16> = value
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&code_points)
+    } else {
+        value.iter().map(|&c| c as char).collect()
+    }
+}
+
+fn encode_string(flags: NegotiateFlags, s: &str) -> Vec<u8> {
+    if flags.contains(NegotiateFlags::UNICODE) {
+        encode_utf16le(s)
+    } else {
+        s.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect()
+    }
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+impl NtlmNegotiate {
+    pub fn parse(message: &[u8]) -> nom::IResult<&[u8], NtlmNegotiate, nom::error::Error<&[u8]>> {
+        let (remaining, ()) = expect_type(message, NEGOTIATE_MESSAGE)?;
+        let (remaining, flags) = context("Failed to get negotiate flags", le_u32)(remaining)?;
+        let (remaining, (_, domain)) = field("Failed to get domain name", message, remaining)?;
+        let (_remaining, (_, workstation)) =
+            field("Failed to get workstation", message, remaining)?;
+        let oem = NegotiateFlags::empty();
+        Ok((
+            &message[message.len()..],
+            Self {
+                flags: NegotiateFlags::from_bits_retain(flags),
+                domain: decode_string(oem, domain),
+                workstation: decode_string(oem, workstation),
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        // the fixed part and a version, which we leave zeroed.
+        const PAYLOAD_OFFSET: usize = 40;
+        let oem = NegotiateFlags::empty();
+        let mut out = Vec::with_capacity(PAYLOAD_OFFSET);
+        let mut payload = vec![];
+        out.extend(NTLMSSP_SIGNATURE);
+        out.extend(NEGOTIATE_MESSAGE.to_le_bytes());
+        out.extend(self.flags.bits().to_le_bytes());
+        let domain = encode_string(oem, &self.domain);
+        field_to_vec(&mut out, &mut payload, PAYLOAD_OFFSET, &domain);
+        let workstation = encode_string(oem, &self.workstation);
+        field_to_vec(&mut out, &mut payload, PAYLOAD_OFFSET, &workstation);
+        out.extend([0; 8]);
+        out.extend(payload);
+        out
+    }
+}
+
+impl NtlmChallenge {
+    pub fn parse(message: &[u8]) -> nom::IResult<&[u8], NtlmChallenge, nom::error::Error<&[u8]>> {
+        let (remaining, ()) = expect_type(message, CHALLENGE_MESSAGE)?;
+        let (remaining, (_, target_name)) = field("Failed to get target name", message, remaining)?;
+        let (remaining, flags) = context("Failed to get negotiate flags", le_u32)(remaining)?;
+        let flags = NegotiateFlags::from_bits_retain(flags);
+        let (remaining, server_challenge) =
+            context("Failed to get server challenge", take(8usize))(remaining)?;
+        let (remaining, _reserved) = context("Failed to get reserved", take(8usize))(remaining)?;
+        let (_remaining, (_, target_info)) =
+            field("Failed to get target info", message, remaining)?;
+        let target_info = match target_info.is_empty() {
+            true => vec![],
+            false => parse_av_pairs(target_info)?.1,
+        };
+        Ok((
+            &message[message.len()..],
+            Self {
+                flags,
+                target_name: decode_string(flags, target_name),
+                server_challenge: server_challenge.try_into().unwrap(),
+                target_info,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        // the fixed part and our version.
+        const PAYLOAD_OFFSET: usize = 56;
+        let mut out = Vec::with_capacity(PAYLOAD_OFFSET);
+        let mut payload = vec![];
+        out.extend(NTLMSSP_SIGNATURE);
+        out.extend(CHALLENGE_MESSAGE.to_le_bytes());
+        let target_name = encode_string(self.flags, &self.target_name);
+        field_to_vec(&mut out, &mut payload, PAYLOAD_OFFSET, &target_name);
+        out.extend(self.flags.bits().to_le_bytes());
+        out.extend(self.server_challenge);
+        out.extend([0; 8]);
+        let target_info = av_pairs_to_vec(&self.target_info);
+        field_to_vec(&mut out, &mut payload, PAYLOAD_OFFSET, &target_info);
+        out.extend(VERSION);
+        out.extend(payload);
+        out
+    }
+}
+
+impl NtlmAuthenticate {
+    pub fn parse(
+        message: &[u8],
+    ) -> nom::IResult<&[u8], NtlmAuthenticate, nom::error::Error<&[u8]>> {
+        let (remaining, ()) = expect_type(message, AUTHENTICATE_MESSAGE)?;
+        let (remaining, lm_response) = field("Failed to get LM response", message, remaining)?;
+        let (remaining, nt_response) = field("Failed to get NT response", message, remaining)?;
+        let (remaining, domain) = field("Failed to get domain name", message, remaining)?;
+        let (remaining, user) = field("Failed to get user name", message, remaining)?;
+        let (remaining, workstation) = field("Failed to get workstation", message, remaining)?;
+        let (remaining, encrypted_random_session_key) =
+            field("Failed to get encrypted session key", message, remaining)?;
+        let (_remaining, flags) = context("Failed to get negotiate flags", le_u32)(remaining)?;
+        let flags = NegotiateFlags::from_bits_retain(flags);
+        let fields = [
+            lm_response,
+            nt_response,
+            domain,
+            user,
+            workstation,
+            encrypted_random_session_key,
+        ];
+        let payload_offset = fields
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(offset, _)| *offset)
+            .min()
+            .unwrap_or(message.len());
+        let mic = (payload_offset >= PAYLOAD_WITH_MIC)
+            .then(|| message.get(MIC_OFFSET..PAYLOAD_WITH_MIC))
+            .flatten()
+            .map(|mic| mic.try_into().unwrap());
+        Ok((
+            &message[message.len()..],
+            Self {
+                flags,
+                lm_response: lm_response.1.to_vec(),
+                nt_response: nt_response.1.to_vec(),
+                domain: decode_string(flags, domain.1),
+                user: decode_string(flags, user.1),
+                workstation: decode_string(flags, workstation.1),
+                encrypted_random_session_key: encrypted_random_session_key.1.to_vec(),
+                mic,
+            },
+        ))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        // the fixed part, a zeroed version and the MIC if there is one.
+        let payload_offset = match self.mic {
+            Some(_) => PAYLOAD_WITH_MIC,
+            None => MIC_OFFSET,
+        };
+        let mut out = Vec::with_capacity(payload_offset);
+        let mut payload = vec![];
+        out.extend(NTLMSSP_SIGNATURE);
+        out.extend(AUTHENTICATE_MESSAGE.to_le_bytes());
+        let domain = encode_string(self.flags, &self.domain);
+        let user = encode_string(self.flags, &self.user);
+        let workstation = encode_string(self.flags, &self.workstation);
+        for value in [
+            &self.lm_response,
+            &self.nt_response,
+            &domain,
+            &user,
+            &workstation,
+            &self.encrypted_random_session_key,
+        ] {
+            field_to_vec(&mut out, &mut payload, payload_offset, value);
+        }
+        out.extend(self.flags.bits().to_le_bytes());
+        out.extend([0; 8]);
+        if let Some(mic) = self.mic {
+            out.extend(mic);
+        }
+        out.extend(payload);
+        out
+    }
+
+    /// Whether this is the null session, no user and no proof of anything.
+    pub fn is_anonymous(&self) -> bool {
+        self.user.is_empty()
+            && self.nt_response.is_empty()
+            && matches!(self.lm_response.as_slice(), [] | [0])
+    }
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).unwrap();
+    parts.iter().for_each(|part| mac.update(part));
+    mac.finalize().into_bytes().into()
+}
+
+/// A password's NT hash, MD4 over it in UTF-16LE. What smbpasswd files
+/// keep rather than the password itself.
+pub fn nt_hash(password: &str) -> [u8; 16] {
+    Md4::digest(encode_utf16le(password)).into()
+}
+
+/// NTOWFv2, the key NTLMv2 responses are made with: the NT hash over the
+/// uppercased user name and the domain just as the client gave it.
+pub fn ntowf_v2(nt_hash: &[u8; 16], user: &str, domain: &str) -> [u8; 16] {
+    let identity = encode_utf16le(&(user.to_uppercase() + domain));
+    hmac_md5(nt_hash, &[&identity])
+}
+
+/// The blob an NTLMv2 response proves knowledge of the password over,
+/// made at `timestamp` (a FILETIME) with the client's own challenge.
+pub fn ntlmv2_blob(timestamp: u64, client_challenge: [u8; 8], target_info: &[AvPair]) -> Vec<u8> {
+    let mut out = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    out.extend(timestamp.to_le_bytes());
+    out.extend(client_challenge);
+    out.extend([0; 4]);
+    out.extend(av_pairs_to_vec(target_info));
+    out.extend([0; 4]);
+    out
+}
+
+/// The NTLMv2 response to `server_challenge`: the NTProofStr, then `blob`.
+pub fn ntlmv2_response(ntowf_v2: &[u8; 16], server_challenge: &[u8; 8], blob: &[u8]) -> Vec<u8> {
+    let mut out = hmac_md5(ntowf_v2, &[server_challenge, blob]).to_vec();
+    out.extend(blob);
+    out
+}
+
+/// The key both sides work out from an NTLMv2 response without sending
+/// it, which for NTLMv2 is also the key exchange key.
+pub fn session_base_key(ntowf_v2: &[u8; 16], nt_proof_str: &[u8]) -> [u8; 16] {
+    hmac_md5(ntowf_v2, &[nt_proof_str])
+}
+
+/// RC4, all KEY_EXCH encrypts the client's session key with.
+pub fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j: u8 = 0;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            byte ^ s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// The MIC over all three messages, as they were sent, with the
+/// AUTHENTICATE's own MIC taken to be zero.
+pub fn mic(
+    exported_session_key: &[u8; 16],
+    negotiate: &[u8],
+    challenge: &[u8],
+    authenticate: &[u8],
+) -> [u8; 16] {
+    let mut authenticate = authenticate.to_vec();
+    if let Some(mic) = authenticate.get_mut(MIC_OFFSET..PAYLOAD_WITH_MIC) {
+        mic.fill(0);
+    }
+    hmac_md5(exported_session_key, &[negotiate, challenge, &authenticate])
+}
+
+/// Which way a signed message is going, each having keys of its own.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// The signature over `message`, the `seq_num`th to go `direction`, made
+/// with the keys that come from `exported_session_key` and the negotiated
+/// `flags`. It's what SPNEGO's mechListMIC is. Only with extended session
+/// security, None without it, the older kind not being worth checking.
+pub fn signature(
+    exported_session_key: &[u8; 16],
+    flags: NegotiateFlags,
+    direction: Direction,
+    seq_num: u32,
+    message: &[u8],
+) -> Option<[u8; 16]> {
+    if !flags.contains(NegotiateFlags::EXTENDED_SESSIONSECURITY) {
+        return None;
+    }
+    let (signing_magic, sealing_magic) = match direction {
+        Direction::ClientToServer => (CLIENT_SIGNING_MAGIC, CLIENT_SEALING_MAGIC),
+        Direction::ServerToClient => (SERVER_SIGNING_MAGIC, SERVER_SEALING_MAGIC),
+    };
+    let signing_key = Md5::digest([&exported_session_key[..], signing_magic].concat());
+    let mut checksum = hmac_md5(&signing_key, &[&seq_num.to_le_bytes(), message])[..8].to_vec();
+    if flags.contains(NegotiateFlags::KEY_EXCH) {
+        checksum = rc4(
+            &sealing_key(exported_session_key, flags, sealing_magic),
+            &checksum,
+        );
+    }
+    let mut out = [0; 16];
+    out[..4].copy_from_slice(&1u32.to_le_bytes());
+    out[4..12].copy_from_slice(&checksum);
+    out[12..].copy_from_slice(&seq_num.to_le_bytes());
+    Some(out)
+}
+
+/// The key a direction's signatures are sealed with, weakened to however
+/// strong the flags say it can be.
+fn sealing_key(exported_session_key: &[u8; 16], flags: NegotiateFlags, magic: &[u8]) -> [u8; 16] {
+    let len = if flags.contains(NegotiateFlags::KEY_128) {
+        16
+    } else if flags.contains(NegotiateFlags::KEY_56) {
+        7
+    } else {
+        5
+    };
+    Md5::digest([&exported_session_key[..len], magic].concat()).into()
+}
+
+/// Compared in constant time, so how long this takes says nothing about
+/// how much of a forged proof was right.
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// The server's side of an exchange, once it's answered the client's
+/// NEGOTIATE and until the AUTHENTICATE comes back. Keeps both messages
+/// as they were sent, for the MIC.
+pub struct ServerChallenge {
+    negotiate: Vec<u8>,
+    challenge: Vec<u8>,
+    server_challenge: [u8; 8],
+    flags: NegotiateFlags,
+}
+
+impl ServerChallenge {
+    /// Answers `negotiate` as a standalone server called `computer` in
+    /// `domain`, going along with whichever of the client's flags we can.
+    /// `timestamp` is now, as a FILETIME. None if it isn't a NEGOTIATE.
+    pub fn new(
+        negotiate: &[u8],
+        computer: &str,
+        domain: &str,
+        timestamp: u64,
+    ) -> Option<ServerChallenge> {
+        let mut server_challenge = [0; 8];
+        getrandom::getrandom(&mut server_challenge)
+            .expect("the OS should have random bytes to give");
+        Self::with_challenge(negotiate, computer, domain, timestamp, server_challenge)
+    }
+
+    fn with_challenge(
+        negotiate: &[u8],
+        computer: &str,
+        domain: &str,
+        timestamp: u64,
+        server_challenge: [u8; 8],
+    ) -> Option<ServerChallenge> {
+        let (_remaining, request) = NtlmNegotiate::parse(negotiate).ok()?;
+        let mut flags = (request.flags & OPTIONAL_FLAGS)
+            | NegotiateFlags::REQUEST_TARGET
+            | NegotiateFlags::NTLM
+            | NegotiateFlags::TARGET_TYPE_SERVER
+            | NegotiateFlags::TARGET_INFO;
+        if !flags.contains(NegotiateFlags::UNICODE) {
+            flags |= NegotiateFlags::OEM;
+        }
+        let challenge = NtlmChallenge {
+            flags,
+            target_name: computer.to_uppercase(),
+            server_challenge,
+            target_info: vec![
+                AvPair::name(MSV_AV_NB_DOMAIN_NAME, &domain.to_uppercase()),
+                AvPair::name(MSV_AV_NB_COMPUTER_NAME, &computer.to_uppercase()),
+                AvPair::name(MSV_AV_DNS_DOMAIN_NAME, &domain.to_lowercase()),
+                AvPair::name(MSV_AV_DNS_COMPUTER_NAME, &computer.to_lowercase()),
+                AvPair {
+                    id: MSV_AV_TIMESTAMP,
+                    value: timestamp.to_le_bytes().to_vec(),
+                },
+            ],
+        };
+        Some(ServerChallenge {
+            negotiate: negotiate.to_vec(),
+            challenge: challenge.to_vec(),
+            server_challenge,
+            flags,
+        })
+    }
+
+    /// The CHALLENGE to send the client.
+    pub fn message(&self) -> &[u8] {
+        &self.challenge
+    }
+
+    /// What the CHALLENGE said the server would do, which whatever
+    /// the AUTHENTICATE says is narrowed down from.
+    pub fn flags(&self) -> NegotiateFlags {
+        self.flags
+    }
+
+    /// Checks the NTLMv2 response in `authenticate` (`raw` being how it came
+    /// in) proves the client knows the password `nt_hash` is of, along with
+    /// its MIC if it says it has one. Gives back the exported session key,
+    /// what SMB2 derives its keys from.
+    pub fn verify(
+        &self,
+        authenticate: &NtlmAuthenticate,
+        raw: &[u8],
+        nt_hash: &[u8; 16],
+    ) -> Option<[u8; 16]> {
+        // anything shorter is an NTLMv1 response, or no response at all.
+        if authenticate.nt_response.len() < NT_PROOF_STR_SIZE + BLOB_AV_PAIRS_OFFSET {
+            return None;
+        }
+        let (nt_proof_str, blob) = authenticate.nt_response.split_at(NT_PROOF_STR_SIZE);
+        let key = ntowf_v2(nt_hash, &authenticate.user, &authenticate.domain);
+        let expected = ntlmv2_response(&key, &self.server_challenge, blob);
+        if !equal(&expected[..NT_PROOF_STR_SIZE], nt_proof_str) {
+            return None;
+        }
+        let key_exchange_key = session_base_key(&key, nt_proof_str);
+        let exported_session_key =
+            match (self.flags & authenticate.flags).contains(NegotiateFlags::KEY_EXCH) {
+                true => {
+                    let encrypted: &[u8; 16] = authenticate
+                        .encrypted_random_session_key
+                        .as_slice()
+                        .try_into()
+                        .ok()?;
+                    rc4(&key_exchange_key, encrypted).try_into().unwrap()
+                }
+                false => key_exchange_key,
+            };
+        let (_remaining, pairs) = parse_av_pairs(&blob[BLOB_AV_PAIRS_OFFSET..]).ok()?;
+        let mic_present = pairs.iter().any(|pair| {
+            pair.id == MSV_AV_FLAGS
+                && pair.value.get(..4).is_some_and(|flags| {
+                    u32::from_le_bytes(flags.try_into().unwrap()) & MSV_AV_FLAG_MIC_PRESENT != 0
+                })
+        });
+        if mic_present {
+            let expected = mic(&exported_session_key, &self.negotiate, &self.challenge, raw);
+            if !equal(&authenticate.mic?, &expected) {
+                return None;
+            }
+        }
+        Some(exported_session_key)
+    }
+}
+
+fn fail<T>(body: &[u8]) -> nom::IResult<&[u8], T, nom::error::Error<&[u8]>> {
+    Err(nom::Err::Error(nom::error::Error::new(
+        body,
+        nom::error::ErrorKind::Verify,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // MS-NLMP's NTLMv2 example, 4.2.4.
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+
+    fn example_target_info() -> Vec<AvPair> {
+        vec![
+            AvPair::name(MSV_AV_NB_DOMAIN_NAME, "Domain"),
+            AvPair::name(MSV_AV_NB_COMPUTER_NAME, "Server"),
+        ]
+    }
+
+    #[test]
+    fn ntlmv2_example() {
+        let nt_hash = nt_hash("Password");
+        assert_eq!(nt_hash.to_vec(), hex("a4f49c406510bdcab6824ee7c30fd852"));
+        let key = ntowf_v2(&nt_hash, "User", "Domain");
+        assert_eq!(key.to_vec(), hex("0c868a403bfd7a93a3001ef22ef02e3f"));
+        let blob = ntlmv2_blob(0, [0xaa; 8], &example_target_info());
+        let response = ntlmv2_response(&key, &SERVER_CHALLENGE, &blob);
+        assert_eq!(
+            response[..16].to_vec(),
+            hex("68cd0ab851e51c96aabc927bebef6a1c")
+        );
+        let session_base_key = session_base_key(&key, &response[..16]);
+        assert_eq!(
+            session_base_key.to_vec(),
+            hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+        );
+        let encrypted = rc4(&session_base_key, &[0x55; 16]);
+        assert_eq!(encrypted, hex("c5dad2544fc9799094ce1ce90bc9d03e"));
+        assert_eq!(rc4(&session_base_key, &encrypted), [0x55; 16]);
+    }
+
+    #[test]
+    fn av_pairs() {
+        #[rustfmt::skip]
+        let encoded = [
+            0x02, 0x00, 0x0c, 0x00,
+            b'D', 0, b'o', 0, b'm', 0, b'a', 0, b'i', 0, b'n', 0,
+            0x07, 0x00, 0x08, 0x00,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let (remaining, pairs) = parse_av_pairs(&encoded).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            pairs,
+            [
+                AvPair::name(MSV_AV_NB_DOMAIN_NAME, "Domain"),
+                AvPair {
+                    id: MSV_AV_TIMESTAMP,
+                    value: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            ]
+        );
+        assert_eq!(av_pairs_to_vec(&pairs), encoded);
+        // without the MsvAvEOL, the list never ends.
+        assert!(parse_av_pairs(&encoded[..28]).is_err());
+    }
+
+    #[test]
+    fn negotiate() {
+        #[rustfmt::skip]
+        let encoded = [
+            b'N', b'T', b'L', b'M', b'S', b'S', b'P', 0,
+            0x01, 0x00, 0x00, 0x00,
+            // UNICODE | REQUEST_TARGET | NTLM | OEM_DOMAIN_SUPPLIED | EXTENDED_SESSIONSECURITY
+            0x05, 0x12, 0x08, 0x00,
+            // domain, at 40
+            0x03, 0x00, 0x03, 0x00, 0x28, 0x00, 0x00, 0x00,
+            // workstation, none
+            0x00, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b'D', b'O', b'M',
+        ];
+        let (_remaining, message) = NtlmMessage::parse(&encoded).unwrap();
+        let expected = NtlmNegotiate {
+            flags: NegotiateFlags::UNICODE
+                | NegotiateFlags::REQUEST_TARGET
+                | NegotiateFlags::NTLM
+                | NegotiateFlags::OEM_DOMAIN_SUPPLIED
+                | NegotiateFlags::EXTENDED_SESSIONSECURITY,
+            domain: "DOM".into(),
+            workstation: "".into(),
+        };
+        assert_eq!(message, NtlmMessage::Negotiate(expected));
+        let NtlmMessage::Negotiate(negotiate) = message else {
+            unreachable!()
+        };
+        assert_eq!(negotiate.to_vec(), encoded);
+        // a domain that runs off the end.
+        let mut truncated = encoded;
+        truncated[16] = 4;
+        assert!(NtlmMessage::parse(&truncated).is_err());
+        assert!(NtlmMessage::parse(b"NTLMSSP\0\x04\0\0\0").is_err());
+    }
+
+    #[test]
+    fn challenge_round_trip() {
+        let negotiate = NtlmNegotiate {
+            flags: NegotiateFlags::UNICODE
+                | NegotiateFlags::NTLM
+                | NegotiateFlags::KEY_EXCH
+                | NegotiateFlags::LM_KEY,
+            domain: "".into(),
+            workstation: "".into(),
+        };
+        let server = ServerChallenge::with_challenge(
+            &negotiate.to_vec(),
+            "Server",
+            "Domain",
+            0x01d0_0000_0000_0000,
+            SERVER_CHALLENGE,
+        )
+        .unwrap();
+        let (_remaining, NtlmMessage::Challenge(challenge)) =
+            NtlmMessage::parse(server.message()).unwrap()
+        else {
+            panic!("not a challenge");
+        };
+        // LM_KEY isn't something we do.
+        assert_eq!(
+            challenge.flags,
+            NegotiateFlags::UNICODE
+                | NegotiateFlags::REQUEST_TARGET
+                | NegotiateFlags::NTLM
+                | NegotiateFlags::TARGET_TYPE_SERVER
+                | NegotiateFlags::TARGET_INFO
+                | NegotiateFlags::KEY_EXCH
+        );
+        assert_eq!(challenge.target_name, "SERVER");
+        assert_eq!(challenge.server_challenge, SERVER_CHALLENGE);
+        assert_eq!(challenge.target_info[0], AvPair::name(2, "DOMAIN"));
+        assert_eq!(challenge.target_info[3], AvPair::name(3, "server"));
+        assert_eq!(challenge.to_vec(), server.message());
+    }
+
+    /// The client's side of MS-NLMP's example, against a challenge from
+    /// us, with a MIC if `with_mic`.
+    fn authenticate(server: &ServerChallenge, password: &str, with_mic: bool) -> Vec<u8> {
+        let (_remaining, challenge) = NtlmChallenge::parse(server.message()).unwrap();
+        let key = ntowf_v2(&nt_hash(password), "User", "Domain");
+        let mut target_info = challenge.target_info.clone();
+        if with_mic {
+            target_info.push(AvPair {
+                id: MSV_AV_FLAGS,
+                value: MSV_AV_FLAG_MIC_PRESENT.to_le_bytes().to_vec(),
+            });
+        }
+        let blob = ntlmv2_blob(0, [0xaa; 8], &target_info);
+        let nt_response = ntlmv2_response(&key, &challenge.server_challenge, &blob);
+        let session_base_key = session_base_key(&key, &nt_response[..16]);
+        let mut authenticate = NtlmAuthenticate {
+            flags: challenge.flags,
+            lm_response: vec![0; 24],
+            nt_response,
+            domain: "Domain".into(),
+            user: "User".into(),
+            workstation: "COMPUTER".into(),
+            encrypted_random_session_key: rc4(&session_base_key, &[0x55; 16]),
+            mic: with_mic.then_some([0; 16]),
+        };
+        let mut encoded = authenticate.to_vec();
+        if with_mic {
+            authenticate.mic = Some(mic(
+                &[0x55; 16],
+                &server.negotiate,
+                server.message(),
+                &encoded,
+            ));
+            encoded = authenticate.to_vec();
+        }
+        encoded
+    }
+
+    #[test]
+    fn verify_ntlmv2() {
+        let negotiate = NtlmNegotiate {
+            flags: NegotiateFlags::UNICODE | NegotiateFlags::NTLM | NegotiateFlags::KEY_EXCH,
+            domain: "".into(),
+            workstation: "".into(),
+        }
+        .to_vec();
+        let server =
+            ServerChallenge::with_challenge(&negotiate, "Server", "Domain", 0, SERVER_CHALLENGE)
+                .unwrap();
+        for with_mic in [false, true] {
+            let raw = authenticate(&server, "Password", with_mic);
+            let (_remaining, NtlmMessage::Authenticate(parsed)) = NtlmMessage::parse(&raw).unwrap()
+            else {
+                panic!("not an authenticate");
+            };
+            assert_eq!(parsed.user, "User");
+            assert_eq!(parsed.mic.is_some(), with_mic);
+            assert!(!parsed.is_anonymous());
+            assert_eq!(parsed.to_vec(), raw);
+            // the client's key, not the one both sides worked out.
+            assert_eq!(
+                server.verify(&parsed, &raw, &nt_hash("Password")),
+                Some([0x55; 16])
+            );
+            assert_eq!(server.verify(&parsed, &raw, &nt_hash("password")), None);
+        }
+
+        // a MIC that doesn't cover what was sent.
+        let mut raw = authenticate(&server, "Password", true);
+        let (_remaining, mut parsed) = NtlmAuthenticate::parse(&raw).unwrap();
+        parsed.mic.as_mut().unwrap()[0] ^= 1;
+        raw[MIC_OFFSET] ^= 1;
+        assert_eq!(server.verify(&parsed, &raw, &nt_hash("Password")), None);
+
+        // NTLMv1 doesn't get a look in.
+        parsed.nt_response.truncate(24);
+        assert_eq!(server.verify(&parsed, &raw, &nt_hash("Password")), None);
+    }
+
+    #[test]
+    fn signature_example() {
+        // MS-NLMP's GSS_WrapEx example, 4.2.4.4, which seals "Plaintext"
+        // with the same RC4 handle before sealing its checksum.
+        let flags = NegotiateFlags::KEY_EXCH
+            | NegotiateFlags::KEY_56
+            | NegotiateFlags::KEY_128
+            | NegotiateFlags::EXTENDED_SESSIONSECURITY
+            | NegotiateFlags::SIGN
+            | NegotiateFlags::SEAL;
+        let plaintext = encode_utf16le("Plaintext");
+        let sealing_key = sealing_key(&[0x55; 16], flags, CLIENT_SEALING_MAGIC);
+        let unsealed = signature(
+            &[0x55; 16],
+            flags - NegotiateFlags::KEY_EXCH,
+            Direction::ClientToServer,
+            0,
+            &plaintext,
+        )
+        .unwrap();
+        let sealed = rc4(&sealing_key, &[&plaintext[..], &unsealed[4..12]].concat());
+        assert_eq!(
+            sealed[..18].to_vec(),
+            hex("54e50165bf1936dc996020c1811b0f06fb5f")
+        );
+        assert_eq!(sealed[18..].to_vec(), hex("7fb38ec5c55d4976"));
+        assert_eq!(unsealed[..4], [1, 0, 0, 0]);
+        assert_eq!(unsealed[12..], [0, 0, 0, 0]);
+
+        // and nothing at all without extended session security.
+        let flags = flags - NegotiateFlags::EXTENDED_SESSIONSECURITY;
+        assert_eq!(
+            signature(&[0x55; 16], flags, Direction::ClientToServer, 0, b""),
+            None
+        );
+    }
+
+    #[test]
+    fn anonymous() {
+        let authenticate = NtlmAuthenticate {
+            flags: NegotiateFlags::UNICODE | NegotiateFlags::ANONYMOUS,
+            lm_response: vec![0],
+            nt_response: vec![],
+            domain: "".into(),
+            user: "".into(),
+            workstation: "".into(),
+            encrypted_random_session_key: vec![],
+            mic: None,
+        };
+        let encoded = authenticate.to_vec();
+        let (_remaining, parsed) = NtlmAuthenticate::parse(&encoded).unwrap();
+        assert!(parsed.is_anonymous());
+        assert_eq!(parsed, authenticate);
+    }
+}
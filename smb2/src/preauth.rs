@@ -0,0 +1,64 @@
+//! The SMB 3.1.1 preauthentication integrity hash, a running SHA-512 over
+//! the NEGOTIATE and SESSION_SETUP messages that went back and forth before
+//! there was a key to sign them with. Both sides derive their keys from it,
+//! so if anything in between was tampered with they won't agree on them.
+
+use sha2::{Digest, Sha512};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreauthIntegrityHash([u8; 64]);
+
+/// All zeroes, what the hash starts from before the NEGOTIATE request.
+impl Default for PreauthIntegrityHash {
+    fn default() -> Self {
+        Self([0; 64])
+    }
+}
+
+impl PreauthIntegrityHash {
+    /// Chains in `message`, the whole thing as it was sent, header and all.
+    pub fn update(&mut self, message: &[u8]) {
+        let mut hasher = Sha512::new();
+        hasher.update(self.0);
+        hasher.update(message);
+        self.0 = hasher.finalize().into();
+    }
+
+    /// What the keys are derived from, once the last SESSION_SETUP request is in.
+    pub fn value(&self) -> &[u8; 64] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chained_over_every_message() {
+        let mut hash = PreauthIntegrityHash::default();
+        hash.update(b"NEGOTIATE");
+        assert_eq!(
+            hash.value().to_vec(),
+            hex(concat!(
+                "4d7763d2ce39d624b4cb626195d2a17132b137def50b274176a9a80534322fa8",
+                "75a4dbe6c1dac4c0f55a618249fbf4058698381bf4d95b674103e7ca7f83552a",
+            ))
+        );
+        hash.update(b"NEGOTIATE response");
+        assert_eq!(
+            hash.value().to_vec(),
+            hex(concat!(
+                "9d49826973ec13fed0cfc35898f5560d566b99bc5eb30002652b30dcf53e0747",
+                "35af6de8f1e81173a69163007ee4fb974912cfe3169ff0ec4295422f1da917d1",
+            ))
+        );
+    }
+}
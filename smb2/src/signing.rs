@@ -0,0 +1,266 @@
+//! Message signing. Once a session has a key, either side can sign what it
+//! sends so the other can tell it wasn't tampered with on the way, which
+//! algorithm depends on the dialect and, for 3.1.1, what was negotiated.
+
+use aes::Aes128;
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce};
+use cmac::Cmac;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::message::{Command, Dialect, HeaderFlags, SigningAlgorithm};
+use crate::preauth::PreauthIntegrityHash;
+
+// where things the signature depends on live in the header.
+const FLAGS_OFFSET: usize = 16;
+const COMMAND_OFFSET: usize = 12;
+const MESSAGE_ID_OFFSET: usize = 24;
+const SIGNATURE_OFFSET: usize = 48;
+const SIGNATURE_SIZE: usize = 16;
+const HEADER_SIZE: usize = SIGNATURE_OFFSET + SIGNATURE_SIZE;
+
+/// SP800-108's KDF in counter mode with HMAC-SHA256, what every SMB 3 key
+/// is derived from the session key with. `label` and `context` are taken as
+/// is, SMB's come with their NUL terminators. Gives back `length` bytes.
+pub fn kdf(key: &[u8], label: &[u8], context: &[u8], length: usize) -> Vec<u8> {
+    let bits = (length as u32 * 8).to_be_bytes();
+    let mut out = Vec::with_capacity(length.next_multiple_of(32));
+    for i in 1..=length.div_ceil(32) as u32 {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+        mac.update(&i.to_be_bytes());
+        mac.update(label);
+        mac.update(&[0]);
+        mac.update(context);
+        mac.update(&bits);
+        out.extend(mac.finalize().into_bytes());
+    }
+    out.truncate(length);
+    out
+}
+
+/// What a session's messages are signed with.
+#[derive(Clone)]
+pub struct SigningKey {
+    algorithm: SigningAlgorithm,
+    key: [u8; 16],
+}
+
+/// Keys don't belong in logs.
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    /// The key for a session set up with `session_key` on a connection that
+    /// negotiated `dialect`. `negotiated` is what SIGNING_CAPABILITIES settled
+    /// on, if the client sent it, and `preauth_integrity` the session's final
+    /// preauth integrity hash, both of which only 3.1.1 has.
+    pub fn new(
+        dialect: Dialect,
+        negotiated: Option<SigningAlgorithm>,
+        session_key: &[u8],
+        preauth_integrity: Option<&PreauthIntegrityHash>,
+    ) -> SigningKey {
+        // only the first 16 bytes of the session key are ever used.
+        let mut truncated = [0; 16];
+        let len = session_key.len().min(16);
+        truncated[..len].copy_from_slice(&session_key[..len]);
+        let derive = |label: &[u8], context: &[u8]| -> [u8; 16] {
+            kdf(&truncated, label, context, 16).try_into().unwrap()
+        };
+        match dialect {
+            Dialect::Smb202 | Dialect::Smb210 | Dialect::Wildcard => SigningKey {
+                algorithm: SigningAlgorithm::HmacSha256,
+                key: truncated,
+            },
+            Dialect::Smb300 | Dialect::Smb302 => SigningKey {
+                algorithm: SigningAlgorithm::AesCmac,
+                key: derive(b"SMB2AESCMAC\0", b"SmbSign\0"),
+            },
+            Dialect::Smb311 => SigningKey {
+                algorithm: negotiated.unwrap_or(SigningAlgorithm::AesCmac),
+                key: derive(
+                    b"SMBSigningKey\0",
+                    preauth_integrity
+                        .map(|preauth_integrity| preauth_integrity.value().as_slice())
+                        .unwrap_or_default(),
+                ),
+            },
+        }
+    }
+
+    pub fn algorithm(&self) -> SigningAlgorithm {
+        self.algorithm
+    }
+
+    /// The signature of `message`, a whole message as it goes on the wire
+    /// (padding included, if it's part of a compound), computed as if its
+    /// signature field were zero. None if it's too short to have a header.
+    fn signature(&self, message: &[u8]) -> Option<[u8; SIGNATURE_SIZE]> {
+        if message.len() < HEADER_SIZE {
+            return None;
+        }
+        let zeroes = [0; SIGNATURE_SIZE];
+        let parts = [
+            &message[..SIGNATURE_OFFSET],
+            &zeroes[..],
+            &message[HEADER_SIZE..],
+        ];
+        let signature = match self.algorithm {
+            SigningAlgorithm::HmacSha256 => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap();
+                parts.iter().for_each(|part| mac.update(part));
+                mac.finalize().into_bytes()[..SIGNATURE_SIZE]
+                    .try_into()
+                    .unwrap()
+            }
+            SigningAlgorithm::AesCmac => {
+                let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(&self.key).unwrap();
+                parts.iter().for_each(|part| mac.update(part));
+                mac.finalize().into_bytes().into()
+            }
+            SigningAlgorithm::AesGmac => {
+                // the MessageId, then whether it's a response and whether it's a CANCEL.
+                let mut nonce = [0; 12];
+                nonce[..8].copy_from_slice(&message[MESSAGE_ID_OFFSET..MESSAGE_ID_OFFSET + 8]);
+                let flags =
+                    u32::from_le_bytes(message[FLAGS_OFFSET..FLAGS_OFFSET + 4].try_into().unwrap());
+                let command = u16::from_le_bytes(
+                    message[COMMAND_OFFSET..COMMAND_OFFSET + 2]
+                        .try_into()
+                        .unwrap(),
+                );
+                if HeaderFlags::from_bits_retain(flags).contains(HeaderFlags::SERVER_TO_REDIR) {
+                    nonce[8] |= 0x1;
+                }
+                if command == Command::Cancel as u16 {
+                    nonce[8] |= 0x2;
+                }
+                // GMAC is GCM with nothing to encrypt, and everything authenticated.
+                let aad = parts.concat();
+                let tag = Aes128Gcm::new_from_slice(&self.key)
+                    .unwrap()
+                    .encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut [])
+                    .unwrap();
+                tag.into()
+            }
+        };
+        Some(signature)
+    }
+
+    /// Sets SMB2_FLAGS_SIGNED in `message`'s header and fills in its signature.
+    pub fn sign(&self, message: &mut [u8]) {
+        if message.len() < HEADER_SIZE {
+            return;
+        }
+        let flags = u32::from_le_bytes(message[FLAGS_OFFSET..FLAGS_OFFSET + 4].try_into().unwrap())
+            | HeaderFlags::SIGNED.bits();
+        message[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&flags.to_le_bytes());
+        let signature = self.signature(message).unwrap();
+        message[SIGNATURE_OFFSET..HEADER_SIZE].copy_from_slice(&signature);
+    }
+
+    /// Whether `message` carries the signature it should.
+    pub fn verify(&self, message: &[u8]) -> bool {
+        let Some(signature) = self.signature(message) else {
+            return false;
+        };
+        // compared in constant time, so how long this takes says nothing
+        // about how much of a forged signature was right.
+        message[SIGNATURE_OFFSET..HEADER_SIZE]
+            .iter()
+            .zip(signature)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::SmbMessageHeaderVariant;
+    use crate::message::{SmbBody, SmbEcho, SmbMessage, SmbMessageHeader};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // from Microsoft's "the anatomy of signing and cryptographic keys".
+    const SESSION_KEY: &str = "7cd451825d0450d235424e44ba6e78cc";
+
+    fn echo() -> Vec<u8> {
+        SmbMessage {
+            header: SmbMessageHeader {
+                protocol_id: u32::from_ne_bytes([0xFE, b'S', b'M', b'B']),
+                header_size: HEADER_SIZE as u16,
+                credit_charge: 1,
+                status: ntstatus::STATUS_SUCCESS,
+                command: Command::Echo,
+                credit_request_response: 1,
+                flags: HeaderFlags::empty(),
+                next_command: 0,
+                message_id: 5,
+                variant: SmbMessageHeaderVariant::Sync { tree_id: 0 },
+                session_id: 0x1234,
+                signature: 0,
+            },
+            body: SmbBody::Echo(SmbEcho { size: 4 }),
+        }
+        .to_vec()
+    }
+
+    #[test]
+    fn signing_key_derivation() {
+        assert_eq!(
+            kdf(&hex(SESSION_KEY), b"SMB2AESCMAC\0", b"SmbSign\0", 16),
+            hex("0b7e9c5cac36c0f6ea9ab275298cedce")
+        );
+        let key = SigningKey::new(Dialect::Smb302, None, &hex(SESSION_KEY), None);
+        assert_eq!(key.algorithm(), SigningAlgorithm::AesCmac);
+        assert_eq!(key.key.to_vec(), hex("0b7e9c5cac36c0f6ea9ab275298cedce"));
+        // what came before the session's keys changes all of them.
+        let mut preauth_integrity = PreauthIntegrityHash::default();
+        preauth_integrity.update(b"NEGOTIATE");
+        let key = SigningKey::new(
+            Dialect::Smb311,
+            Some(SigningAlgorithm::AesGmac),
+            &hex(SESSION_KEY),
+            Some(&preauth_integrity),
+        );
+        assert_eq!(key.algorithm(), SigningAlgorithm::AesGmac);
+        assert_eq!(key.key.to_vec(), hex("85170d322d081f47cde9f14b36bb87bb"));
+    }
+
+    #[test]
+    fn signatures() {
+        let session_key = hex(SESSION_KEY);
+        let cases = [
+            (Dialect::Smb210, None, "8e831d85637ac39776f6a365d6f65f12"),
+            (Dialect::Smb300, None, "12866d1743df8441f1db7611f96c257f"),
+            (
+                Dialect::Smb311,
+                Some(SigningAlgorithm::AesGmac),
+                "d1b20cd5abf6968282faf067c26e4f05",
+            ),
+        ];
+        for (dialect, algorithm, signature) in cases {
+            let key = SigningKey::new(dialect, algorithm, &session_key, None);
+            let mut message = echo();
+            key.sign(&mut message);
+            assert_eq!(&message[16..20], [0x08, 0, 0, 0]);
+            assert_eq!(message[48..64].to_vec(), hex(signature), "{dialect:?}");
+            assert!(key.verify(&message));
+            message[66] ^= 1;
+            assert!(!key.verify(&message));
+        }
+        assert!(!SigningKey::new(Dialect::Smb300, None, &session_key, None).verify(&echo()));
+    }
+}
@@ -0,0 +1,305 @@
+//! SPNEGO, the wrapper clients put around NTLMSSP (or Kerberos) in
+//! SESSION_SETUP's security buffer so both sides can agree on which to use.
+//! The server lists what it can do in its NEGOTIATE response's NegTokenInit,
+//! the client's first SESSION_SETUP is a NegTokenInit saying which it picked
+//! with that mechanism's first token in it, and everything after that is a
+//! NegTokenResp carrying the rest of the exchange.
+//!
+//! Only as much DER as the tokens need is understood: lengths are definite,
+//! and fields this doesn't care about are skipped.
+
+use nom::bytes::complete::take;
+use nom::error::context;
+use nom::number::complete::u8 as be_u8;
+
+/// SPNEGO itself, 1.3.6.1.5.5.2, as the contents of its DER encoding.
+pub const SPNEGO_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+/// NTLMSSP, 1.3.6.1.4.1.311.2.2.10.
+pub const NTLMSSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a];
+
+const APPLICATION_0: u8 = 0x60;
+const SEQUENCE: u8 = 0x30;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+// the explicit context specific tags fields are numbered with.
+const CONTEXT_0: u8 = 0xa0;
+const CONTEXT_1: u8 = 0xa1;
+const CONTEXT_2: u8 = 0xa2;
+const CONTEXT_3: u8 = 0xa3;
+
+/// Where the exchange is at, as a NegTokenResp says.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NegState {
+    AcceptCompleted = 0,
+    AcceptIncomplete = 1,
+    Reject = 2,
+    RequestMic = 3,
+}
+
+impl TryFrom<u8> for NegState {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::AcceptCompleted),
+            1 => Ok(Self::AcceptIncomplete),
+            2 => Ok(Self::Reject),
+            3 => Ok(Self::RequestMic),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct NegTokenInit {
+    /// The mechanisms on offer, most preferred first, as OIDs.
+    pub mech_types: Vec<Vec<u8>>,
+    /// The first token of the first of `mech_types`, if it's started already.
+    pub mech_token: Option<Vec<u8>>,
+    pub mech_list_mic: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct NegTokenResp {
+    pub neg_state: Option<NegState>,
+    /// Which mechanism the server went with, in its first NegTokenResp.
+    pub supported_mech: Option<Vec<u8>>,
+    pub response_token: Option<Vec<u8>>,
+    /// A signature over the mechanism list, so neither side can be talked
+    /// into something weaker than they both could have done.
+    pub mech_list_mic: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpnegoToken {
+    Init(NegTokenInit),
+    Resp(NegTokenResp),
+}
+
+impl SpnegoToken {
+    pub fn parse(token: &[u8]) -> nom::IResult<&[u8], SpnegoToken, nom::error::Error<&[u8]>> {
+        let (remaining, (tag, _)) = tlv(token)?;
+        match tag {
+            APPLICATION_0 => NegTokenInit::parse(token).map(|(r, t)| (r, SpnegoToken::Init(t))),
+            CONTEXT_1 => NegTokenResp::parse(token).map(|(r, t)| (r, SpnegoToken::Resp(t))),
+            _ => fail(remaining),
+        }
+    }
+}
+
+impl NegTokenInit {
+    /// A NegTokenInit inside its GSS-API framing, as the first token
+    /// always is.
+    pub fn parse(token: &[u8]) -> nom::IResult<&[u8], NegTokenInit, nom::error::Error<&[u8]>> {
+        let (remaining, body) = expect(APPLICATION_0, token)?;
+        let (body, oid) = expect(OID, body)?;
+        if oid != SPNEGO_OID {
+            return fail(body);
+        }
+        let (_, body) = expect(CONTEXT_0, body)?;
+        let (_, mut fields) = expect(SEQUENCE, body)?;
+        let mut init = NegTokenInit::default();
+        while !fields.is_empty() {
+            let (rest, (tag, value)) = tlv(fields)?;
+            fields = rest;
+            match tag {
+                CONTEXT_0 => {
+                    let (_, mut oids) = expect(SEQUENCE, value)?;
+                    while !oids.is_empty() {
+                        let (rest, oid) = expect(OID, oids)?;
+                        init.mech_types.push(oid.to_vec());
+                        oids = rest;
+                    }
+                }
+                CONTEXT_2 => init.mech_token = Some(expect(OCTET_STRING, value)?.1.to_vec()),
+                CONTEXT_3 => init.mech_list_mic = Some(expect(OCTET_STRING, value)?.1.to_vec()),
+                // reqFlags, which nobody uses.
+                _ => {}
+            }
+        }
+        Ok((remaining, init))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut fields = der(CONTEXT_0, &mech_list_to_vec(&self.mech_types));
+        if let Some(mech_token) = &self.mech_token {
+            fields.extend(der(CONTEXT_2, &der(OCTET_STRING, mech_token)));
+        }
+        if let Some(mech_list_mic) = &self.mech_list_mic {
+            fields.extend(der(CONTEXT_3, &der(OCTET_STRING, mech_list_mic)));
+        }
+        let mut body = der(OID, SPNEGO_OID);
+        body.extend(der(CONTEXT_0, &der(SEQUENCE, &fields)));
+        der(APPLICATION_0, &body)
+    }
+}
+
+impl NegTokenResp {
+    pub fn parse(token: &[u8]) -> nom::IResult<&[u8], NegTokenResp, nom::error::Error<&[u8]>> {
+        let (remaining, body) = expect(CONTEXT_1, token)?;
+        let (_, mut fields) = expect(SEQUENCE, body)?;
+        let mut resp = NegTokenResp::default();
+        while !fields.is_empty() {
+            let (rest, (tag, value)) = tlv(fields)?;
+            fields = rest;
+            match tag {
+                CONTEXT_0 => {
+                    let (_, state) = expect(ENUMERATED, value)?;
+                    let state = match state {
+                        [state] => NegState::try_from(*state).ok(),
+                        _ => None,
+                    };
+                    resp.neg_state = Some(state.ok_or_else(|| error(value))?);
+                }
+                CONTEXT_1 => resp.supported_mech = Some(expect(OID, value)?.1.to_vec()),
+                CONTEXT_2 => resp.response_token = Some(expect(OCTET_STRING, value)?.1.to_vec()),
+                CONTEXT_3 => resp.mech_list_mic = Some(expect(OCTET_STRING, value)?.1.to_vec()),
+                _ => {}
+            }
+        }
+        Ok((remaining, resp))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut fields = vec![];
+        if let Some(neg_state) = self.neg_state {
+            fields.extend(der(CONTEXT_0, &der(ENUMERATED, &[neg_state as u8])));
+        }
+        if let Some(supported_mech) = &self.supported_mech {
+            fields.extend(der(CONTEXT_1, &der(OID, supported_mech)));
+        }
+        if let Some(response_token) = &self.response_token {
+            fields.extend(der(CONTEXT_2, &der(OCTET_STRING, response_token)));
+        }
+        if let Some(mech_list_mic) = &self.mech_list_mic {
+            fields.extend(der(CONTEXT_3, &der(OCTET_STRING, mech_list_mic)));
+        }
+        der(CONTEXT_1, &der(SEQUENCE, &fields))
+    }
+}
+
+/// The MechTypeList `mech_types` make up, DER encoded, which is what the
+/// mechListMIC is a signature over.
+pub fn mech_list_to_vec(mech_types: &[Vec<u8>]) -> Vec<u8> {
+    let oids: Vec<u8> = mech_types.iter().flat_map(|oid| der(OID, oid)).collect();
+    der(SEQUENCE, &oids)
+}
+
+/// A DER tag, and its value.
+type Tlv<'a> = (u8, &'a [u8]);
+
+/// A tag, then its length, then that many bytes of value.
+fn tlv(body: &[u8]) -> nom::IResult<&[u8], Tlv<'_>, nom::error::Error<&[u8]>> {
+    let (remaining, tag) = context("Failed to get DER tag", be_u8)(body)?;
+    let (remaining, first) = context("Failed to get DER length", be_u8)(remaining)?;
+    let (remaining, len) = match first {
+        0..=0x7f => (remaining, first as usize),
+        // indefinite lengths aren't DER, and nothing here is anywhere near 4GB.
+        0x81..=0x84 => {
+            let (remaining, len) =
+                context("Failed to get DER length", take(first & 0x7f))(remaining)?;
+            let len = len.iter().fold(0, |len, &byte| (len << 8) | byte as usize);
+            (remaining, len)
+        }
+        _ => return fail(body),
+    };
+    let (remaining, value) = context("Failed to get DER value", take(len))(remaining)?;
+    Ok((remaining, (tag, value)))
+}
+
+/// The value of a `tag` that has to be next.
+fn expect(tag: u8, body: &[u8]) -> nom::IResult<&[u8], &[u8], nom::error::Error<&[u8]>> {
+    let (remaining, (found, value)) = tlv(body)?;
+    if found != tag {
+        return fail(body);
+    }
+    Ok((remaining, value))
+}
+
+fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match value.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let bytes = (len as u32).to_be_bytes();
+            let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+            out.push(0x80 | (4 - skip) as u8);
+            out.extend(&bytes[skip..]);
+        }
+    }
+    out.extend(value);
+    out
+}
+
+fn error(body: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Error(nom::error::Error::new(body, nom::error::ErrorKind::Verify))
+}
+
+fn fail<T>(body: &[u8]) -> nom::IResult<&[u8], T, nom::error::Error<&[u8]>> {
+    Err(error(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neg_token_init() {
+        // what Windows sends, cut down to NTLMSSP, with a made up token.
+        #[rustfmt::skip]
+        let encoded = [
+            0x60, 0x28,
+            0x06, 0x06, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x02,
+            0xa0, 0x1e,
+            0x30, 0x1c,
+            0xa0, 0x0e,
+            0x30, 0x0c,
+            0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a,
+            0xa2, 0x0a,
+            0x04, 0x08, b'N', b'T', b'L', b'M', b'S', b'S', b'P', 0,
+        ];
+        let expected = NegTokenInit {
+            mech_types: vec![NTLMSSP_OID.to_vec()],
+            mech_token: Some(b"NTLMSSP\0".to_vec()),
+            mech_list_mic: None,
+        };
+        let (remaining, token) = SpnegoToken::parse(&encoded).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(token, SpnegoToken::Init(expected));
+        let SpnegoToken::Init(init) = token else {
+            unreachable!()
+        };
+        assert_eq!(init.to_vec(), encoded);
+        // a field that runs off the end.
+        assert!(SpnegoToken::parse(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn neg_token_resp() {
+        let resp = NegTokenResp {
+            neg_state: Some(NegState::AcceptIncomplete),
+            supported_mech: Some(NTLMSSP_OID.to_vec()),
+            // long enough to need a long form length.
+            response_token: Some(vec![0xaa; 200]),
+            mech_list_mic: Some(vec![0xbb; 16]),
+        };
+        let encoded = resp.to_vec();
+        assert_eq!(encoded[..4], [0xa1, 0x81, 0xf8, 0x30]);
+        let (remaining, token) = SpnegoToken::parse(&encoded).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(token, SpnegoToken::Resp(resp));
+
+        #[rustfmt::skip]
+        let completed = [
+            0xa1, 0x07, 0x30, 0x05, 0xa0, 0x03, 0x0a, 0x01, 0x00,
+        ];
+        let (_remaining, resp) = NegTokenResp::parse(&completed).unwrap();
+        assert_eq!(resp.neg_state, Some(NegState::AcceptCompleted));
+        assert_eq!(resp.to_vec(), completed);
+        let mut unknown_state = completed;
+        unknown_state[8] = 4;
+        assert!(NegTokenResp::parse(&unknown_state).is_err());
+    }
+}